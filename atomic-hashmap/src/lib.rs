@@ -17,6 +17,18 @@
 //!
 //! See [this blog post](https://ticki.github.io/blog/an-atomic-hash-table/)
 //! for details.
+//!
+//! # Growth
+//!
+//! The map never resizes, so there is no rehashing phase, in which writers
+//! would be blocked. It grows one bucket at a time instead: When an insertion
+//! collides with a leaf of another key, the leaf is replaced (through CAS) by
+//! a subtable containing both pairs. Only the colliding bucket is touched, and
+//! the replaced leaf is retired through `conc`, so readers holding a guard to
+//! it are unaffected.
+//!
+//! Hence, the latency of an insertion is bounded by the depth of the tree,
+//! rather than by the size of the map.
 
 #![feature(box_patterns)]
 
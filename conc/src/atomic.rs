@@ -4,8 +4,138 @@ use std::{mem, ptr};
 use std::sync::atomic::{self, AtomicPtr};
 use std::marker::PhantomData;
 
-use add_garbage_box;
 use guard::Guard;
+use nested::Retire;
+use shared::{self, Shared};
+use epoch::EpochGuard;
+use {add_garbage_box, add_garbage_box_epoch, add_garbage_box_with, destroy_or_add_garbage_box};
+use settings;
+
+/// Load a pointer, ordering only the accesses through it after the load.
+///
+/// This mimics the consume ordering, which isn't available in Rust. ARM and POWER order a load
+/// before the loads depending on its value (e.g. dereferencing the loaded pointer), so a relaxed
+/// load followed by a compiler fence (which keeps the compiler from reordering the dependent
+/// accesses) spares the barrier of an acquire load there. Elsewhere, acquire loads are cheap
+/// (x86) or needed (Alpha), so they are used instead.
+#[cfg(any(
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+))]
+fn load_consume<T>(ptr: &AtomicPtr<T>) -> *mut T {
+    let ptr = ptr.load(atomic::Ordering::Relaxed);
+    atomic::compiler_fence(atomic::Ordering::Acquire);
+    ptr
+}
+
+/// Load a pointer, ordering only the accesses through it after the load.
+///
+/// This target doesn't order dependent loads without a barrier, or the barrier is free anyway, so
+/// this is an acquire load.
+#[cfg(not(any(
+    target_arch = "arm",
+    target_arch = "aarch64",
+    target_arch = "powerpc",
+    target_arch = "powerpc64",
+)))]
+fn load_consume<T>(ptr: &AtomicPtr<T>) -> *mut T {
+    ptr.load(atomic::Ordering::Acquire)
+}
+
+/// How the values of an `Atomic` are reclaimed.
+///
+/// This is the second type parameter of `Atomic`, which is `Collect` by default. The policy is
+/// part of the type rather than a flag in the container, so `Atomic<T>` stays a single pointer,
+/// and the operations specific to a policy (e.g. `load_static()`) are only available for it.
+///
+/// Besides `Collect`, `Leak` and `Epoch`, a destructor (`fn(Box<T>)`) is a policy, which reclaims the
+/// values by passing them to it (see `Atomic::with_dtor()`). Only this policy takes up space in
+/// the container.
+///
+/// This trait is sealed, as the crate relies on the policies for soundness.
+pub trait Policy<T>: private::Sealed {
+    /// Are the values leaked rather than reclaimed?
+    #[doc(hidden)]
+    const LEAK: bool;
+    /// Can the values be loaded under an epoch?
+    #[doc(hidden)]
+    const EPOCH: bool = false;
+
+    /// Get the custom destructor of the values, if any.
+    #[doc(hidden)]
+    fn dtor(&self) -> Option<fn(Box<T>)>;
+}
+
+/// The default policy: Values are queued as garbage, once they are replaced.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Collect;
+
+impl<T> Policy<T> for Collect {
+    const LEAK: bool = false;
+
+    fn dtor(&self) -> Option<fn(Box<T>)> {
+        None
+    }
+}
+
+/// The policy of leaking containers: Values are never reclaimed.
+///
+/// See `Atomic::leaking()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Leak;
+
+impl<T> Policy<T> for Leak {
+    const LEAK: bool = true;
+
+    fn dtor(&self) -> Option<fn(Box<T>)> {
+        None
+    }
+}
+
+/// The policy of read-mostly containers: Values are held back until no epoch can read them.
+///
+/// See `Atomic::read_mostly()`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Epoch;
+
+impl<T> Policy<T> for Epoch {
+    const LEAK: bool = false;
+    const EPOCH: bool = true;
+
+    fn dtor(&self) -> Option<fn(Box<T>)> {
+        None
+    }
+}
+
+impl<T> Policy<T> for fn(Box<T>) {
+    const LEAK: bool = false;
+
+    fn dtor(&self) -> Option<fn(Box<T>)> {
+        Some(*self)
+    }
+}
+
+/// An `Atomic<T>`, which never reclaims its values.
+///
+/// See `Atomic::leaking()`.
+pub type LeakingAtomic<T> = Atomic<T, Leak>;
+
+/// An `Atomic<T>`, which is mostly read, and rarely written.
+///
+/// See `Atomic::read_mostly()`.
+pub type ReadMostly<T> = Atomic<T, Epoch>;
+
+mod private {
+    /// Prevents implementing `Policy` outside this crate.
+    pub trait Sealed {}
+
+    impl Sealed for super::Collect {}
+    impl Sealed for super::Leak {}
+    impl Sealed for super::Epoch {}
+    impl<T> Sealed for fn(Box<T>) {}
+}
 
 /// A concurrently accessible and updatable optional pointer.
 ///
@@ -16,7 +146,12 @@ use guard::Guard;
 /// or any variant thereof.
 ///
 /// It conveniently wraps this crate's API in a seamless manner.
-pub struct Atomic<T> {
+///
+/// Values of zero-sized types without destructors are never retired, as there is nothing to
+/// reclaim, so marker types can be stored without any overhead.
+///
+/// How the values are reclaimed is determined by the policy `P` (see `Policy`).
+pub struct Atomic<T, P: Policy<T> = Collect> {
     /// The inner atomic pointer.
     inner: AtomicPtr<T>,
     /// Make the `Sync` and `Send` (and other OIBITs) transitive.
@@ -30,6 +165,8 @@ pub struct Atomic<T> {
     ///
     /// `Send` is transitive for future-proofing.
     _marker: PhantomData<T>,
+    /// The reclamation policy.
+    policy: P,
 }
 
 impl<T> Atomic<T> {
@@ -39,9 +176,233 @@ impl<T> Atomic<T> {
             // Convert the box to a raw pointer.
             inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
             _marker: PhantomData,
+            policy: Collect,
         }
     }
 
+}
+
+impl<T> ReadMostly<T> {
+    /// Create a new `Atomic<T>`, which is mostly read, and rarely written.
+    ///
+    /// Besides `load()`, the values of this container can be loaded through `load_epoch()`, which
+    /// protects them by stamping the epoch of the thread rather than by a hazard, making the loads
+    /// nearly free. In turn, the values replaced are held back, until no thread can read them in
+    /// its epoch anymore, and every store advances the epoch. This suits data, which is read all
+    /// the time, but written seldom, e.g. configuration or routing tables.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// let config = conc::Atomic::read_mostly(Some(Box::new("fast")));
+    /// assert_eq!(*config.load_epoch(Ordering::Acquire).unwrap(), "fast");
+    ///
+    /// config.store(Some(Box::new("safe")), Ordering::Release);
+    /// assert_eq!(*config.load(Ordering::Acquire).unwrap(), "safe");
+    /// ```
+    pub fn read_mostly(init: Option<Box<T>>) -> ReadMostly<T> {
+        Atomic {
+            inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
+            _marker: PhantomData,
+            policy: Epoch,
+        }
+    }
+
+    /// Get a reference to the current content through the epoch of the current thread.
+    ///
+    /// Rather than through a hazard, the value is protected by the epoch of the current thread,
+    /// which the returned `EpochGuard` keeps stamped, making this considerably cheaper than
+    /// `load()`. The guard holds back every value retired from read-mostly containers, while it is
+    /// alive, so it should be dropped soon (see `Atomic::read_mostly()`).
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    ///
+    /// This is only available for read-mostly containers.
+    pub fn load_epoch(&self, ordering: atomic::Ordering) -> Option<EpochGuard<T>> {
+        EpochGuard::maybe_new(|| unsafe { shared::untagged(self.load_raw(ordering)).as_ref() })
+    }
+}
+
+impl<T> Atomic<T, fn(Box<T>)> {
+    /// Create a new `Atomic<T>`, whose values are destroyed by a custom destructor.
+    ///
+    /// When a value is reclaimed (at some point after it is replaced by `store()`, `swap()`, CAS,
+    /// or the container is dropped, and no guards protect it), its box is given to `dtor` rather
+    /// than being dropped. This is useful for values needing cleanup, which their `Drop` doesn't
+    /// do (e.g. gracefully closing a socket), as no call site replacing values can forget it.
+    ///
+    /// The destructor is the policy of the container (see `Policy`), which is hence of type
+    /// `Atomic<T, fn(Box<T>)>`.
+    pub fn with_dtor(init: Option<Box<T>>, dtor: fn(Box<T>)) -> Atomic<T, fn(Box<T>)> {
+        Atomic {
+            inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
+            _marker: PhantomData,
+            policy: dtor,
+        }
+    }
+}
+
+impl<T> LeakingAtomic<T> {
+    /// Create a new `Atomic<T>`, which never reclaims its values.
+    ///
+    /// Values stored in this container live forever: They are never queued as garbage, not even
+    /// when the container itself is dropped. This is useful for data which is effectively
+    /// immutable (e.g. interned tables or configuration set at startup), as it avoids the cost
+    /// of garbage tracking entirely, and allows the cheaper `load_static()`.
+    ///
+    /// Storing many values will of course leak memory, so this should only be used for data,
+    /// which rarely changes.
+    pub fn leaking(init: Option<Box<T>>) -> LeakingAtomic<T> {
+        Atomic {
+            inner: AtomicPtr::new(init.map_or(ptr::null_mut(), Box::into_raw)),
+            _marker: PhantomData,
+            policy: Leak,
+        }
+    }
+
+    /// Create a new leaking `Atomic<T>` holding a static value.
+    ///
+    /// This acts like `Atomic::leaking()`, except that it holds a reference rather than a box, so
+    /// nothing is allocated. Together with `store_static()`, `swap_static()`, and
+    /// `compare_and_store_static()`, this allows switching between a few static values (e.g. the
+    /// states of a state machine) without allocating or retiring anything.
+    pub fn from_static(init: Option<&'static T>) -> LeakingAtomic<T> {
+        Atomic {
+            inner: AtomicPtr::new(init.map_or(ptr::null_mut(), |x| x as *const T as *mut T)),
+            _marker: PhantomData,
+            policy: Leak,
+        }
+    }
+
+    /// Get a reference to the current content.
+    ///
+    /// As the values of a leaking container (see `Atomic::leaking()`) are never reclaimed, no
+    /// guard is needed to protect them, making this considerably cheaper than `load()`.
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    ///
+    /// This is only available for leaking containers:
+    ///
+    /// ```rust,compile_fail
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// conc::Atomic::new(Some(Box::new(2))).load_static(Ordering::Relaxed);
+    /// ```
+    pub fn load_static(&self, ordering: atomic::Ordering) -> Option<&'static T> {
+        // Since the value is never reclaimed, it lives for the rest of the program.
+        unsafe { shared::untagged(self.load_raw(ordering)).as_ref() }
+    }
+
+    /// Store a static value.
+    ///
+    /// This acts like `store()`, but nothing is allocated (see `Atomic::from_static()`).
+    pub fn store_static(&self, new: Option<&'static T>, ordering: atomic::Ordering) {
+        self.inner.store(new.map_or(ptr::null_mut(), |x| x as *const T as *mut T), ordering);
+    }
+
+    /// Swap the current value with a static value.
+    ///
+    /// As the old value is never reclaimed, it is returned as a static reference rather than a
+    /// guard.
+    pub fn swap_static(&self, new: Option<&'static T>, ordering: atomic::Ordering)
+    -> Option<&'static T> {
+        let new = new.map_or(ptr::null_mut(), |x| x as *const T as *mut T);
+        unsafe { shared::untagged(self.inner.swap(new, ordering)).as_ref() }
+    }
+
+    /// Store a static value, if the current value matches `old`.
+    ///
+    /// The values are compared by address. If they match, `new` is stored and `Ok(())` is
+    /// returned. Otherwise, the current value is returned in `Err`.
+    pub fn compare_and_store_static(
+        &self,
+        old: Option<&'static T>,
+        new: Option<&'static T>,
+        ordering: atomic::Ordering,
+    ) -> Result<(), Option<&'static T>> {
+        let old = old.map_or(ptr::null_mut(), |x| x as *const T as *mut T);
+        let new = new.map_or(ptr::null_mut(), |x| x as *const T as *mut T);
+        let cur = self.inner.compare_and_swap(old, new, ordering);
+        if cur == old {
+            Ok(())
+        } else {
+            Err(unsafe { shared::untagged(cur).as_ref() })
+        }
+    }
+}
+
+impl<T, P: Policy<T>> Atomic<T, P> {
+    /// Does this container leak its values rather than reclaiming them?
+    ///
+    /// This is `true` if and only if it was created through `Atomic::leaking()`.
+    pub fn is_leaking(&self) -> bool {
+        P::LEAK
+    }
+
+    /// Is this container read-mostly?
+    ///
+    /// This is `true` if and only if it was created through `Atomic::read_mostly()`.
+    pub fn is_read_mostly(&self) -> bool {
+        P::EPOCH
+    }
+
+    /// Queue the deletion of a value, which has become unreachable from `self`.
+    ///
+    /// If `self` is leaking, this does nothing.
+    ///
+    /// # Safety
+    ///
+    /// This has the same requirements as `add_garbage_box()`.
+    unsafe fn retire(&self, ptr: *const T) {
+        if P::LEAK || self.is_trivial() {
+            return;
+        }
+
+        if P::EPOCH {
+            add_garbage_box_epoch(ptr, self.policy.dtor());
+            return;
+        }
+
+        match self.policy.dtor() {
+            Some(dtor) => add_garbage_box_with(ptr, dtor),
+            None => add_garbage_box(ptr),
+        }
+    }
+
+    /// Queue the deletion of a value, which has been replaced in `self`, or destroy it right away.
+    ///
+    /// If `Settings::reclaim_on_store` is set, and no hazard protects the value, it is destroyed
+    /// right away. Otherwise, this acts like `retire()`.
+    ///
+    /// # Safety
+    ///
+    /// This has the same requirements as `add_garbage_box()`.
+    unsafe fn retire_replaced(&self, ptr: *const T) {
+        if P::LEAK || self.is_trivial() {
+            return;
+        }
+
+        // Epoch loads don't set hazards, so the values of read-mostly containers are never
+        // destroyed right away.
+        if settings::get().reclaim_on_store && !P::EPOCH {
+            destroy_or_add_garbage_box(ptr, self.policy.dtor());
+        } else {
+            self.retire(ptr);
+        }
+    }
+
+    /// Is there nothing to reclaim in the values of this container?
+    ///
+    /// Boxes of zero-sized types don't allocate, so if `T` doesn't need dropping either, and no
+    /// custom destructor is given, the replaced values are simply forgotten rather than retired.
+    fn is_trivial(&self) -> bool {
+        mem::size_of::<T>() == 0 && !mem::needs_drop::<T>() && self.policy.dtor().is_none()
+    }
+
     /// Get a mutable reference to the underlying `std::sync::AtomicPtr`.
     ///
     /// There is no overhead in this.
@@ -89,7 +450,8 @@ impl<T> Atomic<T> {
     /// meantime. You must thus be very careful with what you do.
     ///
     /// You cannot assume any validity of the address. The only assumptions, you can safely make,
-    /// is that this has been the pointer in `self` at some point.
+    /// is that this has been the pointer in `self` at some point. The pointer includes the tag set
+    /// through `compare_and_set_shared()`, if any.
     pub fn load_raw(&self, ordering: atomic::Ordering) -> *mut T {
         self.inner.load(ordering)
     }
@@ -104,14 +466,72 @@ impl<T> Atomic<T> {
     pub fn load(&self, ordering: atomic::Ordering) -> Option<Guard<T>> {
         // Load the inner and wrap it in a guard.
         Guard::maybe_new(|| unsafe {
-            self.load_raw(ordering).as_ref()
+            shared::untagged(self.load_raw(ordering)).as_ref()
         })
     }
 
+    /// Load the current pointer with its tag, protecting it by a guard stored in `guard`.
+    ///
+    /// This replaces the old guard in `guard` (if any) by a guard to the current value, and returns
+    /// the tagged pointer to it, which is protected as long as `guard` is borrowed. Unlike
+    /// `load()`, this keeps the tag (see `Shared<T>`), and the pointer can be passed on to
+    /// `compare_and_set_shared()`.
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    pub fn load_shared<'g>(&self, ordering: atomic::Ordering, guard: &'g mut Option<Guard<T>>)
+    -> Shared<'g, T> {
+        let mut ptr = ptr::null_mut();
+        *guard = Guard::maybe_new(|| unsafe {
+            ptr = self.load_raw(ordering);
+            shared::untagged(ptr).as_ref()
+        });
+
+        Shared::from_raw(ptr)
+    }
+
+    /// Get a reference to the current content of the option with consume ordering.
+    ///
+    /// This acts like `load(atomic::Ordering::Acquire)`, except that only the accesses through the
+    /// guard returned are ordered after the stores preceding the store of the value (the value
+    /// itself and whatever is reached through it), but other memory is not. In exchange, the load
+    /// is relaxed on architectures ordering dependent loads in hardware (ARM and POWER), sparing a
+    /// barrier. On other architectures, this is an acquire load.
+    ///
+    /// Only use this, if the value is accessed through the guard exclusively. Anything else read
+    /// after the load might be stale.
+    pub fn load_consume(&self) -> Option<Guard<T>> {
+        Guard::maybe_new(|| unsafe {
+            shared::untagged(load_consume(&self.inner)).as_ref()
+        })
+    }
+
+    /// Get a reference to the current content of the option, if it satisfies a predicate.
+    ///
+    /// This acts like `load()`, except that `None` is returned, if `pred` returns `false` for the
+    /// current value. The protection of a rejected value ends right away, whereas a dropped guard
+    /// keeps protecting its value for a while (as its hazard is cached by the thread). Hence, this
+    /// is useful for scanning many containers for a few values, without delaying the reclamation
+    /// of the rest.
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    pub fn load_if<F>(&self, ordering: atomic::Ordering, pred: F) -> Option<Guard<T>>
+    where F: FnOnce(&T) -> bool {
+        let guard = self.load(ordering)?;
+        if pred(&guard) {
+            Some(guard)
+        } else {
+            guard.release();
+            None
+        }
+    }
+
     /// Store a new value in the option.
     ///
     /// The old value of `self` will eventually be dropped, at some point after all the guarding
-    /// references are gone.
+    /// references are gone. With `Settings::reclaim_on_store`, it is dropped right away, if there
+    /// are none.
     ///
     /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
     /// documentation for more information.
@@ -120,10 +540,10 @@ impl<T> Atomic<T> {
         // TODO: Use coercions.
         let new = new.map_or(ptr::null_mut(), Box::into_raw);
         // Swap the contents with the new value.
-        let ptr = self.inner.swap(new, ordering);
+        let ptr = shared::untagged(self.inner.swap(new, ordering));
         if !ptr.is_null() {
             // Queue the deletion of the content.
-            unsafe { add_garbage_box(ptr); }
+            unsafe { self.retire_replaced(ptr); }
         }
     }
 
@@ -148,11 +568,11 @@ impl<T> Atomic<T> {
         // otherwise we might introduce premature frees.
         Guard::maybe_new(|| unsafe {
             // Swap the atomic pointer with the new one.
-            self.inner.swap(new_ptr, ordering).as_ref()
+            shared::untagged(self.inner.swap(new_ptr, ordering)).as_ref()
         }).map(|guard| {
             // Since the pointer is now unreachable from the option, it can safely be queued for
             // deletion.
-            unsafe { add_garbage_box(&*guard); }
+            unsafe { self.retire(&*guard); }
 
             guard
         })
@@ -185,7 +605,7 @@ impl<T> Atomic<T> {
 
             // Queue the deletion of now-unreachable `old` (unless it's `None`).
             if !old.is_null() {
-                add_garbage_box(old);
+                self.retire_replaced(shared::untagged(old as *mut T));
             }
 
             Ok(())
@@ -226,6 +646,44 @@ impl<T> Atomic<T> {
         }
     }
 
+    /// Store a value, unless a value is stored already.
+    ///
+    /// If `self` is `None`, `new` is stored. Otherwise, `new` is dropped. In both cases, a guard to
+    /// the value, which ends up in `self`, is returned. This is useful for lazy initialization,
+    /// where several threads might race to initialize the container.
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    pub fn set_if_null(&self, new: Box<T>, ordering: atomic::Ordering) -> Guard<T> {
+        let new = Box::into_raw(new);
+        let mut installed = false;
+
+        // The value is protected before the CAS publishes it, as it could otherwise be replaced
+        // and destroyed by another thread before the guard is created.
+        let guard = Guard::new(|| unsafe {
+            let mut current = ptr::null_mut();
+            loop {
+                let actual = self.inner.compare_and_swap(current, new, ordering);
+                if actual == current {
+                    installed = true;
+                    break &*new;
+                } else if shared::untagged(actual).is_null() {
+                    // A null pointer with a tag is null as well.
+                    current = actual;
+                } else {
+                    break &*shared::untagged(actual);
+                }
+            }
+        });
+
+        if !installed {
+            // `new` was never published, so we still own it.
+            drop(unsafe { Box::from_raw(new) });
+        }
+
+        guard
+    }
+
     /// Swap a (raw) pointer if it matches the specified pointer.
     ///
     /// This compares `self` to `old`. If they match, it is swapped with `new` and a guard to the
@@ -252,22 +710,20 @@ impl<T> Atomic<T> {
         ordering: atomic::Ordering
     ) -> Result<Option<Guard<T>>, Option<Guard<T>>> {
         // Create the guard beforehand to avoid premature frees.
+        let mut actual = ptr::null_mut();
         let guard = Guard::maybe_new(|| {
             // The guard is active, so we can do the CAS now.
-            self.inner.compare_and_swap(old as *mut T, new, ordering).as_ref()
+            actual = self.inner.compare_and_swap(old as *mut T, new, ordering);
+            shared::untagged(actual).as_ref()
         });
 
-        // Convert the guard to a raw pointer.
-        // TODO: Use coercions.
-        let guard_ptr = guard.as_ref().map_or(ptr::null_mut(), |x| &**x as *const T as *mut T);
-
         // Check if the CAS was successful.
-        if guard_ptr as *const T == old {
+        if actual as *const T == old {
             // It was. `self` is now `new`.
 
             // Queue the deletion of now-unreachable `old` (unless it's `None`).
             if !old.is_null() {
-                add_garbage_box(old);
+                self.retire(shared::untagged(old as *mut T));
             }
 
             Ok(guard)
@@ -312,6 +768,128 @@ impl<T> Atomic<T> {
             Err(guard) => Err((guard, new))
         }
     }
+
+    /// Store a value if the current value is the object protected by a guard.
+    ///
+    /// This acts like `compare_and_store`, except that `self` is compared to the object of
+    /// `current` (or null, if `None`). This is the read-validate-write pattern: Load a guard,
+    /// inspect the object, and replace exactly that object, unless it was replaced meanwhile. The
+    /// guard ensures that the object can't be destroyed and its address reused in between, so the
+    /// comparison can't be fooled (the ABA problem).
+    ///
+    /// The guard has no tag, so this fails if the pointer in `self` is tagged. See
+    /// `compare_and_store_shared()` for tagged pointers.
+    ///
+    /// # Example
+    ///
+    /// ```rust
+    /// use conc::Atomic;
+    /// use std::sync::atomic::Ordering;
+    ///
+    /// let a = Atomic::new(Some(Box::new(1)));
+    /// let current = a.load(Ordering::Acquire);
+    /// let new = current.as_ref().map_or(0, |x| **x + 1);
+    /// assert!(a.compare_and_store_guard(current.as_ref(), Some(Box::new(new)), Ordering::AcqRel).is_ok());
+    /// assert_eq!(*a.load(Ordering::Acquire).unwrap(), 2);
+    /// ```
+    pub fn compare_and_store_guard(&self, current: Option<&Guard<T>>, new: Option<Box<T>>, ordering: atomic::Ordering)
+    -> Result<(), Option<Box<T>>> {
+        self.compare_and_store(current.map(Guard::as_ptr), new, ordering)
+    }
+
+    /// Swap a value if the current value is the object protected by a guard.
+    ///
+    /// This acts like `compare_and_swap`, except that `self` is compared to the object of
+    /// `current` (or null, if `None`), like `compare_and_store_guard`.
+    pub fn compare_and_swap_guard(&self, current: Option<&Guard<T>>, new: Option<Box<T>>, ordering: atomic::Ordering)
+    -> Result<Option<Guard<T>>, (Option<Guard<T>>, Option<Box<T>>)> {
+        self.compare_and_swap(current.map(Guard::as_ptr), new, ordering)
+    }
+
+    /// Store a value if the current (tagged) pointer matches the specified one.
+    ///
+    /// This acts like `compare_and_store`, except that `self` is compared to `current`, including
+    /// the tag. `new` is stored without a tag.
+    ///
+    /// Unlike `compare_and_set_shared`, this is safe, as the new value is always owned by `self`,
+    /// and the old one is always queued for deletion (unless it is null).
+    pub fn compare_and_store_shared(&self, current: Shared<T>, new: Option<Box<T>>, ordering: atomic::Ordering)
+    -> Result<(), Option<Box<T>>> {
+        let raw = new.as_ref().map_or(ptr::null_mut(), |x| &**x as *const T as *mut T);
+        if unsafe { self.compare_and_store_raw(current.as_tagged(), raw, ordering) }.is_ok() {
+            // `new` is now in `self` (see `compare_and_store`).
+            mem::forget(new);
+
+            Ok(())
+        } else {
+            Err(new)
+        }
+    }
+
+    /// Store a tagged pointer if the current (tagged) pointer matches the specified one.
+    ///
+    /// This compares `self` to `current`, including the tag. If they match, the value is set to
+    /// `new` and `Ok(())` is returned. Otherwise, `Err(())` is returned.
+    ///
+    /// If the address of `new` differs from the one of `current`, the old value has become
+    /// unreachable from `self`, and is queued for deletion. Changing the tag only (e.g. marking a
+    /// pointer) doesn't queue anything.
+    ///
+    /// The `ordering` defines what constraints the atomic operation has. Refer to the LLVM
+    /// documentation for more information.
+    ///
+    /// # Safety
+    ///
+    /// If it succeeds, the value of `new` is owned by `self`, and will be queued for deletion by
+    /// it. Hence, `new` must be null, created by `Shared::from_box()`, have the address of
+    /// `current`, or point to a value, which is moved from another container without being queued
+    /// for deletion by it.
+    pub unsafe fn compare_and_set_shared(&self, current: Shared<T>, new: Shared<T>, ordering: atomic::Ordering)
+    -> Result<(), ()> {
+        let actual = self.inner.compare_and_swap(current.as_tagged(), new.as_tagged(), ordering);
+        if actual == current.as_tagged() {
+            // Queue the deletion of `current`, unless it is still in `self` (with another tag).
+            if !current.is_null() && current.as_raw() != new.as_raw() {
+                self.retire(current.as_raw());
+            }
+
+            Ok(())
+        } else {
+            Err(())
+        }
+    }
+}
+
+/// Load several `Atomic`s at once.
+///
+/// This acts like calling `load()` on each of `atomics`, but the hazards protecting the values are
+/// published before issuing a single fence, rather than one for every load. This is meant for
+/// operations, which need a few guarded loads up front (e.g. reading the roots of several
+/// indexes).
+///
+/// The values are loaded one after another, so they aren't a consistent snapshot of the
+/// `Atomic`s, just like separate loads aren't.
+///
+/// # Example
+///
+/// ```rust
+/// use conc::Atomic;
+/// use std::sync::atomic::Ordering;
+///
+/// let a = Atomic::new(Some(Box::new(1)));
+/// let b = Atomic::new(None);
+/// let c = Atomic::new(Some(Box::new(3)));
+///
+/// let [a, b, c] = conc::load_all([&a, &b, &c], Ordering::Acquire);
+/// assert_eq!(*a.unwrap(), 1);
+/// assert!(b.is_none());
+/// assert_eq!(*c.unwrap(), 3);
+/// ```
+pub fn load_all<T, P: Policy<T>, const N: usize>(atomics: [&Atomic<T, P>; N], ordering: atomic::Ordering)
+-> [Option<Guard<T>>; N] {
+    Guard::maybe_new_all(|| atomics.map(|x| unsafe {
+        shared::untagged(x.load_raw(ordering)).as_ref()
+    }))
 }
 
 // TODO: Use derive when https://github.com/rust-lang/rust/issues/26925 is fixed.
@@ -321,14 +899,25 @@ impl<T> Default for Atomic<T> {
     }
 }
 
-impl<T> Drop for Atomic<T> {
+impl<T, P: Policy<T>> Retire for Atomic<T, P> {
+    fn retire_children(&mut self) {
+        // Take the value out, such that it isn't retired again, when `self` is dropped.
+        let ptr = shared::untagged(mem::replace(self.inner.get_mut(), ptr::null_mut()));
+
+        if !ptr.is_null() {
+            unsafe { self.retire(ptr); }
+        }
+    }
+}
+
+impl<T, P: Policy<T>> Drop for Atomic<T, P> {
     fn drop(&mut self) {
         // We use the neat `get_mut` to get around the overhead of atomics.
-        let ptr = self.inner.get_mut();
+        let ptr = shared::untagged(*self.inner.get_mut());
 
         if !ptr.is_null() {
             // As the read pointer was not null, we can safely call its destructor.
-            unsafe { add_garbage_box(*ptr); }
+            unsafe { self.retire(ptr); }
         }
     }
 }
@@ -406,6 +995,186 @@ mod tests {
         }
     }
 
+    #[test]
+    fn with_dtor() {
+        static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+        struct Socket {
+            id: usize,
+        }
+
+        fn close(socket: Box<Socket>) {
+            CLOSED.fetch_add(socket.id, atomic::Ordering::Relaxed);
+        }
+
+        let opt = Atomic::with_dtor(Some(Box::new(Socket { id: 1 })), close);
+        opt.store(Some(Box::new(Socket { id: 2 })), atomic::Ordering::Release);
+        let old = opt.swap(Some(Box::new(Socket { id: 4 })), atomic::Ordering::AcqRel).unwrap();
+        let cur = opt.load(atomic::Ordering::Acquire).unwrap();
+        opt.compare_and_store(Some(cur.as_ptr()), Some(Box::new(Socket { id: 8 })),
+                              atomic::Ordering::AcqRel).ok().unwrap();
+
+        // The swapped value is still protected.
+        ::gc().unwrap();
+        assert_eq!(CLOSED.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(old.id, 2);
+
+        drop(old);
+        drop(cur);
+        drop(opt);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(CLOSED.load(atomic::Ordering::Relaxed), 15);
+    }
+
+    #[test]
+    fn with_dtor_zero_sized() {
+        static CLOSED: AtomicUsize = AtomicUsize::new(0);
+
+        struct Token;
+
+        fn close(_: Box<Token>) {
+            CLOSED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let opt = Atomic::with_dtor(Some(Box::new(Token)), close);
+        opt.store(Some(Box::new(Token)), atomic::Ordering::Release);
+        opt.store(Some(Box::new(Token)), atomic::Ordering::Release);
+        drop(opt);
+
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(CLOSED.load(atomic::Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn reclaim_on_store() {
+        settings::set_local(settings::Settings {
+            reclaim_on_store: true,
+            .. Default::default()
+        });
+
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+
+        // The unprotected values are destroyed right away. Other threads creating guards at the
+        // same time can prevent it, but hardly every time.
+        for _ in 0..10 {
+            opt.store(Some(Box::new(Dropper { d: drops.clone() })), atomic::Ordering::Release);
+        }
+        let destroyed = drops.load(atomic::Ordering::Relaxed);
+        assert!(destroyed > 0);
+
+        // The protected value is queued.
+        let guard = opt.load(atomic::Ordering::Acquire);
+        opt.store(None, atomic::Ordering::Release);
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), destroyed);
+
+        drop(guard);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 11);
+
+        // Avoid messing with other tests.
+        settings::set_local(settings::Settings::default());
+    }
+
+    #[test]
+    fn load_consume() {
+        let opt = Atomic::new(Some(Box::new(42)));
+        assert_eq!(*opt.load_consume().unwrap(), 42);
+        opt.store(None, atomic::Ordering::Release);
+        assert!(opt.load_consume().is_none());
+    }
+
+    #[test]
+    fn load_if() {
+        let opt = Atomic::new(Some(Box::new(42)));
+        assert_eq!(*opt.load_if(atomic::Ordering::Relaxed, |&x| x == 42).unwrap(), 42);
+        assert!(opt.load_if(atomic::Ordering::Relaxed, |&x| x == 43).is_none());
+        assert!(Atomic::<u8>::default().load_if(atomic::Ordering::Relaxed, |_| true).is_none());
+    }
+
+    #[test]
+    fn load_if_releases() {
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+        assert!(opt.load_if(atomic::Ordering::Relaxed, |_| false).is_none());
+
+        // The rejected value isn't protected anymore.
+        opt.store(None, atomic::Ordering::Relaxed);
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn tagged() {
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+
+        let mut guard = None;
+        let cur = opt.load_shared(atomic::Ordering::Relaxed, &mut guard);
+        assert_eq!(cur.tag(), 0);
+        assert!(!cur.is_null());
+
+        // Mark the pointer, which doesn't retire it.
+        unsafe {
+            assert!(opt.compare_and_set_shared(cur, cur.with_tag(1), atomic::Ordering::Relaxed).is_ok());
+            assert!(opt.compare_and_set_shared(cur, cur.with_tag(1), atomic::Ordering::Relaxed).is_err());
+        }
+        assert_eq!(opt.load(atomic::Ordering::Relaxed).unwrap().as_ptr(), cur.as_raw());
+        drop(guard);
+
+        let mut guard = None;
+        let cur = opt.load_shared(atomic::Ordering::Relaxed, &mut guard);
+        assert_eq!(cur.tag(), 1);
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+
+        // Replace the marked pointer.
+        let new = Shared::from_box(Box::new(Dropper { d: drops.clone() }));
+        unsafe {
+            assert!(opt.compare_and_set_shared(cur, new, atomic::Ordering::Relaxed).is_ok());
+        }
+        drop(guard);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+
+        // The tag is stripped when the container is dropped.
+        unsafe {
+            assert!(opt.compare_and_set_shared(new, new.with_tag(3), atomic::Ordering::Relaxed).is_ok());
+        }
+        drop(opt);
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn set_if_null() {
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::default();
+
+        let first = opt.set_if_null(Box::new(Dropper { d: drops.clone() }), atomic::Ordering::AcqRel);
+        let second = opt.set_if_null(Box::new(Dropper { d: drops.clone() }), atomic::Ordering::AcqRel);
+        // The second value lost, and was dropped right away.
+        assert_eq!(first.as_ptr(), second.as_ptr());
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+        assert_eq!(opt.load(atomic::Ordering::Relaxed).unwrap().as_ptr(), first.as_ptr());
+    }
+
+    #[test]
+    fn set_if_null_race() {
+        let opt = Arc::new(Atomic::default());
+        let j: Vec<_> = (0..8).map(|i| {
+            let opt = opt.clone();
+            thread::spawn(move || *opt.set_if_null(Box::new(i), atomic::Ordering::AcqRel))
+        }).collect();
+
+        let results: Vec<_> = j.into_iter().map(|x| x.join().unwrap()).collect();
+        let winner = *opt.load(atomic::Ordering::Acquire).unwrap();
+        assert!(results.iter().all(|&x| x == winner));
+    }
+
     #[test]
     fn cas() {
         let bx1 = Box::new(1);
@@ -432,6 +1201,74 @@ mod tests {
         assert!(opt.load(atomic::Ordering::Relaxed).is_none());
     }
 
+    #[test]
+    fn cas_guard() {
+        let drops = Arc::new(AtomicUsize::default());
+        let opt = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+
+        let first = opt.load(atomic::Ordering::Acquire);
+        assert!(opt.compare_and_store_guard(first.as_ref(), None, atomic::Ordering::AcqRel).is_ok());
+        // The inspected object is gone, so the snapshot is stale.
+        let new = Some(Box::new(Dropper { d: drops.clone() }));
+        let new = opt.compare_and_store_guard(first.as_ref(), new, atomic::Ordering::AcqRel)
+            .unwrap_err();
+        assert!(opt.compare_and_store_guard(None, new, atomic::Ordering::AcqRel).is_ok());
+
+        let second = opt.load(atomic::Ordering::Acquire);
+        let (actual, new) = opt.compare_and_swap_guard(first.as_ref(), None, atomic::Ordering::AcqRel)
+            .unwrap_err();
+        assert_eq!(actual.unwrap().as_ptr(), second.as_ref().unwrap().as_ptr());
+        assert!(new.is_none());
+        let old = opt.compare_and_swap_guard(second.as_ref(), None, atomic::Ordering::AcqRel)
+            .unwrap();
+        assert_eq!(old.unwrap().as_ptr(), second.as_ref().unwrap().as_ptr());
+
+        drop((first, second));
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn cas_shared() {
+        let opt = Atomic::new(Some(Box::new(1)));
+
+        let mut guard = None;
+        let cur = opt.load_shared(atomic::Ordering::Acquire, &mut guard);
+        unsafe {
+            assert!(opt.compare_and_set_shared(cur, cur.with_tag(1), atomic::Ordering::Relaxed).is_ok());
+        }
+
+        // The tag is compared.
+        let new = opt.compare_and_store_shared(cur, Some(Box::new(2)), atomic::Ordering::AcqRel)
+            .unwrap_err();
+        assert!(opt.compare_and_store_shared(cur.with_tag(1), new, atomic::Ordering::AcqRel).is_ok());
+        assert_eq!(*opt.load(atomic::Ordering::Acquire).unwrap(), 2);
+        // The new value isn't tagged.
+        assert_eq!(opt.load_shared(atomic::Ordering::Acquire, &mut None).tag(), 0);
+    }
+
+    #[test]
+    fn load_all_protects() {
+        let drops = Arc::new(AtomicUsize::default());
+        let a = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+        let b = Atomic::new(None);
+        let c = Atomic::new(Some(Box::new(Dropper { d: drops.clone() })));
+
+        let [ga, gb, gc] = load_all([&a, &b, &c], atomic::Ordering::Acquire);
+        assert!(gb.is_none());
+        a.store(None, atomic::Ordering::Release);
+        c.store(None, atomic::Ordering::Release);
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+
+        drop(ga);
+        drop(gc);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 2);
+    }
+
     #[test]
     fn cas_raw() {
         unsafe {
@@ -476,7 +1313,7 @@ mod tests {
             }))
         }
 
-        ::gc();
+        ::gc().unwrap();
 
         for i in j {
             i.join().unwrap();
@@ -512,7 +1349,7 @@ mod tests {
 
         opt.store(None, atomic::Ordering::Relaxed);
 
-        ::gc();
+        ::gc().unwrap();
 
         // The 16 are for the `d` variable in the loop above.
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 16_000_000 + 16);
@@ -541,7 +1378,7 @@ mod tests {
             i.join().unwrap();
         }
 
-        ::gc();
+        ::gc().unwrap();
 
         // The 16 are for the `d` variable in the loop above.
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 16_000_000 + 16);
@@ -595,7 +1432,7 @@ mod tests {
         }
 
         drop(d);
-        ::gc();
+        ::gc().unwrap();
 
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 16 * 6);
     }
@@ -631,10 +1468,78 @@ mod tests {
     fn nested() {
         let a: Atomic<Atomic<Atomic<u8>>> = Atomic::new(Some(Box::new(Atomic::new(Some(Box::new(Atomic::new(None)))))));
         a.store(None, atomic::Ordering::Relaxed);
-        ::gc();
+        ::gc().unwrap();
         assert!(a.load(atomic::Ordering::Relaxed).is_none());
     }
 
+    #[test]
+    fn leaking() {
+        let drops = Arc::new(AtomicUsize::default());
+        let d = Dropper {
+            d: drops.clone(),
+        };
+
+        let a = Atomic::leaking(Some(Box::new(d.clone())));
+        assert!(a.is_leaking());
+        let first = a.load_static(atomic::Ordering::Acquire).unwrap();
+
+        a.store(Some(Box::new(d.clone())), atomic::Ordering::Release);
+        a.swap(Some(Box::new(d.clone())), atomic::Ordering::Release);
+        let cur = a.load_static(atomic::Ordering::Acquire).unwrap() as *const Dropper;
+        a.compare_and_swap(Some(cur), None, atomic::Ordering::Release).unwrap();
+        a.store(Some(Box::new(d.clone())), atomic::Ordering::Release);
+        drop(a);
+        ::gc().unwrap();
+
+        // Nothing was reclaimed.
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(first.d.load(atomic::Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn from_static() {
+        static IDLE: u8 = 0;
+        static BUSY: u8 = 1;
+
+        let a = Atomic::from_static(Some(&IDLE));
+        assert!(a.is_leaking());
+        assert_eq!(a.load_static(atomic::Ordering::Acquire), Some(&IDLE));
+
+        a.store_static(Some(&BUSY), atomic::Ordering::Release);
+        assert_eq!(*a.load(atomic::Ordering::Acquire).unwrap(), 1);
+        assert_eq!(a.swap_static(None, atomic::Ordering::AcqRel), Some(&BUSY));
+        assert_eq!(a.compare_and_store_static(Some(&IDLE), Some(&BUSY), atomic::Ordering::AcqRel),
+                   Err(None));
+        a.compare_and_store_static(None, Some(&IDLE), atomic::Ordering::AcqRel).unwrap();
+        assert_eq!(a.load_static(atomic::Ordering::Acquire), Some(&IDLE));
+    }
+
+    #[test]
+    fn zst_no_garbage() {
+        struct Marker;
+
+        thread::spawn(|| {
+            let a = Atomic::new(Some(Box::new(Marker)));
+            for _ in 0..1000 {
+                a.store(Some(Box::new(Marker)), atomic::Ordering::Release);
+                let _ = a.swap(None, atomic::Ordering::AcqRel);
+                let new = Some(Box::new(Marker));
+                assert!(a.compare_and_store(None, new, atomic::Ordering::AcqRel).is_ok());
+            }
+            drop(a);
+
+            let id = thread::current().id();
+            assert!(::stats::threads().iter().all(|x| x.id != id || x.retired == 0));
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn policy_size() {
+        assert_eq!(mem::size_of::<Atomic<u8>>(), mem::size_of::<usize>());
+        assert_eq!(mem::size_of::<LeakingAtomic<u8>>(), mem::size_of::<usize>());
+        assert_eq!(mem::size_of::<ReadMostly<u8>>(), mem::size_of::<usize>());
+    }
+
     #[test]
     fn null_tuple() {
         let a = Atomic::new(Some(Box::new(())));
@@ -0,0 +1,120 @@
+//! Exponential backoff for spin loops.
+//!
+//! Pure spinning is harmful on oversubscribed systems, as the thread we're waiting for might not
+//! be scheduled at all, while we burn its time slice. Instead, spin loops back off exponentially
+//! and eventually yield to the scheduler.
+
+use std::{hint, thread};
+use settings;
+
+/// The maximal exponent of the number of spins in a round.
+const MAX_SPIN_EXPONENT: u32 = 20;
+
+/// A backoff state for a spin loop.
+///
+/// In round `n`, `snooze()` spins `2^n` times, until the limit of rounds (given by the settings)
+/// is reached, after which it yields the thread's time slice.
+pub struct Backoff {
+    /// The current round.
+    round: u32,
+    /// The number of rounds of spinning before yielding.
+    ///
+    /// This is fetched from the settings on the first snooze, keeping construction cheap.
+    limit: Option<u32>,
+}
+
+impl Backoff {
+    /// Create a new backoff state.
+    pub fn new() -> Backoff {
+        Backoff {
+            round: 0,
+            limit: None,
+        }
+    }
+
+    /// Back off.
+    ///
+    /// This shall be called in every iteration of the spin loop, in which the awaited condition
+    /// was not satisfied.
+    pub fn snooze(&mut self) {
+        let limit = match self.limit {
+            Some(limit) => limit,
+            None => {
+                let limit = settings::get().spin_rounds_before_yield;
+                self.limit = Some(limit);
+                limit
+            },
+        };
+
+        if self.round < limit {
+            // Spin with exponentially increasing length.
+            for _ in 0..1u32 << self.round.min(MAX_SPIN_EXPONENT) {
+                hint::spin_loop();
+            }
+
+            self.round += 1;
+        } else {
+            // We've spun for long enough; let another thread run.
+            thread::yield_now();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use settings::{self, Settings};
+
+    #[test]
+    fn lazy_limit() {
+        let mut b = Backoff::new();
+        assert!(b.limit.is_none());
+        b.snooze();
+        assert_eq!(b.limit, Some(settings::get().spin_rounds_before_yield));
+    }
+
+    #[test]
+    fn eventually_yields() {
+        let mut b = Backoff::new();
+        let limit = settings::get().spin_rounds_before_yield;
+        for i in 0..limit {
+            assert_eq!(b.round, i);
+            b.snooze();
+        }
+
+        // Yielding doesn't advance the round.
+        assert_eq!(b.round, limit);
+        b.snooze();
+        assert_eq!(b.round, limit);
+    }
+
+    #[test]
+    fn yield_immediately() {
+        settings::set_local(Settings {
+            spin_rounds_before_yield: 0,
+            .. Default::default()
+        });
+
+        let mut b = Backoff::new();
+        b.snooze();
+        assert_eq!(b.round, 0);
+
+        // Avoid messing with other tests.
+        settings::set_local(Settings::default());
+    }
+
+    #[test]
+    fn huge_limit() {
+        settings::set_local(Settings {
+            spin_rounds_before_yield: !0,
+            .. Default::default()
+        });
+
+        let mut b = Backoff::new();
+        b.round = 64;
+        b.snooze();
+
+        // Avoid messing with other tests.
+        settings::set_local(Settings::default());
+    }
+}
@@ -0,0 +1,198 @@
+//! Runtime benchmarks.
+//!
+//! This measures the performance of the system in the current process and configuration, such
+//! that the settings can be tuned for the particular deployment. The worker threads inherit the
+//! settings of the calling thread.
+//!
+//! # Example
+//!
+//! ```rust
+//! let report = conc::bench::run(conc::bench::Config {
+//!     samples: 16,
+//!     .. Default::default()
+//! });
+//!
+//! println!("median guard latency: {:?}", report.guard_latency.median);
+//! ```
+
+use std::sync::{atomic, Arc};
+use std::time::{Duration, Instant};
+use std::thread;
+
+use {settings, Atomic};
+
+/// The configuration of a benchmark run.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// The number of threads to run the benchmark on.
+    pub threads: usize,
+    /// The number of samples to take of each measurement in each thread.
+    pub samples: usize,
+    /// The number of operations in each sample.
+    ///
+    /// Timing every operation by itself would mostly measure the clock, so the operations are
+    /// timed in batches of this size.
+    pub batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            threads: 4,
+            samples: 1000,
+            batch: 64,
+        }
+    }
+}
+
+/// A distribution of measured durations.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct Distribution {
+    /// The shortest duration.
+    pub min: Duration,
+    /// The mean duration.
+    pub mean: Duration,
+    /// The median duration.
+    pub median: Duration,
+    /// The 99th percentile.
+    pub p99: Duration,
+    /// The longest duration.
+    pub max: Duration,
+}
+
+impl Distribution {
+    /// Calculate the distribution of some samples.
+    fn new(mut samples: Vec<Duration>) -> Distribution {
+        if samples.is_empty() {
+            return Distribution::default();
+        }
+
+        samples.sort();
+        let len = samples.len();
+        let sum = samples.iter().fold(Duration::new(0, 0), |acc, &x| acc + x);
+
+        Distribution {
+            min: samples[0],
+            mean: sum / len as u32,
+            median: samples[len / 2],
+            p99: samples[(len * 99 / 100).min(len - 1)],
+            max: samples[len - 1],
+        }
+    }
+}
+
+/// The report of a benchmark run.
+#[derive(Copy, Clone, PartialEq, Debug)]
+pub struct Report {
+    /// The latency of acquiring a guard (i.e. a protected load).
+    pub guard_latency: Distribution,
+    /// The number of objects retired per second across all the threads.
+    pub retire_throughput: f64,
+    /// The pause of a blocking garbage collection cycle.
+    pub gc_pause: Distribution,
+}
+
+/// Run the benchmarks.
+///
+/// This runs the measurements on `config.threads` threads simultaneously, which all use the
+/// settings of the calling thread.
+pub fn run(config: Config) -> Report {
+    let settings = settings::get();
+    let atomic = Arc::new(Atomic::new(Some(Box::new(0u64))));
+
+    let threads: Vec<_> = (0..config.threads.max(1)).map(|_| {
+        let atomic = atomic.clone();
+        thread::spawn(move || {
+            settings::set_local(settings);
+            measure(&atomic, config)
+        })
+    }).collect();
+
+    let mut guard_latency = Vec::new();
+    let mut gc_pause = Vec::new();
+    let mut retired = 0;
+    let mut retire_time = Duration::new(0, 0);
+    for i in threads {
+        let (latency, pause, n, time) = i.join().unwrap();
+        guard_latency.extend(latency);
+        gc_pause.extend(pause);
+        retired += n;
+        retire_time = retire_time.max(time);
+    }
+
+    let retire_secs = retire_time.as_secs() as f64 + retire_time.subsec_nanos() as f64 * 1e-9;
+    Report {
+        guard_latency: Distribution::new(guard_latency),
+        retire_throughput: if retire_secs > 0.0 { retired as f64 / retire_secs } else { 0.0 },
+        gc_pause: Distribution::new(gc_pause),
+    }
+}
+
+/// Do the measurements of a single thread.
+///
+/// This returns the guard latencies, GC pauses, number of retired objects, and the time spent
+/// retiring them.
+fn measure(atomic: &Atomic<u64>, config: Config) -> (Vec<Duration>, Vec<Duration>, usize, Duration) {
+    let batch = config.batch.max(1);
+    let mut guard_latency = Vec::with_capacity(config.samples);
+    let mut gc_pause = Vec::with_capacity(config.samples);
+    let mut retire_time = Duration::new(0, 0);
+
+    for _ in 0..config.samples {
+        // Measure the guard acquisition.
+        let start = Instant::now();
+        for _ in 0..batch {
+            let _ = atomic.load(atomic::Ordering::Acquire);
+        }
+        guard_latency.push(start.elapsed() / batch as u32);
+
+        // Measure the retirement.
+        let start = Instant::now();
+        for i in 0..batch {
+            atomic.store(Some(Box::new(i as u64)), atomic::Ordering::Release);
+        }
+        retire_time += start.elapsed();
+
+        // Measure the GC pause.
+        let start = Instant::now();
+        ::gc().unwrap();
+        gc_pause.push(start.elapsed());
+    }
+
+    (guard_latency, gc_pause, config.samples * batch, retire_time)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn distribution() {
+        let d = Distribution::new((1..101).map(|x| Duration::from_millis(x)).collect());
+        assert_eq!(d.min, Duration::from_millis(1));
+        assert_eq!(d.median, Duration::from_millis(51));
+        assert_eq!(d.p99, Duration::from_millis(100));
+        assert_eq!(d.max, Duration::from_millis(100));
+        assert!(d.mean > Duration::from_millis(50) && d.mean < Duration::from_millis(51));
+    }
+
+    #[test]
+    fn empty_distribution() {
+        assert_eq!(Distribution::new(Vec::new()), Distribution::default());
+    }
+
+    #[test]
+    fn run_small() {
+        let report = run(Config {
+            threads: 2,
+            samples: 20,
+            batch: 8,
+        });
+
+        assert!(report.guard_latency.min <= report.guard_latency.median);
+        assert!(report.guard_latency.median <= report.guard_latency.p99);
+        assert!(report.guard_latency.p99 <= report.guard_latency.max);
+        assert!(report.gc_pause.min <= report.gc_pause.max);
+        assert!(report.retire_throughput > 0.0);
+    }
+}
@@ -0,0 +1,132 @@
+//! Memory budget.
+//!
+//! The budget limits the number of bytes of garbage pending destruction. When the limit is
+//! exceeded, the next retirement triggers an emergency (blocking) garbage collection. If that
+//! doesn't bring the pending garbage below the limit (e.g. because it is protected by guards), the
+//! callback is invoked, which can then e.g. shed caches. If inline collections are disabled (see
+//! `collector`), the callback is invoked right away.
+//!
+//! The budget is global, unlike the settings, which are thread-local.
+//!
+//! # Accounting
+//!
+//! Only garbage of known size is counted, which is the garbage retired through `add_garbage`,
+//! `add_garbage_box` or `Atomic`. Only the size of the object itself is counted, not memory it
+//! owns (e.g. the buffer of a `Vec`). Garbage is counted once it is exported from the local state
+//! of the thread, which retired it.
+
+use parking_lot::{self, Mutex};
+use std::sync::atomic::{self, AtomicUsize};
+use {collector, global, local};
+use timeline::Trigger;
+
+/// The budget in bytes.
+///
+/// `!0` means that there is no budget.
+static LIMIT: AtomicUsize = AtomicUsize::new(!0);
+/// The callback invoked when the budget is exceeded after an emergency collection.
+static CALLBACK: Mutex<Option<fn(usize)>> = parking_lot::const_mutex(None);
+
+/// Set the budget.
+///
+/// `None` means that there is no budget, which is the default.
+pub fn set_limit(limit: Option<usize>) {
+    LIMIT.store(limit.unwrap_or(!0), atomic::Ordering::Relaxed);
+}
+
+/// Get the budget.
+pub fn limit() -> Option<usize> {
+    let limit = LIMIT.load(atomic::Ordering::Relaxed);
+    if limit == !0 {
+        None
+    } else {
+        Some(limit)
+    }
+}
+
+/// Set the callback invoked when the budget is exceeded.
+///
+/// The callback is invoked with the number of bytes pending, when an emergency collection didn't
+/// bring the pending garbage below the budget. It is run in the thread retiring the garbage, which
+/// exceeded the budget.
+pub fn set_callback(callback: Option<fn(usize)>) {
+    *CALLBACK.lock() = callback;
+}
+
+/// Enforce the budget.
+///
+/// This shall be called after retiring garbage. If the budget is exceeded, an emergency collection
+/// is done, and if that isn't enough, the callback is invoked.
+pub(crate) fn enforce() {
+    let limit = LIMIT.load(atomic::Ordering::Relaxed);
+    if global::pending_bytes() <= limit {
+        return;
+    }
+
+    // Blocking on a collection from within a collection (i.e. from a destructor) would deadlock,
+    // so we leave the enforcement to later retirements.
+    if global::in_critical() {
+        return;
+    }
+
+    // Collect the garbage, unless it is left to a dedicated collector. If the collector is
+    // poisoned, there is nothing we can do about it here.
+    local::export_garbage();
+    if collector::is_inline() {
+        let _ = global::gc(Trigger::Budget);
+    }
+
+    let pending = global::pending_bytes();
+    if pending > limit {
+        // Copy the callback out, such that it can set a new callback without deadlocking.
+        let callback = *CALLBACK.lock();
+        if let Some(callback) = callback {
+            callback(pending);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use Atomic;
+
+    #[test]
+    fn set_get() {
+        // This test touches the global budget, so we only use limits, which never trigger.
+        set_limit(Some(!0 - 1));
+        assert_eq!(limit(), Some(!0 - 1));
+        set_limit(None);
+        assert_eq!(limit(), None);
+    }
+
+    #[test]
+    fn over_budget() {
+        static CALLED: AtomicUsize = AtomicUsize::new(0);
+
+        fn callback(pending: usize) {
+            assert!(pending > 0);
+            CALLED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let a = Atomic::new(Some(Box::new([0u8; 4096])));
+        // Protect the old objects, such that the emergency collection can't reclaim them.
+        let guard = a.load(atomic::Ordering::Relaxed).unwrap();
+
+        set_callback(Some(callback));
+        set_limit(Some(1024));
+        for _ in 0..1000 {
+            let _guard = a.load(atomic::Ordering::Relaxed).unwrap();
+            a.store(Some(Box::new([0u8; 4096])), atomic::Ordering::Relaxed);
+            if CALLED.load(atomic::Ordering::Relaxed) > 0 {
+                break;
+            }
+        }
+        set_limit(None);
+        set_callback(None);
+
+        assert!(CALLED.load(atomic::Ordering::Relaxed) > 0);
+        drop(guard);
+    }
+}
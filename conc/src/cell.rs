@@ -0,0 +1,213 @@
+//! `AtomicCell<T>` for small `Copy` values stored inline.
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{self, AtomicU16, AtomicU32, AtomicU8, AtomicUsize};
+#[cfg(target_has_atomic = "64")]
+use std::sync::atomic::AtomicU64;
+use std::{fmt, mem, ptr};
+use backoff::Backoff;
+
+/// Does `T` fit in the atomic integer `A`?
+///
+/// If so, the value can be accessed as an `A`, as the size is the same, and the alignment is at
+/// least that of `A`.
+fn fits<T, A>() -> bool {
+    mem::size_of::<T>() == mem::size_of::<A>() && mem::align_of::<T>() >= mem::align_of::<A>()
+}
+
+/// Return `$op` with `$a` bound to the pointer `$ptr` to a `T` cast to an atomic integer, if `T`
+/// fits in one, and return `$fallback` otherwise.
+macro_rules! atomic {
+    ($ptr:expr, $a:ident => $op:expr, $fallback:expr) => {
+        atomic!(@try $ptr, $a => $op, AtomicU8);
+        atomic!(@try $ptr, $a => $op, AtomicU16);
+        atomic!(@try $ptr, $a => $op, AtomicU32);
+        #[cfg(target_has_atomic = "64")]
+        atomic!(@try $ptr, $a => $op, AtomicU64);
+        atomic!(@try $ptr, $a => $op, AtomicUsize);
+
+        return $fallback;
+    };
+    (@try $ptr:expr, $a:ident => $op:expr, $atomic:ident) => {
+        if fits::<T, $atomic>() {
+            let $a = $ptr as *const $atomic;
+            return $op;
+        }
+    };
+}
+
+/// A cell storing a `Copy` value inline, which can be loaded and stored concurrently.
+///
+/// Unlike `Atomic<T>`, this involves no allocation, no guards and no garbage, as values are
+/// copied in and out of the cell rather than referenced. This makes it the right tool for small
+/// values like counters, flags or configuration numbers, whereas `Atomic<T>` is for values, which
+/// are large or not `Copy`.
+///
+/// If `T` has the size and (at least) the alignment of an atomic integer, the value is accessed
+/// as such an integer. Otherwise, it is protected by a seqlock, where loads retry while a store
+/// is in progress, and stores exclude each other. See `AtomicCell::is_lock_free()`.
+///
+/// Loads have acquire semantics, and stores have release semantics.
+pub struct AtomicCell<T: Copy> {
+    /// The sequence number of the seqlock.
+    ///
+    /// This is odd while the value is being stored. It is unused, if the value fits in an atomic
+    /// integer.
+    seq: AtomicUsize,
+    /// The inner value.
+    value: UnsafeCell<T>,
+}
+
+unsafe impl<T: Copy + Send> Sync for AtomicCell<T> {}
+
+impl<T: Copy> AtomicCell<T> {
+    /// Create a new cell with some initial value.
+    pub const fn new(value: T) -> AtomicCell<T> {
+        AtomicCell {
+            seq: AtomicUsize::new(0),
+            value: UnsafeCell::new(value),
+        }
+    }
+
+    /// Are the operations on cells of `T` lock-free?
+    ///
+    /// This is the case, if `T` fits in an atomic integer. Otherwise, a seqlock is used.
+    pub fn is_lock_free() -> bool {
+        atomic!(ptr::null::<T>(), _a => true, false);
+    }
+
+    /// Load the value of the cell.
+    pub fn load(&self) -> T {
+        atomic!(self.value.get(), a => unsafe {
+            mem::transmute_copy(&(*a).load(atomic::Ordering::Acquire))
+        }, {
+            let mut backoff = Backoff::new();
+            loop {
+                let seq = self.seq.load(atomic::Ordering::Acquire);
+                if seq & 1 == 0 {
+                    // The read might race with a store, in which case the value read is torn and
+                    // discarded below. Volatile prevents the compiler from assuming otherwise.
+                    let value = unsafe { ptr::read_volatile(self.value.get()) };
+                    // Ensure that the value is read before the sequence number is checked.
+                    atomic::fence(atomic::Ordering::Acquire);
+
+                    if self.seq.load(atomic::Ordering::Relaxed) == seq {
+                        break value;
+                    }
+                }
+
+                backoff.snooze();
+            }
+        });
+    }
+
+    /// Store a new value in the cell.
+    pub fn store(&self, value: T) {
+        self.swap(value);
+    }
+
+    /// Store a new value in the cell, returning the old value.
+    pub fn swap(&self, value: T) -> T {
+        atomic!(self.value.get(), a => unsafe {
+            mem::transmute_copy(&(*a).swap(mem::transmute_copy(&value), atomic::Ordering::AcqRel))
+        }, {
+            // Lock the seqlock by making the sequence number odd.
+            let mut backoff = Backoff::new();
+            let seq = loop {
+                let seq = self.seq.load(atomic::Ordering::Relaxed);
+                if seq & 1 == 0 && self.seq.compare_exchange_weak(
+                    seq,
+                    seq.wrapping_add(1),
+                    atomic::Ordering::Acquire,
+                    atomic::Ordering::Relaxed,
+                ).is_ok() {
+                    break seq;
+                }
+
+                backoff.snooze();
+            };
+            // Ensure that readers see the odd sequence number before the value is overwritten.
+            atomic::fence(atomic::Ordering::Release);
+
+            let old = unsafe { ptr::replace(self.value.get(), value) };
+            // Unlock, publishing the new value.
+            self.seq.store(seq.wrapping_add(2), atomic::Ordering::Release);
+
+            old
+        });
+    }
+
+    /// Get a mutable reference to the value.
+    ///
+    /// This is safe, as the mutable reference ensures that no other thread accesses the cell.
+    pub fn get_mut(&mut self) -> &mut T {
+        unsafe { &mut *self.value.get() }
+    }
+
+    /// Get the inner value.
+    pub fn into_inner(self) -> T {
+        self.value.into_inner()
+    }
+}
+
+impl<T: Copy + Default> Default for AtomicCell<T> {
+    fn default() -> AtomicCell<T> {
+        AtomicCell::new(T::default())
+    }
+}
+
+impl<T: Copy + fmt::Debug> fmt::Debug for AtomicCell<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("AtomicCell").field("value", &self.load()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn lock_free() {
+        assert!(AtomicCell::<u8>::is_lock_free());
+        assert!(AtomicCell::<u32>::is_lock_free());
+        assert!(AtomicCell::<usize>::is_lock_free());
+        assert!(AtomicCell::<Option<&u8>>::is_lock_free());
+        assert!(!AtomicCell::<[u8; 3]>::is_lock_free());
+        assert!(!AtomicCell::<[usize; 4]>::is_lock_free());
+    }
+
+    #[test]
+    fn load_store() {
+        let cell = AtomicCell::new(1u64);
+        assert_eq!(cell.load(), 1);
+        cell.store(2);
+        assert_eq!(cell.swap(3), 2);
+        assert_eq!(cell.into_inner(), 3);
+
+        let mut cell = AtomicCell::new([1usize; 4]);
+        assert_eq!(cell.load(), [1; 4]);
+        cell.store([2; 4]);
+        assert_eq!(cell.swap([3; 4]), [2; 4]);
+        cell.get_mut()[0] = 4;
+        assert_eq!(cell.load(), [4, 3, 3, 3]);
+    }
+
+    #[test]
+    fn no_torn_reads() {
+        static CELL: AtomicCell<[usize; 4]> = AtomicCell::new([0; 4]);
+
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| for i in 0..10000 {
+                    CELL.store([i; 4]);
+                });
+            }
+
+            for _ in 0..10000 {
+                let value = CELL.load();
+                assert!(value.iter().all(|&x| x == value[0]));
+            }
+        });
+    }
+}
@@ -0,0 +1,289 @@
+//! Dedicated collection.
+//!
+//! By default, garbage is collected inline: Retiring garbage occasionally triggers a collection
+//! in the retiring thread (see `Settings::gc_probability`), and so does exceeding the memory
+//! budget. Hence, any operation retiring garbage might run destructors of garbage retired by other
+//! threads, which is a source of latency spikes.
+//!
+//! Disabling the inline collections moves all of the reclamation to explicit collections, e.g.
+//! `conc::gc()` called by the application, or the dedicated collector thread spawned by
+//! `spawn()`. This policy is global, unlike the settings, which are thread-local. Opt-in features
+//! of the thread, which destroy garbage on their own (`Settings::reclaim_on_store` and the
+//! `oom` allocator), still apply.
+//!
+//! As the retiring threads can't help out anymore, garbage piles up, if the collector falls
+//! behind. The watchdog detects this: If no collection has finished for a while, although garbage
+//! is being exported, it invokes a callback.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! fn alarm(lag: Duration) {
+//!     eprintln!("No garbage collected for {:?}.", lag);
+//! }
+//!
+//! conc::collector::set_inline(false);
+//! conc::collector::set_watchdog(Duration::from_millis(100), alarm);
+//! let collector = conc::collector::spawn(Duration::from_millis(10));
+//!
+//! // ...
+//!
+//! collector.stop();
+//! conc::collector::remove_watchdog();
+//! conc::collector::set_inline(true);
+//! ```
+
+use parking_lot::{self, Mutex};
+use std::{io, panic};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+use {foreign, global, thread};
+
+/// Are inline collections enabled?
+static INLINE: AtomicBool = AtomicBool::new(true);
+/// Is a watchdog set?
+///
+/// This allows for checking the watchdog without taking the lock.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+/// The watchdog.
+static WATCHDOG: Mutex<Option<Watchdog>> = parking_lot::const_mutex(None);
+
+/// A watchdog of the collections.
+struct Watchdog {
+    /// The maximal time between two collections, while garbage is exported.
+    max_lag: Duration,
+    /// The callback invoked with the lag, when it exceeds `max_lag`.
+    callback: fn(Duration),
+    /// When the last collection finished (or the watchdog was set).
+    last_collection: Instant,
+    /// When the callback was last invoked.
+    last_alarm: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Check the lag at `now`, returning it, if the callback is to be invoked.
+    ///
+    /// The callback is invoked at most once every `max_lag`, while the collector is behind.
+    fn poll(&mut self, now: Instant) -> Option<Duration> {
+        let lag = now.saturating_duration_since(self.last_collection);
+        if lag <= self.max_lag {
+            return None;
+        }
+
+        if let Some(last_alarm) = self.last_alarm {
+            if now.saturating_duration_since(last_alarm) < self.max_lag {
+                return None;
+            }
+        }
+
+        self.last_alarm = Some(now);
+        Some(lag)
+    }
+}
+
+/// Enable or disable the inline collections.
+///
+/// They are enabled by default.
+pub fn set_inline(enabled: bool) {
+    INLINE.store(enabled, atomic::Ordering::Relaxed);
+}
+
+/// Are the inline collections enabled?
+pub fn is_inline() -> bool {
+    INLINE.load(atomic::Ordering::Relaxed)
+}
+
+/// Set the watchdog, replacing the previous one.
+///
+/// `callback` is invoked with the time since the last collection finished, when it exceeds
+/// `max_lag` and garbage is exported. It runs in the thread exporting the garbage, at most once
+/// every `max_lag`.
+pub fn set_watchdog(max_lag: Duration, callback: fn(Duration)) {
+    let _critical = global::Critical::new();
+    *WATCHDOG.lock() = Some(Watchdog {
+        max_lag: max_lag,
+        callback: callback,
+        last_collection: Instant::now(),
+        last_alarm: None,
+    });
+    WATCHING.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Remove the watchdog.
+pub fn remove_watchdog() {
+    WATCHING.store(false, atomic::Ordering::Relaxed);
+    let _critical = global::Critical::new();
+    *WATCHDOG.lock() = None;
+}
+
+/// Note that a collection finished.
+///
+/// This shall be called by the collector.
+pub(crate) fn record() {
+    if WATCHING.load(atomic::Ordering::Relaxed) {
+        let _critical = global::Critical::new();
+        if let Some(ref mut watchdog) = *WATCHDOG.lock() {
+            watchdog.last_collection = Instant::now();
+        }
+    }
+}
+
+/// Check the watchdog, invoking its callback if the collector is behind.
+///
+/// This shall be called when garbage is exported.
+pub(crate) fn check() {
+    if !WATCHING.load(atomic::Ordering::Relaxed) || global::in_critical() {
+        return;
+    }
+
+    // Copy the callback out, such that it can set a new watchdog without deadlocking.
+    let alarm = {
+        let _critical = global::Critical::new();
+        let mut watchdog = WATCHDOG.lock();
+        watchdog.as_mut().and_then(|x| x.poll(Instant::now()).map(|lag| (x.callback, lag)))
+    };
+
+    if let Some((callback, lag)) = alarm {
+        callback(lag);
+    }
+}
+
+/// A dedicated collector thread.
+///
+/// The thread is stopped, when this is dropped.
+pub struct Collector {
+    /// Should the thread stop?
+    stop: Arc<AtomicBool>,
+    /// The thread.
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Collector {
+    /// Stop the collector thread, waiting for the current collection to finish.
+    pub fn stop(mut self) {
+        self.join();
+    }
+
+    /// Stop the thread and join it, unless it is done already.
+    fn join(&mut self) {
+        self.stop.store(true, atomic::Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.thread().unpark();
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for Collector {
+    fn drop(&mut self) {
+        self.join();
+    }
+}
+
+/// Spawn a thread collecting garbage every `interval`.
+///
+/// The collections wait for each other, so the thread collects about every `interval` or
+/// continuously, if the collections take longer. Panics of destructors don't stop the thread.
+///
+/// # Panics
+///
+/// This panics if the thread couldn't be created. See `try_spawn()`.
+pub fn spawn(interval: Duration) -> Collector {
+    try_spawn(interval).expect("Failed to spawn collector thread.")
+}
+
+/// Spawn a thread collecting garbage every `interval`.
+///
+/// This acts like `spawn()`, except that an error is returned, if the thread couldn't be created.
+pub fn try_spawn(interval: Duration) -> io::Result<Collector> {
+    let stop = Arc::new(AtomicBool::new(false));
+    let stop2 = stop.clone();
+    let thread = thread::Builder::new().name("conc-collector".to_owned()).spawn(move || {
+        while !stop2.load(atomic::Ordering::Relaxed) {
+            // If a destructor panics, the collector is poisoned (depending on the settings), and
+            // we keep trying until the poison is cleared.
+            let _ = panic::catch_unwind(::gc);
+            // The collector is off the paths of the application, so it can afford checking for
+            // vanished foreign threads.
+            foreign::reap();
+            ::std::thread::park_timeout(interval);
+        }
+    })?;
+
+    Ok(Collector {
+        stop: stop,
+        thread: Some(thread),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use Atomic;
+
+    #[test]
+    fn set_get() {
+        assert!(is_inline());
+        set_inline(false);
+        assert!(!is_inline());
+        set_inline(true);
+        assert!(is_inline());
+    }
+
+    #[test]
+    fn watchdog() {
+        fn callback(_: Duration) {}
+
+        let start = Instant::now();
+        let mut watchdog = Watchdog {
+            max_lag: Duration::from_millis(10),
+            callback: callback,
+            last_collection: start,
+            last_alarm: None,
+        };
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(watchdog.poll(ms(5)), None);
+        assert_eq!(watchdog.poll(ms(15)), Some(Duration::from_millis(15)));
+        // The alarm isn't raised again right away.
+        assert_eq!(watchdog.poll(ms(20)), None);
+        assert_eq!(watchdog.poll(ms(25)), Some(Duration::from_millis(25)));
+
+        // A collection resets the lag.
+        watchdog.last_collection = ms(30);
+        assert_eq!(watchdog.poll(ms(35)), None);
+    }
+
+    #[test]
+    fn dedicated_collector() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Dropper;
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let collector = spawn(Duration::from_millis(1));
+        let a = Atomic::new(Some(Box::new(Dropper)));
+        a.store(None, atomic::Ordering::Release);
+        ::local::export_garbage();
+
+        for _ in 0..10000 {
+            if DROPS.load(atomic::Ordering::Relaxed) > 0 {
+                break;
+            }
+            ::std::thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 1);
+
+        collector.stop();
+    }
+}
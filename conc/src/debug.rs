@@ -1,29 +1,441 @@
 //! Runtime debugging tools.
+//!
+//! These are only functional when compiled with the `debug-tools` feature. Without it, debug mode
+//! can't be enabled, and the functions of this module do nothing.
 
 #[cfg(feature = "debug-tools")]
 extern crate backtrace;
 
-/// Execute closure when the environment variable, `CONC_DEBUG_MODE`, is set.
+#[cfg(feature = "debug-tools")]
+use self::backtrace::Backtrace;
+#[cfg(feature = "debug-tools")]
+use parking_lot::{self, Mutex};
+#[cfg(feature = "debug-tools")]
+use std::alloc::{self, Layout};
+#[cfg(feature = "debug-tools")]
+use std::cell::{Cell, RefCell};
+#[cfg(feature = "debug-tools")]
+use std::collections::{BTreeMap, HashMap, VecDeque};
+#[cfg(feature = "debug-tools")]
+use std::{env, ptr, slice, thread};
+#[cfg(feature = "debug-tools")]
+use std::sync::Arc;
+#[cfg(feature = "debug-tools")]
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+#[cfg(feature = "debug-tools")]
+use {sanitize, trace};
+use {global, local, stats};
+
+/// Debug mode hasn't been initialized from `CONC_DEBUG_MODE` yet.
+#[cfg(feature = "debug-tools")]
+const UNINITIALIZED: usize = 0;
+/// Debug mode is disabled.
+#[cfg(feature = "debug-tools")]
+const DISABLED: usize = 1;
+/// Debug mode is enabled.
+#[cfg(feature = "debug-tools")]
+const ENABLED: usize = 2;
+
+/// The state of debug mode.
 ///
-/// When compiled in release mode, this is a NOP.
+/// This is lazily initialized from `CONC_DEBUG_MODE`, unless set before through `enable()` or
+/// `disable()`.
 #[cfg(feature = "debug-tools")]
-pub fn exec<F: FnOnce()>(f: F) {
-    use self::backtrace::Backtrace;
-    use std::env;
+static DEBUG_MODE: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
 
-    thread_local! {
-        /// Is `CONC_DEBUG_MODE` set?
-        ///
-        /// This is cached to avoid expensive repeated syscalls or similar things.
-        static DEBUG_MODE_ENABLED: bool = env::var("CONC_DEBUG_MODE").is_ok();
-        /// Is `CONC_DEBUG_STACKTRACE` set?
+/// The sink receiving the debug events.
+#[cfg(feature = "debug-tools")]
+static SINK: Mutex<Option<Arc<dyn DebugSink>>> = parking_lot::const_mutex(None);
+/// Is a sink set?
+///
+/// This avoids locking `SINK`, when there is no sink.
+#[cfg(feature = "debug-tools")]
+static HAS_SINK: AtomicBool = AtomicBool::new(false);
+
+#[cfg(feature = "debug-tools")]
+thread_local! {
+    /// Is the current thread delivering an event to the sink?
+    ///
+    /// This prevents the events caused by the sink itself from being delivered to it.
+    static IN_SINK: Cell<bool> = Cell::new(false);
+    /// Is `CONC_DEBUG_STACKTRACE` set?
+    ///
+    /// This is cached to avoid expensive repeated syscalls or similar things.
+    static STACK_TRACE_ENABLED: bool = env::var("CONC_DEBUG_STACKTRACE").is_ok();
+    /// The guards created by this thread, which are still alive.
+    ///
+    /// When the thread exits, the guards still alive are reported as leaked.
+    static GUARDS: Tracker = Tracker::new();
+}
+
+/// The guards of every thread, which created guards, and the descriptions of the threads.
+///
+/// This allows for looking up the guards protecting an object. The guards of exited threads are
+/// kept, as they might have been sent to other threads.
+#[cfg(feature = "debug-tools")]
+static TRACKED: Mutex<Vec<(&'static Guards, String)>> = parking_lot::const_mutex(Vec::new());
+
+/// The source of the identifiers of guards.
+#[cfg(feature = "debug-tools")]
+static NEXT_GUARD: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of shards of the retirement registry.
+#[cfg(feature = "debug-tools")]
+const RETIRED_SHARDS: usize = 64;
+
+/// The retired, but not yet reclaimed, pointers and the backtraces of their retirement.
+///
+/// This is sharded by address to avoid every retirement and reclamation contending on a single
+/// lock.
+///
+/// Capturing a backtrace on every retirement is very expensive, so they're only recorded when
+/// `CONC_DEBUG_STACKTRACE` is set, and even then they're left unresolved until an error is
+/// reported.
+#[cfg(feature = "debug-tools")]
+static RETIRED: [Mutex<Option<HashMap<usize, Option<Backtrace>>>>; RETIRED_SHARDS] =
+    [EMPTY_RETIRED; RETIRED_SHARDS];
+
+/// An empty shard of the retirement registry.
+#[cfg(feature = "debug-tools")]
+const EMPTY_RETIRED: Mutex<Option<HashMap<usize, Option<Backtrace>>>> = parking_lot::const_mutex(None);
+
+/// Get the shard of the retirement registry containing `ptr`.
+#[cfg(feature = "debug-tools")]
+fn registry(ptr: *const u8) -> &'static Mutex<Option<HashMap<usize, Option<Backtrace>>>> {
+    // The lowest bits are mostly zero due to alignment, so we skip them.
+    &RETIRED[(ptr.addr() >> 4) % RETIRED_SHARDS]
+}
+
+/// Enable debug mode.
+///
+/// This overrides `CONC_DEBUG_MODE`. Debug mode is process-wide, so it affects every thread.
+#[cfg(feature = "debug-tools")]
+pub fn enable() {
+    DEBUG_MODE.store(ENABLED, atomic::Ordering::Relaxed);
+}
+
+/// Disable debug mode.
+///
+/// This overrides `CONC_DEBUG_MODE`. Debug mode is process-wide, so it affects every thread.
+#[cfg(feature = "debug-tools")]
+pub fn disable() {
+    DEBUG_MODE.store(DISABLED, atomic::Ordering::Relaxed);
+}
+
+/// Is debug mode enabled?
+///
+/// Unless enabled or disabled programmatically, debug mode is enabled if `CONC_DEBUG_MODE` is set.
+#[cfg(feature = "debug-tools")]
+pub fn is_enabled() -> bool {
+    match DEBUG_MODE.load(atomic::Ordering::Relaxed) {
+        UNINITIALIZED => {
+            let mode = if env::var("CONC_DEBUG_MODE").is_ok() { ENABLED } else { DISABLED };
+            // If debug mode was set in the meantime, that takes precedence.
+            match DEBUG_MODE.compare_exchange(
+                UNINITIALIZED,
+                mode,
+                atomic::Ordering::Relaxed,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => mode == ENABLED,
+                Err(actual) => actual == ENABLED,
+            }
+        },
+        mode => mode == ENABLED,
+    }
+}
+
+/// Run `f` with debug mode enabled.
+///
+/// Debug mode is restored to its previous state afterwards, even if `f` panics. Note that debug
+/// mode is process-wide, so other threads running meanwhile are affected as well.
+#[cfg(feature = "debug-tools")]
+pub fn with_enabled<F: FnOnce() -> R, R>(f: F) -> R {
+    /// Restore debug mode on drop.
+    struct Restore(bool);
+
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            if self.0 { enable() } else { disable() }
+        }
+    }
+
+    let _restore = Restore(is_enabled());
+    enable();
+    f()
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this enables debug mode.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn enable() {}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this disables debug mode.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn disable() {}
+
+/// Is debug mode enabled?
+///
+/// Debug mode is only available, when compiled with `debug-tools`, so this returns `false`.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn is_enabled() -> bool {
+    false
+}
+
+/// Run `f`.
+///
+/// When compiled with `debug-tools`, this runs `f` with debug mode enabled.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn with_enabled<F: FnOnce() -> R, R>(f: F) -> R {
+    f()
+}
+
+/// A report of the internal state.
+///
+/// This is a snapshot created by `dump()`. As other threads keep running while it is created, it
+/// isn't necessarily consistent.
+#[derive(Clone, Debug, Default)]
+pub struct StateReport {
+    /// The statistics of the threads, which are still running.
+    ///
+    /// See `stats::threads()`.
+    pub threads: Vec<stats::ThreadStats>,
+    /// The number of garbage objects pending in the global state.
+    ///
+    /// Garbage exported since the last garbage collection isn't counted. This is `None`, if the
+    /// collector was locked.
+    pub global_garbage: Option<usize>,
+    /// The number of bytes of garbage pending in the global state.
+    ///
+    /// See `budget` for how the garbage is accounted.
+    pub pending_bytes: usize,
+    /// The states of the hazards known by the collector.
+    ///
+    /// Hazards created since the last garbage collection aren't counted. This is `None`, if the
+    /// collector was locked.
+    pub hazards: Option<HazardReport>,
+    /// Was the collector locked (usually because a thread was collecting garbage)?
+    pub gc_locked: bool,
+    /// Was the collector poisoned by a panicking destructor?
+    pub poisoned: bool,
+    /// Was the system shut down?
+    pub shut_down: bool,
+}
+
+/// The numbers of hazards in each state.
+#[derive(Clone, Copy, PartialEq, Eq, Debug, Default)]
+pub struct HazardReport {
+    /// The number of hazards not protecting anything.
+    pub free: usize,
+    /// The number of hazards protecting an object.
+    pub protecting: usize,
+    /// The number of hazards blocked by a guard being created.
+    pub blocked: usize,
+    /// The number of dead hazards waiting to be destroyed.
+    pub dead: usize,
+}
+
+/// Create a report of the internal state.
+///
+/// This never waits for ongoing garbage collections, so it can be used to diagnose stalled
+/// reclamation. Unlike the rest of this module, it doesn't require `debug-tools`.
+pub fn dump() -> StateReport {
+    let mut report = global::report();
+    report.threads = local::stats();
+    report
+}
+
+/// Collect all garbage, and panic if any of it is left unreclaimed.
+///
+/// This exports the garbage of the current thread and collects garbage, blocking until it is done
+/// (repeatedly, as destructors might retire new garbage). If any garbage is still pending
+/// afterwards, because it is protected by a guard or its destructor wasn't run (e.g. as the
+/// collector is poisoned), this panics with a listing of it. With `debug-tools`, the listing
+/// includes the backtraces of the retirements, if `CONC_DEBUG_STACKTRACE` is set.
+///
+/// This is meant for asserting at the end of a test, that the structure tested leaves no garbage
+/// behind. As the global state is shared, the garbage of other tests running concurrently shows
+/// up too, so such tests should run one at a time (e.g. with `--test-threads=1`). The garbage
+/// still held locally by other threads cannot be collected either, so they should be joined first.
+pub fn assert_no_garbage() {
+    // Destructors might retire new garbage, so we go on until the thread has none left.
+    loop {
+        if let Err(err) = ::gc() {
+            panic!("conc: Garbage left unreclaimed, as the collection failed: {}", err);
+        }
+
+        if !local::has_garbage() {
+            break;
+        }
+    }
+
+    if let Some(report) = garbage_report(&global::pending_garbage()) {
+        panic!("{}", report);
+    }
+}
+
+/// Describe the garbage left unreclaimed, if any.
+///
+/// `garbage` is as returned by `global::pending_garbage()`.
+fn garbage_report(garbage: &[(*const u8, usize, bool)]) -> Option<String> {
+    if garbage.is_empty() {
+        return None;
+    }
+
+    let mut report = format!("conc: {} garbage object(s) left unreclaimed:\n", garbage.len());
+    for &(ptr, size, protected) in garbage {
+        report += &format!("\n{:?} ", ptr);
+        if size != 0 {
+            report += &format!("({} bytes) ", size);
+        }
+        report += if protected {
+            "is protected by a guard.\n"
+        } else {
+            "is unprotected, but its destructor was not run.\n"
+        };
+        if let Some(backtrace) = retirement(ptr) {
+            report += &format!("Retired at:\n{}", backtrace);
+        }
+    }
+
+    if let Some(n) = guards_held() {
+        if n != 0 {
+            report += &format!("\nThe current thread holds {} guard(s).\n", n);
+        }
+    }
+
+    Some(report)
+}
+
+/// A debug event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum DebugEvent {
+    /// A guard was created.
+    GuardCreated {
+        /// The address of the protected object.
+        ptr: usize,
+    },
+    /// A guard was dropped.
+    GuardDropped {
+        /// The address of the object, which was protected, when the guard was created.
+        ptr: usize,
+    },
+    /// A hazard was set to "free", ending the protection of its pointer.
+    HazardFreed {
+        /// The address of the hazard.
+        hazard: usize,
+    },
+    /// Garbage was added by the current thread.
+    GarbageAdded {
+        /// The address of the garbage.
+        ptr: usize,
+        /// The size hint of the garbage (`0` if unknown).
+        size: usize,
+    },
+    /// Garbage survived `stuck_after` collections (see `Settings`), as it stayed protected.
+    GarbageStuck {
+        /// The address of the garbage.
+        ptr: usize,
+        /// The number of collections, which the garbage survived.
+        collections: usize,
+        /// The number of hazards protecting the garbage.
         ///
-        /// This is cached to avoid expensive repeated syscalls or similar things.
-        static STACK_TRACE_ENABLED: bool = env::var("CONC_DEBUG_STACKTRACE").is_ok();
+        /// If this is `0`, it is protected by a pin, or the guard was dropped since the hazards
+        /// were scanned.
+        hazards: usize,
+    },
+    /// A garbage collection started.
+    GcStarted,
+    /// A garbage collection finished.
+    GcFinished {
+        /// The number of garbage objects left pending.
+        pending: usize,
+    },
+}
+
+/// A receiver of debug events.
+///
+/// The sink is called by the thread, in which the event occurred, possibly while the garbage
+/// collector is locked. Hence, it must not collect garbage itself (e.g. through `conc::gc()`).
+/// Events caused by the sink (e.g. by creating guards) aren't delivered to it.
+pub trait DebugSink: Send + Sync {
+    /// Receive an event.
+    fn event(&self, ev: DebugEvent);
+}
+
+/// Set the sink receiving the debug events.
+///
+/// The events are delivered regardless of whether debug mode is enabled. `None` removes the sink,
+/// which is the default.
+#[cfg(feature = "debug-tools")]
+pub fn set_sink(sink: Option<Box<dyn DebugSink>>) {
+    let mut lock = SINK.lock();
+    HAS_SINK.store(sink.is_some(), atomic::Ordering::Relaxed);
+    *lock = sink.map(Arc::from);
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this sets the sink receiving the debug events.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn set_sink(_: Option<Box<dyn DebugSink>>) {}
+
+/// Deliver the event created by `f` to the sink, if any.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn event<F: FnOnce() -> DebugEvent>(f: F) {
+    let tracing = trace::is_recording();
+    let has_sink = HAS_SINK.load(atomic::Ordering::Relaxed);
+    if !(tracing || has_sink)
+        || IN_SINK.state() == thread::LocalKeyState::Destroyed
+        || IN_SINK.with(|x| x.get()) {
+        return;
     }
 
+    let ev = f();
+    if tracing {
+        trace::record(ev);
+    }
+
+    // Clone the sink out, such that it can replace itself without deadlocking.
+    let sink = if has_sink { SINK.lock().clone() } else { None };
+    if let Some(sink) = sink {
+        /// Unset `IN_SINK` on drop.
+        struct Leave;
+
+        impl Drop for Leave {
+            fn drop(&mut self) {
+                IN_SINK.with(|x| x.set(false));
+            }
+        }
+
+        IN_SINK.with(|x| x.set(true));
+        let _leave = Leave;
+        sink.event(ev);
+    }
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this delivers the event to the sink.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn event<F: FnOnce() -> DebugEvent>(_: F) {}
+
+/// Execute closure when debug mode is enabled.
+///
+/// When compiled in release mode, this is a NOP.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn exec<F: FnOnce()>(f: F) {
     // If enabled, run the closure.
-    if DEBUG_MODE_ENABLED.with(|&x| x) {
+    if is_enabled() {
         f();
         if STACK_TRACE_ENABLED.with(|&x| x) {
             println!("{:?}", Backtrace::new());
@@ -33,8 +445,896 @@ pub fn exec<F: FnOnce()>(f: F) {
 
 /// Do nothing.
 ///
-/// When compiled in debug mode, this will execute the closure when envvar `CONC_DEBUG_MODE` is
-/// set.
+/// When compiled in debug mode, this will execute the closure when debug mode is enabled.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn exec<F: FnOnce()>(_: F) {}
+
+/// Register the retirement of `ptr`.
+///
+/// # Panics
+///
+/// If `ptr` was already retired and not yet reclaimed, this panics with the backtraces of both
+/// retirements (the first one only if `CONC_DEBUG_STACKTRACE` is set), as the pointer would
+/// otherwise be destroyed twice.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn retire(ptr: *const u8) {
+    let first = {
+        let _critical = global::Critical::new();
+        let mut retired = registry(ptr).lock();
+        let retired = retired.get_or_insert_with(HashMap::new);
+
+        match retired.get(&(ptr.addr())) {
+            Some(first) => first.clone(),
+            None => {
+                let backtrace = if STACK_TRACE_ENABLED.with(|&x| x) {
+                    Some(Backtrace::new_unresolved())
+                } else {
+                    None
+                };
+                retired.insert(ptr.addr(), backtrace);
+                return;
+            },
+        }
+    };
+
+    let first = match first {
+        Some(mut backtrace) => {
+            backtrace.resolve();
+            format!("{:?}", backtrace)
+        },
+        None => "(set `CONC_DEBUG_STACKTRACE` to record it)\n".to_owned(),
+    };
+    panic!(
+        "Pointer {:?} retired twice before being reclaimed.\n\nFirst retirement:\n{}\nSecond \
+         retirement:\n{:?}",
+        ptr, first, Backtrace::new()
+    );
+}
+
+/// Register the reclamation of `ptr`.
+///
+/// This must be called before the destructor runs, as the memory could otherwise be reused and
+/// retired again by another thread in the meantime.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn reclaim(ptr: *const u8) {
+    let backtrace = {
+        let _critical = global::Critical::new();
+        registry(ptr).lock().as_mut().and_then(|retired| retired.remove(&(ptr.addr())))
+    };
+
+    // Hand the retirement over to the quarantine.
+    if let Some(Some(backtrace)) = backtrace {
+        if RECLAIMING.state() != thread::LocalKeyState::Destroyed {
+            RECLAIMING.with(|x| *x.borrow_mut() = Some((ptr.addr(), backtrace)));
+        }
+    }
+}
+
+/// Get the backtrace of the retirement of `ptr`, if it was recorded.
+#[cfg(feature = "debug-tools")]
+fn retirement(ptr: *const u8) -> Option<String> {
+    let _critical = global::Critical::new();
+    let mut backtrace = registry(ptr).lock().as_ref()?.get(&(ptr.addr()))?.clone()?;
+    backtrace.resolve();
+    Some(format!("{:?}", backtrace))
+}
+
+/// Get nothing.
+///
+/// When compiled with `debug-tools`, this gets the backtrace of the retirement of `ptr`, if it
+/// was recorded.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+fn retirement(_: *const u8) -> Option<String> {
+    None
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this detects double-retirement of `ptr`.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn retire(_: *const u8) {}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this unregisters the retirement of `ptr`.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn reclaim(_: *const u8) {}
+
+/// The maximal number of reclaimed allocations to keep in quarantine.
+#[cfg(feature = "debug-tools")]
+const QUARANTINE_SIZE: usize = 1024;
+/// The default of the maximal number of bytes to keep in quarantine.
+#[cfg(feature = "debug-tools")]
+const QUARANTINE_BYTES: usize = 1 << 20;
+/// The byte, which quarantined memory is filled with.
+#[cfg(feature = "debug-tools")]
+const POISON: u8 = 0xDE;
+
+/// The quarantine of reclaimed allocations.
+#[cfg(feature = "debug-tools")]
+static QUARANTINE: Mutex<Option<Quarantine>> = parking_lot::const_mutex(None);
+/// The maximal number of bytes to keep in quarantine.
+///
+/// See `set_quarantine_size()`.
+#[cfg(feature = "debug-tools")]
+static QUARANTINE_LIMIT: AtomicUsize = AtomicUsize::new(QUARANTINE_BYTES);
+
+#[cfg(feature = "debug-tools")]
+thread_local! {
+    /// The address and retirement backtrace of the garbage being destroyed by this thread.
+    ///
+    /// The retirement is unregistered before the destructor runs, so it is handed over to the
+    /// quarantine through this.
+    static RECLAIMING: RefCell<Option<(usize, Backtrace)>> = RefCell::new(None);
+}
+
+/// A reclaimed allocation in quarantine.
+#[cfg(feature = "debug-tools")]
+struct Quarantined {
+    /// The allocation.
+    ///
+    /// This is kept as a pointer (rather than an address), such that it can be deallocated.
+    ptr: *mut u8,
+    /// The layout of the allocation.
+    layout: Layout,
+    /// The name of the type of the object, which was reclaimed.
+    type_name: &'static str,
+    /// The backtrace of the retirement of the object, if it was recorded.
+    retired: Option<Backtrace>,
+}
+
+#[cfg(feature = "debug-tools")]
+impl Quarantined {
+    /// Check that the allocation is still poisoned.
+    ///
+    /// This makes the allocation accessible to memory checkers (see `sanitize`).
+    ///
+    /// # Panics
+    ///
+    /// This panics with the retirement of the object, if the allocation was written to after the
+    /// object was reclaimed.
+    ///
+    /// # Safety
+    ///
+    /// The allocation must be in quarantine.
+    unsafe fn check(&self) {
+        sanitize::unpoison(self.ptr, self.layout.size());
+        let bytes = slice::from_raw_parts(self.ptr, self.layout.size());
+        if let Some(offset) = bytes.iter().position(|&x| x != POISON) {
+            panic!(
+                "Reclaimed object of type `{}` at {:?} ({} bytes) was written to at offset {} \
+                 after being destroyed.\n\nRetired at:\n{}",
+                self.type_name, self.ptr, self.layout.size(), offset, self.retirement()
+            );
+        }
+    }
+
+    /// Describe the retirement of the object.
+    fn retirement(&self) -> String {
+        match self.retired {
+            Some(ref backtrace) => {
+                let mut backtrace = backtrace.clone();
+                backtrace.resolve();
+                format!("{:?}", backtrace)
+            },
+            None => "(set `CONC_DEBUG_STACKTRACE` to record it)\n".to_owned(),
+        }
+    }
+}
+
+/// A quarantine of reclaimed allocations.
+///
+/// Rather than freeing reclaimed memory right away, it is poisoned and kept here for a while, such
+/// that use-after-reclaim bugs can be detected, instead of silently corrupting the heap.
+#[cfg(feature = "debug-tools")]
+#[derive(Default)]
+struct Quarantine {
+    /// The quarantined allocations in the order they were quarantined.
+    queue: VecDeque<Quarantined>,
+    /// The quarantined address ranges, mapping start to end.
+    ranges: BTreeMap<usize, usize>,
+    /// The number of bytes in quarantine.
+    bytes: usize,
+}
+
+// The quarantined allocations are owned by the quarantine.
+#[cfg(feature = "debug-tools")]
+unsafe impl Send for Quarantine {}
+
+#[cfg(feature = "debug-tools")]
+impl Quarantine {
+    /// Put an allocation in quarantine.
+    fn insert(&mut self, entry: Quarantined) {
+        self.ranges.insert(entry.ptr.addr(), entry.ptr.addr() + entry.layout.size());
+        self.bytes += entry.layout.size();
+        self.queue.push_back(entry);
+    }
+
+    /// Take the oldest allocation out of the quarantine, if it holds too much.
+    ///
+    /// The quarantine holds too much, when it has more than `QUARANTINE_SIZE` allocations, or more
+    /// than `max_bytes` bytes (if `max_bytes` is `0`, the allocations aren't kept at all).
+    fn evict(&mut self, max_bytes: usize) -> Option<Quarantined> {
+        if self.queue.len() <= QUARANTINE_SIZE && self.bytes <= max_bytes {
+            return None;
+        }
+
+        let entry = self.queue.pop_front()?;
+        self.ranges.remove(&entry.ptr.addr());
+        self.bytes -= entry.layout.size();
+        Some(entry)
+    }
+
+    /// Find the allocation containing `ptr`, if any.
+    fn find(&self, ptr: usize) -> Option<&Quarantined> {
+        let start = self.ranges.range(..ptr + 1).next_back().and_then(|(&start, &end)| {
+            if ptr < end { Some(start) } else { None }
+        })?;
+
+        self.queue.iter().find(|x| x.ptr.addr() == start)
+    }
+}
+
+/// Set the maximal number of bytes to keep in quarantine.
+///
+/// With `debug-tools`, reclaimed boxes are poisoned and kept in a quarantine, until it holds more
+/// than this many bytes (or 1024 allocations), after which the oldest allocations are checked
+/// and freed. Larger quarantines catch uses long after the reclamation, at the cost of memory.
+/// `0` frees the allocations right away, after checking them. The default is 1 MiB.
+#[cfg(feature = "debug-tools")]
+pub fn set_quarantine_size(bytes: usize) {
+    QUARANTINE_LIMIT.store(bytes, atomic::Ordering::Relaxed);
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this sets the maximal number of bytes kept in quarantine.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn set_quarantine_size(_: usize) {}
+
+/// Check the allocations in quarantine.
+///
+/// The quarantined allocations are checked, when they are released from the quarantine, which
+/// might be long after the offending write. This checks all of them right away (e.g. at the end
+/// of a test).
+///
+/// # Panics
+///
+/// This panics with the type and retirement (if `CONC_DEBUG_STACKTRACE` is set) of the object, if
+/// a quarantined allocation was written to after it was reclaimed.
+#[cfg(feature = "debug-tools")]
+pub fn check_quarantine() {
+    let _critical = global::Critical::new();
+    if let Some(ref quarantine) = *QUARANTINE.lock() {
+        for entry in &quarantine.queue {
+            unsafe { entry.check(); }
+            sanitize::poison(entry.ptr, entry.layout.size());
+        }
+    }
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this checks the allocations in quarantine.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn check_quarantine() {}
+
+/// Quarantine a reclaimed allocation instead of freeing it.
+///
+/// The memory is overwritten with a poison pattern. When it is eventually released from the
+/// quarantine, the pattern is checked and the memory is deallocated. `type_name` is the type of the
+/// object, which was reclaimed.
+///
+/// # Panics
+///
+/// This panics if an allocation released from the quarantine was written to after it was
+/// reclaimed.
+///
+/// # Safety
+///
+/// `ptr` must be a live allocation of `layout` (from the global allocator), whose contents have
+/// been dropped.
+#[cfg(feature = "debug-tools")]
+pub(crate) unsafe fn quarantine(ptr: *mut u8, layout: Layout, type_name: &'static str) {
+    // Zero-sized objects don't own any memory.
+    if layout.size() == 0 {
+        return;
+    }
+
+    ptr::write_bytes(ptr, POISON, layout.size());
+    sanitize::poison(ptr, layout.size());
+
+    // Take over the retirement of the object, if it is the one being destroyed.
+    let retired = if RECLAIMING.state() == thread::LocalKeyState::Destroyed {
+        None
+    } else {
+        RECLAIMING.with(|x| {
+            let mut x = x.borrow_mut();
+            match x.take() {
+                Some((addr, backtrace)) if addr == ptr.addr() => Some(backtrace),
+                other => {
+                    *x = other;
+                    None
+                },
+            }
+        })
+    };
+
+    let max_bytes = QUARANTINE_LIMIT.load(atomic::Ordering::Relaxed);
+    let mut released = Vec::new();
+    {
+        let _critical = global::Critical::new();
+        let mut quarantine = QUARANTINE.lock();
+        let quarantine = quarantine.get_or_insert_with(Quarantine::default);
+        quarantine.insert(Quarantined {
+            ptr: ptr,
+            layout: layout,
+            type_name: type_name,
+            retired: retired,
+        });
+        while let Some(entry) = quarantine.evict(max_bytes) {
+            released.push(entry);
+        }
+    }
+
+    for entry in released {
+        release(entry);
+    }
+}
+
+/// Release all the allocations in quarantine.
+///
+/// # Panics
+///
+/// This panics if any of the allocations was written to after it was reclaimed.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn release_quarantine() {
+    let quarantine = {
+        let _critical = global::Critical::new();
+        QUARANTINE.lock().take()
+    };
+    for entry in quarantine.into_iter().flat_map(|x| x.queue) {
+        unsafe { release(entry); }
+    }
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this releases the allocations in quarantine.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn release_quarantine() {}
+
+/// Release an allocation from quarantine.
+///
+/// # Panics
+///
+/// This panics if the allocation was written to after it was reclaimed.
+#[cfg(feature = "debug-tools")]
+unsafe fn release(entry: Quarantined) {
+    entry.check();
+    alloc::dealloc(entry.ptr, entry.layout);
+}
+
+/// Assert that `ptr` doesn't point into a quarantined allocation.
+///
+/// # Panics
+///
+/// This panics if `ptr` points into memory, which was already reclaimed.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn assert_not_quarantined(ptr: *const u8) {
+    let _critical = global::Critical::new();
+    if let Some(ref quarantine) = *QUARANTINE.lock() {
+        if let Some(entry) = quarantine.find(ptr.addr()) {
+            panic!(
+                "Pointer {:?} points into a reclaimed object of type `{}` (at {:?}).\n\nRetired \
+                 at:\n{}",
+                ptr, entry.type_name, entry.ptr, entry.retirement()
+            );
+        }
+    }
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this checks that `ptr` doesn't point into reclaimed memory.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn assert_not_quarantined(_: *const u8) {}
+
+/// The guards created by a thread, which are still alive.
+///
+/// Guards can be sent to other threads, so this is shared with the guards. It is leaked, as the
+/// guards might outlive the thread.
+#[cfg(feature = "debug-tools")]
+#[derive(Debug, Default)]
+struct Guards {
+    /// The protected addresses and creation backtraces of the guards by their identifiers.
+    live: Mutex<HashMap<usize, (usize, Option<Backtrace>)>>,
+}
+
+/// The guards of the current thread, which reports the leaked ones on thread exit.
+#[cfg(feature = "debug-tools")]
+struct Tracker {
+    /// The guards created by the thread.
+    guards: &'static Guards,
+    /// The name of the thread.
+    name: Option<String>,
+}
+
+#[cfg(feature = "debug-tools")]
+impl Tracker {
+    /// Create the tracker of the current thread, registering it in `TRACKED`.
+    fn new() -> Tracker {
+        let tracker = Tracker {
+            guards: Box::leak(Box::new(Guards::default())),
+            name: thread::current().name().map(ToOwned::to_owned),
+        };
+
+        let thread = match tracker.name {
+            Some(ref name) => format!("{} ({:?})", name, thread::current().id()),
+            None => format!("{:?}", thread::current().id()),
+        };
+        // The collector takes the lock, so we can't collect garbage while holding it.
+        let _critical = global::Critical::new();
+        TRACKED.lock().push((tracker.guards, thread));
+
+        tracker
+    }
+
+    /// Describe the guards, which are still alive, if any.
+    fn leak_report(&self) -> Option<String> {
+        let live = self.guards.live.lock();
+        if live.is_empty() {
+            return None;
+        }
+
+        let mut report = format!(
+            "conc: Thread {} exited with {} guard(s) alive. Unless they were sent to other threads, \
+             they are leaked, and the objects protected by them are never reclaimed.\n",
+            self.name.as_ref().map_or("<unnamed>", |x| &x[..]),
+            live.len()
+        );
+        for &(ptr, ref backtrace) in live.values() {
+            report += &format!("\nGuard protecting 0x{:x}, created at:\n", ptr);
+            match *backtrace {
+                Some(ref backtrace) => {
+                    let mut backtrace = backtrace.clone();
+                    backtrace.resolve();
+                    report += &format!("{:?}", backtrace);
+                },
+                None => report += "(set `CONC_DEBUG_STACKTRACE` to record it)\n",
+            }
+        }
+
+        Some(report)
+    }
+}
+
+#[cfg(feature = "debug-tools")]
+impl Drop for Tracker {
+    fn drop(&mut self) {
+        if let Some(report) = self.leak_report() {
+            eprintln!("{}", report);
+        }
+    }
+}
+
+/// A registration of a guard as held.
+///
+/// With `debug-tools`, the guards created by each thread and not yet dropped are tracked, such
+/// that collecting garbage while holding guards, and leaking guards, can be detected.
+#[cfg(feature = "debug-tools")]
+#[derive(Debug)]
+pub(crate) struct Held {
+    /// The guards of the thread, which created the guard, and the identifier of the guard.
+    ///
+    /// This is `None`, if the guard was created after the thread-local state was deinitialized.
+    /// Such guards aren't tracked.
+    guards: Option<(&'static Guards, usize)>,
+}
+
+#[cfg(feature = "debug-tools")]
+impl Held {
+    /// Register a new guard protecting `ptr` as held by the current thread.
+    pub(crate) fn new(ptr: *const u8) -> Held {
+        if GUARDS.state() == thread::LocalKeyState::Destroyed {
+            return Held {
+                guards: None,
+            };
+        }
+
+        let guards = GUARDS.with(|x| x.guards);
+        let id = NEXT_GUARD.fetch_add(1, atomic::Ordering::Relaxed);
+        let backtrace = if STACK_TRACE_ENABLED.with(|&x| x) {
+            Some(Backtrace::new_unresolved())
+        } else {
+            None
+        };
+        guards.live.lock().insert(id, (ptr.addr(), backtrace));
+
+        Held {
+            guards: Some((guards, id)),
+        }
+    }
+}
+
+#[cfg(feature = "debug-tools")]
+impl Drop for Held {
+    fn drop(&mut self) {
+        if let Some((guards, id)) = self.guards {
+            let held = guards.live.lock().remove(&id);
+            if let Some((ptr, _)) = held {
+                event(|| DebugEvent::GuardDropped { ptr: ptr });
+            }
+        }
+    }
+}
+
+/// Get the number of guards created by the current thread, which are still alive.
+///
+/// If the thread-local state is deinitialized, `Some(0)` is returned.
+#[cfg(feature = "debug-tools")]
+pub fn guards_held() -> Option<usize> {
+    if GUARDS.state() == thread::LocalKeyState::Destroyed {
+        Some(0)
+    } else {
+        Some(GUARDS.with(|x| x.guards.live.lock().len()))
+    }
+}
+
+/// Describe the guards protecting `ptr`.
+///
+/// Each guard is described by the thread, which created it, and the backtrace of the creation, if
+/// `CONC_DEBUG_STACKTRACE` is set.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn guards_protecting(ptr: *const u8) -> Vec<String> {
+    let _critical = global::Critical::new();
+    let mut guards = Vec::new();
+    for &(tracked, ref thread) in TRACKED.lock().iter() {
+        for &(addr, ref backtrace) in tracked.live.lock().values() {
+            if addr != ptr.addr() {
+                continue;
+            }
+
+            let mut desc = format!("Guard created by thread {}", thread);
+            if let Some(ref backtrace) = *backtrace {
+                let mut backtrace = backtrace.clone();
+                backtrace.resolve();
+                desc += &format!(", at:\n{:?}", backtrace);
+            }
+            guards.push(desc);
+        }
+    }
+
+    guards
+}
+
+/// Describe the guards protecting `ptr`.
+///
+/// The guards are only tracked, when compiled with `debug-tools`, so this returns nothing.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub(crate) fn guards_protecting(_: *const u8) -> Vec<String> {
+    Vec::new()
+}
+
+/// Warn if the current thread holds guards.
+///
+/// This is used before blocking garbage collections, as the objects protected by the guards can't
+/// be collected.
+#[cfg(feature = "debug-tools")]
+pub(crate) fn warn_if_guards_held() {
+    match guards_held() {
+        Some(0) | None => (),
+        Some(n) => eprintln!(
+            "conc: Collecting garbage while holding {} guard(s) in the current thread. The \
+             objects protected by them cannot be collected.",
+            n
+        ),
+    }
+}
+
+/// Do nothing.
+///
+/// When compiled with `debug-tools`, this warns if the current thread holds guards.
 #[inline]
 #[cfg(not(feature = "debug-tools"))]
-pub fn exec<F: FnOnce()>(_: F) {}
+pub(crate) fn warn_if_guards_held() {}
+
+/// A registration of a guard as held.
+///
+/// When compiled with `debug-tools`, this tracks the guards held by the thread.
+#[cfg(not(feature = "debug-tools"))]
+#[derive(Debug)]
+pub(crate) struct Held;
+
+#[cfg(not(feature = "debug-tools"))]
+impl Held {
+    /// Do nothing.
+    #[inline]
+    pub(crate) fn new(_: *const u8) -> Held {
+        Held
+    }
+}
+
+/// Get the number of guards created by the current thread, which are still alive.
+///
+/// The guards are only counted, when compiled with `debug-tools`, so this returns `None`.
+#[inline]
+#[cfg(not(feature = "debug-tools"))]
+pub fn guards_held() -> Option<usize> {
+    None
+}
+
+#[cfg(all(test, feature = "debug-tools"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn toggle() {
+        disable();
+        assert!(!is_enabled());
+
+        with_enabled(|| assert!(is_enabled()));
+        assert!(!is_enabled());
+
+        enable();
+        assert!(is_enabled());
+        disable();
+        assert!(!is_enabled());
+    }
+
+    #[test]
+    fn sink() {
+        use std::thread;
+        use Atomic;
+
+        static GUARDS: AtomicUsize = AtomicUsize::new(0);
+        static ADDED: AtomicUsize = AtomicUsize::new(0);
+
+        struct Counting;
+
+        impl DebugSink for Counting {
+            fn event(&self, ev: DebugEvent) {
+                // Guards created by the sink aren't reported back to it.
+                let a = Atomic::new(Some(Box::new(0)));
+                drop(a.load(atomic::Ordering::Relaxed));
+
+                match ev {
+                    DebugEvent::GuardCreated { .. } => GUARDS.fetch_add(1, atomic::Ordering::Relaxed),
+                    DebugEvent::GarbageAdded { .. } => ADDED.fetch_add(1, atomic::Ordering::Relaxed),
+                    _ => 0,
+                };
+            }
+        }
+
+        thread::spawn(|| {
+            set_sink(Some(Box::new(Counting)));
+            let a = Atomic::new(Some(Box::new(1)));
+            drop(a.load(atomic::Ordering::Relaxed));
+            a.store(None, atomic::Ordering::Relaxed);
+            set_sink(None);
+        }).join().unwrap();
+
+        assert!(GUARDS.load(atomic::Ordering::Relaxed) >= 1);
+        assert!(ADDED.load(atomic::Ordering::Relaxed) >= 1);
+    }
+
+    #[test]
+    fn guards_held_count() {
+        use std::thread;
+        use Atomic;
+
+        thread::spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            assert_eq!(guards_held(), Some(0));
+
+            let g1 = a.load(atomic::Ordering::Relaxed).unwrap();
+            let g2 = a.load(atomic::Ordering::Relaxed).unwrap().map(|x| x);
+            assert_eq!(guards_held(), Some(2));
+
+            // Guards sent to other threads are still accounted to their creator.
+            thread::spawn(move || drop(g1)).join().unwrap();
+            assert_eq!(guards_held(), Some(1));
+
+            drop(g2);
+            assert_eq!(guards_held(), Some(0));
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn guards_protecting_ptr() {
+        use std::thread;
+        use Atomic;
+
+        // The guard is found, after it was sent to another thread.
+        let g = thread::Builder::new().name("protector".to_owned()).spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            a.load(atomic::Ordering::Relaxed).unwrap()
+        }).unwrap().join().unwrap();
+        let ptr = g.as_ptr() as *const u8;
+
+        let guards = guards_protecting(ptr);
+        assert_eq!(guards.len(), 1);
+        assert!(guards[0].contains("protector"));
+
+        drop(g);
+        assert!(guards_protecting(ptr).is_empty());
+    }
+
+    #[test]
+    fn leaked_guards() {
+        use std::{mem, thread};
+        use Atomic;
+
+        thread::Builder::new().name("leaky".to_owned()).spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            assert_eq!(GUARDS.with(|x| x.leak_report()), None);
+
+            let g = a.load(atomic::Ordering::Relaxed).unwrap();
+            let ptr = g.as_ptr() as usize;
+            mem::forget(g);
+
+            let report = GUARDS.with(|x| x.leak_report()).unwrap();
+            assert!(report.contains("leaky"));
+            assert!(report.contains(&format!("0x{:x}", ptr)));
+        }).unwrap().join().unwrap();
+    }
+
+    #[test]
+    fn checked_gc() {
+        use std::thread;
+        use {Atomic, GcError};
+
+        thread::spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            let g = a.load(atomic::Ordering::Relaxed).unwrap();
+            assert_eq!(::checked_gc(), Err(GcError::GuardsHeld(1)));
+
+            drop(g);
+            ::checked_gc().unwrap();
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn garbage_listing() {
+        assert_eq!(garbage_report(&[]), None);
+
+        let report = garbage_report(&[
+            (ptr::without_provenance(0x10), 8, true),
+            (ptr::without_provenance(0x20), 0, false),
+        ]).unwrap();
+        assert!(report.contains("2 garbage object(s)"));
+        assert!(report.contains("0x10 (8 bytes) is protected"));
+        assert!(report.contains("0x20 is unprotected"));
+    }
+
+    /// Create a quarantine entry of `size` bytes at `addr`.
+    fn quarantined(addr: usize, size: usize) -> Quarantined {
+        Quarantined {
+            ptr: ptr::without_provenance_mut(addr),
+            layout: Layout::from_size_align(size, 8).unwrap(),
+            type_name: "u64",
+            retired: None,
+        }
+    }
+
+    #[test]
+    fn quarantine_find() {
+        let mut q = Quarantine::default();
+        q.insert(quarantined(0x100, 16));
+        q.insert(quarantined(0x200, 8));
+        assert!(q.evict(!0).is_none());
+
+        let find = |x| q.find(x).map(|x| x.ptr.addr());
+        assert_eq!(find(0xF8), None);
+        assert_eq!(find(0x100), Some(0x100));
+        assert_eq!(find(0x10F), Some(0x100));
+        assert_eq!(find(0x110), None);
+        assert_eq!(find(0x204), Some(0x200));
+        assert_eq!(find(0x208), None);
+    }
+
+    #[test]
+    fn quarantine_release() {
+        let mut q = Quarantine::default();
+        for i in 1..QUARANTINE_SIZE + 1 {
+            q.insert(quarantined(i * 8, 8));
+            assert!(q.evict(!0).is_none());
+        }
+
+        // The oldest allocation is released first.
+        q.insert(quarantined(0x100000, 8));
+        assert_eq!(q.evict(!0).map(|x| x.ptr.addr()), Some(8));
+        assert!(q.evict(!0).is_none());
+        assert!(q.find(8).is_none());
+        assert!(q.find(16).is_some());
+    }
+
+    #[test]
+    fn quarantine_bytes() {
+        let mut q = Quarantine::default();
+        q.insert(quarantined(0x100, 64));
+        q.insert(quarantined(0x200, 64));
+        q.insert(quarantined(0x300, 64));
+        assert_eq!(q.bytes, 192);
+
+        // Allocations are released, until the quarantine is within the limit.
+        assert_eq!(q.evict(100).map(|x| x.ptr.addr()), Some(0x100));
+        assert_eq!(q.evict(100).map(|x| x.ptr.addr()), Some(0x200));
+        assert!(q.evict(100).is_none());
+        assert_eq!(q.bytes, 64);
+    }
+
+    #[test]
+    fn quarantine_violation() {
+        use std::panic;
+
+        let x = Box::into_raw(Box::new([POISON; 8]));
+        let entry = Quarantined {
+            ptr: x as *mut u8,
+            layout: Layout::new::<[u8; 8]>(),
+            type_name: "[u8; 8]",
+            retired: None,
+        };
+        unsafe {
+            entry.check();
+            (*x)[3] = 0;
+        }
+
+        let msg = panic::catch_unwind(panic::AssertUnwindSafe(|| unsafe { entry.check() }))
+            .unwrap_err();
+        let msg = msg.downcast_ref::<String>().unwrap();
+        assert!(msg.contains("`[u8; 8]`"));
+        assert!(msg.contains("offset 3"));
+        assert!(msg.contains("CONC_DEBUG_STACKTRACE"));
+
+        drop(unsafe { Box::from_raw(x) });
+    }
+
+    #[test]
+    fn quarantine_poison() {
+        let x = Box::into_raw(Box::new(!0u64));
+        unsafe {
+            quarantine(x as *mut u8, Layout::new::<u64>(), "u64");
+            // The memory is kept around, but poisoned.
+            assert_eq!(*(x as *const [u8; 8]), [POISON; 8]);
+        }
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_retire() {
+        let x = Box::into_raw(Box::new(0u8));
+        retire(x);
+        retire(x);
+    }
+
+    #[test]
+    fn retire_after_reclaim() {
+        let x = Box::into_raw(Box::new(0u8));
+        retire(x);
+        reclaim(x);
+        retire(x);
+        reclaim(x);
+
+        unsafe { drop(Box::from_raw(x)); }
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_add_garbage_box() {
+        let x = Box::into_raw(Box::new(0u64));
+        unsafe {
+            ::add_garbage_box(x);
+            ::add_garbage_box(x);
+        }
+    }
+}
@@ -0,0 +1,259 @@
+//! Deferred callbacks.
+//!
+//! A deferred callback is run after every guard, which existed when it was deferred, is gone. This
+//! is the same condition, which the garbage must meet, but it isn't tied to any object, so it can
+//! be used for e.g. advancing an epoch, once all the current readers are done.
+//!
+//! `synchronize()` is the asynchronous counterpart, i.e. a future completing under the same
+//! condition.
+
+use parking_lot::{self, Mutex};
+use std::future::Future;
+use std::mem;
+use std::pin::Pin;
+use std::task::{Context, Poll, Waker};
+use {fence, global, hazard};
+
+/// The callbacks waiting for the hazards to turn over.
+static DEFERRED: Mutex<Vec<Deferred>> = parking_lot::const_mutex(Vec::new());
+/// The wakers of the pending `Synchronize` futures.
+static WAKERS: Mutex<Vec<Waker>> = parking_lot::const_mutex(Vec::new());
+
+/// A deferred callback.
+struct Deferred {
+    /// The hazards, which must turn over before the callback can run.
+    hazards: hazard::Snapshot,
+    /// The callback.
+    callback: Box<dyn FnOnce() + Send>,
+}
+
+/// Run `f` once every guard existing now is dropped.
+///
+/// Rather than tracking the guards themselves, this waits for every hazard, which is protecting
+/// an object now, to change its state. The callbacks are run by the garbage collections (e.g.
+/// `conc::gc()`), in no particular order and on any thread.
+///
+/// Note that a thread caches the hazards of dropped guards, which might keep them in their state
+/// until the thread creates or drops more guards (or exits). Hence, the callback might run
+/// considerably later than the last guard is dropped. Leaked guards hold back the callback forever.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::Arc;
+/// use std::sync::atomic::{AtomicBool, Ordering};
+///
+/// let done = Arc::new(AtomicBool::new(false));
+/// let done2 = done.clone();
+/// conc::defer(move || done2.store(true, Ordering::Relaxed));
+///
+/// let _ = conc::gc();
+/// assert!(done.load(Ordering::Relaxed));
+/// ```
+pub fn defer<F: FnOnce() + Send + 'static>(f: F) {
+    // Ensure that every hazard set before is visible.
+    fence::heavy();
+    let deferred = Deferred {
+        hazards: hazard::snapshot(),
+        callback: Box::new(f),
+    };
+
+    // The lock is taken by the collector.
+    let _critical = global::Critical::new();
+    DEFERRED.lock().push(deferred);
+}
+
+/// Wait for every guard existing now to be dropped, asynchronously.
+///
+/// The returned future completes under the same condition as the callbacks of `defer()`, without
+/// blocking the thread meanwhile. The pending futures are woken after every garbage collection
+/// (e.g. by `conc::gc()` or the collector thread of `collector::spawn()`), and they check whether
+/// the hazards have turned over, when they are polled.
+///
+/// # Example
+///
+/// ```rust,edition2018
+/// use std::sync::atomic::Ordering;
+///
+/// /// Replace the configuration, and wait for the readers of the old one.
+/// async fn update(config: &conc::Atomic<String>, new: String) {
+///     config.store(Some(Box::new(new)), Ordering::Release);
+///     conc::synchronize().await;
+///     // No reader sees the old configuration anymore.
+/// }
+/// ```
+pub fn synchronize() -> Synchronize {
+    // Ensure that every hazard set before is visible.
+    fence::heavy();
+    Synchronize {
+        hazards: hazard::snapshot(),
+    }
+}
+
+/// A future completing, once every guard, which existed when it was created, is dropped.
+///
+/// See `synchronize()`.
+#[must_use = "futures do nothing unless polled"]
+pub struct Synchronize {
+    /// The hazards, which must turn over before the future completes.
+    hazards: hazard::Snapshot,
+}
+
+impl Future for Synchronize {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = self.get_mut();
+        if this.hazards.is_turned_over() {
+            return Poll::Ready(());
+        }
+
+        {
+            // The lock is taken by the collector.
+            let _critical = global::Critical::new();
+            WAKERS.lock().push(cx.waker().clone());
+        }
+
+        // A collection might have woken the wakers, right before ours was added.
+        if this.hazards.is_turned_over() {
+            Poll::Ready(())
+        } else {
+            Poll::Pending
+        }
+    }
+}
+
+/// Run the deferred callbacks, whose hazards have turned over, and wake the pending futures.
+///
+/// This is called after garbage collections. The callbacks run outside the lock, so they can
+/// defer callbacks themselves, but if the current thread is in a critical section (e.g. running
+/// destructors), nothing is run. If a callback panics, the rest are left for later.
+pub fn run_ready() {
+    if global::in_critical() {
+        return;
+    }
+
+    let wakers = {
+        let _critical = global::Critical::new();
+        mem::replace(&mut *WAKERS.lock(), Vec::new())
+    };
+    for waker in wakers {
+        waker.wake();
+    }
+
+    loop {
+        let callback = {
+            let _critical = global::Critical::new();
+            let mut deferred = DEFERRED.lock();
+            match deferred.iter_mut().position(|x| x.hazards.is_turned_over()) {
+                Some(i) => deferred.swap_remove(i).callback,
+                None => return,
+            }
+        };
+
+        callback();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::task::Wake;
+    use Atomic;
+
+    /// Defer a callback counting its runs in `runs`, which is ready to run right away.
+    ///
+    /// Other tests might leak guards, which would hold back callbacks deferred through `defer()`
+    /// forever, so we skip the snapshot.
+    fn defer_ready(runs: &Arc<AtomicUsize>, f: fn()) {
+        let runs = runs.clone();
+        let _critical = global::Critical::new();
+        DEFERRED.lock().push(Deferred {
+            hazards: hazard::Snapshot::default(),
+            callback: Box::new(move || {
+                f();
+                runs.fetch_add(1, atomic::Ordering::Relaxed);
+            }),
+        });
+    }
+
+    #[test]
+    fn waits_for_guards() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        let a = Atomic::new(Some(Box::new(1)));
+        let guard = a.load(atomic::Ordering::Acquire).unwrap();
+
+        let runs2 = runs.clone();
+        defer(move || {
+            runs2.fetch_add(1, atomic::Ordering::Relaxed);
+        });
+
+        let _ = ::gc();
+        run_ready();
+        assert_eq!(runs.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(*guard, 1);
+    }
+
+    #[test]
+    fn run_once() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        defer_ready(&runs, || ());
+
+        let _ = ::gc();
+        assert_eq!(runs.load(atomic::Ordering::Relaxed), 1);
+        run_ready();
+        assert_eq!(runs.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn nested() {
+        let runs = Arc::new(AtomicUsize::new(0));
+        // Deferring from a callback mustn't deadlock.
+        defer_ready(&runs, || defer(|| ()));
+
+        run_ready();
+        assert_eq!(runs.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    /// A waker counting its wakes.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn synchronize_pending() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let a = Atomic::new(Some(Box::new(1)));
+        let guard = a.load(atomic::Ordering::Acquire).unwrap();
+        let mut sync = synchronize();
+        assert_eq!(Pin::new(&mut sync).poll(&mut cx), Poll::Pending);
+
+        // The future is woken by the collections, but the guard holds it back.
+        run_ready();
+        assert!(counter.0.load(atomic::Ordering::Relaxed) >= 1);
+        assert_eq!(Pin::new(&mut sync).poll(&mut cx), Poll::Pending);
+        assert_eq!(*guard, 1);
+    }
+
+    #[test]
+    fn synchronize_ready() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        // Other tests might leak guards, so we skip the snapshot.
+        let mut sync = Synchronize {
+            hazards: hazard::Snapshot::default(),
+        };
+        assert_eq!(Pin::new(&mut sync).poll(&mut cx), Poll::Ready(()));
+    }
+}
@@ -0,0 +1,288 @@
+//! Background collection on an asynchronous runtime.
+//!
+//! The dedicated collector of `collector` needs an OS thread of its own. An asynchronous
+//! application can collect in the background of its runtime instead: The driver is a
+//! low-priority task, which collects garbage every interval through `conc::gc_async()`, yielding
+//! to the other tasks between the chunks of a collection. Thus, the collection mostly runs when
+//! the workers are otherwise idle.
+//!
+//! The driver is independent of the runtime. It waits for the intervals through the timer given
+//! as `sleep`, e.g. `tokio::time::sleep` or `async_std::task::sleep`. The driver is tuned and
+//! stopped through its `Handle`.
+//!
+//! This module is only available with feature `async-collector`.
+//!
+//! # Example
+//!
+//! ```rust,edition2018
+//! use std::time::Duration;
+//!
+//! async fn sleep(_duration: Duration) {
+//!     // e.g. `tokio::time::sleep(_duration).await`
+//! }
+//!
+//! async fn run() {
+//!     let (driver, handle) = conc::driver::new(Duration::from_millis(10), sleep);
+//!     // e.g. `tokio::spawn(driver)`
+//!
+//!     // ...
+//!
+//!     handle.set_interval(Duration::from_millis(100));
+//!     handle.stop();
+//!     driver.await;
+//! }
+//! ```
+
+use parking_lot::{self, Mutex};
+use std::future::Future;
+use std::panic;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool};
+use std::task::{Context, Poll, Waker};
+use std::time::Duration;
+use {gc_async, GcAsync};
+
+/// The default time slice of a chunk of a collection.
+const DEFAULT_SLICE: Duration = Duration::from_millis(1);
+
+/// The tunables of a driver.
+#[derive(Clone, Copy)]
+struct Config {
+    /// The time between two collections.
+    interval: Duration,
+    /// The time slice of a chunk of a collection.
+    slice: Duration,
+}
+
+/// The state shared between a driver and its handle.
+struct Shared {
+    /// Should the driver stop?
+    stop: AtomicBool,
+    /// The tunables.
+    config: Mutex<Config>,
+    /// The waker of the driver, if it is pending.
+    waker: Mutex<Option<Waker>>,
+}
+
+/// What the driver is doing.
+enum State<F> {
+    /// Waiting for the next collection.
+    Sleeping(Pin<Box<F>>),
+    /// Collecting garbage.
+    Collecting(GcAsync),
+}
+
+/// A task collecting garbage in the background.
+///
+/// This is a future, which is to be spawned on the runtime. It completes, when it is stopped
+/// through its handle.
+#[must_use = "futures do nothing unless polled"]
+pub struct Driver<S, F> {
+    /// The state shared with the handle.
+    shared: Arc<Shared>,
+    /// The timer.
+    sleep: S,
+    /// What the driver is doing.
+    state: State<F>,
+}
+
+// The sleep future is boxed, so the driver can be moved, even when it is pinned.
+impl<S, F> Unpin for Driver<S, F> {}
+
+impl<S, F> Future for Driver<S, F>
+    where S: FnMut(Duration) -> F,
+          F: Future<Output = ()> {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<()> {
+        let this = &mut *self;
+        // Register the waker first, such that a concurrent `stop()` either wakes us or is seen.
+        *this.shared.waker.lock() = Some(cx.waker().clone());
+        if this.shared.stop.load(atomic::Ordering::Acquire) {
+            return Poll::Ready(());
+        }
+
+        let config = *this.shared.config.lock();
+        let done = match this.state {
+            State::Sleeping(ref mut sleep) => {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+
+                false
+            },
+            State::Collecting(ref mut gc) => {
+                // If a destructor panics, the collector is poisoned (depending on the settings),
+                // and we keep trying every interval until the poison is cleared. The future is
+                // dropped afterwards, so its state can't be observed.
+                let res = panic::catch_unwind(panic::AssertUnwindSafe(|| Pin::new(gc).poll(cx)));
+                match res {
+                    Ok(Poll::Pending) => return Poll::Pending,
+                    Ok(Poll::Ready(_)) | Err(_) => true,
+                }
+            },
+        };
+
+        this.state = if done {
+            State::Sleeping(Box::pin((this.sleep)(config.interval)))
+        } else {
+            State::Collecting(gc_async().slice(config.slice))
+        };
+
+        // Yield before going on, such that a timer, which is ready right away, can't make us
+        // loop forever.
+        cx.waker().wake_by_ref();
+        Poll::Pending
+    }
+}
+
+/// A handle to a driver.
+///
+/// The driver is stopped, when this is dropped.
+pub struct Handle {
+    /// The state shared with the driver.
+    shared: Arc<Shared>,
+}
+
+impl Handle {
+    /// Set the time between two collections.
+    ///
+    /// This takes effect after the current interval.
+    pub fn set_interval(&self, interval: Duration) {
+        self.shared.config.lock().interval = interval;
+    }
+
+    /// Set the time slice of a chunk of a collection.
+    ///
+    /// This takes effect from the next collection on. The default is a millisecond.
+    pub fn set_slice(&self, slice: Duration) {
+        self.shared.config.lock().slice = slice;
+    }
+
+    /// Stop the driver.
+    ///
+    /// The driver completes, when it is polled next, abandoning the current collection.
+    pub fn stop(&self) {
+        self.shared.stop.store(true, atomic::Ordering::Release);
+        if let Some(waker) = self.shared.waker.lock().take() {
+            waker.wake();
+        }
+    }
+
+    /// Is the driver stopped?
+    pub fn is_stopped(&self) -> bool {
+        self.shared.stop.load(atomic::Ordering::Acquire)
+    }
+}
+
+impl Drop for Handle {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Create a driver collecting garbage every `interval`.
+///
+/// `sleep` is the timer of the runtime: It is called with a duration, and returns a future
+/// completing after that duration. The driver starts by waiting for the first interval.
+pub fn new<S, F>(interval: Duration, mut sleep: S) -> (Driver<S, F>, Handle)
+    where S: FnMut(Duration) -> F,
+          F: Future<Output = ()> {
+    let shared = Arc::new(Shared {
+        stop: AtomicBool::new(false),
+        config: parking_lot::const_mutex(Config {
+            interval: interval,
+            slice: DEFAULT_SLICE,
+        }),
+        waker: parking_lot::const_mutex(None),
+    });
+
+    let state = State::Sleeping(Box::pin(sleep(interval)));
+    (Driver {
+        shared: shared.clone(),
+        sleep: sleep,
+        state: state,
+    }, Handle {
+        shared: shared,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::future;
+    use std::sync::atomic::AtomicUsize;
+    use std::task::Wake;
+    use Atomic;
+
+    /// A waker counting its wakes.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn collect_and_stop() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+        static SLEEPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Dropper;
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        fn sleep(_: Duration) -> future::Ready<()> {
+            SLEEPS.fetch_add(1, atomic::Ordering::Relaxed);
+            future::ready(())
+        }
+
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let (mut driver, handle) = new(Duration::from_millis(1), sleep);
+
+        let a = Atomic::new(Some(Box::new(Dropper)));
+        a.store(None, atomic::Ordering::Release);
+        ::local::free_hazards();
+
+        // The driver yields after every step, and wakes itself to go on.
+        for polls in 1..10000 {
+            // Go through a few intervals, as concurrent collections might destroy the garbage.
+            let sleeps = SLEEPS.load(atomic::Ordering::Relaxed);
+            if DROPS.load(atomic::Ordering::Relaxed) > 0 && sleeps > 2 {
+                break;
+            }
+            assert!(Pin::new(&mut driver).poll(&mut cx).is_pending());
+            assert_eq!(counter.0.load(atomic::Ordering::Relaxed), polls);
+        }
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 1);
+
+        assert!(!handle.is_stopped());
+        handle.stop();
+        assert!(handle.is_stopped());
+        assert!(Pin::new(&mut driver).poll(&mut cx).is_ready());
+    }
+
+    #[test]
+    fn dropping_handle_stops() {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+        let (mut driver, handle) = new(Duration::from_secs(1), |_| future::pending::<()>());
+
+        assert!(Pin::new(&mut driver).poll(&mut cx).is_pending());
+        assert_eq!(counter.0.load(atomic::Ordering::Relaxed), 0);
+
+        // The pending driver is woken to complete.
+        drop(handle);
+        assert_eq!(counter.0.load(atomic::Ordering::Relaxed), 1);
+        assert!(Pin::new(&mut driver).poll(&mut cx).is_ready());
+    }
+}
@@ -0,0 +1,484 @@
+//! Pluggable reclamation engines.
+//!
+//! The era-based engines (`eras` and `ibr`) share their front-end: Objects are allocated along
+//! with the era of their birth, retired objects carry the era of their retirement, and an object
+//! is destroyed, once no thread protects an interval of eras overlapping its lifetime. What
+//! differs is how readers protect the eras, which is what `ReclaimBackend` abstracts.
+//!
+//! `Atomic<T, B>` and `Guard<T, B>` are generic over the backend, so structures written against
+//! them can select the engine at compile time, e.g. to benchmark the engines against each other.
+//! `eras::Atomic<T>` and `ibr::Atomic<T>` are aliases for the respective backends.
+//!
+//! The hazards of the rest of the crate aren't a backend, as they protect objects without a
+//! header, and neither is QSBR, which needs a front-end of read sections rather than guards (see
+//! `notes/conc-reclaim-backends.md`). Structures generic over those as well can be written against
+//! `reclaim::Protect`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::engine::{Atomic, ReclaimBackend};
+//! use conc::eras::Eras;
+//! use conc::ibr::Ibr;
+//!
+//! fn replace<B: ReclaimBackend>() -> u32 {
+//!     let atomic: Atomic<u32, B> = Atomic::new(Some(1));
+//!     let guard = atomic.swap(Some(2)).unwrap();
+//!     conc::engine::collect();
+//!     *guard
+//! }
+//!
+//! assert_eq!(replace::<Eras>(), 1);
+//! assert_eq!(replace::<Ibr>(), 1);
+//! ```
+
+use parking_lot::{self, Mutex};
+use std::cell::RefCell;
+use std::marker::PhantomData;
+use std::sync::atomic::{self, AtomicPtr, AtomicU64};
+use std::{mem, ops, ptr, thread};
+
+/// The number of retired objects of a thread, after which it collects them.
+const COLLECT_THRESHOLD: usize = 64;
+
+/// Retired objects of exited threads, which are still protected.
+static ORPHANS: Mutex<Vec<Object>> = parking_lot::const_mutex(Vec::new());
+
+thread_local! {
+    /// The objects retired by this thread, which might still be protected.
+    static RETIRED: RefCell<Retired> = RefCell::new(Retired::default());
+}
+
+/// The protection of an era-based reclamation engine.
+///
+/// A backend keeps an era clock, and protects the eras, in which the guards of readers loaded
+/// their objects. Retiring an object advances the clock, and the object is destroyed, once none
+/// of the intervals returned by `intervals()` overlaps the eras from its birth to its retirement.
+///
+/// # Safety
+///
+/// Every era published through a token must be covered by the intervals pushed by `intervals()`,
+/// until the token is released, and publishing must be sequentially consistent.
+pub unsafe trait ReclaimBackend: 'static {
+    /// The protection held by a guard.
+    type Token;
+
+    /// Get the era clock.
+    ///
+    /// This must start above 0, and is advanced by the front-end.
+    fn clock() -> &'static AtomicU64;
+
+    /// Acquire a token protecting no era yet.
+    fn acquire() -> Self::Token;
+
+    /// Protect the current era of the clock through `token`.
+    ///
+    /// This returns `true`, if the era wasn't protected already, in which case the object must
+    /// be loaded again, as it might have been retired before.
+    fn publish(token: &Self::Token) -> bool;
+
+    /// Release the protection of `token`.
+    fn release(token: &Self::Token);
+
+    /// Push the intervals of eras, which are protected, to `intervals`.
+    fn intervals(intervals: &mut Vec<(u64, u64)>);
+}
+
+/// A list of retired objects.
+#[derive(Default)]
+struct Retired {
+    /// The objects.
+    objects: Vec<Object>,
+}
+
+impl Drop for Retired {
+    fn drop(&mut self) {
+        ORPHANS.lock().append(&mut self.objects);
+    }
+}
+
+/// A retired object.
+struct Object {
+    /// The node of the object.
+    ptr: *mut u8,
+    /// The era, in which the object was allocated.
+    birth: u64,
+    /// The era, in which the object was retired.
+    retire: u64,
+    /// The destructor of the node.
+    dtor: unsafe fn(*mut u8),
+    /// The `ReclaimBackend::intervals()` of the backend of the object.
+    intervals: fn(&mut Vec<(u64, u64)>),
+}
+
+// The node is unreachable, and the values of `Atomic<T>` are `Send`.
+unsafe impl Send for Object {}
+
+impl Object {
+    /// Is the object protected by one of `intervals`?
+    fn is_protected(&self, intervals: &[(u64, u64)]) -> bool {
+        intervals.iter().any(|&(lower, upper)| self.birth <= upper && lower <= self.retire)
+    }
+}
+
+/// An object and its header.
+struct Node<T> {
+    /// The era, in which the node was allocated.
+    birth: u64,
+    /// The object.
+    value: T,
+}
+
+/// Get the current era of the backend `B`.
+pub fn era<B: ReclaimBackend>() -> u64 {
+    B::clock().load(atomic::Ordering::SeqCst)
+}
+
+/// Destroy the objects retired by the current thread (and exited threads), which are no longer
+/// protected.
+///
+/// This covers the objects of every backend. It is done automatically, when enough objects are
+/// retired by the thread.
+pub fn collect() {
+    if RETIRED.state() != thread::LocalKeyState::Destroyed {
+        // The destructors might retire objects as well, so we don't borrow the list meanwhile.
+        let objects = RETIRED.with(|x| mem::replace(&mut x.borrow_mut().objects, Vec::new()));
+        let mut kept = destroy_unprotected(objects);
+        RETIRED.with(|x| x.borrow_mut().objects.append(&mut kept));
+    }
+
+    let orphans = mem::replace(&mut *ORPHANS.lock(), Vec::new());
+    if !orphans.is_empty() {
+        let mut kept = destroy_unprotected(orphans);
+        ORPHANS.lock().append(&mut kept);
+    }
+}
+
+/// Destroy the objects of `objects`, which are unprotected, and return the rest.
+fn destroy_unprotected(objects: Vec<Object>) -> Vec<Object> {
+    // Protecting an era is sequentially consistent, so every era protected before the retirement
+    // is visible.
+    atomic::fence(atomic::Ordering::SeqCst);
+
+    // Gather the intervals of every backend once. The backends are told apart by their function,
+    // and if it isn't unique, they are just gathered more than once.
+    let mut backends: Vec<(fn(&mut Vec<(u64, u64)>), Vec<(u64, u64)>)> = Vec::new();
+    let mut kept = Vec::new();
+    for i in objects {
+        let n = match backends.iter().position(|x| x.0 as usize == i.intervals as usize) {
+            Some(n) => n,
+            None => {
+                let mut intervals = Vec::new();
+                (i.intervals)(&mut intervals);
+                backends.push((i.intervals, intervals));
+                backends.len() - 1
+            },
+        };
+
+        if i.is_protected(&backends[n].1) {
+            kept.push(i);
+        } else {
+            unsafe { (i.dtor)(i.ptr); }
+        }
+    }
+
+    kept
+}
+
+/// Retire `node` of backend `B`, which has been unlinked.
+///
+/// # Safety
+///
+/// `node` must be unreachable, and allocated by `Atomic<T, B>`.
+unsafe fn retire<T: Send + 'static, B: ReclaimBackend>(node: *mut Node<T>) {
+    unsafe fn dtor<T>(ptr: *mut u8) {
+        drop(Box::from_raw(ptr as *mut Node<T>));
+    }
+
+    let era = B::clock().load(atomic::Ordering::SeqCst);
+    let object = Object {
+        ptr: node as *mut u8,
+        birth: (*node).birth,
+        retire: era,
+        dtor: dtor::<T>,
+        intervals: B::intervals,
+    };
+
+    // Advance the clock, unless another thread did so already, such that readers, who protect
+    // the era afterwards, don't protect this object.
+    let _ = B::clock()
+        .compare_exchange(era, era + 1, atomic::Ordering::SeqCst, atomic::Ordering::Relaxed);
+
+    if RETIRED.state() == thread::LocalKeyState::Destroyed {
+        ORPHANS.lock().push(object);
+        return;
+    }
+
+    let len = RETIRED.with(|x| {
+        let mut retired = x.borrow_mut();
+        retired.objects.push(object);
+        retired.objects.len()
+    });
+    if len >= COLLECT_THRESHOLD {
+        collect();
+    }
+}
+
+/// A pointer to an object protected through the backend `B`.
+///
+/// The object is protected until the guard is dropped.
+pub struct Guard<T, B: ReclaimBackend> {
+    /// The protection of the object.
+    token: B::Token,
+    /// The object.
+    ptr: *const T,
+}
+
+impl<T, B: ReclaimBackend> Guard<T, B> {
+    /// Get the raw pointer of this guard.
+    pub fn as_ptr(&self) -> *const T {
+        self.ptr
+    }
+}
+
+impl<T, B: ReclaimBackend> ops::Deref for Guard<T, B> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        unsafe { &*self.ptr }
+    }
+}
+
+impl<T, B: ReclaimBackend> Drop for Guard<T, B> {
+    fn drop(&mut self) {
+        B::release(&self.token);
+    }
+}
+
+/// A concurrently accessible option of a value, reclaimed through the backend `B`.
+///
+/// This mirrors `conc::Atomic<T>`, but the values are given by value, as they are allocated
+/// along with their birth era. The operations are sequentially consistent, as the protection
+/// relies on the order of the accesses to the pointer and the clock.
+pub struct Atomic<T: Send + Sync + 'static, B: ReclaimBackend> {
+    /// The node of the value, or null.
+    inner: AtomicPtr<Node<T>>,
+    /// Ownership of the node.
+    _marker: PhantomData<(Box<Node<T>>, B)>,
+}
+
+impl<T: Send + Sync + 'static, B: ReclaimBackend> Atomic<T, B> {
+    /// Create a new concurrent option.
+    pub fn new(init: Option<T>) -> Atomic<T, B> {
+        Atomic {
+            inner: AtomicPtr::new(alloc::<T, B>(init)),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Load and protect the current value.
+    ///
+    /// This protects the current era, and if the clock moves on before the pointer is loaded,
+    /// retries with the new era. `None` is returned, if the option is empty.
+    pub fn load(&self) -> Option<Guard<T, B>> {
+        let token = B::acquire();
+        loop {
+            let node = self.inner.load(atomic::Ordering::SeqCst);
+            // If the clock moved on, the node might have been retired before the protected era.
+            // Protect the new era, and load again.
+            if B::publish(&token) {
+                continue;
+            }
+
+            if node.is_null() {
+                B::release(&token);
+                return None;
+            }
+
+            return Some(Guard {
+                token: token,
+                ptr: unsafe { &(*node).value },
+            });
+        }
+    }
+
+    /// Store a new value, retiring the old one.
+    pub fn store(&self, new: Option<T>) {
+        let old = self.inner.swap(alloc::<T, B>(new), atomic::Ordering::SeqCst);
+        if !old.is_null() {
+            unsafe { retire::<T, B>(old); }
+        }
+    }
+
+    /// Store a new value, returning the old one protected.
+    pub fn swap(&self, new: Option<T>) -> Option<Guard<T, B>> {
+        // The old value is alive in the current era, and it is retired in it or a later one, so
+        // protecting the era before the swap protects it.
+        let token = B::acquire();
+        B::publish(&token);
+
+        let old = self.inner.swap(alloc::<T, B>(new), atomic::Ordering::SeqCst);
+        if old.is_null() {
+            B::release(&token);
+            return None;
+        }
+
+        unsafe {
+            retire::<T, B>(old);
+            Some(Guard {
+                token: token,
+                ptr: &(*old).value,
+            })
+        }
+    }
+
+    /// Store a new value, if the current value is `old`.
+    ///
+    /// `old` is compared by address (`None` for the empty option). On success, the old value is
+    /// retired. On failure, the new value is given back.
+    pub fn compare_and_store(&self, old: Option<*const T>, new: Option<T>)
+        -> Result<(), Option<T>> {
+        let current = self.inner.load(atomic::Ordering::SeqCst);
+        let expected = match old {
+            Some(old) if current.is_null() || unsafe { &(*current).value as *const T } != old => {
+                return Err(new);
+            },
+            None if !current.is_null() => return Err(new),
+            _ => current,
+        };
+
+        let node = alloc::<T, B>(new);
+        match self.inner.compare_exchange(expected, node, atomic::Ordering::SeqCst,
+                                         atomic::Ordering::Relaxed) {
+            Ok(old) => {
+                if !old.is_null() {
+                    unsafe { retire::<T, B>(old); }
+                }
+                Ok(())
+            },
+            Err(_) => Err(unsafe { dealloc(node) }),
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static, B: ReclaimBackend> Default for Atomic<T, B> {
+    fn default() -> Atomic<T, B> {
+        Atomic::new(None)
+    }
+}
+
+impl<T: Send + Sync + 'static, B: ReclaimBackend> Drop for Atomic<T, B> {
+    fn drop(&mut self) {
+        let node = *self.inner.get_mut();
+        if !node.is_null() {
+            // Guards might still refer to the value.
+            unsafe { retire::<T, B>(node); }
+        }
+    }
+}
+
+/// Allocate the node of `value`, born in the current era of `B`.
+fn alloc<T, B: ReclaimBackend>(value: Option<T>) -> *mut Node<T> {
+    value.map_or(ptr::null_mut(), |value| Box::into_raw(Box::new(Node {
+        birth: B::clock().load(atomic::Ordering::SeqCst),
+        value: value,
+    })))
+}
+
+/// Deallocate a node, which was never published, returning its value.
+unsafe fn dealloc<T>(node: *mut Node<T>) -> Option<T> {
+    if node.is_null() {
+        None
+    } else {
+        Some(Box::from_raw(node).value)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use eras::Eras;
+    use ibr::Ibr;
+    use testing::{Counter, Tracked};
+
+    fn nop(_: *mut u8) {}
+
+    fn none(_: &mut Vec<(u64, u64)>) {}
+
+    fn everything(intervals: &mut Vec<(u64, u64)>) {
+        intervals.push((1, u64::max_value()));
+    }
+
+    #[test]
+    fn is_protected() {
+        let object = Object {
+            ptr: ptr::null_mut(),
+            birth: 5,
+            retire: 10,
+            dtor: nop,
+            intervals: none,
+        };
+
+        assert!(!object.is_protected(&[]));
+        assert!(!object.is_protected(&[(1, 4), (11, 20)]));
+        assert!(object.is_protected(&[(1, 5)]));
+        assert!(object.is_protected(&[(10, 12)]));
+        assert!(object.is_protected(&[(6, 7)]));
+        assert!(object.is_protected(&[(1, 20)]));
+    }
+
+    #[test]
+    fn backends_scanned_separately() {
+        unsafe fn dtor(ptr: *mut u8) {
+            drop(Box::from_raw(ptr as *mut Tracked<()>));
+        }
+
+        static COUNTER: Counter = Counter::new();
+
+        let object = |intervals| Object {
+            ptr: Box::into_raw(Box::new(Tracked::with_counter((), &COUNTER))) as *mut u8,
+            birth: 1,
+            retire: 1,
+            dtor: dtor,
+            intervals: intervals,
+        };
+
+        let kept = destroy_unprotected(vec![object(none), object(everything), object(none)]);
+        assert_eq!(COUNTER.destroyed(), 2);
+        assert_eq!(kept.len(), 1);
+
+        // Leak the protected object rather than freeing it here.
+        mem::forget(kept);
+    }
+
+    /// Replace a value through the backend `B`, checking that the old one is protected.
+    ///
+    /// The values are counted in `counter`, which must not be used otherwise.
+    fn replace<B: ReclaimBackend>(counter: &'static Counter) {
+        let a: Atomic<Tracked<u32>, B> = Atomic::new(Some(Tracked::with_counter(1, counter)));
+        let guard = a.swap(Some(Tracked::with_counter(2, counter))).unwrap();
+        collect();
+        assert_eq!(counter.destroyed(), 0);
+        assert_eq!(**guard, 1);
+
+        drop(guard);
+        drop(a);
+        for _ in 0..10000 {
+            collect();
+            if counter.destroyed() >= 2 {
+                break;
+            }
+            thread::yield_now();
+        }
+        assert_eq!(counter.destroyed(), 2);
+    }
+
+    #[test]
+    fn generic() {
+        static ERAS: Counter = Counter::new();
+        static IBR: Counter = Counter::new();
+
+        replace::<Eras>(&ERAS);
+        replace::<Ibr>(&IBR);
+    }
+}
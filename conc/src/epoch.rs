@@ -0,0 +1,343 @@
+//! Epoch-protected loads for read-mostly data.
+//!
+//! Loading through a hazard takes a hazard from the local cache, sets it, and frees it again. For
+//! data, which is read all the time, but rarely written (e.g. configuration or routing tables),
+//! this is a significant part of the read. The `Atomic`s created through `Atomic::read_mostly()`
+//! can instead be loaded through `Atomic::load_epoch()`, which merely stamps the current epoch in
+//! a slot of the thread, and clears it when the returned `EpochGuard` is dropped.
+//!
+//! The values retired from such `Atomic`s advance the epoch, and are held in a limbo queue, until
+//! every thread has either cleared its stamp or stamped a later epoch. Then they are handed over
+//! to the ordinary garbage, which is destroyed once no hazard protects it, so the values can
+//! still be loaded through `Atomic::load()` as well. The limbo is flushed, when it exceeds
+//! `Settings::max_garbage_before_export`, and by `conc::gc()`.
+//!
+//! An epoch guard holds back all the values retired from read-mostly `Atomic`s after it was
+//! created, so it shouldn't be held for long.
+
+use parking_lot::{self, Mutex};
+use std::cell::Cell;
+use std::marker::PhantomData;
+use std::sync::atomic::{self, AtomicUsize};
+use std::{fmt, ops, thread};
+use garbage::Garbage;
+use {fence, global, local, settings};
+
+/// The stamp of a thread, which reads nothing.
+const QUIESCENT: usize = 0;
+
+/// The global epoch.
+///
+/// This starts at 1, as 0 is `QUIESCENT`.
+static EPOCH: AtomicUsize = AtomicUsize::new(1);
+/// Every stamp ever allocated.
+static STAMPS: Mutex<Vec<&'static Stamp>> = parking_lot::const_mutex(Vec::new());
+/// Stamps of exited threads, which are ready to be reused.
+static FREE: Mutex<Vec<&'static Stamp>> = parking_lot::const_mutex(Vec::new());
+/// The garbage retired from read-mostly `Atomic`s, along with the epoch it was retired in.
+static LIMBO: Mutex<Vec<(usize, Garbage)>> = parking_lot::const_mutex(Vec::new());
+
+thread_local! {
+    /// The stamp of this thread, if it has one.
+    static LOCAL: Local = Local::default();
+}
+
+/// The state of a thread.
+#[derive(Default)]
+struct Local {
+    /// The stamp of the thread, if it has one.
+    stamp: Cell<Option<&'static Stamp>>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        if let Some(stamp) = self.stamp.get() {
+            // If guards outlive the state (e.g. in other thread-local destructors), the stamp is
+            // still in use, so we leak it.
+            if stamp.depth.load(atomic::Ordering::Relaxed) == 0 {
+                let _critical = global::Critical::new();
+                FREE.lock().push(stamp);
+            }
+        }
+    }
+}
+
+/// The slot, in which a thread stamps the epoch it reads in.
+struct Stamp {
+    /// The epoch stamped, or `QUIESCENT`.
+    epoch: AtomicUsize,
+    /// The number of epoch guards of the owner.
+    ///
+    /// Only the owner accesses this.
+    depth: AtomicUsize,
+}
+
+/// Take a stamp, reusing a free one, if any.
+fn take() -> &'static Stamp {
+    if let Some(stamp) = FREE.lock().pop() {
+        return stamp;
+    }
+
+    let stamp: &'static Stamp = Box::leak(Box::new(Stamp {
+        epoch: AtomicUsize::new(QUIESCENT),
+        depth: AtomicUsize::new(0),
+    }));
+    let _critical = global::Critical::new();
+    STAMPS.lock().push(stamp);
+
+    stamp
+}
+
+/// Stamp the current epoch, unless the thread stamped already.
+///
+/// The stamp is returned along with whether it is owned by the caller rather than the thread,
+/// which is the case, when the thread-local state is gone.
+fn enter() -> (&'static Stamp, bool) {
+    let (stamp, owned) = if LOCAL.state() != thread::LocalKeyState::Destroyed {
+        (LOCAL.with(|local| match local.stamp.get() {
+            Some(stamp) => stamp,
+            None => {
+                let stamp = take();
+                local.stamp.set(Some(stamp));
+                stamp
+            },
+        }), false)
+    } else {
+        (take(), true)
+    };
+
+    // Nested guards keep the epoch of the outermost one.
+    if stamp.depth.fetch_add(1, atomic::Ordering::Relaxed) == 0 {
+        stamp.epoch.store(EPOCH.load(atomic::Ordering::Acquire), atomic::Ordering::Relaxed);
+        // The collector issues the heavy counterpart before reading the stamps, so either it sees
+        // the stamp, or the loads following this see the values swapped in before.
+        fence::light();
+    }
+
+    (stamp, owned)
+}
+
+/// Clear the stamp taken through `enter()`, unless an outer guard still uses it.
+fn exit(stamp: &'static Stamp, owned: bool) {
+    if stamp.depth.fetch_sub(1, atomic::Ordering::Relaxed) == 1 {
+        // Release the reads done under the stamp to the collector.
+        stamp.epoch.store(QUIESCENT, atomic::Ordering::Release);
+
+        if owned {
+            let _critical = global::Critical::new();
+            FREE.lock().push(stamp);
+        }
+    }
+}
+
+/// Retire garbage, which may be read by the threads in the current epoch.
+///
+/// This advances the epoch, and puts the garbage in the limbo. If the limbo exceeds the limit of
+/// garbage (see `Settings::max_garbage_before_export`), it is flushed.
+pub fn retire(garbage: Garbage) {
+    // The value was made unreachable before, so the threads stamping a later epoch can't read it.
+    let epoch = EPOCH.fetch_add(1, atomic::Ordering::SeqCst);
+
+    let len = {
+        let _critical = global::Critical::new();
+        let mut limbo = LIMBO.lock();
+        limbo.push((epoch, garbage));
+        limbo.len()
+    };
+
+    if len > settings::get().max_garbage_before_export {
+        flush();
+    }
+}
+
+/// Hand the garbage, which no thread can read in its epoch anymore, over to the ordinary garbage.
+///
+/// The garbage might still be protected by hazards, so it is added to the garbage of the current
+/// thread rather than destroyed.
+pub fn flush() {
+    // Only the garbage retired before this is considered, as the stamps read below might miss the
+    // threads stamping an epoch, in which garbage retired afterwards is reachable.
+    let cutoff = EPOCH.load(atomic::Ordering::SeqCst);
+    fence::heavy();
+
+    let oldest = {
+        let _critical = global::Critical::new();
+        STAMPS.lock().iter()
+            .map(|stamp| stamp.epoch.load(atomic::Ordering::Acquire))
+            .filter(|&epoch| epoch != QUIESCENT)
+            .fold(cutoff, usize::min)
+    };
+
+    let ready: Vec<Garbage> = {
+        let _critical = global::Critical::new();
+        let mut limbo = LIMBO.lock();
+        let mut ready = Vec::new();
+        let mut i = 0;
+        while i < limbo.len() {
+            if limbo[i].0 < oldest {
+                ready.push(limbo.swap_remove(i).1);
+            } else {
+                i += 1;
+            }
+        }
+
+        ready
+    };
+
+    // The limbo is released, as adding garbage might run destructors, which retire garbage.
+    for garbage in ready {
+        local::add_garbage(garbage);
+    }
+}
+
+/// A reference protected by the epoch of the current thread.
+///
+/// This is returned by `Atomic::load_epoch()`. Unlike `Guard<T>`, it doesn't hold a hazard, but
+/// keeps the epoch stamped by the thread, and so it can't be sent to other threads.
+pub struct EpochGuard<'a, T: 'a> {
+    /// The protected object.
+    pointer: &'a T,
+    /// The stamp of the thread.
+    stamp: &'static Stamp,
+    /// Is the stamp owned by the guard rather than by the thread?
+    owned: bool,
+    /// Keep the guard on the thread, which stamped.
+    _marker: PhantomData<*const ()>,
+}
+
+impl<'a, T> EpochGuard<'a, T> {
+    /// Create an epoch guard to the pointer returned by `ptr`.
+    ///
+    /// The epoch is stamped before `ptr` is evaluated, and if it returns `None`, the stamp is
+    /// cleared again.
+    pub fn maybe_new<F>(ptr: F) -> Option<EpochGuard<'a, T>>
+    where F: FnOnce() -> Option<&'a T> {
+        let (stamp, owned) = enter();
+        match ptr() {
+            Some(pointer) => Some(EpochGuard {
+                pointer: pointer,
+                stamp: stamp,
+                owned: owned,
+                _marker: PhantomData,
+            }),
+            None => {
+                exit(stamp, owned);
+                None
+            },
+        }
+    }
+
+    /// Get the raw pointer to the protected object.
+    pub fn as_ptr(&self) -> *const T {
+        self.pointer
+    }
+}
+
+impl<'a, T> ops::Deref for EpochGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+impl<'a, T> Drop for EpochGuard<'a, T> {
+    fn drop(&mut self) {
+        exit(self.stamp, self.owned);
+    }
+}
+
+impl<'a, T: fmt::Debug> fmt::Debug for EpochGuard<'a, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("EpochGuard").field("pointer", &self.pointer).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic;
+    use testing::{Counter, Tracked};
+    use {Atomic, ReadMostly};
+
+    #[test]
+    fn protects() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::read_mostly(Some(Box::new(Tracked::with_counter(1, &COUNTER))));
+
+        let guard = a.load_epoch(atomic::Ordering::Acquire).unwrap();
+        a.store(Some(Box::new(Tracked::with_counter(2, &COUNTER))), atomic::Ordering::Release);
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 0);
+        assert_eq!(**guard, 1);
+
+        drop(guard);
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
+    #[test]
+    fn nested() {
+        thread::spawn(|| {
+            let a = Atomic::read_mostly(Some(Box::new(1)));
+            let outer = a.load_epoch(atomic::Ordering::Acquire).unwrap();
+            let stamp = outer.stamp.epoch.load(atomic::Ordering::Relaxed);
+            a.store(Some(Box::new(2)), atomic::Ordering::Release);
+
+            // The inner guard keeps the epoch of the outer one.
+            let inner = a.load_epoch(atomic::Ordering::Acquire).unwrap();
+            assert_eq!(*inner, 2);
+            assert_eq!(inner.stamp.epoch.load(atomic::Ordering::Relaxed), stamp);
+            drop(outer);
+            assert_eq!(inner.stamp.epoch.load(atomic::Ordering::Relaxed), stamp);
+
+            let stamp = inner.stamp;
+            drop(inner);
+            assert_eq!(stamp.epoch.load(atomic::Ordering::Relaxed), QUIESCENT);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn none() {
+        let a = ReadMostly::<u8>::read_mostly(None);
+        assert!(a.load_epoch(atomic::Ordering::Acquire).is_none());
+    }
+
+    #[test]
+    fn bounded_limbo() {
+        thread::spawn(|| {
+            let a = Atomic::read_mostly(Some(Box::new(0)));
+            for i in 0..1000 {
+                a.store(Some(Box::new(i)), atomic::Ordering::Release);
+            }
+
+            // Other tests might hold epoch guards, so the limbo is only flushed on the way.
+            assert!(LIMBO.lock().len() < 1000);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn spam() {
+        let a = Arc::new(Atomic::read_mostly(Some(Box::new([0usize; 4]))));
+
+        let readers: Vec<_> = (0..4).map(|_| {
+            let a = a.clone();
+            thread::spawn(move || {
+                for _ in 0..10000 {
+                    let x = a.load_epoch(atomic::Ordering::Acquire).unwrap();
+                    assert!(x.iter().all(|&y| y == x[0]));
+                }
+            })
+        }).collect();
+
+        for i in 0..1000 {
+            a.store(Some(Box::new([i; 4])), atomic::Ordering::Release);
+        }
+
+        for j in readers {
+            j.join().unwrap();
+        }
+    }
+}
@@ -0,0 +1,256 @@
+//! Hazard eras.
+//!
+//! This is an alternative reclamation engine implementing hazard eras (Ramalhete and Correia,
+//! "Hazard Eras - Non-Blocking Memory Reclamation", 2017). It keeps the bounded garbage of hazard
+//! pointers, but rather than publishing every pointer loaded, a reader publishes the current era
+//! of a global clock, which it only has to do again, when the clock has moved on. Hence, loads
+//! during a traversal mostly cost a plain read of the clock, as long as nothing is retired
+//! concurrently.
+//!
+//! Every object carries the era, in which it was allocated (its birth), and the era, in which it
+//! was retired. An object is destroyed, once no thread has published an era between the two, as
+//! any reader, which could still refer to it, has published such an era.
+//!
+//! The engine is independent of the rest of the crate: Its objects must be allocated through its
+//! own `Atomic<T>`, as they carry a header with the eras, and they are reclaimed by its own
+//! collections (`collect()`), rather than by `conc::gc()`. It is the `Eras` backend of `engine`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::eras::Atomic;
+//!
+//! let atomic = Atomic::new(Some(1));
+//! let guard = atomic.load().unwrap();
+//! atomic.store(Some(2));
+//!
+//! // The old value is protected by the guard.
+//! conc::eras::collect();
+//! assert_eq!(*guard, 1);
+//! ```
+
+use engine::{self, ReclaimBackend};
+use parking_lot::{self, Mutex};
+use std::cell::RefCell;
+use std::sync::atomic::{self, AtomicU64};
+use std::thread;
+
+/// The era of a slot, which publishes none.
+const NONE: u64 = 0;
+
+/// The era clock.
+///
+/// This starts at 1, as 0 is `NONE`.
+static ERA: AtomicU64 = AtomicU64::new(1);
+/// Every slot ever allocated.
+static SLOTS: Mutex<Vec<&'static AtomicU64>> = parking_lot::const_mutex(Vec::new());
+/// Slots of exited threads, which are ready to be reused.
+static FREE: Mutex<Vec<&'static AtomicU64>> = parking_lot::const_mutex(Vec::new());
+
+thread_local! {
+    /// The slots of this thread, which are not in use.
+    static LOCAL: RefCell<Local> = RefCell::new(Local::default());
+}
+
+/// The state of a thread.
+#[derive(Default)]
+struct Local {
+    /// Slots not in use.
+    slots: Vec<&'static AtomicU64>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        FREE.lock().append(&mut self.slots);
+    }
+}
+
+/// The hazard eras backend.
+///
+/// Every guard publishes an era in a slot of its own.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Eras;
+
+/// A slot publishing the era of a guard.
+pub struct Slot {
+    /// The era published.
+    era: &'static AtomicU64,
+}
+
+unsafe impl ReclaimBackend for Eras {
+    type Token = Slot;
+
+    fn clock() -> &'static AtomicU64 {
+        &ERA
+    }
+
+    fn acquire() -> Slot {
+        let slot = if LOCAL.state() == thread::LocalKeyState::Destroyed {
+            None
+        } else {
+            LOCAL.with(|x| x.borrow_mut().slots.pop())
+        };
+
+        let era = slot.or_else(|| FREE.lock().pop()).unwrap_or_else(|| {
+            // Slots are reused, so they are never deallocated.
+            let slot: &'static AtomicU64 = Box::leak(Box::new(AtomicU64::new(NONE)));
+            SLOTS.lock().push(slot);
+            slot
+        });
+
+        Slot {
+            era: era,
+        }
+    }
+
+    fn publish(slot: &Slot) -> bool {
+        let now = ERA.load(atomic::Ordering::SeqCst);
+        if slot.era.load(atomic::Ordering::Relaxed) == now {
+            false
+        } else {
+            slot.era.store(now, atomic::Ordering::SeqCst);
+            true
+        }
+    }
+
+    fn release(slot: &Slot) {
+        slot.era.store(NONE, atomic::Ordering::Release);
+        if LOCAL.state() == thread::LocalKeyState::Destroyed {
+            FREE.lock().push(slot.era);
+        } else {
+            LOCAL.with(|x| x.borrow_mut().slots.push(slot.era));
+        }
+    }
+
+    fn intervals(intervals: &mut Vec<(u64, u64)>) {
+        intervals.extend(SLOTS.lock().iter()
+            .map(|x| x.load(atomic::Ordering::SeqCst))
+            .filter(|&x| x != NONE)
+            .map(|x| (x, x)));
+    }
+}
+
+/// A pointer to an object protected by a hazard era.
+///
+/// The object is protected until the guard is dropped.
+pub type Guard<T> = engine::Guard<T, Eras>;
+
+/// A concurrently accessible option of a value, reclaimed through hazard eras.
+///
+/// See `engine::Atomic<T, B>`.
+pub type Atomic<T> = engine::Atomic<T, Eras>;
+
+/// Get the current era.
+pub fn era() -> u64 {
+    engine::era::<Eras>()
+}
+
+/// Destroy the objects retired by the current thread (and exited threads), which are no longer
+/// protected.
+///
+/// This is done automatically, when enough objects are retired by the thread. Objects of the
+/// other engines are collected as well (see `engine::collect()`).
+pub fn collect() {
+    engine::collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use testing::{Counter, Tracked};
+
+    /// Collect, until `n` objects of `counter` are destroyed.
+    ///
+    /// The guards of concurrently running tests might protect the objects for a while, as they
+    /// protect every object alive in their era.
+    fn wait_for(counter: &Counter, n: usize) {
+        for _ in 0..10000 {
+            collect();
+            if counter.destroyed() >= n {
+                break;
+            }
+            thread::yield_now();
+        }
+
+        assert_eq!(counter.destroyed(), n);
+    }
+
+    #[test]
+    fn load_store() {
+        let a = Atomic::new(None);
+        assert!(a.load().is_none());
+        a.store(Some(1));
+        assert_eq!(*a.load().unwrap(), 1);
+        assert_eq!(*a.swap(Some(2)).unwrap(), 1);
+
+        let cur = a.load().unwrap().as_ptr();
+        assert_eq!(a.compare_and_store(None, Some(3)), Err(Some(3)));
+        assert_eq!(a.compare_and_store(Some(cur), Some(4)), Ok(()));
+        assert_eq!(*a.load().unwrap(), 4);
+    }
+
+    #[test]
+    fn protection() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Tracked::with_counter(1, &COUNTER)));
+
+        let guard = a.load().unwrap();
+        a.store(None);
+        collect();
+        assert_eq!(COUNTER.destroyed(), 0);
+        assert_eq!(**guard, 1);
+
+        drop(guard);
+        wait_for(&COUNTER, 1);
+    }
+
+    #[test]
+    fn era_advances() {
+        let a = Atomic::new(Some(1));
+        let era = era();
+        a.store(Some(2));
+        assert!(super::era() > era);
+    }
+
+    #[test]
+    fn orphans() {
+        static COUNTER: Counter = Counter::new();
+
+        thread::spawn(|| {
+            let a = Atomic::new(Some(Tracked::with_counter(1, &COUNTER)));
+            a.store(Some(Tracked::with_counter(2, &COUNTER)));
+        }).join().unwrap();
+
+        wait_for(&COUNTER, 2);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Arc::new(Atomic::new(Some(Tracked::with_counter(0, &COUNTER))));
+
+        let mut j = Vec::new();
+        for _ in 0..8 {
+            let a = a.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..10000 {
+                    if i % 4 == 0 {
+                        a.store(Some(Tracked::with_counter(i, &COUNTER)));
+                    } else {
+                        // Reading a reclaimed object would likely trip the canary.
+                        a.load().unwrap().check();
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        drop(a);
+        wait_for(&COUNTER, 20001);
+    }
+}
@@ -0,0 +1,161 @@
+//! Thread-exit notifications.
+//!
+//! The local state is torn down by its thread-local destructor, but when that runs relative to
+//! the other thread-local destructors is platform specific, and on some platforms, it might not
+//! run at all. This module notifies the thread of its exit through the mechanism of the
+//! platform instead:
+//!
+//! - On Unix, through the destructor of a pthread key, which runs after the thread-local
+//!   destructors, and is run again, if they set it anew.
+//! - On Windows, through a TLS callback receiving `DLL_THREAD_DETACH`.
+//! - Elsewhere, through the destructor of a thread-local.
+//!
+//! The callbacks are kept in a thread-local without destructor, so they remain accessible,
+//! however late the notification comes.
+
+use std::cell::Cell;
+use std::ptr;
+
+thread_local! {
+    /// The callbacks of this thread, or null, if none are registered.
+    static CALLBACKS: Cell<*mut Vec<fn()>> = Cell::new(ptr::null_mut());
+}
+
+/// Run `f`, when the current thread exits.
+///
+/// The callbacks are run in the order, in which they were registered, and callbacks registered
+/// meanwhile are run afterwards. They must not panic, as they might run in a context, which
+/// can't unwind.
+pub fn at_exit(f: fn()) {
+    CALLBACKS.with(|x| {
+        if x.get().is_null() {
+            x.set(Box::into_raw(Box::new(Vec::new())));
+            imp::arm();
+        }
+
+        unsafe { (*x.get()).push(f); }
+    });
+}
+
+/// Run the callbacks of the current thread.
+///
+/// This is called by the notification of the platform.
+fn run() {
+    loop {
+        let callbacks = CALLBACKS.with(|x| x.replace(ptr::null_mut()));
+        if callbacks.is_null() {
+            break;
+        }
+
+        for f in *unsafe { Box::from_raw(callbacks) } {
+            f();
+        }
+    }
+}
+
+#[cfg(unix)]
+mod imp {
+    use libc;
+    use std::ptr;
+    use std::sync::Once;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    /// The pthread key, whose destructor notifies the thread.
+    static KEY: AtomicUsize = AtomicUsize::new(0);
+
+    /// The destructor of the key.
+    unsafe extern "C" fn dtor(_: *mut libc::c_void) {
+        super::run();
+    }
+
+    /// Make sure the current thread is notified.
+    pub fn arm() {
+        static CREATE: Once = Once::new();
+
+        CREATE.call_once(|| {
+            let mut key: libc::pthread_key_t = 0;
+            let res = unsafe { libc::pthread_key_create(&mut key, Some(dtor)) };
+            assert_eq!(res, 0, "Failed to create a pthread key.");
+            KEY.store(key as usize, atomic::Ordering::Relaxed);
+        });
+
+        // The destructor is only run, if the value is not null.
+        let key = KEY.load(atomic::Ordering::Relaxed) as libc::pthread_key_t;
+        unsafe { libc::pthread_setspecific(key, ptr::without_provenance(1)); }
+    }
+}
+
+#[cfg(windows)]
+mod imp {
+    /// The reason given to TLS callbacks, when a thread exits.
+    const DLL_THREAD_DETACH: u32 = 3;
+
+    /// The TLS callback, which the loader calls on thread events.
+    #[used]
+    #[link_section = ".CRT$XLB"]
+    static CALLBACK: unsafe extern "system" fn(*mut u8, u32, *mut u8) = callback;
+
+    unsafe extern "system" fn callback(_: *mut u8, reason: u32, _: *mut u8) {
+        if reason == DLL_THREAD_DETACH {
+            super::run();
+        }
+    }
+
+    /// Make sure the current thread is notified.
+    ///
+    /// Every thread is notified by the loader already.
+    pub fn arm() {}
+}
+
+#[cfg(not(any(unix, windows)))]
+mod imp {
+    /// A thread-local, whose destructor notifies the thread.
+    struct Notifier;
+
+    impl Drop for Notifier {
+        fn drop(&mut self) {
+            super::run();
+        }
+    }
+
+    thread_local! {
+        static NOTIFIER: Notifier = Notifier;
+    }
+
+    /// Make sure the current thread is notified.
+    pub fn arm() {
+        // If the thread-locals are being destroyed already, the callbacks are run by the loop of
+        // the notification in progress.
+        let _ = NOTIFIER.try_with(|_| ());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    #[test]
+    fn notified() {
+        static RUNS: AtomicUsize = AtomicUsize::new(0);
+
+        // Panicking in the callbacks would abort, so they just count.
+        fn first() {
+            RUNS.fetch_add(1, atomic::Ordering::SeqCst);
+            // Registering while running is allowed.
+            at_exit(second);
+        }
+
+        fn second() {
+            RUNS.fetch_add(10, atomic::Ordering::SeqCst);
+        }
+
+        thread::spawn(|| {
+            at_exit(first);
+            assert_eq!(RUNS.load(atomic::Ordering::SeqCst), 0);
+        }).join().unwrap();
+
+        assert_eq!(RUNS.load(atomic::Ordering::SeqCst), 11);
+    }
+}
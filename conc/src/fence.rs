@@ -0,0 +1,184 @@
+//! Asymmetric memory fences.
+//!
+//! Guards are created far more often than garbage is collected, so rather than having both sides
+//! issue a full fence, the reader side (`light()`) is reduced to a compiler barrier, while the
+//! collector side (`heavy()`) issues a process-wide barrier, which forces every running thread
+//! through a full memory barrier. Since a process-wide barrier orders the collector against all
+//! the other threads, the readers don't need to fence on their own.
+//!
+//! The process-wide barrier is `membarrier(2)` on Linux and `FlushProcessWriteBuffers` on Windows.
+//! If no such barrier is available, both sides fall back to a regular `SeqCst` fence.
+
+use std::sync::atomic::{self, AtomicUsize};
+
+/// The strategy has not been determined yet.
+const UNINITIALIZED: usize = 0;
+/// No process-wide barrier is available, so both sides use regular fences.
+const SYMMETRIC: usize = 1;
+/// A process-wide barrier is available, so readers only need compiler barriers.
+const ASYMMETRIC: usize = 2;
+
+/// The fencing strategy in use.
+///
+/// This is determined the first time a fence is issued.
+static STRATEGY: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+
+/// Get the fencing strategy, determining it if necessary.
+fn strategy() -> usize {
+    let strategy = STRATEGY.load(atomic::Ordering::Relaxed);
+
+    if strategy != UNINITIALIZED {
+        strategy
+    } else {
+        // Determine the strategy. Several threads might race to do this, but initialization is
+        // idempotent, so we don't care.
+        let strategy = if sys::init() { ASYMMETRIC } else { SYMMETRIC };
+        STRATEGY.store(strategy, atomic::Ordering::Relaxed);
+
+        strategy
+    }
+}
+
+/// Issue the reader side of the fence.
+///
+/// This is cheap and is meant to be used on hot paths (e.g. guard creation). It is only ordered
+/// against `heavy()`, not against other `light()` fences.
+#[inline]
+pub fn light() {
+    if strategy() == ASYMMETRIC {
+        // The collector's heavy fence acts on our behalf, so all we need is to prevent the
+        // compiler from reordering.
+        atomic::compiler_fence(atomic::Ordering::SeqCst);
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+}
+
+/// Issue the collector side of the fence.
+///
+/// This is expensive, as it might interrupt every other thread of the process, and should thus
+/// only be used on cold paths (e.g. before scanning the hazards during garbage collection).
+pub fn heavy() {
+    if strategy() == ASYMMETRIC {
+        sys::barrier();
+    } else {
+        atomic::fence(atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(target_os = "linux")]
+mod sys {
+    use libc;
+    use std::sync::atomic::{self, AtomicUsize};
+
+    /// Query the supported commands.
+    const MEMBARRIER_CMD_QUERY: libc::c_int = 0;
+    /// Issue a barrier on all threads of all processes (slow, but needs no registration).
+    const MEMBARRIER_CMD_GLOBAL: libc::c_int = 1 << 0;
+    /// Issue a barrier on all running threads of this process.
+    const MEMBARRIER_CMD_PRIVATE_EXPEDITED: libc::c_int = 1 << 3;
+    /// Register the intent to use `MEMBARRIER_CMD_PRIVATE_EXPEDITED`.
+    const MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED: libc::c_int = 1 << 4;
+
+    /// The `membarrier` command used for issuing barriers.
+    static COMMAND: AtomicUsize = AtomicUsize::new(0);
+
+    /// Call `membarrier(2)`.
+    fn membarrier(cmd: libc::c_int) -> libc::c_long {
+        unsafe { libc::syscall(libc::SYS_membarrier, cmd, 0 as libc::c_int) }
+    }
+
+    /// Set up `membarrier`, returning `false` if it is unavailable.
+    pub fn init() -> bool {
+        let supported = membarrier(MEMBARRIER_CMD_QUERY);
+        if supported < 0 {
+            // The kernel doesn't know `membarrier` at all.
+            return false;
+        }
+
+        // Prefer the expedited command, which is much cheaper, but requires registration.
+        let cmd = if supported & MEMBARRIER_CMD_PRIVATE_EXPEDITED as libc::c_long != 0
+            && membarrier(MEMBARRIER_CMD_REGISTER_PRIVATE_EXPEDITED) == 0 {
+            MEMBARRIER_CMD_PRIVATE_EXPEDITED
+        } else if supported & MEMBARRIER_CMD_GLOBAL as libc::c_long != 0 {
+            MEMBARRIER_CMD_GLOBAL
+        } else {
+            return false;
+        };
+
+        COMMAND.store(cmd as usize, atomic::Ordering::Relaxed);
+        true
+    }
+
+    /// Issue a barrier on every thread of the process.
+    pub fn barrier() {
+        let res = membarrier(COMMAND.load(atomic::Ordering::Relaxed) as libc::c_int);
+        assert!(res == 0, "`membarrier` failed after successful registration.");
+    }
+}
+
+#[cfg(windows)]
+mod sys {
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FlushProcessWriteBuffers();
+    }
+
+    /// `FlushProcessWriteBuffers` is available on every supported version of Windows.
+    pub fn init() -> bool {
+        true
+    }
+
+    /// Issue a barrier on every thread of the process.
+    pub fn barrier() {
+        unsafe { FlushProcessWriteBuffers(); }
+    }
+}
+
+#[cfg(not(any(target_os = "linux", windows)))]
+mod sys {
+    /// There is no process-wide barrier on this platform.
+    pub fn init() -> bool {
+        false
+    }
+
+    /// Never called, as `init()` fails.
+    pub fn barrier() {
+        unreachable!();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn strategy_is_determined() {
+        light();
+        assert!(STRATEGY.load(atomic::Ordering::Relaxed) != UNINITIALIZED);
+    }
+
+    #[test]
+    fn light_and_heavy() {
+        for _ in 0..100 {
+            light();
+            heavy();
+        }
+    }
+
+    #[test]
+    fn heavy_cross_thread() {
+        let mut j = Vec::new();
+        for _ in 0..8 {
+            j.push(thread::spawn(|| for _ in 0..100 {
+                light();
+                heavy();
+            }));
+        }
+
+        for i in j {
+            i.join().unwrap();
+        }
+    }
+}
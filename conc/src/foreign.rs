@@ -0,0 +1,188 @@
+//! Threads not spawned by Rust.
+//!
+//! Threads created by foreign code (e.g. C libraries or a JVM), which call into `conc`, might
+//! never run the thread-local destructors, nor the exit notification of the platform, e.g. if
+//! they are terminated abruptly. Their local state, that is, the cached garbage and hazards, would
+//! then be leaked, and the hazards would block the garbage they protect forever.
+//!
+//! There is no portable way of telling such threads apart, so they must `attach()` before using
+//! `conc`. An attached thread caches nothing locally: Its garbage is exported right away, and its
+//! hazards are kept in its global registration. When the thread has vanished without tearing down
+//! its state, `reap()` kills its cached hazards and removes the registration. This is done by the
+//! dedicated collector (see `collector::spawn()`), or must be called by the application otherwise.
+//!
+//! The hazards held by guards are left alone, as the guards might have been sent to another thread
+//! and still be in use. They are freed along with the guards, unless the guards vanished with the
+//! thread, in which case they are leaked.
+//!
+//! Vanished threads are detected through `/proc/self/task` on Linux. On the other platforms, the
+//! threads are never considered vanished, so only the cached garbage is saved.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::atomic::Ordering;
+//!
+//! // Called at the start of a thread created by foreign code.
+//! conc::foreign::attach();
+//!
+//! let atomic = conc::Atomic::new(Some(Box::new(1)));
+//! atomic.store(Some(Box::new(2)), Ordering::Release);
+//! ```
+
+#[cfg(target_os = "linux")]
+use libc;
+use local;
+
+/// Attach the current thread as a foreign thread.
+///
+/// The garbage and hazards cached by the thread so far are released, and from now on, it caches
+/// nothing locally, at the cost of exporting every garbage object on its own. Attaching more than
+/// once has no effect.
+pub fn attach() {
+    local::attach_foreign(OsThread::current());
+}
+
+/// Tear down the states of the attached threads, which have vanished.
+///
+/// Their cached hazards are killed, and their registrations are removed. The number of threads
+/// torn down is returned.
+///
+/// Checking the threads involves the file system, so this shouldn't be called too often.
+pub fn reap() -> usize {
+    local::reap_foreign()
+}
+
+/// An OS thread, whose liveness can be checked.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct OsThread {
+    /// The thread identifier of the kernel, if the thread can be checked.
+    #[cfg(target_os = "linux")]
+    tid: Option<libc::pid_t>,
+}
+
+impl OsThread {
+    /// Get the current thread.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn current() -> OsThread {
+        let tid = unsafe { libc::syscall(libc::SYS_gettid) } as libc::pid_t;
+        // If the task isn't visible (e.g. `/proc` isn't mounted), the thread can't be checked.
+        OsThread {
+            tid: if task_exists(tid) { Some(tid) } else { None },
+        }
+    }
+
+    /// Get the current thread.
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn current() -> OsThread {
+        OsThread {}
+    }
+
+    /// Might the thread still be alive?
+    ///
+    /// This errs on the side of alive, e.g. when the identifier was reused by another thread.
+    #[cfg(target_os = "linux")]
+    pub(crate) fn is_alive(&self) -> bool {
+        self.tid.map_or(true, task_exists)
+    }
+
+    /// Might the thread still be alive?
+    #[cfg(not(target_os = "linux"))]
+    pub(crate) fn is_alive(&self) -> bool {
+        true
+    }
+}
+
+/// Does the task `tid` of this process exist?
+#[cfg(target_os = "linux")]
+fn task_exists(tid: libc::pid_t) -> bool {
+    ::std::path::Path::new(&format!("/proc/self/task/{}", tid)).exists()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic;
+    use std::thread;
+    use Atomic;
+
+    #[test]
+    fn no_local_garbage() {
+        thread::spawn(|| {
+            attach();
+            attach();
+
+            let a = Atomic::new(Some(Box::new(1)));
+            a.store(Some(Box::new(2)), atomic::Ordering::Release);
+            assert!(!local::has_garbage());
+            assert_eq!(*a.load(atomic::Ordering::Acquire).unwrap(), 2);
+        }).join().unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reap_vanished() {
+        use std::mem;
+        use std::sync::mpsc;
+        use std::time::Duration;
+
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            attach();
+            let a = Atomic::new(Some(Box::new(1)));
+            mem::forget(a.load(atomic::Ordering::Acquire));
+            send.send((thread::current().id(), OsThread::current())).unwrap();
+
+            // Vanish without running any destructors or notifications.
+            unsafe { libc::syscall(libc::SYS_exit, 0); }
+        });
+
+        let (id, os_thread) = recv.recv().unwrap();
+        assert!(os_thread.tid.is_some());
+        while os_thread.is_alive() {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // Dedicated collectors of other tests might reap it as well.
+        reap();
+        assert!(::stats::threads().iter().all(|x| x.id != id));
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn reap_keeps_sent_guards() {
+        use std::sync::mpsc;
+        use std::time::Duration;
+        use testing::{Counter, Tracked};
+
+        static COUNTER: Counter = Counter::new();
+
+        let a: &'static Atomic<Tracked<i32>> = Box::leak(Box::new(Atomic::default()));
+        a.store(Some(Box::new(Tracked::with_counter(1, &COUNTER))), atomic::Ordering::Release);
+
+        let (send, recv) = mpsc::channel();
+        thread::spawn(move || {
+            attach();
+            send.send((a.load(atomic::Ordering::Acquire).unwrap(), OsThread::current())).unwrap();
+
+            // Vanish without running any destructors or notifications.
+            unsafe { libc::syscall(libc::SYS_exit, 0); }
+        });
+
+        let (guard, os_thread) = recv.recv().unwrap();
+        while os_thread.is_alive() {
+            thread::sleep(Duration::from_millis(1));
+        }
+        reap();
+
+        // The guard outlived its thread, so its hazard must still protect the object.
+        a.store(None, atomic::Ordering::Release);
+        ::gc().unwrap();
+        assert_eq!(**guard, 1);
+        assert_eq!(COUNTER.destroyed(), 0);
+
+        guard.release();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+}
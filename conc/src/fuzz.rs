@@ -0,0 +1,253 @@
+//! Fuzzing of the reclamation protocol.
+//!
+//! This drives randomized interleavings of operations (guard creation, retirement, CAS storms and
+//! garbage collection) through the public API on multiple threads, and checks the outcome by
+//! counting the constructions and destructions of the objects involved. This detects leaks, double
+//! frees and (to some extent) use-after-free.
+//!
+//! The objects are `testing::Tracked` values counted in a fresh `testing::Counter` for each run,
+//! and the operations are picked by the pseudorandom number generator of each thread (see
+//! `conc::seed()`), which the run seeds.
+//!
+//! The harness is generic over the structure being fuzzed, so you can fuzz your own structures by
+//! implementing `Target`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::fuzz;
+//!
+//! let report = fuzz::run(fuzz::AtomicTarget::new(4), fuzz::Config {
+//!     ops: 1000,
+//!     .. Default::default()
+//! });
+//!
+//! println!("destroyed {} objects", report.objects);
+//! ```
+
+use std::sync::atomic;
+use std::sync::Arc;
+use std::thread;
+
+use {local, settings, Atomic};
+use testing::{Counter, Tracked};
+
+/// The configuration of a fuzzing run.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Config {
+    /// The number of threads to run the operations on.
+    pub threads: usize,
+    /// The number of operations to run in each thread.
+    pub ops: usize,
+    /// The seed of the operations.
+    ///
+    /// Thread `n` uses seed `seed + n`. Note that the interleaving of the threads is up to the
+    /// scheduler, so a seed doesn't fully determine the run.
+    pub seed: u64,
+}
+
+impl Default for Config {
+    fn default() -> Config {
+        Config {
+            threads: 4,
+            ops: 10000,
+            seed: 0,
+        }
+    }
+}
+
+/// The report of a successful fuzzing run.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Report {
+    /// The total number of operations run.
+    pub ops: usize,
+    /// The number of objects created (and destroyed) during the run.
+    pub objects: usize,
+}
+
+/// Generate a pseudorandom number.
+///
+/// This draws from the generator of the current thread, which `run()` seeds.
+pub fn random() -> usize {
+    local::random()
+}
+
+/// Generate a pseudorandom number below `n` for picking an operation.
+///
+/// # Panics
+///
+/// This panics if `n` is zero.
+pub fn below(n: usize) -> usize {
+    random() % n
+}
+
+/// A structure to be fuzzed.
+pub trait Target: Send + Sync {
+    /// Perform a random operation on the structure.
+    ///
+    /// The operation shall be picked through `below()` (or `random()`), such that it is
+    /// determined by the seed. The objects stored in the structure shall be created through
+    /// `Tracked::with_counter(.., counter)`, such that the run can account for them. When the
+    /// target is dropped, it shall retire the objects it contains.
+    fn step(&self, counter: &'static Counter);
+}
+
+/// A target fuzzing a set of `Atomic`s.
+pub struct AtomicTarget {
+    /// The atomics to operate on.
+    slots: Vec<Atomic<Tracked<usize>>>,
+}
+
+impl AtomicTarget {
+    /// Create a target of `slots` atomics.
+    ///
+    /// A low number of slots means more contention between the threads.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `slots` is zero.
+    pub fn new(slots: usize) -> AtomicTarget {
+        assert!(slots > 0, "Fuzzing target without any slots.");
+
+        AtomicTarget {
+            slots: (0..slots).map(|_| Atomic::default()).collect(),
+        }
+    }
+}
+
+impl Target for AtomicTarget {
+    fn step(&self, counter: &'static Counter) {
+        let slot = &self.slots[below(self.slots.len())];
+
+        match below(8) {
+            // Create a guard.
+            0 | 1 => if let Some(guard) = slot.load(atomic::Ordering::Acquire) {
+                guard.check();
+            },
+            // Retire the current object by replacing it.
+            2 => {
+                let new = Tracked::with_counter(random(), counter);
+                slot.store(Some(Box::new(new)), atomic::Ordering::Release);
+            },
+            // Retire the current object by removing it.
+            3 => slot.store(None, atomic::Ordering::Release),
+            // Swap the current object, and access it after retirement.
+            4 => {
+                let new = Tracked::with_counter(random(), counter);
+                if let Some(old) = slot.swap(Some(Box::new(new)), atomic::Ordering::AcqRel) {
+                    old.check();
+                }
+            },
+            // Storm the slot with CASs.
+            5 => for _ in 0..below(16) {
+                let cur = slot.load(atomic::Ordering::Acquire);
+                let new = Tracked::with_counter(random(), counter);
+                let res = slot.compare_and_swap(
+                    cur.as_ref().map(|x| x.as_ptr()),
+                    Some(Box::new(new)),
+                    atomic::Ordering::AcqRel,
+                );
+
+                match res {
+                    Ok(Some(old)) | Err((Some(old), _)) => old.check(),
+                    _ => (),
+                }
+            },
+            // Collect garbage if possible.
+            6 => {
+                let _ = ::try_gc();
+            },
+            // Collect garbage.
+            _ => ::gc().unwrap(),
+        }
+    }
+}
+
+/// Fuzz a target.
+///
+/// This runs `config.ops` random operations on `target` in each of `config.threads` threads,
+/// which all use the settings of the calling thread. When they're done, the target is dropped,
+/// the garbage collected and the counter of the objects checked.
+///
+/// Every run counts its objects in a new counter, which is leaked, as `Tracked` needs a static
+/// one.
+///
+/// # Panics
+///
+/// This panics if any of the operations panics, or if objects were leaked or destroyed twice.
+pub fn run<T: Target + 'static>(target: T, config: Config) -> Report {
+    let settings = settings::get();
+    let counter: &'static Counter = Box::leak(Box::new(Counter::new()));
+    let target = Arc::new(target);
+
+    let threads: Vec<_> = (0..config.threads.max(1)).map(|n| {
+        let target = target.clone();
+        thread::spawn(move || {
+            settings::set_local(settings);
+            local::seed(config.seed.wrapping_add(n as u64));
+
+            for _ in 0..config.ops {
+                target.step(counter);
+            }
+        })
+    }).collect();
+
+    for i in threads {
+        i.join().unwrap();
+    }
+
+    // Drop the target to retire the objects it holds. The threads have exited, so they've
+    // exported all their garbage.
+    drop(target);
+    counter.assert_balanced();
+
+    Report {
+        ops: config.threads.max(1) * config.ops,
+        objects: counter.created(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::mem;
+
+    #[test]
+    fn atomic_target() {
+        let report = run(AtomicTarget::new(4), Config {
+            threads: 4,
+            ops: 2000,
+            seed: 42,
+        });
+
+        assert_eq!(report.ops, 8000);
+        assert!(report.objects > 0);
+    }
+
+    #[test]
+    fn single_slot() {
+        run(AtomicTarget::new(1), Config {
+            threads: 8,
+            ops: 500,
+            seed: 1,
+        });
+    }
+
+    #[test]
+    #[should_panic]
+    fn leaking_target() {
+        struct Leaky;
+
+        impl Target for Leaky {
+            fn step(&self, counter: &'static Counter) {
+                mem::forget(Tracked::with_counter(0, counter));
+            }
+        }
+
+        run(Leaky, Config {
+            threads: 1,
+            ops: 1,
+            seed: 0,
+        });
+    }
+}
@@ -1,6 +1,362 @@
 //! Literal garbage.
 
-use debug;
+use parking_lot::{self, Mutex};
+use std::any;
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::sync::mpsc;
+use std::{mem, panic, process, thread};
+use std::time::Instant;
+use {debug, global, local};
+use settings::PanicPolicy;
+use stats::DestructorStats;
+
+/// The size (in bytes) from which garbage is considered large.
+///
+/// Large garbage is kept separate from small garbage, such that it can be prioritized when memory
+/// is to be recovered.
+pub const LARGE: usize = 1024;
+/// The number of items to prefetch ahead, when destroying garbage in batch.
+const PREFETCH_DISTANCE: usize = 4;
+/// The number of items destroyed between checks of the deadline.
+///
+/// Reading the clock is relatively expensive, so we don't do it for every item.
+const DEADLINE_INTERVAL: usize = 64;
+/// The minimal number of parallel items, from which a batch is split among several threads.
+///
+/// Handing garbage to the workers is costly, so smaller batches are destroyed by the current
+/// thread alone.
+const PARALLEL_THRESHOLD: usize = 4096;
+/// The bit of `Garbage.size`, which flags the garbage as safe to destroy concurrently.
+///
+/// No object can span more than half of the address space, so this bit is free to use.
+const PARALLEL: usize = !(!0 >> 1);
+/// The bit of `Garbage.size`, which flags the garbage as urgent.
+///
+/// Size hints are far below a quarter of the address space, so this bit is free to use as well.
+const URGENT: usize = PARALLEL >> 1;
+/// The bit of `Garbage.size`, which flags the destructor as merely freeing memory.
+///
+/// Such a destructor runs no user code, so it can't allocate (see `Garbage::is_plain()`).
+const PLAIN: usize = URGENT >> 1;
+/// The bits of `Garbage.size`, which are flags rather than the size.
+const FLAGS: usize = PARALLEL | URGENT | PLAIN;
+
+/// The destructor workers.
+///
+/// These are spawned when first needed, and then live for the rest of the process, waiting for
+/// chunks of garbage to destroy, such that collections don't spawn threads.
+static WORKERS: Mutex<Vec<mpsc::Sender<Job>>> = parking_lot::const_mutex(Vec::new());
+
+/// A chunk of garbage to be destroyed by a worker.
+struct Job {
+    /// The garbage to destroy.
+    chunk: Vec<Garbage>,
+    /// How to handle panicking destructors.
+    policy: PanicPolicy,
+    /// The deadline of the destruction, if any.
+    deadline: Option<Instant>,
+    /// The channel to send back the garbage not destroyed and the panic of a destructor, if any.
+    done: mpsc::Sender<(Vec<Garbage>, thread::Result<()>)>,
+}
+
+/// The statistics of the destructors, keyed by their address.
+static DESTRUCTORS: Mutex<Option<HashMap<usize, Destructor>>> = parking_lot::const_mutex(None);
+/// The number of slots of the per-thread cache of named destructors.
+const NAMED_SLOTS: usize = 8;
+
+thread_local! {
+    /// The destructors, which this thread has named recently, indexed by a hash of their address.
+    static NAMED: [Cell<usize>; NAMED_SLOTS] = Default::default();
+}
+
+/// The statistics of a destructor.
+#[derive(Default)]
+struct Destructor {
+    /// The name of the type of the garbage, if known.
+    name: Option<&'static str>,
+    /// The number of garbage objects exported to the global state.
+    exported: usize,
+    /// The number of garbage objects destroyed.
+    destroyed: usize,
+}
+
+/// Name the destructor `dtor` after the type `T`.
+///
+/// The names are cached per thread, such that retiring objects of the same few types over and
+/// over doesn't lock the table.
+fn name<T>(dtor: usize) {
+    let cached = NAMED.state() != thread::LocalKeyState::Destroyed && NAMED.with(|named| {
+        named[(dtor >> 4) % NAMED_SLOTS].replace(dtor) == dtor
+    });
+
+    if !cached {
+        // The table is allocated in, so the collector must not run meanwhile.
+        let _critical = global::Critical::new();
+        DESTRUCTORS.lock().get_or_insert_with(HashMap::new).entry(dtor).or_default().name =
+            Some(any::type_name::<T>());
+    }
+}
+
+/// Numbers of garbage objects by destructor, which are added to the statistics on drop.
+///
+/// Garbage is mostly handled in runs of the same destructor, so this is a list of runs rather than
+/// a map, and the statistics are only locked once per batch.
+struct Tally {
+    /// The runs of destructors and their lengths.
+    runs: Vec<(usize, usize)>,
+    /// Is the garbage destroyed (rather than exported)?
+    destroyed: bool,
+}
+
+impl Tally {
+    /// Create an empty tally of garbage exported or, if `destroyed`, destroyed.
+    fn new(destroyed: bool) -> Tally {
+        Tally {
+            runs: Vec::new(),
+            destroyed: destroyed,
+        }
+    }
+
+    /// Count a garbage object.
+    fn count(&mut self, garbage: &Garbage) {
+        let dtor = garbage.dtor as usize;
+        match self.runs.last_mut() {
+            Some(&mut (last, ref mut n)) if last == dtor => *n += 1,
+            _ => self.runs.push((dtor, 1)),
+        }
+    }
+}
+
+impl Drop for Tally {
+    fn drop(&mut self) {
+        if self.runs.is_empty() {
+            return;
+        }
+
+        let _critical = global::Critical::new();
+        let mut lock = DESTRUCTORS.lock();
+        let table = lock.get_or_insert_with(HashMap::new);
+        for &(dtor, n) in &self.runs {
+            let entry = table.entry(dtor).or_default();
+            if self.destroyed {
+                entry.destroyed += n;
+            } else {
+                entry.exported += n;
+            }
+        }
+    }
+}
+
+/// Count garbage exported to the global state in the statistics of its destructors.
+pub fn account_exported(garbage: &[Garbage]) {
+    let mut tally = Tally::new(false);
+    for i in garbage {
+        tally.count(i);
+    }
+}
+
+/// Get the statistics of every destructor, which garbage was exported or destroyed with.
+pub fn destructors() -> Vec<DestructorStats> {
+    let _critical = global::Critical::new();
+    let lock = DESTRUCTORS.lock();
+    lock.iter().flat_map(|table| table.iter()).filter(|&(_, x)| x.exported + x.destroyed > 0)
+        .map(|(&dtor, x)| DestructorStats {
+            dtor: dtor,
+            type_name: x.name,
+            exported: x.exported,
+            destroyed: x.destroyed,
+        }).collect()
+}
+
+/// Destroy a batch of garbage.
+///
+/// Destroying lots of small objects tends to be bound by memory latency and branch mispredictions,
+/// so rather than destroying the garbage in arbitrary order, it is grouped by destructor, and the
+/// objects are prefetched a few items ahead of their destruction.
+///
+/// A panicking destructor is handled according to `policy`. The garbage is destroyed from the back
+/// of `batch`, so if the panic is propagated, the garbage not yet destroyed remains in `batch`.
+///
+/// If `deadline` is given, the destruction stops when it is reached, leaving the rest of the
+/// garbage in `batch`. The deadline is only checked every few items.
+pub fn destroy_batch(batch: &mut Vec<Garbage>, policy: PanicPolicy, deadline: Option<Instant>) {
+    // Group the garbage by destructor.
+    batch.sort_unstable_by_key(|garbage| garbage.dtor as usize);
+    // The destroyed garbage is counted, also if a destructor panics.
+    let mut tally = Tally::new(true);
+
+    for n in 0.. {
+        if let Some(deadline) = deadline {
+            if n % DEADLINE_INTERVAL == 0 && Instant::now() >= deadline {
+                break;
+            }
+        }
+
+        let garbage = match batch.pop() {
+            Some(garbage) => garbage,
+            None => break,
+        };
+
+        // Prefetch the object, which is to be destroyed a few items ahead.
+        if let Some(i) = batch.len().checked_sub(PREFETCH_DISTANCE) {
+            prefetch(batch[i].ptr);
+        }
+
+        tally.count(&garbage);
+
+        match policy {
+            PanicPolicy::Propagate => drop(garbage),
+            PanicPolicy::Abort => {
+                if panic::catch_unwind(panic::AssertUnwindSafe(|| drop(garbage))).is_err() {
+                    // The panic message has already been printed by the panic hook.
+                    process::abort();
+                }
+            },
+            PanicPolicy::Isolate => {
+                let _ = panic::catch_unwind(panic::AssertUnwindSafe(|| drop(garbage)));
+            },
+        }
+    }
+}
+
+/// Destroy a batch of garbage using up to `threads` threads.
+///
+/// This acts like `destroy_batch`, except that if the batch has lots of garbage flagged as
+/// parallel, that garbage is split among `threads - 1` worker threads and the current thread,
+/// which also destroys the rest of the garbage.
+///
+/// If a destructor panics and the panic is propagated, the other threads finish their part, and
+/// all the garbage not yet destroyed remains in `batch`, before the panic is resumed.
+pub fn destroy_batch_parallel(
+    batch: &mut Vec<Garbage>,
+    policy: PanicPolicy,
+    deadline: Option<Instant>,
+    threads: usize,
+) {
+    if threads <= 1 || batch.iter().filter(|x| x.is_parallel()).count() < PARALLEL_THRESHOLD {
+        return destroy_batch(batch, policy, deadline);
+    }
+
+    // Split the parallel garbage into a chunk for each worker. The current thread keeps the rest.
+    let mut parallel = Vec::new();
+    let mut i = 0;
+    while i < batch.len() {
+        if batch[i].is_parallel() {
+            parallel.push(batch.swap_remove(i));
+        } else {
+            i += 1;
+        }
+    }
+    let chunk_size = (parallel.len() + threads - 1) / threads;
+    let mut chunks: Vec<Vec<Garbage>> = Vec::with_capacity(threads - 1);
+    while parallel.len() > chunk_size {
+        let at = parallel.len() - chunk_size;
+        chunks.push(parallel.split_off(at));
+    }
+    batch.append(&mut parallel);
+
+    // Hand out the chunks to the workers.
+    let workers = workers(chunks.len());
+    let (done, results) = mpsc::channel();
+    let mut sent = 0;
+    for chunk in chunks {
+        let job = Job {
+            chunk: chunk,
+            policy: policy,
+            deadline: deadline,
+            done: done.clone(),
+        };
+
+        let res = match workers.get(sent) {
+            Some(worker) => worker.send(job),
+            None => Err(mpsc::SendError(job)),
+        };
+
+        // If there is no worker for the chunk, it is left to the current thread.
+        match res {
+            Ok(()) => sent += 1,
+            Err(mpsc::SendError(job)) => batch.extend(job.chunk),
+        }
+    }
+
+    let mut panics = Vec::new();
+    if let Err(payload) = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+        destroy_batch(batch, policy, deadline)
+    })) {
+        panics.push(payload);
+    }
+
+    // Wait for the workers, and put back the garbage, which they didn't destroy.
+    for _ in 0..sent {
+        // The panics of the destructors are caught, so the worker always answers.
+        let (mut chunk, res) = results.recv().unwrap();
+        batch.append(&mut chunk);
+        if let Err(payload) = res {
+            panics.push(payload);
+        }
+    }
+
+    if let Some(payload) = panics.pop() {
+        panic::resume_unwind(payload);
+    }
+}
+
+/// Get (up to) `n` destructor workers, spawning the missing ones.
+///
+/// Fewer workers are returned, if they couldn't be spawned.
+fn workers(n: usize) -> Vec<mpsc::Sender<Job>> {
+    // Spawning a worker allocates, which must not collect garbage, while the lock is held.
+    let _critical = global::Critical::new();
+    let mut workers = WORKERS.lock();
+
+    while workers.len() < n {
+        let (sender, jobs) = mpsc::channel();
+        if thread::Builder::new()
+            .name("conc-destructor".to_owned())
+            .spawn(move || work(jobs))
+            .is_err() {
+            break;
+        }
+
+        workers.push(sender);
+    }
+
+    workers.iter().take(n).cloned().collect()
+}
+
+/// Run a destructor worker, destroying the chunks of garbage received through `jobs`.
+fn work(jobs: mpsc::Receiver<Job>) {
+    // Destructors running on the worker must not block on the collector.
+    let _critical = global::Critical::new();
+
+    for mut job in jobs {
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            destroy_batch(&mut job.chunk, job.policy, job.deadline)
+        }));
+
+        // The worker never exits, so the garbage retired by the destructors is exported now.
+        local::try_export_garbage();
+
+        // If the collector is gone, there is nobody to report to.
+        let _ = job.done.send((job.chunk, res));
+    }
+}
+
+/// Hint the CPU to fetch the cache line of `ptr`.
+///
+/// This never faults, even if `ptr` is invalid.
+#[inline]
+fn prefetch(ptr: *const u8) {
+    #[cfg(target_arch = "x86_64")]
+    unsafe {
+        use std::arch::x86_64;
+        x86_64::_mm_prefetch::<{ x86_64::_MM_HINT_T0 }>(ptr as *const i8);
+    }
+
+    #[cfg(not(target_arch = "x86_64"))]
+    let _ = ptr;
+}
 
 /// An object to be deleted eventually.
 ///
@@ -9,6 +365,9 @@ use debug;
 ///
 /// When it's dropped, the destructor of the garbage runs.
 ///
+/// Garbage is three words wide and is stored inline in the garbage queues, so retiring an object
+/// doesn't require an allocation.
+///
 /// See also: ideology.
 #[derive(Debug)]
 pub struct Garbage {
@@ -18,6 +377,11 @@ pub struct Garbage {
     ///
     /// The argument given when called is the `self.ptr` field.
     dtor: unsafe fn(*const u8),
+    /// A hint of the number of bytes freed by the destructor.
+    ///
+    /// `0` means that the size is unknown. The highest bits are the `PARALLEL`, `URGENT` and
+    /// `PLAIN` flags.
+    size: usize,
 }
 
 impl Garbage {
@@ -26,14 +390,56 @@ impl Garbage {
     /// This takes the pointer and destructor (which takes pointer as argument) and construct the
     /// corresponding garbage item.
     pub fn new(ptr: *const u8, dtor: fn(*const u8)) -> Garbage {
-        debug_assert!(ptr as usize > 0, "Creating garbage with invalid pointer.");
+        debug_assert!(!ptr.is_null(), "Creating garbage with invalid pointer.");
 
         Garbage {
             ptr: ptr,
             dtor: dtor,
+            size: 0,
         }
     }
 
+    /// Create a garbage item given a reference and a destructor taking it.
+    ///
+    /// The size hint is set to the size of `T`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must stay valid until the garbage is dropped, even though `'a` might end earlier.
+    pub unsafe fn new_ref<'a, T>(ptr: &'a T, dtor: fn(&'a T)) -> Garbage {
+        // As `T` is sized, `&T` and `*const u8` are ABI-compatible, so calling the erased
+        // destructor with `self.ptr` is the same as calling `dtor` with `ptr`.
+        let dtor = mem::transmute::<fn(&'a T), fn(*const u8)>(dtor);
+        name::<T>(dtor as usize);
+        Garbage::new(ptr as *const T as *const u8, dtor).with_size(mem::size_of::<T>())
+    }
+
+    /// Set the size hint of the garbage.
+    ///
+    /// This is the (estimated) number of bytes, which the destructor frees.
+    pub fn with_size(mut self, size: usize) -> Garbage {
+        self.size = self.size & FLAGS | size & !FLAGS;
+        self
+    }
+
+    /// Flag the garbage as safe to destroy concurrently with other garbage.
+    ///
+    /// Parallel garbage can be destroyed on a worker thread of the collector (see
+    /// `Settings::destructor_threads`).
+    pub fn parallel(mut self) -> Garbage {
+        self.size |= PARALLEL;
+        self
+    }
+
+    /// Flag the garbage as urgent.
+    ///
+    /// Urgent garbage is exported and collected right away, and destroyed before the rest of the
+    /// garbage by every collection (see `conc::add_garbage_urgent()`).
+    pub fn urgent(mut self) -> Garbage {
+        self.size |= URGENT;
+        self
+    }
+
     /// Create a garbage item deallocating and dropping a box.
     ///
     /// Assuming `item` is a pointer representing a `Box`, this creates a garbage item, which has
@@ -55,25 +461,91 @@ impl Garbage {
     pub unsafe fn new_box<T>(item: *const T) -> Garbage {
         unsafe fn dtor<T>(ptr: *const u8)  {
             // Drop the box represented by `ptr`.
+            #[cfg(not(feature = "debug-tools"))]
             Box::from_raw(ptr as *mut u8 as *mut T);
+
+            // In debug mode, we only drop the contents, and then put the memory in quarantine,
+            // such that use-after-reclaim can be detected.
+            #[cfg(feature = "debug-tools")]
+            {
+                use std::{alloc, any, ptr};
+
+                ptr::drop_in_place(ptr as *mut u8 as *mut T);
+                debug::quarantine(ptr as *mut u8, alloc::Layout::new::<T>(), any::type_name::<T>());
+            }
         }
 
+        // Without drop glue, dropping the box only deallocates it, unless it is quarantined.
+        let plain = !mem::needs_drop::<T>() && !cfg!(feature = "debug-tools");
+
+        name::<T>(dtor::<T> as usize);
         Garbage {
             ptr: item as *const u8,
             dtor: dtor::<T>,
+            size: mem::size_of::<T>() | if plain { PLAIN } else { 0 },
         }
     }
 
+    /// Create a garbage item giving a box to a custom destructor.
+    ///
+    /// This acts like `new_box()`, except that `dtor` is called with the box rather than dropping
+    /// it. With `debug-tools`, the box isn't quarantined, as it is handed to `dtor`.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `new_box()`.
+    pub unsafe fn new_box_with<T>(item: *const T, dtor: fn(Box<T>)) -> Garbage {
+        // As `T` is sized, `Box<T>` and `*const u8` are ABI-compatible, so calling the erased
+        // destructor with `self.ptr` is the same as calling `dtor` with the box.
+        let dtor = mem::transmute::<fn(Box<T>), fn(*const u8)>(dtor);
+        name::<T>(dtor as usize);
+        Garbage::new(item as *const u8, dtor).with_size(mem::size_of::<T>())
+    }
+
     /// Get the inner pointer of the garbage.
     pub fn ptr(&self) -> *const u8 {
         self.ptr
     }
+
+    /// Get the size hint of the garbage.
+    ///
+    /// `0` means that the size is unknown.
+    pub fn size(&self) -> usize {
+        self.size & !FLAGS
+    }
+
+    /// Is this garbage large?
+    ///
+    /// Garbage of unknown size is considered small.
+    pub fn is_large(&self) -> bool {
+        self.size() >= LARGE
+    }
+
+    /// Is this garbage safe to destroy concurrently?
+    pub fn is_parallel(&self) -> bool {
+        self.size & PARALLEL != 0
+    }
+
+    /// Is this garbage urgent?
+    pub fn is_urgent(&self) -> bool {
+        self.size & URGENT != 0
+    }
+
+    /// Does destroying this garbage merely free memory?
+    ///
+    /// This is the case for boxes of types without drop glue. Their destruction runs no user code,
+    /// so it neither allocates nor takes locks, and is safe when allocation fails (see `oom`).
+    pub fn is_plain(&self) -> bool {
+        self.size & PLAIN != 0
+    }
 }
 
 impl Drop for Garbage {
     fn drop(&mut self) {
         // Print message in debug mode.
         debug::exec(|| println!("Destroying garbage: {:?}", self));
+        // Unregister the retirement before the memory can be reused.
+        debug::reclaim(self.ptr);
 
         unsafe { (self.dtor)(self.ptr); }
     }
@@ -91,8 +563,247 @@ mod tests {
 
     #[test]
     fn ptr() {
-        let g = Garbage::new(0x2 as *const u8, nop);
-        assert_eq!(g.ptr() as usize, 2);
+        let g = Garbage::new(ptr::without_provenance(0x2), nop);
+        assert_eq!(g.ptr().addr(), 2);
+    }
+
+    #[test]
+    fn inline_size() {
+        assert_eq!(mem::size_of::<Garbage>(), 3 * mem::size_of::<usize>());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(Garbage::new(ptr::without_provenance(0x2), nop).size, 0);
+        assert_eq!(Garbage::new(ptr::without_provenance(0x2), nop).with_size(7).size, 7);
+        assert!(!Garbage::new(ptr::without_provenance(0x2), nop).is_large());
+        assert!(Garbage::new(ptr::without_provenance(0x2), nop).with_size(LARGE).is_large());
+
+        unsafe {
+            let g = Garbage::new_box(Box::into_raw(Box::new([0u8; LARGE])));
+            assert_eq!(g.size(), LARGE);
+            assert!(g.is_large());
+        }
+    }
+
+    #[test]
+    fn plain() {
+        assert!(!Garbage::new(ptr::without_provenance(0x2), nop).is_plain());
+
+        unsafe {
+            let g = Garbage::new_box(Box::into_raw(Box::new(0u64)));
+            assert_eq!(g.is_plain(), !cfg!(feature = "debug-tools"));
+            assert_eq!(g.size(), 8);
+            assert!(!Garbage::new_box(Box::into_raw(Box::new(String::new()))).is_plain());
+        }
+    }
+
+    #[test]
+    fn parallel() {
+        let g = Garbage::new(ptr::without_provenance(0x2), nop).with_size(7);
+        assert!(!g.is_parallel());
+
+        let g = g.parallel();
+        assert!(g.is_parallel());
+        assert_eq!(g.size(), 7);
+        assert!(!g.is_large());
+
+        let g = g.with_size(LARGE);
+        assert!(g.is_parallel());
+        assert_eq!(g.size(), LARGE);
+    }
+
+    #[test]
+    fn urgent() {
+        let g = Garbage::new(ptr::without_provenance(0x2), nop).with_size(7);
+        assert!(!g.is_urgent());
+
+        let g = g.urgent().parallel();
+        assert!(g.is_urgent());
+        assert!(g.is_parallel());
+        assert_eq!(g.size(), 7);
+
+        let g = g.with_size(LARGE);
+        assert!(g.is_urgent());
+        assert_eq!(g.size(), LARGE);
+    }
+
+    #[test]
+    fn destroy_batch_grouped() {
+        use std::cell::RefCell;
+
+        thread_local! {
+            static ORDER: RefCell<Vec<(u8, usize)>> = RefCell::new(Vec::new());
+        }
+
+        fn a(x: *const u8) {
+            ORDER.with(|o| o.borrow_mut().push((0, x as usize)));
+        }
+
+        fn b(x: *const u8) {
+            ORDER.with(|o| o.borrow_mut().push((1, x as usize)));
+        }
+
+        let mut batch = Vec::new();
+        for i in 1..100 {
+            batch.push(Garbage::new(ptr::without_provenance(i), if i % 2 == 0 { a } else { b }));
+        }
+        destroy_batch(&mut batch, PanicPolicy::Propagate, None);
+        assert!(batch.is_empty());
+
+        ORDER.with(|o| {
+            let o = o.borrow();
+            assert_eq!(o.len(), 99);
+            // Each destructor runs in a single contiguous group.
+            let switches = o.windows(2).filter(|w| w[0].0 != w[1].0).count();
+            assert_eq!(switches, 1);
+        });
+    }
+
+    #[test]
+    fn destroy_batch_deadline() {
+        use std::time::Duration;
+
+        let mut batch: Vec<_> =
+            (1..100).map(|i| Garbage::new(ptr::without_provenance(i), nop)).collect();
+        destroy_batch(&mut batch, PanicPolicy::Propagate, Some(Instant::now()));
+        assert_eq!(batch.len(), 99);
+
+        destroy_batch(&mut batch, PanicPolicy::Propagate, Some(Instant::now() + Duration::from_secs(60)));
+        assert!(batch.is_empty());
+    }
+
+    #[test]
+    fn destroy_batch_panic() {
+        use std::panic;
+
+        fn panic(_: *const u8) {
+            panic!();
+        }
+
+        let mut batch = vec![Garbage::new(ptr::without_provenance(0x1), panic)];
+        for i in 2..10 {
+            batch.push(Garbage::new(ptr::without_provenance(i), nop));
+        }
+        let len = batch.len();
+
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            destroy_batch(&mut batch, PanicPolicy::Propagate, None)
+        }));
+        assert!(res.is_err());
+        // Only the panicking item and the ones destroyed before it are gone.
+        assert!(batch.len() < len);
+        for i in &batch {
+            assert!(i.dtor as usize != panic as usize);
+        }
+        destroy_batch(&mut batch, PanicPolicy::Propagate, None);
+    }
+
+    #[test]
+    fn destroy_batch_isolate() {
+        use std::cell::Cell;
+
+        thread_local! {
+            static N: Cell<usize> = Cell::new(0);
+        }
+
+        fn panic(_: *const u8) {
+            panic!();
+        }
+
+        fn dtor(_: *const u8) {
+            N.with(|n| n.set(n.get() + 1));
+        }
+
+        let mut batch = vec![Garbage::new(ptr::without_provenance(0x1), panic)];
+        for i in 2..10 {
+            batch.push(Garbage::new(ptr::without_provenance(i), dtor));
+        }
+        batch.push(Garbage::new(ptr::without_provenance(0x10), panic));
+
+        destroy_batch(&mut batch, PanicPolicy::Isolate, None);
+        assert!(batch.is_empty());
+        assert_eq!(N.with(|n| n.get()), 8);
+    }
+
+    #[test]
+    fn destroy_batch_parallel_threads() {
+        use std::collections::HashSet;
+        use std::sync::Mutex;
+        use std::sync::atomic::{self, AtomicUsize};
+
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+        static THREADS: Mutex<Option<HashSet<thread::ThreadId>>> = Mutex::new(None);
+
+        fn dtor(_: *const u8) {
+            DESTROYED.fetch_add(1, atomic::Ordering::Relaxed);
+            THREADS.lock().unwrap().get_or_insert_with(HashSet::new).insert(thread::current().id());
+        }
+
+        let mut batch: Vec<_> = (1..2 * PARALLEL_THRESHOLD)
+            .map(|i| Garbage::new(ptr::without_provenance(i), dtor).parallel())
+            .collect();
+        batch.push(Garbage::new(ptr::without_provenance(0x1), dtor));
+        destroy_batch_parallel(&mut batch, PanicPolicy::Propagate, None, 4);
+
+        assert!(batch.is_empty());
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 2 * PARALLEL_THRESHOLD);
+        assert!(THREADS.lock().unwrap().as_ref().unwrap().len() > 1);
+    }
+
+    #[test]
+    fn destroy_batch_parallel_panic() {
+        use std::sync::atomic::{self, AtomicUsize};
+
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(_: *const u8) {
+            DESTROYED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn panic(_: *const u8) {
+            panic!();
+        }
+
+        let mut batch: Vec<_> = (2..2 * PARALLEL_THRESHOLD)
+            .map(|i| Garbage::new(ptr::without_provenance(i), dtor).parallel())
+            .collect();
+        batch.push(Garbage::new(ptr::without_provenance(0x1), panic).parallel());
+        let len = batch.len();
+
+        let res = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            destroy_batch_parallel(&mut batch, PanicPolicy::Propagate, None, 4)
+        }));
+        assert!(res.is_err());
+        // Only the panicking item and the destroyed ones are gone.
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed) + batch.len() + 1, len);
+
+        destroy_batch(&mut batch, PanicPolicy::Propagate, None);
+    }
+
+    #[test]
+    fn destroy_batch_parallel_reuse_workers() {
+        use std::collections::HashSet;
+        use std::sync::Mutex;
+
+        static THREADS: Mutex<Option<HashSet<thread::ThreadId>>> = Mutex::new(None);
+
+        fn dtor(_: *const u8) {
+            THREADS.lock().unwrap().get_or_insert_with(HashSet::new).insert(thread::current().id());
+        }
+
+        for _ in 0..2 {
+            let mut batch: Vec<_> = (1..2 * PARALLEL_THRESHOLD)
+                .map(|i| Garbage::new(ptr::without_provenance(i), dtor).parallel())
+                .collect();
+            destroy_batch_parallel(&mut batch, PanicPolicy::Propagate, None, 4);
+        }
+
+        // The second collection used the workers spawned by the first one (or by other tests).
+        let mut threads = THREADS.lock().unwrap().take().unwrap();
+        threads.remove(&thread::current().id());
+        assert!(!threads.is_empty());
+        assert!(threads.len() <= 3);
     }
 
     #[test]
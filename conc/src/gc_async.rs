@@ -0,0 +1,160 @@
+//! Asynchronous garbage collection.
+
+use std::future::Future;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+use std::time::{Duration, Instant};
+use {debug, global, local, GcError};
+
+/// The default time slice of a chunk of an asynchronous collection.
+const DEFAULT_SLICE: Duration = Duration::from_millis(1);
+
+/// Collect garbage asynchronously.
+///
+/// This acts like `conc::gc()`, except that the returned future collects the garbage in chunks
+/// of bounded length (by default about a millisecond, see `GcAsync::slice()`), yielding to the
+/// executor between them. Rather than blocking, while another thread is collecting, it yields
+/// and tries again later. Hence, a collection run from an asynchronous task doesn't monopolize
+/// the worker running it.
+///
+/// The garbage of the current thread is exported, when the future is created. Like
+/// `conc::gc_until()`, each chunk scans the hazards, so the chunks can overrun their slice
+/// slightly.
+///
+/// # Example
+///
+/// ```rust,edition2018
+/// async fn cleanup() {
+///     conc::gc_async().await.unwrap();
+/// }
+/// ```
+pub fn gc_async() -> GcAsync {
+    // Warn (in debug mode) if the thread holds guards, as their objects can't be collected.
+    debug::warn_if_guards_held();
+    // Export the local garbage to ensure that the garbage of the current thread gets collected.
+    local::export_garbage();
+    GcAsync {
+        slice: DEFAULT_SLICE,
+    }
+}
+
+/// A future collecting garbage in chunks.
+///
+/// See `gc_async()`.
+#[must_use = "futures do nothing unless polled"]
+pub struct GcAsync {
+    /// The time slice of a chunk.
+    slice: Duration,
+}
+
+impl GcAsync {
+    /// Set the time slice of a chunk.
+    pub fn slice(self, slice: Duration) -> GcAsync {
+        GcAsync {
+            slice: slice,
+        }
+    }
+}
+
+impl Future for GcAsync {
+    type Output = Result<(), GcError>;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Result<(), GcError>> {
+        let deadline = Instant::now() + self.slice;
+        match global::try_gc_until(deadline) {
+            // The chunk finished before the deadline, so the unprotected garbage is gone.
+            Ok(_) if Instant::now() < deadline => (),
+            Err(GcError::Empty) => (),
+            // Either the deadline was reached, or another thread is collecting. Yield, and go on
+            // with the next chunk, when we're polled again.
+            Ok(_) | Err(GcError::Busy) => {
+                cx.waker().wake_by_ref();
+                return Poll::Pending;
+            },
+            Err(err) => return Poll::Ready(Err(err)),
+        }
+
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::task::{Wake, Waker};
+    use Atomic;
+
+    /// A waker counting its wakes.
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Poll `future` until it completes, returning the result and the number of polls.
+    fn block_on<F: Future + Unpin>(mut future: F) -> (F::Output, usize) {
+        let counter = Arc::new(CountingWaker(AtomicUsize::new(0)));
+        let waker = Waker::from(counter.clone());
+        let mut cx = Context::from_waker(&waker);
+
+        let mut polls = 0;
+        loop {
+            polls += 1;
+            if let Poll::Ready(x) = Pin::new(&mut future).poll(&mut cx) {
+                return (x, polls);
+            }
+            // A pending poll must wake the task to be polled again.
+            assert_eq!(counter.0.load(atomic::Ordering::Relaxed), polls);
+        }
+    }
+
+    #[test]
+    fn collects() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Dropper;
+
+        impl Drop for Dropper {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let a = Atomic::new(Some(Box::new(Dropper)));
+        a.store(None, atomic::Ordering::Release);
+        ::local::free_hazards();
+
+        // Concurrent collections might destroy the garbage as well.
+        let (res, _) = block_on(gc_async());
+        assert_eq!(res, Ok(()));
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn chunks() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Slow;
+
+        impl Drop for Slow {
+            fn drop(&mut self) {
+                ::std::thread::sleep(Duration::from_millis(1));
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        for _ in 0..20 {
+            unsafe { ::add_garbage_box(Box::into_raw(Box::new(Slow))); }
+        }
+
+        let (res, polls) = block_on(gc_async().slice(Duration::from_millis(2)));
+        assert_eq!(res, Ok(()));
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 20);
+        // Concurrent collections might have done part of the work.
+        assert!(polls >= 1);
+    }
+}
@@ -1,18 +1,401 @@
 //! The global state.
 
-use parking_lot::Mutex;
-use std::collections::HashSet;
-use std::{mem, panic};
-use {rand, hazard, mpsc, debug, settings};
+use parking_lot::{self, Mutex, MutexGuard};
+use std::cell::Cell;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::{error, fmt, mem, panic, ptr, thread};
+use std::time::Instant;
+use {collector, defer, fence, garbage, hazard, local, mpsc, numa, debug, settings, stall,
+     timeline};
+use timeline::Trigger;
+use backoff::Backoff;
 use garbage::Garbage;
 
-lazy_static! {
-    /// The global state.
+/// The number of shards of the global state.
+///
+/// Each NUMA node is mapped to a shard, such that threads on different nodes don't contend for
+/// the same cache lines. If there are more nodes than shards, several nodes share a shard. The
+/// hazard slots are sharded the same way (see `hazard`).
+pub const SHARDS: usize = 8;
+
+/// Get the shard of the current thread.
+pub fn shard() -> usize {
+    numa::current_node() % SHARDS
+}
+
+/// The number of bytes of garbage pending in the global state.
+///
+/// This only counts garbage of known size. Garbage is counted when it is exported to the global
+/// state and uncounted when it is destroyed.
+static PENDING_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of garbage objects pending in the global state.
+///
+/// This is accounted like `PENDING_BYTES`.
+static PENDING_ITEMS: AtomicUsize = AtomicUsize::new(0);
+/// The highest value of `PENDING_ITEMS` since start or the last reset.
+static HIGH_WATER_ITEMS: AtomicUsize = AtomicUsize::new(0);
+/// The highest value of `PENDING_BYTES` since start or the last reset.
+static HIGH_WATER_BYTES: AtomicUsize = AtomicUsize::new(0);
+/// The number of urgent garbage objects pending in the global state.
+///
+/// This is accounted like `PENDING_ITEMS`. While it is non-zero, ticks collect garbage more often.
+static URGENT_ITEMS: AtomicUsize = AtomicUsize::new(0);
+/// The factor, by which ticks are more likely to collect garbage, while urgent garbage is pending.
+const URGENT_BOOST: usize = 16;
+/// The number of garbage objects, which got stuck since start.
+///
+/// See `Settings::stuck_after`.
+static STUCK_ITEMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Get the number of bytes of garbage pending in the global state.
+///
+/// Garbage of unknown size and garbage, which isn't exported yet, isn't counted.
+pub fn pending_bytes() -> usize {
+    PENDING_BYTES.load(atomic::Ordering::Relaxed)
+}
+
+/// Get the number of garbage objects pending in the global state.
+///
+/// Unlike `pending()`, this doesn't block, and counts the garbage exported since the last
+/// collection.
+pub fn pending_items() -> usize {
+    PENDING_ITEMS.load(atomic::Ordering::Relaxed)
+}
+
+/// Get the number of garbage objects, which got stuck since start.
+///
+/// This doesn't decrease, when the stuck garbage is eventually destroyed.
+pub fn stuck_items() -> usize {
+    STUCK_ITEMS.load(atomic::Ordering::Relaxed)
+}
+
+/// Get the highest numbers of garbage objects and bytes pending since start or the last reset.
+pub fn high_water() -> (usize, usize) {
+    (
+        HIGH_WATER_ITEMS.load(atomic::Ordering::Relaxed),
+        HIGH_WATER_BYTES.load(atomic::Ordering::Relaxed),
+    )
+}
+
+/// Reset the high-water marks to the current numbers of garbage objects and bytes pending.
+pub fn reset_high_water() {
+    let items = PENDING_ITEMS.load(atomic::Ordering::Relaxed);
+    let bytes = PENDING_BYTES.load(atomic::Ordering::Relaxed);
+    HIGH_WATER_ITEMS.store(items, atomic::Ordering::Relaxed);
+    HIGH_WATER_BYTES.store(bytes, atomic::Ordering::Relaxed);
+}
+
+/// Account for garbage entering the global state.
+fn account(garbage: &[Garbage]) {
+    garbage::account_exported(garbage);
+    let bytes = garbage.iter().map(Garbage::size).fold(0, usize::wrapping_add);
+    let items = PENDING_ITEMS.fetch_add(garbage.len(), atomic::Ordering::Relaxed) + garbage.len();
+    let urgent = garbage.iter().filter(|x| x.is_urgent()).count();
+    if urgent > 0 {
+        URGENT_ITEMS.fetch_add(urgent, atomic::Ordering::Relaxed);
+    }
+    let bytes = PENDING_BYTES.fetch_add(bytes, atomic::Ordering::Relaxed).wrapping_add(bytes);
+
+    HIGH_WATER_ITEMS.fetch_max(items, atomic::Ordering::Relaxed);
+    HIGH_WATER_BYTES.fetch_max(bytes, atomic::Ordering::Relaxed);
+}
+
+thread_local! {
+    /// Is the current thread in a critical section?
+    static CRITICAL: Cell<bool> = Cell::new(false);
+}
+
+/// Is the current thread in a critical section?
+///
+/// This is the case while the thread is collecting garbage (i.e. while the destructors of the
+/// garbage run), or holding a lock, which the collector takes. Blocking on a garbage collection in
+/// this state would deadlock.
+pub fn in_critical() -> bool {
+    CRITICAL.state() != thread::LocalKeyState::Destroyed && CRITICAL.with(|x| x.get())
+}
+
+/// A guard marking the current thread as being in a critical section, until it is dropped.
+///
+/// Critical sections can be nested.
+pub struct Critical {
+    /// Was the thread in a critical section already?
+    outer: bool,
+}
+
+impl Critical {
+    /// Enter a critical section.
+    pub fn new() -> Critical {
+        Critical {
+            outer: set_critical(true),
+        }
+    }
+}
+
+impl Drop for Critical {
+    fn drop(&mut self) {
+        set_critical(self.outer);
+    }
+}
+
+/// Set whether the current thread is in a critical section, returning the previous value.
+fn set_critical(critical: bool) -> bool {
+    if CRITICAL.state() != thread::LocalKeyState::Destroyed {
+        CRITICAL.with(|x| x.replace(critical))
+    } else {
+        false
+    }
+}
+
+/// The maximal number of recycled garbage segments to keep.
+const MAX_RECYCLED_SEGMENTS: usize = 64;
+
+/// Recycled garbage segments.
+///
+/// Garbage is stored inline in segments, which are exported as a whole. When the collector has
+/// emptied a segment, it is put here, such that it can be reused without allocating.
+static SEGMENTS: Mutex<Vec<Vec<Garbage>>> = parking_lot::const_mutex(Vec::new());
+
+/// Get an empty segment for storing garbage.
+///
+/// If possible, this reuses a segment emptied by the collector, avoiding allocation.
+pub fn segment() -> Vec<Garbage> {
+    SEGMENTS.lock().pop().unwrap_or_else(Vec::new)
+}
+
+/// Allocate segments, until at least `n` with capacity for `capacity` objects are ready for reuse.
+///
+/// `n` is limited by the bound on the recycled segments.
+pub fn preallocate_segments(n: usize, capacity: usize) {
+    if capacity == 0 {
+        return;
+    }
+
+    let n = n.min(MAX_RECYCLED_SEGMENTS);
+    loop {
+        {
+            let _critical = Critical::new();
+            let segments = SEGMENTS.lock();
+            if segments.iter().filter(|x| x.capacity() >= capacity).count() >= n {
+                return;
+            }
+        }
+
+        // Allocate the segment outside the critical section. If the pool is full, a smaller
+        // segment is replaced, and dropped outside the critical section as well.
+        let segment = Vec::with_capacity(capacity);
+        let replaced = {
+            let _critical = Critical::new();
+            let mut segments = SEGMENTS.lock();
+            if segments.len() < MAX_RECYCLED_SEGMENTS {
+                segments.push(segment);
+                None
+            } else if let Some(x) = segments.iter_mut().find(|x| x.capacity() < capacity) {
+                Some(mem::replace(x, segment))
+            } else {
+                return;
+            }
+        };
+        drop(replaced);
+    }
+}
+
+/// Release the recycled segments.
+pub fn release_segments() {
+    // Take the segments out before dropping them, to keep the critical section short.
+    let segments = {
+        let _critical = Critical::new();
+        mem::replace(&mut *SEGMENTS.lock(), Vec::new())
+    };
+    drop(segments);
+}
+
+/// Recycle an empty segment, such that it can be reused through `segment()`.
+fn recycle_segment(segment: Vec<Garbage>) {
+    debug_assert!(segment.is_empty(), "Recycling non-empty garbage segment.");
+
+    let _critical = Critical::new();
+    let mut segments = SEGMENTS.lock();
+    // Keep the pool bounded. Segments without capacity aren't worth keeping.
+    if segments.len() < MAX_RECYCLED_SEGMENTS && segment.capacity() > 0 {
+        segments.push(segment);
+    }
+}
+
+/// A lane of the pending garbage.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+enum Lane {
+    /// The urgent garbage.
+    Urgent,
+    /// The garbage of size `garbage::LARGE` or more.
+    Large,
+    /// The rest of the garbage.
+    Small,
+}
+
+/// The lanes in the order, in which they are collected.
+///
+/// The urgent garbage goes first, as its destructors release scarce resources (e.g. file
+/// descriptors), followed by the large garbage, as it matters the most for recovering memory.
+const LANES: [Lane; 3] = [Lane::Urgent, Lane::Large, Lane::Small];
+
+/// Pending garbage, segregated by urgency and size.
+///
+/// Large garbage is kept apart from small garbage, such that the collector can prioritize it, when
+/// memory is to be recovered, and such that the small garbage can be destroyed in batch with
+/// better cache locality. Urgent garbage is kept apart from both, regardless of its size.
+struct Pending {
+    /// The garbage flagged as urgent.
+    urgent: Vec<Garbage>,
+    /// The garbage smaller than `garbage::LARGE`.
+    small: Vec<Garbage>,
+    /// The garbage of size `garbage::LARGE` or more.
+    large: Vec<Garbage>,
+}
+
+impl Pending {
+    /// Create a new, empty set of pending garbage.
+    const fn new() -> Pending {
+        Pending {
+            urgent: Vec::new(),
+            small: Vec::new(),
+            large: Vec::new(),
+        }
+    }
+
+    /// Move the unprotected garbage into `doomed`.
+    ///
+    /// Only the garbage of `lane` is scanned. The protected garbage ages by a collection.
+    fn take_unprotected(
+        &mut self,
+        lane: Lane,
+        active: &HashSet<*const u8>,
+        doomed: &mut Vec<Garbage>,
+        ages: &mut Ages,
+    ) {
+        let list = match lane {
+            Lane::Urgent => &mut self.urgent,
+            Lane::Large => &mut self.large,
+            Lane::Small => &mut self.small,
+        };
+
+        let mut i = 0;
+        while i < list.len() {
+            if active.contains(&list[i].ptr()) {
+                // The garbage is protected, so we keep it.
+                ages.survived(list[i].ptr());
+                i += 1;
+            } else {
+                ages.forget(list[i].ptr());
+                doomed.push(list.swap_remove(i));
+            }
+        }
+    }
+
+    /// Destroy the unprotected garbage, which is plain (see `Garbage::is_plain()`).
+    ///
+    /// The number of garbage objects and bytes destroyed, and how many of the objects were urgent,
+    /// is returned.
+    fn destroy_plain(&mut self, active: &HashSet<*const u8>, ages: &mut Ages) -> (usize, usize, usize) {
+        let (mut items, mut bytes, mut urgent) = (0, 0, 0);
+        for list in &mut [&mut self.urgent, &mut self.large, &mut self.small] {
+            let mut i = 0;
+            while i < list.len() {
+                if list[i].is_plain() && !active.contains(&list[i].ptr()) {
+                    let garbage = list.swap_remove(i);
+                    ages.forget(garbage.ptr());
+                    items += 1;
+                    bytes = garbage.size().wrapping_add(bytes);
+                    if garbage.is_urgent() {
+                        urgent += 1;
+                    }
+                } else {
+                    i += 1;
+                }
+            }
+        }
+
+        (items, bytes, urgent)
+    }
+
+    /// Move all the garbage of `garbage` into the pending garbage.
+    fn append(&mut self, garbage: &mut Vec<Garbage>) {
+        for i in garbage.drain(..) {
+            if i.is_urgent() {
+                self.urgent.push(i);
+            } else if i.is_large() {
+                self.large.push(i);
+            } else {
+                self.small.push(i);
+            }
+        }
+    }
+}
+
+/// The ages of the garbage, which survived collections.
+struct Ages {
+    /// The number of collections survived by the garbage objects, by their addresses.
+    ///
+    /// Garbage, which hasn't survived a collection yet, isn't in it. This is `None` until the
+    /// first garbage survives.
+    ages: Option<HashMap<usize, usize>>,
+    /// The age, at which garbage is considered stuck.
     ///
-    /// This state is shared between all the threads.
-    static ref STATE: State = State::new();
+    /// This is the `stuck_after` setting of the collecting thread. If it is `0`, garbage doesn't
+    /// age.
+    stuck_after: usize,
+    /// The garbage, which got stuck in the current collection.
+    stuck: Vec<usize>,
 }
 
+impl Ages {
+    /// Create a new, empty set of ages.
+    const fn new() -> Ages {
+        Ages {
+            ages: None,
+            stuck_after: 0,
+            stuck: Vec::new(),
+        }
+    }
+
+    /// Age `ptr` by a collection, which it survived.
+    fn survived(&mut self, ptr: *const u8) {
+        if self.stuck_after == 0 {
+            return;
+        }
+
+        let age = self.ages.get_or_insert_with(HashMap::new).entry(ptr.addr()).or_insert(0);
+        *age += 1;
+        if *age == self.stuck_after {
+            self.stuck.push(ptr.addr());
+        }
+    }
+
+    /// Forget the age of `ptr`, which is about to be destroyed.
+    fn forget(&mut self, ptr: *const u8) {
+        if let Some(ref mut ages) = self.ages {
+            ages.remove(&ptr.addr());
+        }
+    }
+
+    /// Iterate over the addresses of the stuck garbage and its age.
+    fn stuck(&self) -> impl Iterator<Item = (usize, usize)> + '_ {
+        let stuck_after = self.stuck_after;
+        self.ages.iter()
+            .flat_map(|ages| ages.iter())
+            .filter(move |&(_, &age)| stuck_after != 0 && age >= stuck_after)
+            .map(|(&ptr, &age)| (ptr, age))
+    }
+}
+
+/// The global state.
+///
+/// This state is shared between all the threads. It is constructed at compile time, so accessing
+/// it doesn't require any initialization check.
+static STATE: State = State::new();
+
 /// Create a new hazard.
 ///
 /// This creates a new hazard and registers it in the global state. It's secondary, writer part is
@@ -21,6 +404,14 @@ pub fn create_hazard() -> hazard::Writer {
     STATE.create_hazard()
 }
 
+/// Create a batch of new hazards.
+///
+/// This creates `n` new hazards and registers them all in the global state at once, amortizing
+/// the cost of registration. Their secondary, writer parts are returned.
+pub fn create_hazards(n: usize) -> Vec<hazard::Writer> {
+    STATE.create_hazards(n)
+}
+
 /// Export garbage into the global state.
 ///
 /// This adds the garbage, which will eventually be destroyed, to the global state. Note that this
@@ -31,33 +422,237 @@ pub fn export_garbage(garbage: Vec<Garbage>) {
 
 /// Attempt to garbage collect.
 ///
-/// If another garbage collection is currently running, the thread will do nothing, and `Err(())`
-/// will be returned. Otherwise, it returns `Ok(())`.
+/// If another garbage collection is currently running, the thread will do nothing, and
+/// `Err(GcError::Busy)` will be returned. If the state is poisoned, `Err(GcError::Poisoned)` is
+/// returned. If there is no garbage, `Err(GcError::Empty)` is returned. Otherwise, it returns
+/// `Ok(())`.
 ///
 /// # Panic
 ///
-/// If a destructor panics, this will panic as well.
-pub fn try_gc() -> Result<(), ()> {
-    STATE.try_gc()
+/// If a destructor panics, this will panic as well (depending on the settings), and the state
+/// will be poisoned.
+pub fn try_gc() -> Result<(), GcError> {
+    STATE.try_gc(None)
+}
+
+/// The next ticket to hand out to a blocking collector.
+static NEXT_TICKET: AtomicUsize = AtomicUsize::new(0);
+/// The ticket of the blocking collector, whose turn it is.
+static NOW_SERVING: AtomicUsize = AtomicUsize::new(0);
+
+/// The turn of a blocking collector.
+///
+/// When this is dropped (also when unwinding), the turn is passed to the next collector.
+struct Turn;
+
+impl Drop for Turn {
+    fn drop(&mut self) {
+        NOW_SERVING.fetch_add(1, atomic::Ordering::Release);
+    }
+}
+
+/// Garbage collect, blocking until it is done.
+///
+/// If another garbage collection is running, this waits for it to finish. The blocking collectors
+/// are admitted in FIFO order through tickets, such that none of them starve, when many threads
+/// collect at once (e.g. when they all exceed the memory budget). Collectors using `try_gc` aren't
+/// queued, but can't starve the queue either, as the collector at the front of the queue blocks
+/// on the lock rather than retrying.
+///
+/// If the state is poisoned, `Err(GcError::Poisoned)` is returned. If there is no garbage,
+/// `Err(GcError::Empty)` is returned. Otherwise, it returns `Ok(())`.
+///
+/// `trigger` is recorded in the timeline (see `timeline`).
+///
+/// This must not be called while the current thread is in a critical section, as it would
+/// deadlock.
+pub fn gc(trigger: Trigger) -> Result<(), GcError> {
+    debug_assert!(!in_critical(), "Blocking on garbage collection in a critical section.");
+
+    // Take a ticket and wait for our turn.
+    let ticket = NEXT_TICKET.fetch_add(1, atomic::Ordering::Relaxed);
+    let mut backoff = Backoff::new();
+    while NOW_SERVING.load(atomic::Ordering::Acquire) != ticket {
+        backoff.snooze();
+    }
+
+    let res = {
+        let _turn = Turn;
+        STATE.gc(trigger)
+    };
+
+    // The callbacks might collect garbage themselves, so they must run after our turn.
+    defer::run_ready();
+    res
+}
+
+/// Destroy the unprotected garbage of `garbage`, which is kept outside the global state.
+///
+/// This blocks until any ongoing garbage collection is done, and leaves the protected garbage in
+/// `garbage`. The poison of the global state doesn't apply, as the garbage isn't part of it.
+///
+/// If a destructor panics, this acts according to the `on_dtor_panic` setting, and the garbage not
+/// yet destroyed is left in `garbage`.
+pub fn destroy_unprotected(garbage: &mut Vec<Garbage>) {
+    debug_assert!(!in_critical(), "Blocking on garbage collection in a critical section.");
+
+    STATE.destroy_unprotected(garbage);
+}
+
+/// Destroy the unprotected garbage, whose destruction merely frees memory.
+///
+/// This is the collection run, when an allocation fails (see `oom`): Only plain garbage (see
+/// `Garbage::is_plain()`) is destroyed, as other destructors run user code, which might allocate
+/// or take the locks held by the failing allocation. The rest of the garbage is left to the next
+/// collection, and no deferred callbacks are run.
+///
+/// On success, the number of garbage objects destroyed is returned. If it is zero,
+/// `Err(GcError::Empty)` is returned.
+pub fn reclaim_plain() -> Result<usize, GcError> {
+    STATE.reclaim_plain()
+}
+
+/// Attempt to garbage collect until a deadline.
+///
+/// This acts like `try_gc`, except that destroying garbage stops when `deadline` is reached. On
+/// success, the number of garbage objects left pending is returned.
+pub fn try_gc_until(deadline: Instant) -> Result<usize, GcError> {
+    STATE.collect(None, Some(deadline), Trigger::Deadline)
+}
+
+/// Clear the poison of the global state.
+pub fn clear_poison() {
+    STATE.clear_poison();
+}
+
+/// Has the system been shut down?
+static SHUT_DOWN: AtomicBool = AtomicBool::new(false);
+
+/// Mark the system as shut down.
+///
+/// This is irreversible.
+pub fn shut_down() {
+    SHUT_DOWN.store(true, atomic::Ordering::SeqCst);
+}
+
+/// Has the system been shut down?
+pub fn is_shut_down() -> bool {
+    SHUT_DOWN.load(atomic::Ordering::SeqCst)
+}
+
+/// Get the number of garbage objects pending in the global state.
+///
+/// This blocks until any ongoing garbage collection is done. Garbage exported since the last
+/// collection isn't counted.
+pub fn pending() -> usize {
+    STATE.garbo.lock().pending()
+}
+
+/// Report the global state.
+///
+/// The threads of the report are left empty. See `debug::dump()`.
+pub fn report() -> debug::StateReport {
+    STATE.report()
+}
+
+/// Get the garbage pending in the global state.
+///
+/// This blocks until any ongoing garbage collection is done. See `State::pending_garbage()`.
+pub fn pending_garbage() -> Vec<(*const u8, usize, bool)> {
+    STATE.pending_garbage()
+}
+
+/// Get the garbage in the global state, which is stuck.
+///
+/// This blocks until any ongoing garbage collection is done. See `State::stuck_garbage()`.
+pub fn stuck_garbage() -> Vec<stall::Stuck> {
+    STATE.stuck_garbage()
 }
 
 /// Tick the clock.
 ///
-/// This shall be called when new garbage is added, as it will trigger a GC by some probability.
+/// This shall be called when new garbage is added, as it will trigger a GC by some probability,
+/// unless inline collections are disabled (see `collector`).
+///
+/// The GC triggered by this only collects the garbage of the current thread's NUMA node, such
+/// that the destructors mostly touch node-local memory.
 pub fn tick() {
+    collector::check();
+    stall::check();
+
+    // While urgent garbage is pending, collections are more likely.
+    let mut probability = settings::get().gc_probability;
+    if URGENT_ITEMS.load(atomic::Ordering::Relaxed) > 0 {
+        probability = probability.saturating_mul(URGENT_BOOST);
+    }
+
     // Generate a random number and compare it against the probability.
-    if rand::random::<usize>() < settings::get().gc_probability {
+    if collector::is_inline() && local::random() < probability {
         // The outfall was to (attempt at) GC.
-        let _ = try_gc();
+        let _ = STATE.collect(Some(shard()), None, Trigger::Tick);
     }
 }
 
+/// Attempt to collect the urgent garbage of the current thread's NUMA node right away.
+///
+/// This shall be called when urgent garbage was exported. It doesn't wait for ongoing collections,
+/// and it does nothing, if collections are left to a dedicated collector (see `collector`), or if
+/// the current thread can't collect (e.g. as it is running destructors).
+pub fn collect_urgent() {
+    if collector::is_inline() && !in_critical() {
+        let _ = STATE.collect(Some(shard()), None, Trigger::Urgent);
+    }
+}
+
+/// An error from a garbage collection.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum GcError {
+    /// Another thread is currently collecting garbage.
+    ///
+    /// Retrying later will succeed.
+    Busy,
+    /// There was no garbage to collect.
+    ///
+    /// Note that garbage, which isn't exported from the other threads yet, isn't seen by the
+    /// collector.
+    Empty,
+    /// A previous collection panicked, leaving the collector poisoned.
+    ///
+    /// No garbage is collected until `conc::clear_poison()` is called.
+    Poisoned,
+    /// The calling thread holds this many guards.
+    ///
+    /// The objects protected by them can't be collected. This is only returned by
+    /// `conc::checked_gc()`.
+    GuardsHeld(usize),
+    /// This many garbage objects couldn't be reclaimed, as they stayed protected.
+    ///
+    /// This is only returned by `conc::shutdown()`.
+    Unreclaimed(usize),
+}
+
+impl fmt::Display for GcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            GcError::Busy => f.write_str("another thread is collecting garbage"),
+            GcError::Empty => f.write_str("there is no garbage to collect"),
+            GcError::Poisoned => f.write_str("the garbage collector is poisoned"),
+            GcError::GuardsHeld(n) => write!(f, "the current thread holds {} guards", n),
+            GcError::Unreclaimed(n) => write!(f, "{} garbage objects couldn't be reclaimed", n),
+        }
+    }
+}
+
+impl error::Error for GcError {}
+
 /// A message to the global state.
 enum Message {
     /// Add new garbage.
     Garbage(Vec<Garbage>),
     /// Add a new hazard.
     NewHazard(hazard::Reader),
+    /// Add a batch of new hazards.
+    NewHazards(Vec<hazard::Reader>),
 }
 
 /// The global state.
@@ -65,33 +660,128 @@ enum Message {
 /// The global state is shared between all threads and keeps track of the garbage and the active
 /// hazards.
 ///
-/// It is divided into two parts: The channel and the garbo. The channel buffers messages, which
+/// It is divided into two parts: The channels and the garbo. The channels buffer messages, which
 /// will eventually be executed at garbo, which holds all the data structures and is protected by a
-/// mutex. The garbo holds the other end to the channel.
+/// mutex. Only the holder of the garbo receives from the channels.
+///
+/// There is a channel for each shard, and the garbo keeps the garbage of each shard separately.
 struct State {
-    /// The message-passing channel.
-    chan: mpsc::Sender<Message>,
+    /// The message-passing channels of each shard.
+    chans: [mpsc::Queue<Message>; SHARDS],
     /// The garbo part of the state.
     garbo: Mutex<Garbo>,
+    /// Is the state poisoned?
+    ///
+    /// This is set when a garbage collection panics, and cleared by `clear_poison`.
+    poisoned: AtomicBool,
+}
+
+/// A guard poisoning the state, unless disarmed.
+///
+/// This is held during garbage collection, such that the state is poisoned if the collection
+/// unwinds. On success, it is disarmed by `mem::forget`.
+struct PoisonGuard<'a> {
+    /// The poison flag of the state.
+    poisoned: &'a AtomicBool,
+}
+
+impl<'a> Drop for PoisonGuard<'a> {
+    fn drop(&mut self) {
+        self.poisoned.store(true, atomic::Ordering::Release);
+    }
 }
 
 impl State {
     /// Initialize a new state.
-    fn new() -> State {
-        // Create the message-passing channel.
-        let (send, recv) = mpsc::channel();
-
-        // Construct the state from the two halfs of the channel.
+    const fn new() -> State {
         State {
-            chan: send,
-            garbo: Mutex::new(Garbo {
-                chan: recv,
-                garbage: Vec::new(),
-                hazards: Vec::new(),
-            })
+            chans: [
+                mpsc::Queue::new(), mpsc::Queue::new(), mpsc::Queue::new(), mpsc::Queue::new(),
+                mpsc::Queue::new(), mpsc::Queue::new(), mpsc::Queue::new(), mpsc::Queue::new(),
+            ],
+            garbo: parking_lot::const_mutex(Garbo {
+                garbage: [
+                    Pending::new(), Pending::new(), Pending::new(), Pending::new(),
+                    Pending::new(), Pending::new(), Pending::new(), Pending::new(),
+                ],
+                doomed: Vec::new(),
+                hazards: [
+                    Vec::new(), Vec::new(), Vec::new(), Vec::new(),
+                    Vec::new(), Vec::new(), Vec::new(), Vec::new(),
+                ],
+                unordered: false,
+                ages: Ages::new(),
+            }),
+            poisoned: AtomicBool::new(false),
         }
     }
 
+    /// See `report()`.
+    fn report(&self) -> debug::StateReport {
+        let mut report = debug::StateReport {
+            pending_bytes: pending_bytes(),
+            poisoned: self.poisoned.load(atomic::Ordering::Acquire),
+            shut_down: is_shut_down(),
+            .. Default::default()
+        };
+
+        if let Some(garbo) = self.garbo.try_lock() {
+            let mut hazards = debug::HazardReport::default();
+            for hazard in garbo.hazards.iter().flatten() {
+                match hazard.try_get() {
+                    Some(hazard::State::Free) => hazards.free += 1,
+                    Some(hazard::State::Protect(_)) => hazards.protecting += 1,
+                    Some(hazard::State::Dead) => hazards.dead += 1,
+                    None => hazards.blocked += 1,
+                }
+            }
+
+            report.global_garbage = Some(garbo.pending());
+            report.hazards = Some(hazards);
+        } else {
+            report.gc_locked = true;
+        }
+
+        report
+    }
+
+    /// Get the garbage pending in this state.
+    ///
+    /// Each object is given by its address, its size hint and whether it is protected by a hazard.
+    /// Garbage exported since the last garbage collection isn't included.
+    fn pending_garbage(&self) -> Vec<(*const u8, usize, bool)> {
+        let _critical = Critical::new();
+        let garbo = self.garbo.lock();
+
+        let protected: HashSet<_> = garbo.hazards.iter().flatten()
+            .filter_map(|hazard| match hazard.try_get() {
+                Some(hazard::State::Protect(ptr)) => Some(ptr),
+                _ => None,
+            })
+            .collect();
+
+        garbo.garbage.iter()
+            .flat_map(|x| x.urgent.iter().chain(&x.large).chain(&x.small))
+            .chain(&garbo.doomed)
+            .map(|x| (x.ptr(), x.size(), protected.contains(&x.ptr())))
+            .collect()
+    }
+
+    /// Get the garbage, which is stuck, and what protects it.
+    ///
+    /// The garbage is stuck, if it survived at least `stuck_after` (the setting of the thread,
+    /// which collected last) collections. It is ordered from the oldest to the youngest.
+    fn stuck_garbage(&self) -> Vec<stall::Stuck> {
+        let _critical = Critical::new();
+        let garbo = self.garbo.lock();
+
+        let mut stuck: Vec<_> = garbo.ages.stuck()
+            .map(|(ptr, age)| garbo.describe_stuck(ptr, age))
+            .collect();
+        stuck.sort_by_key(|x| cmp::Reverse(x.collections));
+        stuck
+    }
+
     /// Create a new hazard.
     ///
     /// This creates a new hazard and registers it in the global state. It's secondary, writer part
@@ -100,37 +790,215 @@ impl State {
         // Create the hazard.
         let (writer, reader) = hazard::create();
         // Communicate the new hazard to the global state through the channel.
-        self.chan.send(Message::NewHazard(reader));
+        self.chans[shard()].send(Message::NewHazard(reader));
         // Return the other half of the hazard.
         writer
     }
 
+    /// Create a batch of new hazards.
+    ///
+    /// This creates `n` new hazards and registers them in the global state through a single
+    /// message. Their secondary, writer parts are returned.
+    fn create_hazards(&self, n: usize) -> Vec<hazard::Writer> {
+        let mut writers = Vec::with_capacity(n);
+        let mut readers = Vec::with_capacity(n);
+
+        // Create the hazards.
+        for _ in 0..n {
+            let (writer, reader) = hazard::create();
+            writers.push(writer);
+            readers.push(reader);
+        }
+
+        // Communicate the new hazards to the global state through the channel.
+        self.chans[shard()].send(Message::NewHazards(readers));
+        // Return the other halves of the hazards.
+        writers
+    }
+
     /// Export garbage into the global state.
     ///
     /// This adds the garbage, which will eventually be destroyed, to the global state.
     fn export_garbage(&self, garbage: Vec<Garbage>) {
+        // Account for the garbage.
+        account(&garbage);
         // Send the garbage to the message-passing channel of the state.
-        self.chan.send(Message::Garbage(garbage));
+        self.chans[shard()].send(Message::Garbage(garbage));
     }
 
     /// Try to collect the garbage.
     ///
-    /// This will handle all of the messages in the channel and then attempt at collect the
-    /// garbage. If another thread is currently collecting garbage, `Err(())` is returned,
-    /// otherwise it returns `Ok(())`.
+    /// This will handle all of the messages in the channels and then attempt at collect the
+    /// garbage of shard `only` or, if `None`, of every shard. If another thread is currently
+    /// collecting garbage, `Err(GcError::Busy)` is returned, if the state is poisoned,
+    /// `Err(GcError::Poisoned)` is returned, if there is no garbage, `Err(GcError::Empty)` is
+    /// returned, otherwise it returns `Ok(())`.
     ///
     /// Garbage collection works by scanning the hazards and dropping all the garbage which is not
     /// currently active in the hazards.
-    fn try_gc(&self) -> Result<(), ()> {
-        // Lock the "garbo" (the part of the state needed to GC).
-        if let Some(mut garbo) = self.garbo.try_lock() {
-            // Collect the garbage.
-            garbo.gc();
+    fn try_gc(&self, only: Option<usize>) -> Result<(), GcError> {
+        self.collect(only, None, Trigger::Explicit).map(|_| ())
+    }
 
-            Ok(())
+    /// Try to collect the garbage, stopping at a deadline.
+    ///
+    /// This acts like `try_gc`, except that if `deadline` is given, the destruction of garbage
+    /// stops when it is reached. On success, the number of garbage objects left pending is
+    /// returned. `trigger` is recorded in the timeline.
+    fn collect(&self, only: Option<usize>, deadline: Option<Instant>, trigger: Trigger)
+        -> Result<usize, GcError> {
+        if self.poisoned.load(atomic::Ordering::Acquire) {
+            return Err(GcError::Poisoned);
+        }
+
+        // Lock the "garbo" (the part of the state needed to GC).
+        if let Some(garbo) = self.garbo.try_lock() {
+            let res = self.collect_locked(garbo, only, deadline, trigger);
+            defer::run_ready();
+            res
         } else {
             // Another thread is collecting.
-            Err(())
+            Err(GcError::Busy)
+        }
+    }
+
+    /// Collect the garbage, blocking until the garbo is available.
+    ///
+    /// This acts like `try_gc`, except that it waits for other collections to finish, instead of
+    /// returning `Err(GcError::Busy)`.
+    fn gc(&self, trigger: Trigger) -> Result<(), GcError> {
+        if self.poisoned.load(atomic::Ordering::Acquire) {
+            return Err(GcError::Poisoned);
+        }
+
+        self.collect_locked(self.garbo.lock(), None, None, trigger).map(|_| ())
+    }
+
+    /// See `reclaim_plain()`.
+    fn reclaim_plain(&self) -> Result<usize, GcError> {
+        if self.poisoned.load(atomic::Ordering::Acquire) {
+            return Err(GcError::Poisoned);
+        }
+
+        let _critical = Critical::new();
+        let mut garbo = self.garbo.lock();
+        let start = timeline::begin();
+        let guard = PoisonGuard { poisoned: &self.poisoned };
+        let (scanned, destroyed) = garbo.reclaim_plain(&self.chans);
+        mem::forget(guard);
+        if let Some(start) = start {
+            timeline::record(start, Trigger::OutOfMemory, scanned, destroyed);
+        }
+
+        if destroyed > 0 {
+            Ok(destroyed)
+        } else {
+            Err(GcError::Empty)
+        }
+    }
+
+    /// Collect the garbage with the garbo locked.
+    ///
+    /// See `collect`.
+    fn collect_locked(
+        &self,
+        mut garbo: MutexGuard<Garbo>,
+        only: Option<usize>,
+        deadline: Option<Instant>,
+        trigger: Trigger,
+    ) -> Result<usize, GcError> {
+        // The previous holder of the lock might have poisoned the state in the meantime.
+        if self.poisoned.load(atomic::Ordering::Acquire) {
+            return Err(GcError::Poisoned);
+        }
+
+        // Collect the garbage, poisoning the state if it panics.
+        let _critical = Critical::new();
+        let start = timeline::begin();
+        let guard = PoisonGuard { poisoned: &self.poisoned };
+        debug::event(|| debug::DebugEvent::GcStarted);
+        let scanned = garbo.gc(&self.chans, only, deadline);
+        mem::forget(guard);
+        let pending = garbo.pending();
+        let stuck = garbo.report_stuck();
+        STUCK_ITEMS.fetch_add(stuck, atomic::Ordering::Relaxed);
+        collector::record();
+        stall::sample();
+        debug::event(|| debug::DebugEvent::GcFinished { pending: pending });
+        if let Some(start) = start {
+            timeline::record(start, trigger, scanned, scanned - pending);
+        }
+        // The destructors might have retired garbage (e.g. the children of the garbage), which is
+        // cached by this thread. It is left to the next collection, and exported right away, such
+        // that it isn't stuck in the cache, if this thread doesn't retire garbage anymore. This
+        // doesn't collect, so chains of garbage retiring garbage don't recurse.
+        local::export_nested_garbage();
+
+        if scanned > 0 {
+            Ok(pending)
+        } else {
+            Err(GcError::Empty)
+        }
+    }
+
+    /// Destroy the unprotected garbage of `garbage`, which is kept outside the state.
+    ///
+    /// See `destroy_unprotected`.
+    fn destroy_unprotected(&self, garbage: &mut Vec<Garbage>) {
+        /// Garbage, which is put back on drop.
+        ///
+        /// This ensures that the garbage left by a panicking destructor isn't destroyed during
+        /// unwinding.
+        struct Leftovers<'a> {
+            /// The garbage to destroy.
+            doomed: Vec<Garbage>,
+            /// Where to put the garbage left.
+            garbage: &'a mut Vec<Garbage>,
+        }
+
+        impl<'a> Drop for Leftovers<'a> {
+            fn drop(&mut self) {
+                self.garbage.append(&mut self.doomed);
+            }
+        }
+
+        let mut doomed = Vec::new();
+        {
+            let mut garbo = self.garbo.lock();
+            let _critical = Critical::new();
+            let active = garbo.scan(&self.chans, shard());
+
+            let mut i = 0;
+            while i < garbage.len() {
+                if active.contains(&garbage[i].ptr()) {
+                    i += 1;
+                } else {
+                    doomed.push(garbage.swap_remove(i));
+                }
+            }
+        }
+
+        // The unprotected garbage is unreachable, so it can't become protected again. Hence, the
+        // destructors can run without holding the garbo.
+        let mut leftovers = Leftovers {
+            doomed: doomed,
+            garbage: garbage,
+        };
+        garbage::destroy_batch(&mut leftovers.doomed, settings::get().on_dtor_panic, None);
+    }
+
+    /// Clear the poison of the state.
+    fn clear_poison(&self) {
+        self.poisoned.store(false, atomic::Ordering::Release);
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        // Do a final GC, unless the state is poisoned, in which case it is better to leak the
+        // garbage.
+        if !*self.poisoned.get_mut() {
+            self.garbo.get_mut().gc(&self.chans, None, None);
         }
     }
 }
@@ -139,77 +1007,255 @@ impl panic::RefUnwindSafe for State {}
 
 /// The garbo part of the state.
 ///
-/// This part is supposed to act like the garbage collecting part. It handles hazards, garbage, and
-/// the receiving point of the message-passing channel.
+/// This part is supposed to act like the garbage collecting part. It handles hazards and garbage,
+/// and is the only receiver of the message-passing channel.
 struct Garbo {
-    /// The channel of messages.
-    chan: mpsc::Receiver<Message>,
-    /// The to-be-destroyed garbage.
-    garbage: Vec<Garbage>,
-    /// The current hazards.
-    hazards: Vec<hazard::Reader>,
+    /// The to-be-destroyed garbage of each shard.
+    garbage: [Pending; SHARDS],
+    /// Garbage, which is unprotected and about to be destroyed.
+    ///
+    /// This is only non-empty outside a garbage collection, if a destructor panicked or the deadline
+    /// of the collection was reached, in which case the rest of it is destroyed by the next
+    /// collection.
+    doomed: Vec<Garbage>,
+    /// The current hazards of each shard, ordered by the address of their slots.
+    ///
+    /// The hazards are kept in the shard, which their slots were allocated in. As the slots are
+    /// allocated in blocks, one per thread, ordering them groups the hazards by thread and block,
+    /// such that the scan sweeps the blocks sequentially rather than jumping between them.
+    hazards: [Vec<hazard::Reader>; SHARDS],
+    /// Were hazards registered since the hazards were last ordered?
+    unordered: bool,
+    /// The ages of the protected garbage.
+    ages: Ages,
 }
 
 impl Garbo {
     /// Handle a given message.
     ///
     /// "Handle" in this case refers to applying the operation defined by the message to the state,
-    /// effectually executing the instruction of the message.
-    fn handle(&mut self, msg: Message) {
+    /// effectually executing the instruction of the message. `shard` is the shard the message was
+    /// sent to.
+    fn handle(&mut self, msg: Message, shard: usize) {
         match msg {
-            // Append the garbage bulk to the garbage list.
-            Message::Garbage(mut garbage) => self.garbage.append(&mut garbage),
+            // Append the garbage bulk to the garbage list of the shard.
+            Message::Garbage(mut garbage) => {
+                self.garbage[shard].append(&mut garbage);
+                // The segment is now empty, so we can reuse it.
+                recycle_segment(garbage);
+            },
             // Register the new hazard into the state.
-            Message::NewHazard(hazard) => self.hazards.push(hazard),
+            Message::NewHazard(hazard) => {
+                self.hazards[hazard.shard()].push(hazard);
+                self.unordered = true;
+            },
+            // Register the batch of new hazards into the state. The hazards of a batch are created
+            // by a single thread, so they don't necessarily share a shard.
+            Message::NewHazards(hazards) => {
+                for hazard in hazards {
+                    self.hazards[hazard.shard()].push(hazard);
+                }
+                self.unordered = true;
+            },
+        }
+    }
+
+    /// Handle all the messages in `chans` and garbage collect all unused garbage.
+    ///
+    /// If `only` is `Some(shard)`, only the garbage of `shard` is collected. The hazards of every
+    /// shard are scanned regardless, as any of them might protect the garbage.
+    ///
+    /// If `deadline` is given, the destruction of garbage stops when it is reached, and the rest of
+    /// the unprotected garbage is destroyed by the next collection.
+    ///
+    /// The number of garbage objects pending, when the hazards were scanned, is returned. If it
+    /// is zero, there was no garbage to collect.
+    ///
+    /// # Panic
+    ///
+    /// If a destructor panics, this will act according to the `on_dtor_panic` setting of the
+    /// current thread, which by default means panicking as well.
+    fn gc(&mut self, chans: &[mpsc::Queue<Message>; SHARDS], only: Option<usize>, deadline: Option<Instant>) -> usize {
+        // Print message in debug mode.
+        debug::exec(|| println!("Collecting garbage."));
+
+        let active = self.scan(chans, only.unwrap_or_else(shard));
+
+        // The dead hazards are destroyed, even if there is no garbage to collect.
+        let scanned = self.pending();
+        if scanned == 0 {
+            return 0;
+        }
+
+        // Scan the garbage for unused objects. The lanes are destroyed one after another (in every
+        // shard), in the order of `LANES`.
+        let shards = match only {
+            Some(shard) => shard..shard + 1,
+            None => 0..SHARDS,
+        };
+        let settings = settings::get();
+        // Destroy the leftovers of a panicking or timed out collection first. Since they were
+        // unprotected, they're unreachable and can't become protected again.
+        self.destroy_doomed(&settings, deadline);
+        self.ages.stuck_after = settings.stuck_after;
+        for &lane in &LANES {
+            for pending in &mut self.garbage[shards.clone()] {
+                pending.take_unprotected(lane, &active, &mut self.doomed, &mut self.ages);
+            }
+
+            self.destroy_doomed(&settings, deadline);
+        }
+
+        scanned
+    }
+
+    /// Handle all the messages in `chans` and destroy the unprotected plain garbage.
+    ///
+    /// See `reclaim_plain()`. The number of garbage objects pending, when the hazards were
+    /// scanned, and the number of them destroyed is returned.
+    fn reclaim_plain(&mut self, chans: &[mpsc::Queue<Message>; SHARDS]) -> (usize, usize) {
+        let active = self.scan(chans, shard());
+        let scanned = self.pending();
+
+        let (mut items, mut bytes, mut urgent) = (0, 0, 0);
+        for pending in &mut self.garbage {
+            let (i, b, u) = pending.destroy_plain(&active, &mut self.ages);
+            items += i;
+            bytes = b.wrapping_add(bytes);
+            urgent += u;
+        }
+
+        PENDING_ITEMS.fetch_sub(items, atomic::Ordering::Relaxed);
+        URGENT_ITEMS.fetch_sub(urgent, atomic::Ordering::Relaxed);
+        PENDING_BYTES.fetch_sub(bytes, atomic::Ordering::Relaxed);
+
+        (scanned, items)
+    }
+
+    /// Report the garbage, which got stuck in the last collection.
+    ///
+    /// Unlike the rest of the garbage, which is protected, the stuck garbage isn't silently kept:
+    /// What protects it is looked up and reported as a debug event. The number of garbage objects
+    /// reported is returned.
+    fn report_stuck(&mut self) -> usize {
+        let stuck = mem::replace(&mut self.ages.stuck, Vec::new());
+        for &addr in &stuck {
+            let report = self.describe_stuck(addr, self.ages.stuck_after);
+            debug::event(|| debug::DebugEvent::GarbageStuck {
+                ptr: report.ptr,
+                collections: report.collections,
+                hazards: report.hazards,
+            });
+            debug::exec(|| println!("Garbage stuck: {:?}", report));
+        }
+
+        stuck.len()
+    }
+
+    /// Describe the stuck garbage at `addr` of age `age`.
+    fn describe_stuck(&self, addr: usize, age: usize) -> stall::Stuck {
+        // The pointer is only compared, so it doesn't need provenance.
+        let ptr = ptr::without_provenance(addr);
+        stall::Stuck {
+            ptr: addr,
+            collections: age,
+            hazards: self.hazards.iter().flatten()
+                .filter(|hazard| hazard.try_get() == Some(hazard::State::Protect(ptr)))
+                .count(),
+            guards: debug::guards_protecting(ptr),
         }
     }
 
-    /// Handle all the messages and garbage collect all unused garbage.
-    ///
-    /// # Panic
+    /// Handle all the messages in `chans` and scan the hazards.
     ///
-    /// If a destructor panics, this will panic as well.
-    fn gc(&mut self) {
-        // Print message in debug mode.
-        debug::exec(|| println!("Collecting garbage."));
+    /// The messages and the hazards are handled starting with the ones of shard `start`, i.e.
+    /// usually the one local to the collecting thread. The dead hazards are destroyed, and the set
+    /// of pointers protected by the rest is returned.
+    fn scan(&mut self, chans: &[mpsc::Queue<Message>; SHARDS], start: usize) -> HashSet<*const u8> {
+        // Handle all the messages sent, starting with the ones of our own shard.
+        for i in 0..SHARDS {
+            let shard = (start + i) % SHARDS;
+            for msg in chans[shard].recv_all() {
+                self.handle(msg, shard);
+            }
+        }
 
-        // Handle all the messages sent.
-        for msg in self.chan.recv_all() {
-            self.handle(msg);
+        // Order the new hazards by their slots. The scan below keeps the order, so this only sorts,
+        // when hazards were registered.
+        if self.unordered {
+            for hazards in &mut self.hazards {
+                hazards.sort_unstable_by_key(hazard::Reader::addr);
+            }
+            self.unordered = false;
         }
 
+        // Issue the collector side of the asymmetric fence, such that the hazards set by readers
+        // (which only issued a light fence) are visible to us.
+        fence::heavy();
+
         // Create the set which will keep the _active_ hazards.
-        let mut active = HashSet::with_capacity(self.hazards.len());
-
-        // Take out the hazards and go over them one-by-one.
-        let len = self.hazards.len(); // TODO: This should be substituted into next line.
-        for hazard in mem::replace(&mut self.hazards, Vec::with_capacity(len)) {
-            match hazard.get() {
-                // The hazard is dead, so the other end (the writer) is not available anymore,
-                // hence we can safely destroy it.
-                hazard::State::Dead => unsafe { hazard.destroy() },
-                // The hazard is free and must thus be put back to the hazard list.
-                hazard::State::Free => self.hazards.push(hazard),
-                hazard::State::Protect(ptr) => {
-                    // This hazard is active, hence we insert the pointer it contains in our
-                    // "active" set.
-                    active.insert(ptr);
-                    // Since the hazard is still alive, we must put it back to the hazard list for
-                    // future use.
-                    self.hazards.push(hazard);
-                },
+        let mut active = HashSet::with_capacity(self.hazards.iter().map(Vec::len).sum());
+
+        // Go over the hazards of each shard, starting with our own, whose slots are likely in local
+        // memory. Any hazard might protect any garbage, so every shard is scanned.
+        for i in 0..SHARDS {
+            let hazards = &mut self.hazards[(start + i) % SHARDS];
+
+            // Take out the hazards and go over them one-by-one.
+            let len = hazards.len(); // TODO: This should be substituted into next line.
+            for hazard in mem::replace(hazards, Vec::with_capacity(len)) {
+                match hazard.get() {
+                    // The hazard is dead, so the other end (the writer) is not available anymore,
+                    // hence we can safely destroy it.
+                    hazard::State::Dead => unsafe { hazard.destroy() },
+                    // The hazard is free and must thus be put back to the hazard list.
+                    hazard::State::Free => hazards.push(hazard),
+                    hazard::State::Protect(ptr) => {
+                        // This hazard is active, hence we insert the pointer it contains in our
+                        // "active" set.
+                        active.insert(ptr);
+                        // Since the hazard is still alive, we must put it back to the hazard list
+                        // for future use.
+                        hazards.push(hazard);
+                    },
+                }
             }
         }
 
-        // Scan the garbage for unused objects.
-        self.garbage.retain(|garbage| active.contains(&garbage.ptr()))
+        active
     }
-}
 
-impl Drop for Garbo {
-    fn drop(&mut self) {
-        // Do a final GC.
-        self.gc();
+    /// Get the number of garbage objects pending.
+    fn pending(&self) -> usize {
+        self.garbage.iter()
+            .map(|x| x.urgent.len() + x.small.len() + x.large.len())
+            .sum::<usize>() + self.doomed.len()
+    }
+
+    /// Destroy the doomed garbage.
+    ///
+    /// If `deadline` is reached, the rest of the doomed garbage is left for the next collection.
+    ///
+    /// If a destructor panics, the garbage destroyed in this batch stays accounted for, as we don't
+    /// know exactly what was destroyed. This overestimates the pending garbage, which is harmless.
+    fn destroy_doomed(&mut self, settings: &settings::Settings, deadline: Option<Instant>) {
+        let items = self.doomed.len();
+        let bytes = self.doomed.iter().map(Garbage::size).fold(0, usize::wrapping_add);
+        let urgent = self.doomed.iter().filter(|x| x.is_urgent()).count();
+        garbage::destroy_batch_parallel(
+            &mut self.doomed,
+            settings.on_dtor_panic,
+            deadline,
+            settings.destructor_threads,
+        );
+        // Only the garbage destroyed is unaccounted for.
+        let left = self.doomed.iter().map(Garbage::size).fold(0, usize::wrapping_add);
+        PENDING_ITEMS.fetch_sub(items - self.doomed.len(), atomic::Ordering::Relaxed);
+        if urgent > 0 {
+            let left = self.doomed.iter().filter(|x| x.is_urgent()).count();
+            URGENT_ITEMS.fetch_sub(urgent - left, atomic::Ordering::Relaxed);
+        }
+        PENDING_BYTES.fetch_sub(bytes.wrapping_sub(left), atomic::Ordering::Relaxed);
     }
 }
 
@@ -218,6 +1264,14 @@ mod tests {
     use super::*;
     use garbage::Garbage;
     use std::{panic, ptr};
+    use std::sync::atomic::{self, AtomicUsize};
+    use std::time::Duration;
+
+    /// Export garbage to a given shard of a state.
+    fn send(s: &State, shard: usize, garbage: Vec<Garbage>) {
+        account(&garbage);
+        s.chans[shard].send(Message::Garbage(garbage));
+    }
 
     #[test]
     fn dtor_runs() {
@@ -233,16 +1287,384 @@ mod tests {
             let h = s.create_hazard();
             h.protect(&*b);
             s.export_garbage(vec![Garbage::new(&*b, dtor)]);
-            while s.try_gc().is_err() {}
+            while s.try_gc(None).is_err() {}
             assert_eq!(*b, 0);
-            while s.try_gc().is_err() {}
+            while s.try_gc(None).is_err() {}
             h.free();
-            while s.try_gc().is_err() {}
+            while s.try_gc(None).is_err() {}
             assert_eq!(*b, 1);
             h.kill();
         }
     }
 
+    #[test]
+    fn create_hazards() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let hs = s.create_hazards(8);
+        assert_eq!(hs.len(), 8);
+
+        let b = Box::new(0);
+        for h in &hs {
+            assert!(h.is_blocked());
+            h.free();
+        }
+        hs[5].protect(&*b);
+        s.export_garbage(vec![Garbage::new(&*b, dtor)]);
+        while s.try_gc(None).is_err() {}
+        assert_eq!(*b, 0);
+        hs[5].free();
+        while s.try_gc(None).is_err() {}
+        assert_eq!(*b, 1);
+
+        for h in hs {
+            h.kill();
+        }
+    }
+
+    #[test]
+    fn shard_local_gc() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let a = Box::new(0);
+        let b = Box::new(0);
+        send(&s, 0, vec![Garbage::new(&*a, dtor)]);
+        send(&s, 1, vec![Garbage::new(&*b, dtor)]);
+
+        // Only collect the first shard.
+        while s.try_gc(Some(0)).is_err() {}
+        assert_eq!(*a, 1);
+        assert_eq!(*b, 0);
+
+        // Collect everything.
+        while s.try_gc(None).is_err() {}
+        assert_eq!(*b, 1);
+    }
+
+    #[test]
+    fn shard_local_gc_respects_hazards() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        let s = State::new();
+        let b = Box::new(0);
+        // The hazard is registered in another shard than the garbage.
+        let (h, r) = hazard::create();
+        h.protect(&*b);
+        s.chans[2].send(Message::NewHazard(r));
+        send(&s, 3, vec![Garbage::new(&*b, dtor)]);
+
+        while s.try_gc(Some(3)).is_err() {}
+        assert_eq!(*b, 0);
+        h.free();
+        while s.try_gc(Some(3)).is_err() {}
+        assert_eq!(*b, 1);
+        h.kill();
+    }
+
+    #[test]
+    fn deadline() {
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(_: *const u8) {
+            DESTROYED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let s = State::new();
+        s.export_garbage(
+            (1..1001).map(|i| Garbage::new(ptr::without_provenance(i), dtor)).collect()
+        );
+
+        // The deadline has passed, so nothing is destroyed.
+        assert_eq!(s.collect(None, Some(Instant::now()), Trigger::Deadline), Ok(1000));
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 0);
+
+        // The leftovers are destroyed by the next collection.
+        let deadline = Instant::now() + Duration::from_secs(60);
+        assert_eq!(s.collect(None, Some(deadline), Trigger::Deadline), Ok(0));
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 1000);
+    }
+
+    #[test]
+    fn parallel_destructors() {
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(_: *const u8) {
+            DESTROYED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        settings::set_local(settings::Settings {
+            destructor_threads: 4,
+            .. Default::default()
+        });
+
+        let s = State::new();
+        s.export_garbage(
+            (1..10001)
+                .map(|i| Garbage::new(ptr::without_provenance(i), dtor).parallel())
+                .collect()
+        );
+        assert_eq!(s.collect(None, None, Trigger::Explicit), Ok(0));
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 10000);
+
+        // Avoid messing with other tests.
+        settings::set_local(settings::Settings::default());
+    }
+
+    #[test]
+    fn nested_garbage() {
+        use std::sync::atomic::AtomicPtr;
+
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        /// A link of a chain, which retires the next link, when it is destroyed.
+        struct Link {
+            next: AtomicPtr<Link>,
+        }
+
+        impl Drop for Link {
+            fn drop(&mut self) {
+                let next = *self.next.get_mut();
+                if !next.is_null() {
+                    unsafe { ::add_garbage_box(next); }
+                }
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        fn chain(len: usize) -> *mut Link {
+            (0..len).fold(ptr::null_mut(), |next, _| Box::into_raw(Box::new(Link {
+                next: AtomicPtr::new(next),
+            })))
+        }
+
+        // Many chains are retired at once, so the destructors retire garbage, while the rest of
+        // the batch is being destroyed.
+        for _ in 0..1000 {
+            unsafe { ::add_garbage_box(chain(4)); }
+        }
+        unsafe { ::add_garbage_box(chain(100)); }
+
+        // Every collection destroys at least one link of the long chain.
+        for _ in 0..200 {
+            if DROPS.load(atomic::Ordering::Relaxed) == 4100 {
+                break;
+            }
+            ::gc().unwrap();
+        }
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 4100);
+    }
+
+    #[test]
+    fn nested_garbage_parallel() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        struct Child;
+
+        impl Drop for Child {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        struct Parent;
+
+        impl Drop for Parent {
+            fn drop(&mut self) {
+                // This might run on a worker thread, which exits after the collection.
+                unsafe { ::add_garbage_box(Box::into_raw(Box::new(Child))); }
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        settings::set_local(settings::Settings {
+            destructor_threads: 4,
+            .. Default::default()
+        });
+
+        for _ in 0..5000 {
+            unsafe { ::add_garbage_box_parallel(Box::into_raw(Box::new(Parent))); }
+        }
+        for _ in 0..100 {
+            if DROPS.load(atomic::Ordering::Relaxed) == 10000 {
+                break;
+            }
+            ::gc().unwrap();
+        }
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 10000);
+
+        // Avoid messing with other tests.
+        settings::set_local(settings::Settings::default());
+    }
+
+    #[test]
+    fn report() {
+        let s = State::new();
+        s.export_garbage(vec![Garbage::new(ptr::without_provenance(0x1), |_| {})]);
+        let h = s.create_hazard();
+        h.protect(ptr::without_provenance(0x2));
+        // Handle the messages without destroying the garbage.
+        s.garbo.lock().scan(&s.chans, 0);
+
+        let report = s.report();
+        assert!(!report.gc_locked);
+        assert_eq!(report.global_garbage, Some(1));
+        assert_eq!(report.hazards.unwrap().protecting, 1);
+
+        {
+            let _lock = s.garbo.lock();
+            let report = s.report();
+            assert!(report.gc_locked);
+            assert_eq!(report.global_garbage, None);
+            assert_eq!(report.hazards, None);
+        }
+
+        h.free();
+        while s.try_gc(None).is_err() {}
+        h.kill();
+    }
+
+    #[test]
+    fn pending_garbage() {
+        let s = State::new();
+        s.export_garbage(vec![
+            Garbage::new(ptr::without_provenance(0x1), |_| {}).with_size(8),
+            Garbage::new(ptr::without_provenance(0x2), |_| {}),
+        ]);
+        let h = s.create_hazard();
+        h.protect(ptr::without_provenance(0x2));
+        // Handle the messages without destroying the garbage.
+        s.garbo.lock().scan(&s.chans, 0);
+
+        let mut garbage = s.pending_garbage();
+        garbage.sort();
+        assert_eq!(
+            garbage,
+            [(ptr::without_provenance(0x1), 8, false), (ptr::without_provenance(0x2), 0, true)]
+        );
+
+        h.free();
+        while s.try_gc(None).is_err() {}
+        assert!(s.pending_garbage().is_empty());
+        h.kill();
+    }
+
+    #[test]
+    fn empty() {
+        let s = State::new();
+        assert_eq!(s.try_gc(None), Err(GcError::Empty));
+
+        s.export_garbage(vec![Garbage::new(ptr::without_provenance(0x1), |_| {})]);
+        assert_eq!(s.try_gc(None), Ok(()));
+        assert_eq!(s.try_gc(None), Err(GcError::Empty));
+    }
+
+    #[test]
+    fn blocking_gc_contention() {
+        let j: Vec<_> = (0..16).map(|_| thread::spawn(|| {
+            for _ in 0..100 {
+                export_garbage(vec![Garbage::new(ptr::without_provenance(0x1), |_| {})]);
+                match gc(Trigger::Explicit) {
+                    Ok(()) | Err(GcError::Empty) => (),
+                    Err(err) => panic!("Unexpected error: {}", err),
+                }
+            }
+        })).collect();
+
+        for i in j {
+            i.join().unwrap();
+        }
+
+        // Every ticket has been served.
+        assert!(NOW_SERVING.load(atomic::Ordering::SeqCst) >= 1600);
+    }
+
+    #[test]
+    fn segments() {
+        for _ in 0..1000 {
+            let mut seg = segment();
+            assert!(seg.is_empty());
+            seg.reserve(64);
+            recycle_segment(seg);
+        }
+    }
+
+    #[test]
+    fn preallocated_segments() {
+        preallocate_segments(2, 1000);
+        let seg = segment();
+        assert!(seg.is_empty());
+        recycle_segment(seg);
+        assert!(SEGMENTS.lock().iter().any(|x| x.capacity() >= 1000));
+    }
+
+    #[test]
+    fn size_segregation() {
+        let mut p = Pending::new();
+        let mut v = vec![
+            Garbage::new(ptr::without_provenance(0x1), |_| {}),
+            Garbage::new(ptr::without_provenance(0x2), |_| {}).with_size(::garbage::LARGE),
+            Garbage::new(ptr::without_provenance(0x3), |_| {}).with_size(8),
+        ];
+        p.append(&mut v);
+
+        assert!(v.is_empty());
+        assert_eq!(p.small.len(), 2);
+        assert_eq!(p.large.len(), 1);
+        assert_eq!(p.large[0].ptr().addr(), 0x2);
+    }
+
+    #[test]
+    fn large_first() {
+        thread_local! {
+            static ORDER: ::std::cell::RefCell<Vec<usize>> = Default::default();
+        }
+
+        fn dtor(x: *const u8) {
+            ORDER.with(|o| o.borrow_mut().push(x as usize));
+        }
+
+        let s = State::new();
+        send(&s, 0, vec![Garbage::new(ptr::without_provenance(0x1), dtor)]);
+        let large = Garbage::new(ptr::without_provenance(0x2), dtor).with_size(::garbage::LARGE);
+        send(&s, 1, vec![large]);
+        while s.try_gc(None).is_err() {}
+
+        ORDER.with(|o| assert_eq!(*o.borrow(), [0x2, 0x1]));
+    }
+
+    #[test]
+    fn urgent_first() {
+        thread_local! {
+            static ORDER: ::std::cell::RefCell<Vec<usize>> = Default::default();
+        }
+
+        fn dtor(x: *const u8) {
+            ORDER.with(|o| o.borrow_mut().push(x as usize));
+        }
+
+        let s = State::new();
+        let large = Garbage::new(ptr::without_provenance(0x1), dtor).with_size(::garbage::LARGE);
+        send(&s, 0, vec![large, Garbage::new(ptr::without_provenance(0x2), dtor)]);
+        send(&s, 1, vec![Garbage::new(ptr::without_provenance(0x3), dtor).urgent()]);
+        while s.try_gc(None).is_err() {}
+
+        ORDER.with(|o| assert_eq!(*o.borrow(), [0x3, 0x1, 0x2]));
+    }
+
     #[test]
     fn clean_up_state() {
         fn dtor(x: *const u8) {
@@ -276,16 +1698,22 @@ mod tests {
 
         let s = State::new();
         let b = Box::new(0);
-        let h = create_hazard();
+        let h = s.create_hazard();
         h.protect(&*b);
-        s.export_garbage(vec![Garbage::new(&*b, dtor), Garbage::new(0x2 as *const u8, panic)]);
+        s.export_garbage(vec![
+            Garbage::new(&*b, dtor),
+            Garbage::new(ptr::without_provenance(0x2), panic),
+        ]);
         let _ = panic::catch_unwind(|| {
-            while s.try_gc().is_err() {}
+            while s.try_gc(None).is_err() {}
         });
         assert_eq!(*b, 0);
+        assert_eq!(s.try_gc(None), Err(GcError::Poisoned));
+        s.clear_poison();
         h.free();
-        while s.try_gc().is_err() {}
+        while s.try_gc(None).is_err() {}
         assert_eq!(*b, 1);
+        h.kill();
     }
 
     #[test]
@@ -297,7 +1725,71 @@ mod tests {
 
         let s = State::new();
         s.export_garbage(vec![Garbage::new(ptr::null(), dtor)]);
-        while s.try_gc().is_err() {}
+        while s.try_gc(None).is_err() {}
+    }
+
+    #[test]
+    fn isolate_dtor_panic() {
+        fn panic(_: *const u8) {
+            panic!();
+        }
+
+        fn dtor(x: *const u8) {
+            unsafe {
+                (*(x as *const AtomicUsize)).fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        settings::set_local(settings::Settings {
+            on_dtor_panic: settings::PanicPolicy::Isolate,
+            .. Default::default()
+        });
+
+        let n = AtomicUsize::new(0);
+        let s = State::new();
+        let mut garbage = vec![Garbage::new(ptr::without_provenance(0x1), panic)];
+        for _ in 0..16 {
+            garbage.push(Garbage::new(&n as *const AtomicUsize as *const u8, dtor));
+        }
+        s.export_garbage(garbage);
+        while s.try_gc(None).is_err() {}
+
+        assert_eq!(n.load(atomic::Ordering::Relaxed), 16);
+
+        // Avoid messing with other tests.
+        settings::set_local(settings::Settings::default());
+    }
+
+    #[test]
+    fn panic_leftovers_destroyed() {
+        fn panic(_: *const u8) {
+            panic!();
+        }
+
+        fn dtor(x: *const u8) {
+            unsafe {
+                (*(x as *const AtomicUsize)).fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let n = AtomicUsize::new(0);
+        let s = State::new();
+        let mut garbage = vec![Garbage::new(ptr::without_provenance(0x1), panic)];
+        for _ in 0..16 {
+            garbage.push(Garbage::new(&n as *const AtomicUsize as *const u8, dtor));
+        }
+        s.export_garbage(garbage);
+
+        assert!(panic::catch_unwind(panic::AssertUnwindSafe(|| {
+            while s.try_gc(None).is_err() {}
+        })).is_err());
+        assert_eq!(s.try_gc(None), Err(GcError::Poisoned));
+        assert_eq!(s.try_gc(None), Err(GcError::Poisoned));
+
+        s.clear_poison();
+        while s.try_gc(None).is_err() {}
+
+        assert_eq!(n.load(atomic::Ordering::Relaxed), 16);
     }
 
     #[cfg(debug_assertions)]
@@ -309,4 +1801,95 @@ mod tests {
         h.free();
         mem::forget(h);
     }
+
+    #[test]
+    #[cfg(not(feature = "debug-tools"))]
+    fn reclaim_plain_only() {
+        static S: State = State::new();
+        static DESTROYED: AtomicUsize = AtomicUsize::new(0);
+
+        fn dtor(_: *const u8) {
+            DESTROYED.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        send(&S, 0, vec![
+            unsafe { Garbage::new_box(Box::into_raw(Box::new(0u64))) },
+            Garbage::new(ptr::without_provenance(0x1), dtor),
+        ]);
+
+        // Only the box is destroyed, as the other destructor might allocate.
+        assert_eq!(S.reclaim_plain(), Ok(1));
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 0);
+        assert_eq!(S.garbo.lock().pending(), 1);
+        assert_eq!(S.reclaim_plain(), Err(GcError::Empty));
+
+        S.try_gc(None).unwrap();
+        assert_eq!(DESTROYED.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn hazards_ordered() {
+        static S: State = State::new();
+
+        // The hazards of different threads are taken from different blocks.
+        let mut hazards = Vec::new();
+        for _ in 0..8 {
+            hazards.extend(thread::spawn(|| S.create_hazards(4)).join().unwrap());
+        }
+        hazards.extend(S.create_hazards(4));
+        for h in &hazards {
+            h.free();
+        }
+        S.export_garbage(vec![Garbage::new(ptr::without_provenance(0x1), |_| {})]);
+        S.try_gc(None).unwrap();
+
+        let garbo = S.garbo.lock();
+        assert!(!garbo.unordered);
+        for hazards in &garbo.hazards {
+            let addrs: Vec<_> = hazards.iter().map(hazard::Reader::addr).collect();
+            assert!(addrs.windows(2).all(|x| x[0] < x[1]));
+        }
+        assert_eq!(garbo.hazards.iter().map(Vec::len).sum::<usize>(), 36);
+        drop(garbo);
+
+        for h in hazards {
+            h.kill();
+        }
+    }
+
+    #[test]
+    fn stuck_garbage() {
+        settings::set_local(settings::Settings {
+            stuck_after: 3,
+            .. Default::default()
+        });
+
+        let s = State::new();
+        let ptr = ptr::without_provenance(0x483);
+        let h = s.create_hazard();
+        h.protect(ptr);
+        s.export_garbage(vec![Garbage::new(ptr, |_| {})]);
+        s.try_gc(None).unwrap();
+        s.try_gc(None).unwrap();
+        assert!(s.stuck_garbage().is_empty());
+
+        let before = stuck_items();
+        s.try_gc(None).unwrap();
+        assert!(stuck_items() > before);
+        assert_eq!(s.stuck_garbage(), [stall::Stuck {
+            ptr: 0x483,
+            collections: 3,
+            hazards: 1,
+            guards: Vec::new(),
+        }]);
+
+        // The age is forgotten, when the garbage is destroyed.
+        h.free();
+        s.try_gc(None).unwrap();
+        assert!(s.stuck_garbage().is_empty());
+        assert!(s.garbo.lock().ages.ages.as_ref().unwrap().is_empty());
+
+        h.kill();
+        settings::set_local(Default::default());
+    }
 }
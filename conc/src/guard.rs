@@ -1,8 +1,9 @@
 //! RAII guards for hazards.
 
-use std::ops;
-use std::sync::atomic;
-use {hazard, local};
+use std::{array, marker, ops};
+use pin::LongGuard;
+use shared::Shared;
+use {debug, fence, hazard, local};
 
 #[cfg(debug_assertions)]
 use std::cell::Cell;
@@ -24,10 +25,110 @@ pub fn debug_assert_no_create() {
     CURRENT_CREATING.with(|x| assert_eq!(x.get(), 0));
 }
 
+/// Protect a pointer by a hazard.
+///
+/// This evaluates `ptr` with garbage collection blocked, and protects the pointer returned by a
+/// hazard, which is returned along with the pointer. See `Guard::try_new` for details.
+pub fn protect<'a, T: ?Sized, F, E>(ptr: F) -> Result<(hazard::Writer, &'a T), E>
+where F: FnOnce() -> Result<&'a T, E> {
+    // Increment the number of guards currently being created.
+    #[cfg(debug_assertions)]
+    CURRENT_CREATING.with(|x| x.set(x.get() + 1));
+
+    // Get a hazard in blocked state.
+    let hazard = local::get_hazard();
+
+    // This fence is necessary for ensuring that `hazard` does not get reordered to after `ptr`
+    // has run. It is only a light fence, as the collector issues the heavy counterpart before
+    // scanning the hazards.
+    fence::light();
+
+    // Right here, any garbage collection is blocked, due to the hazard above. This ensures
+    // that between the potential read in `ptr` and it being protected by the hazard, there
+    // will be no premature free.
+
+    // Evaluate the pointer through the closure.
+    let res = ptr();
+
+    // Decrement the number of guards currently being created.
+    #[cfg(debug_assertions)]
+    CURRENT_CREATING.with(|x| x.set(x.get() - 1));
+
+    match res {
+        Ok(ptr) => {
+            // Check that we aren't protecting an already reclaimed object.
+            debug::assert_not_quarantined(ptr as *const T as *const u8);
+
+            // Now that we have the pointer, we can protect it by the hazard, unblocking a pending
+            // garbage collection if it exists.
+            hazard.protect(ptr as *const T as *const u8);
+            debug::event(|| debug::DebugEvent::GuardCreated {
+                ptr: ptr as *const T as *const u8 as usize,
+            });
+
+            Ok((hazard, ptr))
+        },
+        Err(err) => {
+            // Set the hazard to free to ensure that the hazard doesn't remain blocking.
+            hazard.free();
+
+            Err(err)
+        }
+    }
+}
+
+/// Protect several pointers by hazards at once.
+///
+/// This acts like `protect`, but all the hazards are blocked before a single fence is issued,
+/// after which `ptrs` is evaluated. The pointers returned by it are protected by a hazard each,
+/// which is returned along with the pointer. `None` is kept as is.
+pub fn protect_all<'a, T: ?Sized, F, const N: usize>(ptrs: F)
+-> [Option<(hazard::Writer, &'a T)>; N]
+where F: FnOnce() -> [Option<&'a T>; N] {
+    #[cfg(debug_assertions)]
+    CURRENT_CREATING.with(|x| x.set(x.get() + 1));
+
+    // Get the hazards in blocked state. These are all covered by the fence below.
+    let mut hazards: [Option<hazard::Writer>; N] = array::from_fn(|_| Some(local::get_hazard()));
+    fence::light();
+
+    // Any garbage collection is blocked now, so the pointers can be read.
+    let ptrs = ptrs();
+
+    #[cfg(debug_assertions)]
+    CURRENT_CREATING.with(|x| x.set(x.get() - 1));
+
+    array::from_fn(|i| {
+        let hazard = hazards[i].take().unwrap();
+        match ptrs[i] {
+            Some(ptr) => {
+                debug::assert_not_quarantined(ptr as *const T as *const u8);
+
+                hazard.protect(ptr as *const T as *const u8);
+                debug::event(|| debug::DebugEvent::GuardCreated {
+                    ptr: ptr as *const T as *const u8 as usize,
+                });
+
+                Some((hazard, ptr))
+            },
+            None => {
+                // Unblock the hazard, before it is put back to the cache.
+                hazard.free();
+
+                None
+            },
+        }
+    })
+}
+
 /// A RAII guard protecting from garbage collection.
 ///
 /// This "guards" the held pointer against garbage collection. First when all guards of said
 /// pointer is gone (the data is unreachable), it can be collected.
+///
+/// A guard coerces into a guard of any type, which `&T` coerces to, keeping the same hazard, e.g.
+/// `Guard<T>` into `Guard<dyn Trait>` (for `T: Trait`), or `Guard<[T; N]>` into `Guard<[T]>`. A
+/// `Guard<Box<dyn Trait>>` can be turned into a `Guard<dyn Trait>` through `map`.
 // TODO: Remove this `'static` bound.
 #[must_use = "\
     You are getting a `conc::Guard<T>` without using it, which means it is potentially \
@@ -40,6 +141,8 @@ pub struct Guard<T: 'static + ?Sized> {
     hazard: hazard::Writer,
     /// The pointer to the protected object.
     pointer: &'static T,
+    /// The registration of the guard as held by the thread, which created it.
+    held: debug::Held,
 }
 
 impl<T: ?Sized> Guard<T> {
@@ -51,47 +154,13 @@ impl<T: ?Sized> Guard<T> {
     /// This means that the closure can return and error and abort the creation of the guard.
     pub fn try_new<F, E>(ptr: F) -> Result<Guard<T>, E>
     where F: FnOnce() -> Result<&'static T, E> {
-        // Increment the number of guards currently being created.
-        #[cfg(debug_assertions)]
-        CURRENT_CREATING.with(|x| x.set(x.get() + 1));
-
-        // Get a hazard in blocked state.
-        let hazard = local::get_hazard();
-
-        // This fence is necessary for ensuring that `hazard` does not get reordered to after `ptr`
-        // has run.
-        // TODO: Is this fence even necessary?
-        atomic::fence(atomic::Ordering::SeqCst);
+        let (hazard, ptr) = protect(ptr)?;
 
-        // Right here, any garbage collection is blocked, due to the hazard above. This ensures
-        // that between the potential read in `ptr` and it being protected by the hazard, there
-        // will be no premature free.
-
-        // Evaluate the pointer through the closure.
-        let res = ptr();
-
-        // Decrement the number of guards currently being created.
-        #[cfg(debug_assertions)]
-        CURRENT_CREATING.with(|x| x.set(x.get() - 1));
-
-        match res {
-            Ok(ptr) => {
-                // Now that we have the pointer, we can protect it by the hazard, unblocking a pending
-                // garbage collection if it exists.
-                hazard.protect(ptr as *const T as *const u8);
-
-                Ok(Guard {
-                    hazard: hazard,
-                    pointer: ptr,
-                })
-            },
-            Err(err) => {
-                // Set the hazard to free to ensure that the hazard doesn't remain blocking.
-                hazard.free();
-
-                Err(err)
-            }
-        }
+        Ok(Guard {
+            hazard: hazard,
+            pointer: ptr,
+            held: debug::Held::new(ptr as *const T as *const u8),
+        })
     }
 
     /// Create a new guard.
@@ -121,6 +190,19 @@ impl<T: ?Sized> Guard<T> {
         Guard::try_new(|| ptr().ok_or(())).ok()
     }
 
+    /// Conditionally create several guards at once.
+    ///
+    /// This acts like `maybe_new`, but `ptrs` evaluates to several pointers, which are protected
+    /// through a single fence rather than one each. The same restrictions apply to the closure.
+    pub fn maybe_new_all<F, const N: usize>(ptrs: F) -> [Option<Guard<T>>; N]
+    where F: FnOnce() -> [Option<&'static T>; N] {
+        protect_all(ptrs).map(|x| x.map(|(hazard, ptr)| Guard {
+            hazard: hazard,
+            pointer: ptr,
+            held: debug::Held::new(ptr as *const T as *const u8),
+        }))
+    }
+
     /// Map the pointer to another.
     ///
     /// This allows one to map a pointer to a pointer e.g. to an object referenced by the old. It
@@ -131,6 +213,7 @@ impl<T: ?Sized> Guard<T> {
         Guard {
             hazard: self.hazard,
             pointer: f(self.pointer),
+            held: self.held,
         }
     }
 
@@ -143,6 +226,7 @@ impl<T: ?Sized> Guard<T> {
         Ok(Guard {
             hazard: self.hazard,
             pointer: f(self.pointer)?,
+            held: self.held,
         })
     }
 
@@ -152,18 +236,81 @@ impl<T: ?Sized> Guard<T> {
     pub fn maybe_map<U: ?Sized, F>(self, f: F) -> Option<Guard<U>>
     where F: FnOnce(&T) -> Option<&U> {
         let hazard = self.hazard;
+        let held = self.held;
         f(self.pointer).map(|res| Guard {
             hazard: hazard,
             pointer: res,
+            held: held,
         })
     }
 
+    /// Convert the guard into a long guard of the same object.
+    ///
+    /// The long guard takes over the hazard of the guard, and shares it with its clones, rather
+    /// than each taking a hazard of its own. When the last of them drops, the hazard is freed
+    /// right away instead of being cached. Hence, this is preferable for holding on to an object
+    /// for long (e.g. during I/O), whereas guards are cheaper otherwise.
+    pub fn pin_long(self) -> LongGuard<T> {
+        // The long guard might be dropped by another thread, so it isn't registered as held.
+        let Guard { hazard, pointer, held } = self;
+        drop(held);
+
+        LongGuard::new(hazard, pointer)
+    }
+
     /// Get the raw pointer of this guard.
     pub fn as_ptr(&self) -> *const T {
         self.pointer
     }
+
+    /// Drop the guard, ending the protection right away.
+    ///
+    /// Dropping a guard caches its hazard in the current thread, which keeps protecting the
+    /// pointer until the cache is full. This frees the hazard first, so the object can be
+    /// reclaimed by the next garbage collection.
+    pub(crate) fn release(self) {
+        self.hazard.free();
+    }
+}
+
+impl<T> Guard<T> {
+    /// Get an untagged pointer to the protected object, protected as long as the guard lives.
+    pub fn shared(&self) -> Shared<'_, T> {
+        Shared::from_raw(self.pointer as *const T as *mut T)
+    }
+
+    /// Retire the protected object, consuming the guard.
+    ///
+    /// `dtor` is run on the object, once it is no longer protected by any guard, like
+    /// `conc::add_garbage()`. The hazard of this guard is freed right away, so the object only
+    /// waits for the other guards.
+    ///
+    /// This is meant for the unlink-then-retire idiom: Load a guard, unlink the object from the
+    /// structure (e.g. through a CAS), and then retire it through its guard. The object must be
+    /// unreachable by then (see the unreachability criterion of `conc::add_garbage()`).
+    pub fn defer_destroy(self, dtor: fn(&'static T))
+    where T: Sync {
+        ::add_garbage(self.pointer, dtor);
+        self.release();
+    }
+
+    /// Retire the protected box, consuming the guard.
+    ///
+    /// This acts like `defer_destroy()`, except that the object is dropped as a `Box<T>`, like
+    /// `conc::add_garbage_box()`.
+    ///
+    /// # Safety
+    ///
+    /// The object must have been allocated through `Box::new(x)` or alike, must be unreachable
+    /// from any structure, and must not be retired otherwise.
+    pub unsafe fn defer_destroy_box(self) {
+        ::add_garbage_box(self.pointer);
+        self.release();
+    }
 }
 
+impl<T: ?Sized + marker::Unsize<U>, U: ?Sized> ops::CoerceUnsized<Guard<U>> for Guard<T> {}
+
 impl<T: ?Sized> ops::Deref for Guard<T> {
     type Target = T;
 
@@ -175,7 +322,7 @@ impl<T: ?Sized> ops::Deref for Guard<T> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::mem;
+    use std::{fmt, mem, ptr};
 
     use Atomic;
     use std::sync::atomic;
@@ -235,10 +382,79 @@ mod tests {
         let a = Atomic::new(Some(Box::new((7, 13))));
         let g = a.load(atomic::Ordering::Relaxed).unwrap().map(|&(_, ref b)| b);
         drop(a);
-        ::gc();
+        ::gc().unwrap();
         assert_eq!(*g, 13);
     }
 
+    #[test]
+    fn shared() {
+        let g = Guard::new(|| &7u64);
+        assert_eq!(g.shared().as_raw(), g.as_ptr());
+        assert_eq!(g.shared().tag(), 0);
+        assert_eq!(*unsafe { g.shared().as_ref() }.unwrap(), 7);
+    }
+
+    #[test]
+    fn defer_destroy() {
+        use testing::{Counter, Tracked};
+
+        static COUNTER: Counter = Counter::new();
+
+        fn dtor(x: &'static Tracked<u8>) {
+            drop(unsafe { Box::from_raw(x as *const Tracked<u8> as *mut Tracked<u8>) });
+        }
+
+        let ptr: &'static _ = Box::leak(Box::new(Tracked::with_counter(1, &COUNTER)));
+        let other = Guard::new(|| ptr);
+        Guard::new(|| ptr).defer_destroy(dtor);
+
+        // The other guard still protects the object.
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 0);
+        assert_eq!(**other, 1);
+
+        other.release();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
+    #[test]
+    fn defer_destroy_box() {
+        use testing::{Counter, Tracked};
+
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Box::new(Tracked::with_counter(2, &COUNTER))));
+        let g = a.load(atomic::Ordering::Acquire).unwrap();
+        // Unlink the object without retiring it, and retire it through its guard.
+        let inner = unsafe { a.get_inner() };
+        let old = g.as_ptr() as *mut _;
+        let res = inner.compare_exchange(old, ptr::null_mut(), atomic::Ordering::AcqRel,
+                                         atomic::Ordering::Acquire);
+        assert!(res.is_ok());
+        unsafe { g.defer_destroy_box(); }
+
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
+    #[test]
+    fn unsize() {
+        let a = Atomic::new(Some(Box::new(7u64)));
+        let g: Guard<dyn fmt::Display> = a.load(atomic::Ordering::Relaxed).unwrap();
+        drop(a);
+        ::gc().unwrap();
+        assert_eq!(g.to_string(), "7");
+
+        let a: Atomic<Box<dyn Fn() -> u8 + Sync>> = Atomic::new(Some(Box::new(Box::new(|| 3))));
+        let g = a.load(atomic::Ordering::Relaxed).unwrap();
+        let g: Guard<dyn Fn() -> u8 + Sync> = g.map(|x| &**x);
+        assert_eq!(g(), 3);
+
+        let g: Guard<[u8]> = Guard::new(|| &[1, 2, 3]);
+        assert_eq!(&*g, [1, 2, 3]);
+    }
+
     #[test]
     #[should_panic]
     fn panic_during_guard_creation() {
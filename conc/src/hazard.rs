@@ -12,11 +12,100 @@
 //!
 //! The asymmetry of a hazard pair is strictly speaking not necessary, but it allows to enforce
 //! rules (e.g. only the reader/global part may deallocate the hazard box).
-
-use std::sync::atomic::{self, AtomicPtr};
+//!
+//! Hazards are not allocated individually. Instead, they are taken from blocks of
+//! `ARENA_BLOCK_SIZE` hazards, which are reserved by each thread. When a hazard is destroyed, its
+//! slot is recycled for future hazards rather than being deallocated. In other words, creating a
+//! hazard only rarely hits the allocator.
+//!
+//! The blocks are sharded by NUMA node (see `global::shard()`): A thread reserves its slots from
+//! the shard of its node, and the slots are recycled into the shard they were allocated in, so the
+//! hazards written by a thread stay in memory local to its node. The collectors still read the
+//! hazards of every shard, as a reader on any node might protect any garbage.
+
+use parking_lot::{self, Mutex};
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
 use std::{mem, thread};
 
-use {debug, local};
+use backoff::Backoff;
+use {debug, global, local, stats};
+
+/// The number of hazards allocated at once.
+const ARENA_BLOCK_SIZE: usize = 64;
+
+/// The slots of each shard.
+static SHARDS: [Shard; global::SHARDS] = [
+    Shard::new(), Shard::new(), Shard::new(), Shard::new(),
+    Shard::new(), Shard::new(), Shard::new(), Shard::new(),
+];
+
+/// The slots of a shard.
+struct Shard {
+    /// Slots of destroyed hazards, which are ready to be reused.
+    recycled: Mutex<Vec<&'static AtomicPtr<u8>>>,
+    /// Every block of slots ever allocated in this shard.
+    ///
+    /// This allows for counting the hazards in each state without involving the collector.
+    blocks: Mutex<Vec<&'static [AtomicPtr<u8>]>>,
+}
+
+impl Shard {
+    /// Create an empty shard.
+    const fn new() -> Shard {
+        Shard {
+            recycled: parking_lot::const_mutex(Vec::new()),
+            blocks: parking_lot::const_mutex(Vec::new()),
+        }
+    }
+
+    /// Give slots of this shard away for recycling.
+    fn recycle(&self, slots: &mut Vec<&'static AtomicPtr<u8>>) {
+        let _critical = global::Critical::new();
+        self.recycled.lock().append(slots);
+    }
+}
+
+/// The number of snapshots referring to slots.
+///
+/// The slots of a snapshot might be recycled in the meantime, so no block is released, while this
+/// is non-zero (see `release()`).
+static SNAPSHOTS: AtomicUsize = AtomicUsize::new(0);
+
+/// Call `f` with every block of slots ever allocated.
+fn for_each_block<F: FnMut(&'static [AtomicPtr<u8>])>(mut f: F) {
+    let _critical = global::Critical::new();
+    for shard in &SHARDS {
+        for &block in shard.blocks.lock().iter() {
+            f(block);
+        }
+    }
+}
+
+thread_local! {
+    /// Slots reserved by this thread, which are not yet in use.
+    static ARENA: RefCell<Arena> = RefCell::new(Arena {
+        slots: Vec::with_capacity(ARENA_BLOCK_SIZE),
+        shard: 0,
+    });
+}
+
+/// The slots reserved by a thread.
+struct Arena {
+    /// The slots, which are not yet in use.
+    slots: Vec<&'static AtomicPtr<u8>>,
+    /// The shard, which the slots were reserved from.
+    shard: usize,
+}
+
+impl Drop for Arena {
+    fn drop(&mut self) {
+        // Give the slots left away for recycling, as the thread exits. Otherwise, every
+        // short-lived thread would keep most of a block reserved forever.
+        SHARDS[self.shard].recycle(&mut self.slots);
+    }
+}
 
 /// Pointers to this represents the blocked state.
 static BLOCKED: u8 = 0;
@@ -61,19 +150,266 @@ pub enum State {
 /// will block until it no longer is. This is useful for blocking garbage collection while a value
 /// is being read (avoiding the ABA problem).
 pub fn create() -> (Writer, Reader) {
-    // Allocate the hazard on the heap.
-    let ptr = unsafe {
-        &*Box::into_raw(Box::new(AtomicPtr::new(&BLOCKED as *const u8 as *mut u8)))
+    // Take a slot from the arena.
+    let (ptr, shard) = if ARENA.state() == thread::LocalKeyState::Destroyed {
+        // The thread-local arena was deinitialized, so we reserve a block to take the slot from,
+        // and give the rest of it away for recycling.
+        let shard = global::shard();
+        let mut slots = Vec::with_capacity(ARENA_BLOCK_SIZE);
+        reserve(shard, &mut slots);
+        let ptr = slots.pop().unwrap();
+        SHARDS[shard].recycle(&mut slots);
+
+        (ptr, shard)
+    } else {
+        ARENA.with(|arena| {
+            let mut arena = arena.borrow_mut();
+
+            // Refill the arena from the shard of the current node, if it is empty.
+            if arena.slots.is_empty() {
+                let shard = global::shard();
+                arena.shard = shard;
+                reserve(shard, &mut arena.slots);
+            }
+
+            (arena.slots.pop().unwrap(), arena.shard)
+        })
     };
 
+    // The slot might have been used by a hazard, which is now destroyed, so we reset its state.
+    ptr.store(&BLOCKED as *const u8 as *mut u8, atomic::Ordering::Release);
+
     // Construct the values.
     (Writer {
         ptr: ptr,
     }, Reader {
         ptr: ptr,
+        shard: shard,
     })
 }
 
+/// Reserve a block of slots from shard `shard`.
+///
+/// This pushes up to `ARENA_BLOCK_SIZE` slots to `slots`, taken from the recycled slots if
+/// possible. Otherwise, a new block of slots is allocated.
+fn reserve(shard: usize, slots: &mut Vec<&'static AtomicPtr<u8>>) {
+    {
+        // Take the slots from the recycled ones.
+        let _critical = global::Critical::new();
+        let mut recycled = SHARDS[shard].recycled.lock();
+        let len = recycled.len();
+        slots.extend(recycled.drain(len.saturating_sub(ARENA_BLOCK_SIZE)..));
+    }
+
+    if slots.is_empty() {
+        // Nothing to recycle, so we allocate a new block.
+        slots.extend(allocate_block(shard));
+    }
+}
+
+/// Release the blocks of the shards, whose slots are all unused.
+///
+/// This is used by `conc::shutdown()`. The slots reserved by the current thread are given back
+/// first. A shard is released, if every slot of it has been recycled, and no snapshot is alive, as
+/// nothing can refer to the slots then. Otherwise (e.g. if a guard is still alive, or another
+/// thread keeps slots reserved), the shard is left as is.
+pub fn release() {
+    if ARENA.state() != thread::LocalKeyState::Destroyed {
+        ARENA.with(|arena| {
+            let mut arena = arena.borrow_mut();
+            let shard = arena.shard;
+            SHARDS[shard].recycle(&mut arena.slots);
+        });
+    }
+
+    for shard in &SHARDS {
+        // Take the blocks out before deallocating them, to keep the critical section short.
+        let blocks = {
+            let _critical = global::Critical::new();
+            let mut blocks = shard.blocks.lock();
+            let mut recycled = shard.recycled.lock();
+            if recycled.len() != blocks.len() * ARENA_BLOCK_SIZE
+                || SNAPSHOTS.load(atomic::Ordering::SeqCst) != 0 {
+                continue;
+            }
+
+            *recycled = Vec::new();
+            mem::replace(&mut *blocks, Vec::new())
+        };
+
+        for block in blocks {
+            unsafe { drop(Box::from_raw(block as *const [AtomicPtr<u8>] as *mut [AtomicPtr<u8>])); }
+        }
+    }
+}
+
+/// Allocate a new block of slots in shard `shard`.
+///
+/// The block is allocated by the thread, which is going to use it, so (with the usual first-touch
+/// policy) its memory is local to the node of the shard.
+fn allocate_block(shard: usize) -> &'static [AtomicPtr<u8>] {
+    // Since slots are recycled, this is only deallocated, when none of its slots is in use (see
+    // `release()`), meaning that it is safe to leak it as `'static`.
+    let block: &'static [AtomicPtr<u8>] = unsafe {
+        &*Box::into_raw((0..ARENA_BLOCK_SIZE)
+            .map(|_| AtomicPtr::new(&DEAD as *const u8 as *mut u8))
+            .collect::<Vec<_>>()
+            .into_boxed_slice())
+    };
+
+    let _critical = global::Critical::new();
+    SHARDS[shard].blocks.lock().push(block);
+    block
+}
+
+/// Allocate slots, until at least `n` slots exist in the shard of the current thread.
+///
+/// The new slots are ready to be reused, and the threads on the same node refill their arenas from
+/// these, so they don't have to allocate when creating hazards later on.
+pub fn preallocate(n: usize) {
+    let shard = global::shard();
+    loop {
+        {
+            let _critical = global::Critical::new();
+            if SHARDS[shard].blocks.lock().len() * ARENA_BLOCK_SIZE >= n {
+                return;
+            }
+        }
+
+        // Allocate the block outside the critical section.
+        let block = allocate_block(shard);
+        let _critical = global::Critical::new();
+        SHARDS[shard].recycled.lock().extend(block);
+    }
+}
+
+/// Count the hazards in each state.
+///
+/// This reads every slot ever allocated, so it is linear in the peak number of hazards.
+pub fn count() -> stats::HazardStats {
+    let mut count = stats::HazardStats::default();
+
+    for_each_block(|block| {
+        count.allocated += block.len();
+        for slot in block.iter() {
+            let ptr = slot.load(atomic::Ordering::Relaxed) as *const u8;
+            if ptr != &DEAD {
+                count.live += 1;
+                if ptr != &FREE && ptr != &BLOCKED {
+                    count.protecting += 1;
+                }
+            }
+        }
+    });
+
+    count
+}
+
+/// Might `ptr` be protected by a hazard?
+///
+/// This reads every slot ever allocated, like `count()`. A blocked hazard might be about to
+/// protect any pointer, so it counts as protecting `ptr`.
+///
+/// For the answer to be reliable, `ptr` must be unreachable, and the collector side of the fence
+/// (`fence::heavy()`) must be issued after it became unreachable.
+pub fn may_protect(ptr: *const u8) -> bool {
+    let _critical = global::Critical::new();
+    SHARDS.iter().any(|shard| shard.blocks.lock().iter().any(|block| block.iter().any(|slot| {
+        let state = slot.load(atomic::Ordering::Acquire) as *const u8;
+        state == ptr || state == &BLOCKED
+    })))
+}
+
+/// Get the set of pointers protected by hazards, or `None` if a hazard is blocked.
+///
+/// This reads every slot ever allocated, like `may_protect()`, without waiting for the collector's
+/// lock or for blocked hazards. A blocked hazard might be about to protect any pointer, so nothing
+/// can be told then.
+///
+/// For the set to be reliable, the collector side of the fence (`fence::heavy()`) must be issued
+/// before.
+pub fn protected() -> Option<HashSet<*const u8>> {
+    let mut protected = HashSet::new();
+    let mut blocked = false;
+    for_each_block(|block| for slot in block.iter() {
+        let ptr = slot.load(atomic::Ordering::Acquire) as *const u8;
+        if ptr == &BLOCKED {
+            blocked = true;
+        } else if ptr != &DEAD && ptr != &FREE {
+            protected.insert(ptr);
+        }
+    });
+
+    if blocked {
+        None
+    } else {
+        Some(protected)
+    }
+}
+
+/// A snapshot of the hazards, which are protecting objects or blocked.
+///
+/// See `snapshot()`.
+#[derive(Default)]
+pub struct Snapshot {
+    /// The slots of the hazards and their state at the time of the snapshot.
+    slots: Vec<(&'static AtomicPtr<u8>, usize)>,
+}
+
+impl Snapshot {
+    /// Has every hazard of the snapshot changed its state since?
+    ///
+    /// The hazards, which have changed, are removed from the snapshot, so later calls only check
+    /// the rest. A hazard, which is set back to the same state, is considered unchanged.
+    pub fn is_turned_over(&mut self) -> bool {
+        if self.slots.is_empty() {
+            return true;
+        }
+
+        self.slots.retain(|&(slot, state)| slot.load(atomic::Ordering::Acquire).addr() == state);
+        if self.slots.is_empty() {
+            // The snapshot no longer refers to any slot.
+            SNAPSHOTS.fetch_sub(1, atomic::Ordering::SeqCst);
+        }
+
+        self.slots.is_empty()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.slots.is_empty() {
+            SNAPSHOTS.fetch_sub(1, atomic::Ordering::SeqCst);
+        }
+    }
+}
+
+/// Take a snapshot of the hazards, which are protecting objects or blocked.
+///
+/// This reads every slot ever allocated, like `count()`. For the snapshot to include every hazard
+/// set before, the collector side of the fence (`fence::heavy()`) must be issued before.
+pub fn snapshot() -> Snapshot {
+    // Count the snapshot before reading the blocks, such that they aren't released meanwhile.
+    SNAPSHOTS.fetch_add(1, atomic::Ordering::SeqCst);
+
+    let mut slots = Vec::new();
+    for_each_block(|block| {
+        for slot in block.iter() {
+            let ptr = slot.load(atomic::Ordering::Acquire) as *const u8;
+            if ptr != &DEAD && ptr != &FREE {
+                slots.push((slot, ptr.addr()));
+            }
+        }
+    });
+    if slots.is_empty() {
+        SNAPSHOTS.fetch_sub(1, atomic::Ordering::SeqCst);
+    }
+
+    Snapshot {
+        slots: slots,
+    }
+}
+
 /// An hazard reader.
 ///
 /// This wraps a hazard and provides only ability to read and deallocate it. It is created through
@@ -84,43 +420,69 @@ pub fn create() -> (Writer, Reader) {
 pub struct Reader {
     /// The pointer to the heap-allocated hazard.
     ptr: &'static AtomicPtr<u8>,
+    /// The shard, which the hazard's slot belongs to.
+    shard: usize,
 }
 
 impl Reader {
     /// Get the state of the hazard.
     ///
-    /// It will spin until the hazard is no longer in a blocked state, unless it is in debug mode,
-    /// where it will panic given enough spins.
+    /// It will spin (backing off) until the hazard is no longer in a blocked state, unless it is
+    /// in debug mode, where it will panic given enough spins.
     pub fn get(&self) -> State {
         // In debug mode, we count the number of spins. In release mode, this should be trivially
         // optimized out.
         let mut spins = 0;
+        let mut backoff = Backoff::new();
 
         // Spin until not blocked.
         loop {
-            let ptr = self.ptr.load(atomic::Ordering::Acquire) as *const u8;
-
             // Blocked means that the hazard is blocked by another thread, and we must loop until
             // it assumes another state.
-            if ptr == &BLOCKED {
-                // Increment the number of spins.
-                spins += 1;
-                debug_assert!(spins < 100_000_000, "\
-                    Hazard blocked for 100 millions rounds. Panicking as chances are that it will \
-                    never get unblocked.\
-                ");
-
-                continue;
-            } else if ptr == &FREE {
-                return State::Free;
-            } else if ptr == &DEAD {
-                return State::Dead;
-            } else {
-                return State::Protect(ptr);
+            if let Some(state) = self.try_get() {
+                return state;
             }
+
+            // Increment the number of spins.
+            spins += 1;
+            debug_assert!(spins < 100_000_000, "\
+                Hazard blocked for 100 millions rounds. Panicking as chances are that it will \
+                never get unblocked.\
+            ");
+
+            backoff.snooze();
+        }
+    }
+
+    /// Get the state of the hazard without spinning.
+    ///
+    /// If the hazard is blocked, `None` is returned.
+    pub fn try_get(&self) -> Option<State> {
+        let ptr = self.ptr.load(atomic::Ordering::Acquire) as *const u8;
+
+        if ptr == &BLOCKED {
+            None
+        } else if ptr == &FREE {
+            Some(State::Free)
+        } else if ptr == &DEAD {
+            Some(State::Dead)
+        } else {
+            Some(State::Protect(ptr))
         }
     }
 
+    /// Get the address of the hazard's slot.
+    ///
+    /// Slots of the same block have consecutive addresses.
+    pub fn addr(&self) -> usize {
+        self.ptr as *const AtomicPtr<u8> as usize
+    }
+
+    /// Get the shard, which the hazard's slot belongs to.
+    pub fn shard(&self) -> usize {
+        self.shard
+    }
+
     /// Destroy the hazard.
     ///
     /// # Safety
@@ -135,8 +497,12 @@ impl Reader {
     pub unsafe fn destroy(self) {
         debug_assert!(self.get() == State::Dead, "Prematurely freeing an active hazard.");
 
-        // Load the pointer and deallocate it.
-        Box::from_raw(self.ptr as *const AtomicPtr<u8> as *mut AtomicPtr<u8>);
+        // Recycle the slot into its shard, such that it can be used by future hazards on the same
+        // node.
+        {
+            let _critical = global::Critical::new();
+            SHARDS[self.shard].recycled.lock().push(self.ptr);
+        }
         // Ensure that the RAII destructor doesn't kick in and crashes the program.
         mem::forget(self);
     }
@@ -166,6 +532,11 @@ pub struct Writer {
 }
 
 impl Writer {
+    /// Get the hazard of this writer.
+    pub fn as_raw(&self) -> &'static AtomicPtr<u8> {
+        self.ptr
+    }
+
     /// Is the hazard blocked?
     pub fn is_blocked(&self) -> bool {
         self.ptr.load(atomic::Ordering::Acquire) as *const u8 == &BLOCKED
@@ -181,6 +552,9 @@ impl Writer {
     /// This sets the state to `State::Free`.
     pub fn free(&self) {
         self.ptr.store(&FREE as *const u8 as *mut u8, atomic::Ordering::Release);
+        debug::event(|| debug::DebugEvent::HazardFreed {
+            hazard: self.ptr as *const AtomicPtr<u8> as usize,
+        });
     }
 
     /// Protect a pointer with the hazard.
@@ -271,8 +645,8 @@ mod tests {
 
         w.protect(ptr::null());
         assert_eq!(r.get(), State::Protect(ptr::null()));
-        w.protect(0x1 as *const u8);
-        assert_eq!(r.get(), State::Protect(0x1 as *const u8));
+        w.protect(ptr::without_provenance(0x1));
+        assert_eq!(r.get(), State::Protect(ptr::without_provenance(0x1)));
 
         w.kill();
         unsafe {
@@ -322,6 +696,85 @@ mod tests {
         }
     }
 
+    #[test]
+    fn distinct_slots() {
+        let mut v = Vec::new();
+        for _ in 0..ARENA_BLOCK_SIZE * 4 {
+            v.push(create());
+        }
+
+        let mut ptrs: Vec<_> = v.iter().map(|&(_, ref r)| r.ptr as *const AtomicPtr<u8>).collect();
+        ptrs.sort();
+        ptrs.dedup();
+        assert_eq!(ptrs.len(), ARENA_BLOCK_SIZE * 4);
+
+        for (w, r) in v {
+            w.kill();
+            unsafe { r.destroy(); }
+        }
+    }
+
+    #[test]
+    fn recycled_slot_is_reset() {
+        for _ in 0..ARENA_BLOCK_SIZE * 4 {
+            let (w, r) = create();
+            assert!(w.is_blocked());
+            w.kill();
+            unsafe { r.destroy(); }
+        }
+    }
+
+    #[test]
+    fn cross_thread_recycle() {
+        for _ in 0..16 {
+            let (w, r) = thread::spawn(|| create()).join().unwrap();
+            w.kill();
+            unsafe { r.destroy(); }
+        }
+    }
+
+    #[test]
+    fn release_keeps_used() {
+        let (w, r) = create();
+        release();
+
+        // The slot is in use, so its block is kept.
+        w.protect(&0u8);
+        assert!(may_protect(&0u8));
+        w.kill();
+        unsafe { r.destroy(); }
+    }
+
+    #[test]
+    fn preallocate_slots() {
+        preallocate(ARENA_BLOCK_SIZE * 3 + 1);
+        assert!(SHARDS[global::shard()].blocks.lock().len() >= 4);
+
+        let (w, r) = create();
+        w.kill();
+        unsafe { r.destroy(); }
+    }
+
+    #[test]
+    fn snapshot_turns_over() {
+        let (w, r) = create();
+        w.protect(ptr::without_provenance(0x1234));
+
+        // Only consider our own hazard, as other tests might leak theirs.
+        let mut snapshot = snapshot();
+        snapshot.slots.retain(|&(slot, _)| slot as *const _ == r.ptr as *const _);
+        assert_eq!(snapshot.slots.len(), 1);
+        assert!(!snapshot.is_turned_over());
+
+        w.protect(ptr::without_provenance(0x1234));
+        assert!(!snapshot.is_turned_over());
+        w.free();
+        assert!(snapshot.is_turned_over());
+
+        w.kill();
+        unsafe { r.destroy(); }
+    }
+
     /* FIXME: These tests are broken as the unwinding calls dtor of `Writer`, which double panics.
         #[cfg(debug_assertions)]
         #[test]
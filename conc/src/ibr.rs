@@ -0,0 +1,367 @@
+//! Interval-based reclamation.
+//!
+//! This is an alternative reclamation engine implementing 2GEIBR, the two global epochs variant
+//! of interval-based reclamation (Wen et al., "Interval-Based Memory Reclamation", 2018). Like
+//! hazard eras, every object carries the era, in which it was allocated (its birth), and the era,
+//! in which it was retired. However, rather than publishing an era per guard, every thread
+//! reserves a single interval of eras: It starts at the era, in which the first guard of the
+//! thread was created, and is extended, as the thread loads objects in later eras. The interval
+//! is released, when the last guard of the thread is dropped.
+//!
+//! Hence, the protection is amortized over all the guards of a thread, while the garbage stays
+//! bounded: A stalled thread only holds back objects, which were alive during its interval, as
+//! opposed to the epochs of epoch-based reclamation, which hold back everything retired after.
+//!
+//! The engine is independent of the rest of the crate: Its objects must be allocated through its
+//! own `Atomic<T>`, as they carry a header with the eras, and they are reclaimed by its own
+//! collections (`collect()`), rather than by `conc::gc()`. It is the `Ibr` backend of `engine`.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::ibr::Atomic;
+//!
+//! let atomic = Atomic::new(Some(1));
+//! let guard = atomic.load().unwrap();
+//! atomic.store(Some(2));
+//!
+//! // The old value is protected by the interval of the thread.
+//! conc::ibr::collect();
+//! assert_eq!(*guard, 1);
+//! ```
+
+use engine::{self, ReclaimBackend};
+use parking_lot::{self, Mutex};
+use std::cell::RefCell;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use std::thread;
+
+/// The era of a reservation, which reserves none.
+const NONE: u64 = 0;
+
+/// The era clock.
+///
+/// This starts at 1, as 0 is `NONE`.
+static ERA: AtomicU64 = AtomicU64::new(1);
+/// Every reservation ever allocated.
+static RESERVATIONS: Mutex<Vec<&'static Reservation>> = parking_lot::const_mutex(Vec::new());
+/// Reservations of exited threads, which are ready to be reused.
+static FREE: Mutex<Vec<&'static Reservation>> = parking_lot::const_mutex(Vec::new());
+
+thread_local! {
+    /// The reservation of this thread, if it has one.
+    static LOCAL: RefCell<Local> = RefCell::new(Local::default());
+}
+
+/// The state of a thread.
+#[derive(Default)]
+struct Local {
+    /// The reservation of the thread, if it has one.
+    reservation: Option<&'static Reservation>,
+}
+
+impl Drop for Local {
+    fn drop(&mut self) {
+        if let Some(reservation) = self.reservation {
+            // If guards outlive the state (e.g. in other thread-local destructors), the
+            // reservation is still in use, so we leak it.
+            if reservation.guards.load(atomic::Ordering::Relaxed) == 0 {
+                FREE.lock().push(reservation);
+            }
+        }
+    }
+}
+
+/// The interval of eras reserved by a thread.
+struct Reservation {
+    /// The first era of the interval, or `NONE`.
+    lower: AtomicU64,
+    /// The last era of the interval, or `NONE`.
+    upper: AtomicU64,
+    /// The number of guards using the reservation.
+    ///
+    /// This is only accessed by the thread owning the reservation.
+    guards: AtomicUsize,
+}
+
+impl Reservation {
+    /// Create a new reservation reserving no interval.
+    fn new() -> Reservation {
+        Reservation {
+            lower: AtomicU64::new(NONE),
+            upper: AtomicU64::new(NONE),
+            guards: AtomicUsize::new(0),
+        }
+    }
+
+    /// Add a guard, reserving the current era, if it is the first one.
+    fn enter(&self) {
+        if self.guards.fetch_add(1, atomic::Ordering::Relaxed) == 0 {
+            let era = ERA.load(atomic::Ordering::SeqCst);
+            // The upper bound is set first, such that a collection seeing the lower bound sees
+            // the interval.
+            self.upper.store(era, atomic::Ordering::SeqCst);
+            self.lower.store(era, atomic::Ordering::SeqCst);
+        }
+    }
+
+    /// Extend the interval to the current era.
+    ///
+    /// This returns `true`, if the interval was extended.
+    fn extend(&self) -> bool {
+        let era = ERA.load(atomic::Ordering::SeqCst);
+        if era > self.upper.load(atomic::Ordering::Relaxed) {
+            self.upper.store(era, atomic::Ordering::SeqCst);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Remove a guard, releasing the interval, if it was the last one.
+    ///
+    /// This returns `true`, if the interval was released.
+    fn leave(&self) -> bool {
+        if self.guards.fetch_sub(1, atomic::Ordering::Relaxed) == 1 {
+            self.lower.store(NONE, atomic::Ordering::Release);
+            self.upper.store(NONE, atomic::Ordering::Release);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Get the reserved interval, if any.
+    fn interval(&self) -> Option<(u64, u64)> {
+        let lower = self.lower.load(atomic::Ordering::SeqCst);
+        let upper = self.upper.load(atomic::Ordering::SeqCst);
+        // If the upper bound is gone, the interval was released meanwhile, and any interval
+        // reserved afterwards starts after the collection.
+        if lower == NONE || upper == NONE {
+            None
+        } else {
+            Some((lower, upper))
+        }
+    }
+}
+
+/// The interval-based reclamation backend.
+///
+/// The guards of a thread share the interval reserved by it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Ibr;
+
+/// The reservation of a guard.
+pub struct Token {
+    /// The reservation.
+    reservation: &'static Reservation,
+    /// Is the reservation detached from the thread?
+    detached: bool,
+}
+
+unsafe impl ReclaimBackend for Ibr {
+    type Token = Token;
+
+    fn clock() -> &'static AtomicU64 {
+        &ERA
+    }
+
+    fn acquire() -> Token {
+        let token = get_reservation();
+        token.reservation.enter();
+        token
+    }
+
+    fn publish(token: &Token) -> bool {
+        token.reservation.extend()
+    }
+
+    fn release(token: &Token) {
+        if token.reservation.leave() && token.detached {
+            FREE.lock().push(token.reservation);
+        }
+    }
+
+    fn intervals(intervals: &mut Vec<(u64, u64)>) {
+        intervals.extend(RESERVATIONS.lock().iter().filter_map(|x| x.interval()));
+    }
+}
+
+/// Get a reservation, which is not used by other threads.
+///
+/// This is the reservation of the current thread, unless its state is destroyed, in which case a
+/// detached reservation is returned, which is freed, once its guards are gone.
+fn get_reservation() -> Token {
+    if LOCAL.state() != thread::LocalKeyState::Destroyed {
+        let reservation = LOCAL.with(|x| {
+            let mut local = x.borrow_mut();
+            if local.reservation.is_none() {
+                local.reservation = Some(new_reservation());
+            }
+            local.reservation.unwrap()
+        });
+        Token {
+            reservation: reservation,
+            detached: false,
+        }
+    } else {
+        Token {
+            reservation: new_reservation(),
+            detached: true,
+        }
+    }
+}
+
+/// Get a reservation reserving no interval.
+fn new_reservation() -> &'static Reservation {
+    FREE.lock().pop().unwrap_or_else(|| {
+        // Reservations are reused, so they are never deallocated.
+        let reservation: &'static Reservation = Box::leak(Box::new(Reservation::new()));
+        RESERVATIONS.lock().push(reservation);
+        reservation
+    })
+}
+
+/// A pointer to an object protected by the interval of the thread.
+///
+/// The object is protected until the guard is dropped.
+pub type Guard<T> = engine::Guard<T, Ibr>;
+
+/// A concurrently accessible option of a value, reclaimed through interval-based reclamation.
+///
+/// See `engine::Atomic<T, B>`.
+pub type Atomic<T> = engine::Atomic<T, Ibr>;
+
+/// Get the current era.
+pub fn era() -> u64 {
+    engine::era::<Ibr>()
+}
+
+/// Destroy the objects retired by the current thread (and exited threads), which are no longer
+/// protected.
+///
+/// This is done automatically, when enough objects are retired by the thread. Objects of the
+/// other engines are collected as well (see `engine::collect()`).
+pub fn collect() {
+    engine::collect();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use testing::{Counter, Tracked};
+
+    /// Collect, until `n` objects of `counter` are destroyed.
+    ///
+    /// The guards of concurrently running tests might protect the objects for a while, as they
+    /// protect every object alive in their interval.
+    fn wait_for(counter: &Counter, n: usize) {
+        for _ in 0..10000 {
+            collect();
+            if counter.destroyed() >= n {
+                break;
+            }
+            thread::yield_now();
+        }
+
+        assert_eq!(counter.destroyed(), n);
+    }
+
+    #[test]
+    fn load_store() {
+        let a = Atomic::new(None);
+        assert!(a.load().is_none());
+        a.store(Some(1));
+        assert_eq!(*a.load().unwrap(), 1);
+        assert_eq!(*a.swap(Some(2)).unwrap(), 1);
+
+        let cur = a.load().unwrap().as_ptr();
+        assert_eq!(a.compare_and_store(None, Some(3)), Err(Some(3)));
+        assert_eq!(a.compare_and_store(Some(cur), Some(4)), Ok(()));
+        assert_eq!(*a.load().unwrap(), 4);
+    }
+
+    #[test]
+    fn protection() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Tracked::with_counter(1, &COUNTER)));
+        let b = Atomic::new(Some(Tracked::with_counter(2, &COUNTER)));
+
+        let guard = a.load().unwrap();
+        // Loading another object extends the interval, but keeps protecting the first one.
+        b.store(Some(Tracked::with_counter(3, &COUNTER)));
+        let guard_b = b.load().unwrap();
+        a.store(None);
+        collect();
+        assert_eq!(COUNTER.destroyed(), 0);
+
+        drop(guard);
+        drop(guard_b);
+        drop(a);
+        drop(b);
+        wait_for(&COUNTER, 3);
+    }
+
+    #[test]
+    fn interval() {
+        let reservation = Reservation::new();
+        assert_eq!(reservation.interval(), None);
+
+        reservation.enter();
+        let (lower, upper) = reservation.interval().unwrap();
+        assert_eq!(lower, upper);
+
+        // Retiring an object advances the clock.
+        Atomic::new(Some(1)).store(None);
+        reservation.enter();
+        assert_eq!(reservation.interval(), Some((lower, upper)));
+        assert!(reservation.extend());
+        assert!(reservation.interval().unwrap().1 > upper);
+
+        assert!(!reservation.leave());
+        assert!(reservation.leave());
+        assert_eq!(reservation.interval(), None);
+    }
+
+    #[test]
+    fn orphans() {
+        static COUNTER: Counter = Counter::new();
+
+        thread::spawn(|| {
+            let a = Atomic::new(Some(Tracked::with_counter(1, &COUNTER)));
+            a.store(Some(Tracked::with_counter(2, &COUNTER)));
+        }).join().unwrap();
+
+        wait_for(&COUNTER, 2);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Arc::new(Atomic::new(Some(Tracked::with_counter(0, &COUNTER))));
+
+        let mut j = Vec::new();
+        for _ in 0..8 {
+            let a = a.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..10000 {
+                    if i % 4 == 0 {
+                        a.store(Some(Tracked::with_counter(i, &COUNTER)));
+                    } else {
+                        // Reading a reclaimed object would likely trip the canary.
+                        a.load().unwrap().check();
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        drop(a);
+        wait_for(&COUNTER, 20001);
+    }
+}
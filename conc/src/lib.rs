@@ -8,15 +8,62 @@
 //!
 //! - **High-level API**
 //!     * `Atomic<T>` for an lockless readable and writable container.
+//!     * `load_all()` for loading several `Atomic`s with a single fence.
+//!     * `ReadMostly<T>` for containers loaded through epochs rather than hazards.
+//!     * `LeakingAtomic<T>` for containers of values, which are never reclaimed.
+//!     * `AtomicCell<T>` for small `Copy` values stored inline, without guards or garbage.
+//!     * `Shared<'g, T>` for tagged pointers, which can be compared and swapped cheaply.
+//!     * `MaybeOwned<T>` for returning either guarded or owned values.
 //!     * `sync` for basic datastructures implemented through `conc`.
 //!         - `Treiber<T>` for concurrent stacks.
 //!         - `Stm<T>` for a simple implementation of STM.
+//!         - `ClockCache<K, V>` for caches with clock (second-chance) eviction.
+//!         - `DirtyMap<K, V>` for tracking the values, which write-back caches must flush.
+//!         - `PageCache<V>` for caches of pinnable pages with a total weight budget.
+//!         - `IdAllocator` for allocating integer IDs (e.g. block numbers).
+//!         - `Versioned<T>` for keeping multiple recent versions of a value.
+//!         - `BloomFilter` for approximate membership with growable bit arrays.
+//!         - `DisjointSet` for union-find over concurrently merged sets.
+//!         - `HashMultiMap<K, V>` for mapping keys to bags of values.
+//!         - `Deque<T>` for concurrent double-ended queues.
+//!     * `prelude` for importing the commonly used items at once.
 //! - **Low-level API**
 //!     * `add_garbage()` for queuing destruction of garbage.
+//!     * `add_garbage_parallel()` for garbage, which can be destroyed on several threads.
+//!     * `add_garbage_urgent()` for garbage holding scarce resources, which is collected first.
 //!     * `Guard<T>` for blocking destruction.
+//!     * `LongGuard<T>` for blocking destruction for long, sharing a hazard between clones.
+//!     * `defer()` for running a callback once the current guards are gone.
+//!     * `synchronize()` for awaiting the current guards asynchronously.
+//!     * `scope()` for reclaiming data borrowing from the stack.
+//!     * `nested` for retiring the children of nested structures along with them.
+//!     * `reclaim` for writing structures generic over the reclamation scheme.
+//!     * `eras` for the hazard eras engine, an alternative to the hazards of this crate.
+//!     * `ibr` for interval-based reclamation, another alternative engine.
+//!     * `engine` for selecting between these engines at compile time.
+//!     * `thread` for spawning threads, which clean up reliably on exit.
+//!     * `foreign` for attaching threads created by foreign code.
 //! - **Runtime control**
 //!     * `gc()` for collecting garbage to reduce memory.
+//!     * `gc_until()` for collecting garbage within a time slice.
+//!     * `gc_async()` for collecting garbage from an asynchronous task.
+//!     * `run_exit_gc()` for destroying the remaining garbage at exit.
+//!     * `shutdown()` for tearing down the system (e.g. before unloading a plugin).
 //!     * `settings` for reconfiguring the system on-the-go.
+//!     * `warm_up()` for allocating up front, rather than on the first operations.
+//!     * `LocalState` for handing the local state of exiting threads over to other threads.
+//!     * `budget` for limiting the memory used by pending garbage.
+//!     * `collector` for moving the collections out of the threads retiring garbage.
+//!     * `driver` for collecting in the background of an asynchronous runtime.
+//!     * `stats` for monitoring the system.
+//!     * `stall` for detecting garbage, which piles up without being reclaimed.
+//!     * `timeline` for recording the garbage collection cycles.
+//!     * `oom` for collecting garbage when allocation fails.
+//!     * `bench` for measuring the performance of the current configuration.
+//! - **Testing**
+//!     * `fuzz` for fuzzing the reclamation protocol and structures built upon it.
+//!     * `testing` for counting destructions and detecting leaks in tests.
+//!     * `trace` for recording the reclamation events, and replaying them for debugging.
 //!
 //! ## Why?
 //!
@@ -51,7 +98,34 @@
 //!
 //! Enable feature `debug-tools` and set environment variable `CONC_DEBUG_MODE`. For example,
 //! `CONC_DEBUG_MODE=1 cargo test --features debug-tools`. To get stacktraces after each message,
-//! set environment variable `CONC_DEBUG_STACKTRACE`.
+//! set environment variable `CONC_DEBUG_STACKTRACE`. Debug mode can also be toggled at runtime
+//! through `debug::enable()` and `debug::disable()`, or enabled for a region of code through
+//! `debug::with_enabled()`.
+//!
+//! `debug::dump()` creates a report of the internal state (e.g. the pending garbage and the states
+//! of the hazards), which is useful when the garbage isn't reclaimed as expected. It is available
+//! without `debug-tools` as well. Similarly, `debug::assert_no_garbage()` collects garbage and
+//! panics with a listing of the garbage left unreclaimed, if any, which is handy at the end of
+//! tests.
+//!
+//! With `debug-tools`, retiring the same pointer twice before it is reclaimed panics, rather than
+//! causing a double free long after the mistake. Note that this tracks every retired pointer,
+//! which slows down retirement considerably. If `CONC_DEBUG_STACKTRACE` is set, the panic
+//! message includes the backtrace of the first retirement as well.
+//!
+//! Furthermore, reclaimed boxes aren't freed right away, but poisoned and kept in a quarantine for
+//! a while (see `debug::set_quarantine_size()`). Creating a guard to quarantined memory panics,
+//! and so does writing to it, when it is released from the quarantine or checked through
+//! `debug::check_quarantine()`. The panic names the type of the object and the offset written to,
+//! along with the backtrace of the retirement, if `CONC_DEBUG_STACKTRACE` is set.
+//!
+//! When running under Valgrind or AddressSanitizer, enable feature `sanitize-hooks` (which
+//! implies `debug-tools`) as well. It marks the quarantined memory as inaccessible to the memory
+//! checker, such that accesses to reclaimed objects are reported where they happen.
+//!
+//! When a thread exits while guards it created are still alive (e.g. due to `mem::forget`), the
+//! guarded addresses are reported, along with the backtraces of the creation of the guards, if
+//! `CONC_DEBUG_STACKTRACE` is set.
 //!
 //! ### Examples
 //!
@@ -134,6 +208,10 @@
 //! instruction, this means that if you are traversing a list or something like that, this library
 //! might not be for you.
 //!
+//! Where the platform supports it (`membarrier` on Linux, `FlushProcessWriteBuffers` on Windows),
+//! reads do not issue a memory fence. Instead, the collector forces a process-wide barrier before
+//! scanning the hazards.
+//!
 //! ## Settings
 //!
 //! You can reconfigure the system on-the-go through the `settings` module.
@@ -144,30 +222,79 @@
 //! conc::settings::set_local(conc::settings::Settings::low_memory());
 //! ```
 
-#![feature(thread_local_state, const_fn)]
+#![feature(thread_local_state, const_fn, strict_provenance_lints, coerce_unsized, unsize,
+           cfg_sanitize)]
 #![deny(missing_docs)]
+#![warn(fuzzy_provenance_casts)]
 
-#[macro_use]
-extern crate lazy_static;
-extern crate rand;
 extern crate parking_lot;
+#[cfg(unix)]
+extern crate libc;
 
 mod atomic;
-mod debug;
+mod backoff;
+pub mod bench;
+pub mod budget;
+mod cell;
+pub mod collector;
+pub mod debug;
+mod defer;
+#[cfg(feature = "async-collector")]
+pub mod driver;
+pub mod engine;
+mod epoch;
+pub mod eras;
+mod exit;
+mod fence;
+pub mod foreign;
+pub mod fuzz;
 mod garbage;
+mod gc_async;
 mod global;
 mod guard;
 mod hazard;
+pub mod ibr;
 mod local;
+mod maybe_owned;
 mod mpsc;
+pub mod nested;
+mod numa;
+pub mod oom;
+mod pin;
+pub mod prelude;
+pub mod reclaim;
+#[cfg(feature = "debug-tools")]
+mod sanitize;
+pub mod scope;
 pub mod settings;
+mod shared;
+pub mod stall;
+pub mod stats;
 pub mod sync;
+pub mod testing;
+pub mod thread;
+pub mod timeline;
+#[cfg(feature = "debug-tools")]
+pub mod trace;
 
-pub use atomic::Atomic;
+pub use atomic::{load_all, Atomic, Collect, Epoch, Leak, LeakingAtomic, Policy, ReadMostly};
+pub use cell::AtomicCell;
+pub use defer::{defer, synchronize, Synchronize};
+pub use epoch::EpochGuard;
+pub use gc_async::{gc_async, GcAsync};
+pub use global::GcError;
 pub use guard::Guard;
+pub use local::LocalState;
+pub use maybe_owned::MaybeOwned;
+pub use pin::LongGuard;
+pub use scope::scope;
+pub use shared::{Align16, Align8, Shared};
 
 use std::mem;
+use std::time::{Duration, Instant};
+use backoff::Backoff;
 use garbage::Garbage;
+use timeline::Trigger;
 
 /// Attempt to collect garbage.
 ///
@@ -179,8 +306,13 @@ use garbage::Garbage;
 /// If another thread is currently doing 2., it will be skipped. This makes it different from
 /// `conc::gc()`, which will block.
 ///
-/// If 2. fails (that is, another thread is garbage collecting), `Err(())` is returned. Otherwise
-/// `Ok(())` is returned.
+/// The result tells what to do next:
+///
+/// - `Err(GcError::Busy)`: Another thread is doing 2. Retry later, or use `conc::gc()`.
+/// - `Err(GcError::Empty)`: There was no garbage to collect, so there is no need to retry.
+/// - `Err(GcError::Poisoned)`: A destructor panicked in a previous collection. Retrying won't
+///   help until `conc::clear_poison()` is called.
+/// - `Ok(())`: The garbage was collected.
 ///
 /// # Use case
 ///
@@ -197,8 +329,12 @@ use garbage::Garbage;
 ///
 /// # Panic
 ///
-/// If a destructor panics during the garbage collection, theis function will panic aswell.
-pub fn try_gc() -> Result<(), ()> {
+/// If a destructor panics during the garbage collection, theis function will panic aswell, unless
+/// another policy is set through `Settings::on_dtor_panic`. This poisons the collector, such that
+/// no garbage is collected until `conc::clear_poison()` is called.
+pub fn try_gc() -> Result<(), GcError> {
+    // Release the garbage of the read-mostly `Atomic`s, which no thread can read anymore.
+    epoch::flush();
     // Export the local garbage to ensure that the garbage of the current thread gets collected.
     local::export_garbage();
     // Run the global GC.
@@ -213,7 +349,18 @@ pub fn try_gc() -> Result<(), ()> {
 /// 2. Collect all the garbage and run destructors on the unused items.
 ///
 /// If another thread is currently doing 2., it will block until it can be done. This makes it
-/// different from `conc::try_gc()`, which will skip the step.
+/// different from `conc::try_gc()`, which will skip the step. While blocking, the thread backs off
+/// as configured by `Settings::spin_rounds_before_yield`. Threads blocking in this function are
+/// served in FIFO order, so none of them is starved by the others.
+///
+/// If the collector is poisoned, `Err(GcError::Poisoned)` is returned. Otherwise `Ok(())` is
+/// returned, also if there was no garbage to collect.
+///
+/// # Guards
+///
+/// The objects protected by guards held by the calling thread itself cannot be collected. When
+/// compiled with `debug-tools`, a warning is printed if this is the case. See also
+/// `conc::checked_gc()`.
 ///
 /// # Use case
 ///
@@ -231,12 +378,188 @@ pub fn try_gc() -> Result<(), ()> {
 ///
 /// # Panic
 ///
-/// If a destructor panics during the garbage collection, theis function will panic aswell.
-pub fn gc() {
+/// If a destructor panics during the garbage collection, theis function will panic aswell, unless
+/// another policy is set through `Settings::on_dtor_panic`. This poisons the collector, such that
+/// no garbage is collected until `conc::clear_poison()` is called.
+pub fn gc() -> Result<(), GcError> {
+    // Warn (in debug mode) if the thread holds guards, as their objects can't be collected.
+    debug::warn_if_guards_held();
+    // Release the garbage of the read-mostly `Atomic`s, which no thread can read anymore.
+    epoch::flush();
     // Export the local garbage to ensure that the garbage of the current thread gets collected.
     local::export_garbage();
-    // Try to garbage collect until it succeeds.
-    while let Err(()) = global::try_gc() {}
+    // Garbage collect, waiting for our turn.
+    match global::gc(Trigger::Explicit) {
+        Err(GcError::Empty) => Ok(()),
+        res => res,
+    }
+}
+
+/// Collect garbage until a deadline.
+///
+/// This acts like `conc::gc()`, except that it stops destroying garbage when `deadline` is
+/// reached, and returns the number of garbage objects left in the global state. The rest of the
+/// unprotected garbage is destroyed by later collections. This allows collecting garbage in idle
+/// time slices of known length, rather than in an unbounded cycle.
+///
+/// The deadline is only checked every few destructors, and the hazards are scanned regardless, so
+/// the deadline can be overrun slightly.
+///
+/// If the deadline passes while waiting for another thread to finish collecting,
+/// `Err(GcError::Busy)` is returned. If the collector is poisoned, `Err(GcError::Poisoned)` is
+/// returned.
+pub fn gc_until(deadline: Instant) -> Result<usize, GcError> {
+    // Warn (in debug mode) if the thread holds guards, as their objects can't be collected.
+    debug::warn_if_guards_held();
+    // Export the local garbage to ensure that the garbage of the current thread gets collected.
+    local::export_garbage();
+    // Try to garbage collect until it succeeds or the deadline has passed.
+    let mut backoff = Backoff::new();
+    loop {
+        match global::try_gc_until(deadline) {
+            Err(GcError::Busy) if Instant::now() < deadline => backoff.snooze(),
+            Err(GcError::Empty) => return Ok(0),
+            res => return res,
+        }
+    }
+}
+
+/// Collect garbage, unless the current thread holds guards.
+///
+/// This acts like `conc::gc()`, except that if the current thread holds any guards, it returns
+/// `Err(GcError::GuardsHeld(n))` without collecting. This is useful for ensuring that all
+/// garbage retired by the thread is in fact destroyed.
+///
+/// The guards are only counted when compiled with `debug-tools`. Otherwise, this is equivalent
+/// to `conc::gc()`.
+pub fn checked_gc() -> Result<(), GcError> {
+    match debug::guards_held() {
+        Some(0) | None => gc(),
+        Some(n) => Err(GcError::GuardsHeld(n)),
+    }
+}
+
+/// Run a final garbage collection before exiting.
+///
+/// Statics are never dropped, so the garbage still pending at exit is never destroyed. This is a
+/// problem if the destructors release resources, which aren't automatically released by the OS,
+/// and it makes leak checkers like Valgrind report the pending garbage as leaked, drowning real
+/// leaks.
+///
+/// This shall be called at the end of `main`, after the other threads are joined (their local
+/// garbage is exported when they exit). It collects garbage until no garbage is left in the
+/// current thread, as destructors might retire new garbage (e.g. when destroying nested
+/// structures).
+///
+/// This is best-effort: Garbage protected by guards, which are still alive, isn't destroyed.
+pub fn run_exit_gc() -> Result<(), GcError> {
+    loop {
+        gc()?;
+
+        // The destructors might have retired garbage, which is left to the next collection.
+        if !local::has_garbage() && !local::take_nested() {
+            break;
+        }
+    }
+
+    // Release the memory kept for debugging.
+    debug::release_quarantine();
+
+    Ok(())
+}
+
+/// Shut down the system.
+///
+/// This is meant for tearing down `conc`, when it is embedded in a dynamically loaded library,
+/// which is about to be unloaded. Statics are never dropped, so otherwise the pending garbage and
+/// the memory cached for reuse would be leaked.
+///
+/// It does the following:
+///
+/// 1. Stop accepting new garbage. Garbage added after the shutdown is leaked, except from the
+///    destructors run by the final collection.
+/// 2. Collect garbage until all of it is destroyed or `timeout` has passed, waiting for the guards
+///    protecting it to be dropped.
+/// 3. Release the memory cached for reuse, including the slots of the hazards, unless some of
+///    them are still in use (e.g. by guards alive).
+///
+/// The other threads should be joined beforehand, as garbage, which they haven't exported, can't
+/// be collected. Shutting down is irreversible.
+///
+/// If the collector is poisoned, `Err(GcError::Poisoned)` is returned. If some garbage is still
+/// protected after the timeout, `Err(GcError::Unreclaimed(n))` is returned, where `n` is the
+/// number of such objects. Otherwise `Ok(())` is returned.
+pub fn shutdown(timeout: Duration) -> Result<(), GcError> {
+    global::shut_down();
+    // The hazards cached by this thread are killed, such that the final collection destroys them,
+    // and their slots can be released.
+    local::kill_hazards();
+    let res = drain(timeout);
+    global::release_segments();
+    hazard::release();
+
+    res
+}
+
+/// Collect garbage until all of it is destroyed or `timeout` has passed.
+///
+/// See `conc::shutdown()`.
+fn drain(timeout: Duration) -> Result<(), GcError> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        run_exit_gc()?;
+
+        let pending = global::pending();
+        if pending == 0 {
+            return Ok(());
+        } else if Instant::now() >= deadline {
+            return Err(GcError::Unreclaimed(pending));
+        }
+
+        // Wait for the guards to be dropped.
+        ::std::thread::sleep(Duration::from_millis(1));
+    }
+}
+
+/// Clear the poison of the collector.
+///
+/// When a destructor panics (and the panic is propagated), the collection is left half-finished,
+/// and the collector is poisoned, refusing to collect garbage, as the state of the structures
+/// the destructors operate on might be broken. If you know that it is safe to proceed, this
+/// function allows garbage collection again, and the next collection will run the destructors of
+/// the rest of the unprotected garbage.
+pub fn clear_poison() {
+    global::clear_poison();
+}
+
+/// Seed the current thread's pseudorandom number generator.
+///
+/// Whether adding garbage triggers a garbage collection is decided at random (see
+/// `Settings::gc_probability`). By default, every thread gets its own seed, but fixing it makes the
+/// collection cadence of the thread reproducible, which is useful for tests and debugging.
+pub fn seed(seed: u64) {
+    local::seed(seed);
+}
+
+/// Prepare for `threads` threads using `hazards_per_thread` hazards each.
+///
+/// By default, memory is allocated lazily: a thread registers its first hazards, when it first
+/// creates guards, and the queue of garbage grows as it retires objects. This moves those slow
+/// paths to a point of your choice, by
+///
+/// 1. allocating hazards until there are enough for `threads * hazards_per_thread` guards, ready
+///    to be taken by any thread,
+/// 2. allocating queues with room for `garbage_capacity` objects for `threads` threads, and
+/// 3. filling the cache of hazards and the queue of the current thread.
+///
+/// This is meant to be called at the start of every worker thread of a pool. The global memory is
+/// mostly allocated by the first call, so the others merely warm up their own thread. A good choice
+/// for `garbage_capacity` is `Settings::max_garbage_before_export + 1`, as that is how much
+/// garbage a thread collects, before it exports it.
+pub fn warm_up(threads: usize, hazards_per_thread: usize, garbage_capacity: usize) {
+    hazard::preallocate(threads.saturating_mul(hazards_per_thread));
+    global::preallocate_segments(threads, garbage_capacity);
+    local::warm_up(hazards_per_thread, garbage_capacity);
 }
 
 /// Declare a pointer unreachable garbage to be deleted eventually.
@@ -265,10 +588,14 @@ pub fn gc() {
 ///
 /// If the destructor provided panics under execution, it will cause panic in the garbage
 /// collection, and the destructor won't run again.
+///
+/// The destructor may add garbage itself, e.g. retire the children of the object (see `nested`).
+/// This garbage is never destroyed by the collection running the destructor, but queued like any
+/// other garbage, and destroyed by a subsequent collection. Hence, a chain of objects is destroyed
+/// one link per collection, rather than recursively.
 pub fn add_garbage<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
-    local::add_garbage(unsafe {
-        Garbage::new(ptr as *const T as *const u8 as *mut u8, mem::transmute(dtor))
-    });
+    retire::<T>(ptr);
+    local::add_garbage(unsafe { Garbage::new_ref(ptr, dtor) });
 }
 
 /// Add a heap-allocated `Box<T>` as garbage.
@@ -283,7 +610,130 @@ pub fn add_garbage<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
 /// shall be a valid object, allocated through `Box::new(x)` or alike, and shall only be used as
 /// long as there are hazard protecting it.
 pub unsafe fn add_garbage_box<T>(ptr: *const T) {
+    retire::<T>(ptr);
     local::add_garbage(
         Garbage::new_box(ptr)
     );
 }
+
+/// Declare a pointer unreachable garbage, whose destructor is safe to run concurrently.
+///
+/// This acts like `add_garbage`, except that `dtor` may run concurrently with other destructors,
+/// on a worker thread of the collector (see `Settings::destructor_threads`). Destructors, which
+/// e.g. rely on running in the order of retirement, shouldn't be added through this.
+pub fn add_garbage_parallel<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
+    retire::<T>(ptr);
+    local::add_garbage(unsafe { Garbage::new_ref(ptr, dtor).parallel() });
+}
+
+/// Add a heap-allocated `Box<T>` as garbage, whose destructor is safe to run concurrently.
+///
+/// This acts like `add_garbage_box`, except that the box may be dropped concurrently with other
+/// destructors, on a worker thread of the collector (see `add_garbage_parallel`).
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+pub unsafe fn add_garbage_box_parallel<T: Send>(ptr: *const T) {
+    retire::<T>(ptr);
+    local::add_garbage(
+        Garbage::new_box(ptr).parallel()
+    );
+}
+
+/// Declare a pointer unreachable garbage, which holds a scarce resource.
+///
+/// This acts like `add_garbage`, except that the garbage is collected ahead of other garbage:
+/// it is exported to the global state right away, a collection is attempted right after, and it
+/// is destroyed before other garbage of the same collection. Until it is destroyed, ticks collect
+/// garbage more often.
+///
+/// This is meant for garbage, whose destruction releases something scarcer than memory (e.g. file
+/// descriptors or locks). It makes retirement more expensive, so it shouldn't be used for common
+/// garbage.
+pub fn add_garbage_urgent<T: Sync>(ptr: &'static T, dtor: fn(&'static T)) {
+    retire::<T>(ptr);
+    local::add_garbage(unsafe { Garbage::new_ref(ptr, dtor).urgent() });
+}
+
+/// Add a heap-allocated `Box<T>` as garbage, which holds a scarce resource.
+///
+/// This acts like `add_garbage_box`, except that the garbage is collected ahead of other garbage
+/// (see `add_garbage_urgent`).
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+pub unsafe fn add_garbage_box_urgent<T>(ptr: *const T) {
+    retire::<T>(ptr);
+    local::add_garbage(
+        Garbage::new_box(ptr).urgent()
+    );
+}
+
+/// Declare a pointer unreachable garbage, which is given to a custom destructor.
+///
+/// This acts like `add_garbage_box`, except that `dtor` is called with the box rather than
+/// dropping it. See `Atomic::with_dtor()`.
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+unsafe fn add_garbage_box_with<T>(ptr: *const T, dtor: fn(Box<T>)) {
+    retire::<T>(ptr);
+    local::add_garbage(
+        Garbage::new_box_with(ptr, dtor)
+    );
+}
+
+/// Destroy an unreachable box right away, if it is unprotected, or add it as garbage otherwise.
+///
+/// If `dtor` is given, the box is given to it rather than dropped.
+///
+/// See `Settings::reclaim_on_store`.
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box`.
+unsafe fn destroy_or_add_garbage_box<T>(ptr: *const T, dtor: Option<fn(Box<T>)>) {
+    retire::<T>(ptr);
+    let garbage = match dtor {
+        Some(dtor) => Garbage::new_box_with(ptr, dtor),
+        None => Garbage::new_box(ptr),
+    };
+
+    // Ensure that every hazard set before `ptr` became unreachable is visible.
+    fence::heavy();
+    if hazard::may_protect(ptr as *const u8) {
+        local::add_garbage(garbage);
+    } else {
+        // Nothing protects the box, and as it is unreachable, nothing can start protecting it.
+        drop(garbage);
+    }
+}
+
+/// Retire a box from a read-mostly `Atomic`, which might still be read under an epoch.
+///
+/// The box is held in the limbo of the epochs, before it becomes ordinary garbage (see `epoch`).
+///
+/// # Safety
+///
+/// This has the same requirements as `add_garbage_box()`.
+unsafe fn add_garbage_box_epoch<T>(ptr: *const T, dtor: Option<fn(Box<T>)>) {
+    retire::<T>(ptr);
+    epoch::retire(match dtor {
+        Some(dtor) => Garbage::new_box_with(ptr, dtor),
+        None => Garbage::new_box(ptr),
+    });
+}
+
+/// Register the retirement of `ptr` in debug mode.
+///
+/// With `debug-tools`, this detects if the same pointer is retired twice before being reclaimed,
+/// which would otherwise lead to a double free.
+fn retire<T>(ptr: *const T) {
+    // Zero-sized objects share addresses, so they can't be tracked.
+    if mem::size_of::<T>() != 0 {
+        debug::retire(ptr as *const u8);
+    }
+}
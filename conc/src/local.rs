@@ -1,13 +1,132 @@
 //! The thread-local state.
 
-use std::{mem, thread};
-use std::cell::RefCell;
-use {global, hazard, guard, debug, settings};
+use parking_lot::{self, Mutex};
+use std::{cmp, mem, ptr, thread};
+use std::cell::{Cell, RefCell};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+use {budget, collector, exit, fence, garbage, global, hazard, guard, debug, settings, stats};
+use foreign::OsThread;
 use garbage::Garbage;
 
 thread_local! {
     /// The state of this thread.
     static STATE: RefCell<State> = RefCell::new(State::default());
+    /// The state of this thread's pseudorandom number generator.
+    ///
+    /// This is kept apart from `STATE`, as it's much cheaper to access a `Cell` than a `RefCell`.
+    static RNG: Cell<u64> = Cell::new(splitmix(SEEDS.fetch_add(1, atomic::Ordering::Relaxed) as u64));
+}
+
+/// The counter from which the default seeds of the threads are derived.
+///
+/// This ensures that no two threads start with the same seed.
+static SEEDS: AtomicUsize = AtomicUsize::new(0);
+
+/// The registrations of the local states, which have added garbage.
+static THREADS: Mutex<Vec<Arc<Registration>>> = parking_lot::const_mutex(Vec::new());
+
+/// The registration of a local state, which makes its statistics visible to other threads.
+///
+/// The counters are only written by the thread owning the state.
+struct Registration {
+    /// The identifier of the thread.
+    id: thread::ThreadId,
+    /// The name of the thread.
+    name: Option<String>,
+    /// The number of garbage objects added by the thread.
+    retired: AtomicUsize,
+    /// The number of garbage objects exported to the global state by the thread.
+    exported: AtomicUsize,
+    /// The number of garbage objects cached by the thread.
+    cached: AtomicUsize,
+    /// The number of hazards created by the thread.
+    hazards_created: AtomicUsize,
+    /// The number of hazards in the cache of the thread.
+    hazards_cached: AtomicUsize,
+    /// The hazards of the thread, if it is foreign.
+    foreign: Option<Foreign>,
+}
+
+/// The hazards of a foreign thread, which are kept in its registration.
+///
+/// If the thread vanishes without tearing down its state, the cached ones are killed by
+/// `reap_foreign()`.
+struct Foreign {
+    /// The thread.
+    os_thread: OsThread,
+    /// The hazards.
+    hazards: Mutex<ForeignHazards>,
+}
+
+/// See `Foreign::hazards`.
+#[derive(Default)]
+struct ForeignHazards {
+    /// The hazards in use.
+    held: Vec<&'static AtomicPtr<u8>>,
+    /// The hazards available, which are set to "free".
+    cached: Vec<hazard::Writer>,
+}
+
+/// Increment a counter, which is only written by the current thread.
+///
+/// This avoids the cost of an atomic read-modify-write.
+fn bump(counter: &AtomicUsize, n: usize) {
+    counter.store(counter.load(atomic::Ordering::Relaxed) + n, atomic::Ordering::Relaxed);
+}
+
+/// Get the statistics of the threads, which have added garbage or created hazards.
+pub fn stats() -> Vec<stats::ThreadStats> {
+    let _critical = global::Critical::new();
+    THREADS.lock().iter().map(|x| stats::ThreadStats {
+        id: x.id,
+        name: x.name.clone(),
+        retired: x.retired.load(atomic::Ordering::Relaxed),
+        exported: x.exported.load(atomic::Ordering::Relaxed),
+        cached: x.cached.load(atomic::Ordering::Relaxed),
+        hazards_created: x.hazards_created.load(atomic::Ordering::Relaxed),
+        hazards_cached: x.hazards_cached.load(atomic::Ordering::Relaxed),
+    }).collect()
+}
+
+/// Seed this thread's pseudorandom number generator.
+pub fn seed(seed: u64) {
+    if RNG.state() != thread::LocalKeyState::Destroyed {
+        RNG.with(|rng| rng.set(splitmix(seed)));
+    }
+}
+
+/// Generate a pseudorandom number.
+///
+/// This is a xorshift64* generator. It is by no means cryptographically secure, but it is more
+/// than good enough for sampling, and it is very fast.
+pub fn random() -> usize {
+    if RNG.state() == thread::LocalKeyState::Destroyed {
+        // The generator was deinitialized, so we simply hash a fresh number instead.
+        return splitmix(SEEDS.fetch_add(1, atomic::Ordering::Relaxed) as u64) as usize;
+    }
+
+    RNG.with(|rng| {
+        let mut x = rng.get();
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        rng.set(x);
+
+        x.wrapping_mul(0x2545F4914F6CDD1D) as usize
+    })
+}
+
+/// Scramble a seed into a state of the generator.
+///
+/// This is the SplitMix64 finalizer. The result is never zero, as xorshift would get stuck there.
+fn splitmix(seed: u64) -> u64 {
+    let mut x = seed.wrapping_add(0x9E3779B97F4A7C15);
+    x = (x ^ (x >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94D049BB133111EB);
+    x ^= x >> 31;
+
+    if x == 0 { 1 } else { x }
 }
 
 /// Add new garbage to be deleted.
@@ -17,20 +136,110 @@ thread_local! {
 pub fn add_garbage(garbage: Garbage) {
     // Print message in debug mode.
     debug::exec(|| println!("Adding garbage: {:?}", garbage));
+    debug::event(|| debug::DebugEvent::GarbageAdded {
+        ptr: garbage.ptr().addr(),
+        size: garbage.size(),
+    });
     // Since this function can trigger a GC, it must not be called inside a guard constructor.
     guard::debug_assert_no_create();
+    // Garbage added after shutdown would never be destroyed, so it is leaked right away. This
+    // doesn't panic, as it might happen while unwinding, which would abort. The destructors run by
+    // the final collection may still add garbage, though.
+    if global::is_shut_down() && !global::in_critical() {
+        debug::exec(|| println!("Leaking garbage added after shutdown: {:?}", garbage));
+        mem::forget(garbage);
+        return;
+    }
 
+    let urgent = garbage.is_urgent();
     if STATE.state() == thread::LocalKeyState::Destroyed {
         // The state was deinitialized, so we must rely on the global state for queueing garbage.
         global::export_garbage(vec![garbage]);
     } else {
         // Add the garbage.
-        if STATE.with(|s| s.borrow_mut().add_garbage(garbage)) {
+        let (exported, scan) = STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            if s.add_garbage(garbage) {
+                (true, None)
+            } else if global::in_critical() || !collector::is_inline() {
+                // Scans are left to the dedicated collector, like collections.
+                (false, None)
+            } else {
+                (false, s.take_scan())
+            }
+        });
+
+        if exported {
             // The local state exported garbage to the global state, hence we must tick in order to
             // ensure that the garbage is periodically collected.
             global::tick();
         }
+
+        // The scan runs outside the borrow, as the destructors may add garbage.
+        if let Some(garbage) = scan {
+            scan_garbage(garbage);
+        }
+    }
+
+    // Urgent garbage doesn't wait for the probabilistic collections.
+    if urgent {
+        global::collect_urgent();
     }
+
+    // Collect the garbage right away, if it exceeds the memory budget.
+    budget::enforce();
+}
+
+/// Destroy the unprotected garbage taken out of the local state for a scan.
+///
+/// The hazards are read without taking the lock of the collector, so this doesn't wait for
+/// ongoing garbage collections. If a hazard is blocked, nothing is destroyed. The garbage left is
+/// put back into the local state, also if a destructor panics.
+fn scan_garbage(garbage: Vec<Garbage>) {
+    /// Garbage, which is put back into the local state on drop.
+    struct Scanned {
+        /// The garbage, which is protected.
+        garbage: Vec<Garbage>,
+        /// The garbage, which is unprotected, and not destroyed yet.
+        doomed: Vec<Garbage>,
+    }
+
+    impl Drop for Scanned {
+        fn drop(&mut self) {
+            let mut garbage = mem::replace(&mut self.garbage, Vec::new());
+            garbage.append(&mut self.doomed);
+            if STATE.state() != thread::LocalKeyState::Destroyed {
+                STATE.with(|s| s.borrow_mut().put_back(garbage));
+            } else if !garbage.is_empty() {
+                global::export_garbage(garbage);
+            }
+        }
+    }
+
+    let mut scanned = Scanned {
+        garbage: garbage,
+        doomed: Vec::new(),
+    };
+
+    // The garbage is unreachable, so after the fence, the hazards set before are visible, and no
+    // hazard can start protecting it.
+    fence::heavy();
+    let active = match hazard::protected() {
+        Some(active) => active,
+        None => return,
+    };
+
+    let mut i = 0;
+    while i < scanned.garbage.len() {
+        if active.contains(&scanned.garbage[i].ptr()) {
+            i += 1;
+        } else {
+            let garbage = scanned.garbage.swap_remove(i);
+            scanned.doomed.push(garbage);
+        }
+    }
+
+    garbage::destroy_batch(&mut scanned.doomed, settings::get().on_dtor_panic, None);
 }
 
 /// Get a blocked hazard.
@@ -81,6 +290,41 @@ pub fn free_hazard(hazard: hazard::Writer) {
     }
 }
 
+/// Set the cached hazards of this thread to "free".
+///
+/// The hazards of dropped guards are cached without being freed, so they keep protecting their
+/// pointer until the cache is full. This frees them, such that the garbage they protect can be
+/// destroyed.
+pub fn free_hazards() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| if let Ok(mut s) = s.try_borrow_mut() {
+            s.free_hazards();
+        });
+    }
+}
+
+/// Kill the cached hazards of this thread.
+///
+/// This is used by `conc::shutdown()`, such that the final collection destroys the hazards, and
+/// their slots can be released.
+pub fn kill_hazards() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| if let Ok(mut s) = s.try_borrow_mut() {
+            s.kill_hazards();
+        });
+    }
+}
+
+/// Prepare this thread for using `hazards` hazards and caching `garbage_capacity` garbage objects.
+///
+/// This registers the thread, fills its cache of hazards and reserves space for its garbage, such
+/// that the first operations don't have to allocate.
+pub fn warm_up(hazards: usize, garbage_capacity: usize) {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| s.borrow_mut().warm_up(hazards, garbage_capacity));
+    }
+}
+
 /// Export the garbage of this thread to the global state.
 ///
 /// This is useful for propagating accumulated garbage such that it can be destroyed by the next
@@ -92,14 +336,248 @@ pub fn export_garbage() {
     // We can only export when the TLS variable isn't destroyed. Otherwise, there would be nothing
     // to export!
     if STATE.state() != thread::LocalKeyState::Destroyed {
-        STATE.with(|s| s.borrow_mut().export_garbage());
         // We tick after the state is no longer reserved, as the tick could potentially call
-        // destructor that access the TLS variable.
-        global::tick();
+        // destructor that access the TLS variable. If there was nothing to export, we don't touch
+        // the global state at all.
+        if STATE.with(|s| s.borrow_mut().export_garbage()) {
+            global::tick();
+        }
+    }
+}
+
+/// Export the garbage of this thread, unless the local state is in use.
+///
+/// This acts like `export_garbage`, except that it does nothing if the local state is currently
+/// borrowed (e.g. when called from an allocator, which is called while adding garbage), and that it
+/// never collects garbage.
+pub fn try_export_garbage() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| if let Ok(mut s) = s.try_borrow_mut() {
+            s.export_garbage();
+        });
+    }
+}
+
+/// Export the garbage, which the destructors run by a collection of this thread retired.
+///
+/// This acts like `try_export_garbage`, but notes the export for `take_nested()`.
+pub fn export_nested_garbage() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| if let Ok(mut s) = s.try_borrow_mut() {
+            if s.export_garbage() {
+                s.nested = true;
+            }
+        });
+    }
+}
+
+/// Check if garbage was exported through `export_nested_garbage()` since the last call.
+pub fn take_nested() -> bool {
+    STATE.state() != thread::LocalKeyState::Destroyed
+        && STATE.with(|s| mem::replace(&mut s.borrow_mut().nested, false))
+}
+
+/// Export the garbage and free the cached hazards of this thread, if possible.
+///
+/// Like `try_export_garbage`, this does nothing if the local state is currently borrowed, and it
+/// never collects garbage, so it can be called from a panic hook.
+pub fn flush() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| if let Ok(mut s) = s.try_borrow_mut() {
+            s.export_garbage();
+            s.free_hazards();
+        });
+    }
+}
+
+/// Attach the current thread as the foreign thread `os_thread`.
+///
+/// See `foreign::attach()`.
+pub fn attach_foreign(os_thread: OsThread) {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| {
+            let mut s = s.borrow_mut();
+            if s.foreign.is_none() {
+                // Release the local caches, and register anew with the hazards kept globally.
+                s.exit();
+                s.foreign = Some(os_thread);
+                s.register();
+            }
+        });
+    }
+}
+
+/// Tear down the states of the foreign threads, which have vanished.
+///
+/// See `foreign::reap()`.
+pub fn reap_foreign() -> usize {
+    // Checking the threads involves the file system, so it's done without holding the lock.
+    let foreign: Vec<Arc<Registration>> = {
+        let _critical = global::Critical::new();
+        THREADS.lock().iter().filter(|x| x.foreign.is_some()).cloned().collect()
+    };
+    let vanished: Vec<_> = foreign.into_iter()
+        .filter(|x| !x.foreign.as_ref().unwrap().os_thread.is_alive())
+        .collect();
+    if vanished.is_empty() {
+        return 0;
+    }
+
+    let _critical = global::Critical::new();
+    let mut reaped = Vec::new();
+    THREADS.lock().retain(|x| if vanished.iter().any(|y| Arc::ptr_eq(x, y)) {
+        reaped.push(x.clone());
+        false
+    } else {
+        true
+    });
+
+    for registration in &reaped {
+        let mut hazards = registration.foreign.as_ref().unwrap().hazards.lock();
+        // The guards holding the other hazards might have been sent to other threads, so only the
+        // cached hazards are killed. The held ones are released by their guards as usual.
+        hazards.held.clear();
+        for hazard in hazards.cached.drain(..) {
+            hazard.kill();
+        }
+    }
+
+    reaped.len()
+}
+
+/// Does the current thread have garbage, which isn't exported yet?
+pub fn has_garbage() -> bool {
+    STATE.state() != thread::LocalKeyState::Destroyed
+        && STATE.with(|s| !s.borrow().garbage.is_empty())
+}
+
+/// The maximal number of local states parked (see `LocalState::park()`).
+const MAX_PARKED: usize = 64;
+
+/// The local states parked for reuse by other threads.
+static PARKED: Mutex<Vec<LocalState>> = parking_lot::const_mutex(Vec::new());
+
+/// The local state of a thread, detached from the thread.
+///
+/// Setting up the local state (registering the thread, creating hazards, allocating the queue of
+/// garbage) and tearing it down on exit is a fixed cost of every thread using `conc`, which adds up
+/// when lots of short-lived threads are spawned. A thread about to exit can instead detach its
+/// still warm state, and hand it to another thread, which adopts it, or park it for reuse by the
+/// threads spawned later.
+///
+/// The hazards are freed on detachment, so a detached state protects nothing. Its garbage isn't
+/// destroyed before it is adopted, though, so a detached state shouldn't be kept for long. When
+/// dropped, the garbage is exported and the hazards are killed, like when a thread exits.
+///
+/// # Example
+///
+/// ```rust
+/// use std::sync::atomic::Ordering;
+/// use std::thread;
+///
+/// let state = thread::spawn(|| {
+///     let atomic = conc::Atomic::new(Some(Box::new(1)));
+///     assert_eq!(*atomic.load(Ordering::Acquire).unwrap(), 1);
+///     conc::LocalState::detach()
+/// }).join().unwrap();
+///
+/// // The hazard of the guard is reused by this thread.
+/// assert!(state.cached_hazards() > 0);
+/// state.adopt();
+/// ```
+#[derive(Default)]
+pub struct LocalState {
+    /// The cached garbage.
+    garbage: Vec<Garbage>,
+    /// The cached hazards, which are all free.
+    hazards: Vec<hazard::Writer>,
+    /// The number of hazards created in the previous batch.
+    ///
+    /// See `State::hazard_batch_size`.
+    hazard_batch_size: usize,
+}
+
+impl LocalState {
+    /// Detach the local state from the current thread.
+    ///
+    /// The cached garbage and hazards are moved out of the thread, leaving it with an empty state.
+    /// If the thread is foreign, nothing is cached, so the state returned is empty.
+    pub fn detach() -> LocalState {
+        if STATE.state() == thread::LocalKeyState::Destroyed {
+            LocalState::default()
+        } else {
+            STATE.with(|s| s.borrow_mut().detach())
+        }
+    }
+
+    /// Adopt the state by the current thread.
+    ///
+    /// The garbage and hazards are added to the ones of the current thread. If the garbage exceeds
+    /// the limit (see `Settings::max_garbage_before_export`), it is exported.
+    pub fn adopt(self) {
+        if STATE.state() == thread::LocalKeyState::Destroyed {
+            // The state is dropped, which exports the garbage.
+            return;
+        }
+
+        if STATE.with(|s| s.borrow_mut().adopt(self)) {
+            global::tick();
+        }
+    }
+
+    /// Park the state for reuse by another thread.
+    ///
+    /// The garbage is exported, such that it isn't held back by the pool, and the hazards are
+    /// kept for the thread taking the state through `unpark()`. If too many states are parked
+    /// already, the state is dropped.
+    pub fn park(mut self) {
+        if !self.garbage.is_empty() {
+            global::export_garbage(mem::replace(&mut self.garbage, global::segment()));
+        }
+
+        let _critical = global::Critical::new();
+        let mut parked = PARKED.lock();
+        if parked.len() < MAX_PARKED {
+            parked.push(self);
+        }
+    }
+
+    /// Take a parked state, if any.
+    ///
+    /// The state is usually adopted right away, e.g. at the start of a thread.
+    pub fn unpark() -> Option<LocalState> {
+        PARKED.lock().pop()
+    }
+
+    /// Get the number of garbage objects in the state.
+    pub fn cached_garbage(&self) -> usize {
+        self.garbage.len()
+    }
+
+    /// Get the number of hazards in the state.
+    pub fn cached_hazards(&self) -> usize {
+        self.hazards.len()
+    }
+}
+
+impl Drop for LocalState {
+    fn drop(&mut self) {
+        for hazard in self.hazards.drain(..) {
+            hazard.kill();
+        }
+
+        if !self.garbage.is_empty() {
+            global::export_garbage(mem::replace(&mut self.garbage, Vec::new()));
+        }
     }
 }
 
 /// A thread-local state.
+///
+/// The state is lazy, in the sense that the global state is not touched until it is necessary.
+/// Threads, which never add garbage, never export anything and aren't registered (see
+/// `stats::threads()`), and threads, which only hold few guards at a time, only create few
+/// hazards.
 #[derive(Default)]
 struct State {
     /// The cached garbage waiting to be exported to the global state.
@@ -119,6 +597,35 @@ struct State {
     ///
     /// It is useful for knowing when to free the hazards to allow garbage collection.
     available_hazards_free_before: usize,
+    /// The number of hazards created in the previous batch.
+    ///
+    /// Batches start out with a single hazard and then double in size (up to the limit given in
+    /// the settings), such that threads needing few hazards register few hazards.
+    hazard_batch_size: usize,
+    /// The registration of this state.
+    ///
+    /// The state is registered, when it first adds garbage. Taking guards only creates hazards,
+    /// which the collectors need to see anyway.
+    registration: Option<Arc<Registration>>,
+    /// The number of hazards created by this state.
+    ///
+    /// This is kept here, as the state might not be registered yet.
+    hazards_created: usize,
+    /// Is the state torn down on exit (see `on_exit()`)?
+    armed: bool,
+    /// The thread, if it is attached as a foreign thread.
+    ///
+    /// The state of a foreign thread caches nothing: Garbage is exported right away, and hazards
+    /// are kept in the registration.
+    foreign: Option<OsThread>,
+    /// Was garbage retired by the destructors of a collection exported since the last
+    /// `take_nested()`?
+    nested: bool,
+    /// The amount of garbage, which was left by the last scan.
+    ///
+    /// The next scan happens, when the garbage grows by `scan_threshold` beyond this (see
+    /// `take_scan()`).
+    scanned: usize,
 }
 
 impl State {
@@ -129,38 +636,217 @@ impl State {
 
     /// See `get_hazard()`.
     fn get_hazard(&mut self) -> hazard::Writer {
+        if self.foreign.is_some() {
+            return self.get_foreign_hazard();
+        }
+
         // Check if there is hazards in the cache.
         if let Some(hazard) = self.available_hazards.pop() {
             // There is; we don't need to create a new hazard.
 
+            // The popped hazard might have been below the "free" mark, so we must ensure that
+            // the mark doesn't exceed the cache.
+            if self.available_hazards_free_before > self.available_hazards.len() {
+                self.available_hazards_free_before = self.available_hazards.len();
+            }
+
             // Since the hazard popped from the cache is not blocked, we must block the hazard to
             // satisfy the requirements of this function.
             hazard.block();
+            self.update_hazards_cached();
             hazard
         } else {
-            // There is not; we must create a new hazard.
-            global::create_hazard()
+            // There is not; we must create new hazards. To avoid registering hazards one-by-one,
+            // we create a batch of them and cache the surplus.
+            self.hazard_batch_size = (self.hazard_batch_size * 2)
+                .min(settings::get().hazard_batch_size)
+                .max(1);
+            let mut hazards = global::create_hazards(self.hazard_batch_size);
+            let hazard = hazards.pop().unwrap();
+
+            // The new hazards are blocked, so we set the surplus to "free" before caching it.
+            for i in &hazards {
+                i.free();
+            }
+            self.available_hazards = hazards;
+            self.available_hazards_free_before = self.available_hazards.len();
+            let created = self.hazard_batch_size;
+            self.created_hazards(created);
+            self.update_hazards_cached();
+
+            hazard
         }
     }
 
+    /// Get a hazard, which is kept in the registration.
+    fn get_foreign_hazard(&mut self) -> hazard::Writer {
+        let registration = self.register();
+        let foreign = registration.foreign.as_ref().unwrap();
+
+        let cached = {
+            let _critical = global::Critical::new();
+            foreign.hazards.lock().cached.pop()
+        };
+        let hazard = match cached {
+            Some(hazard) => {
+                hazard.block();
+                hazard
+            },
+            None => {
+                bump(&registration.hazards_created, 1);
+                global::create_hazard()
+            },
+        };
+
+        let _critical = global::Critical::new();
+        let mut hazards = foreign.hazards.lock();
+        hazards.held.push(hazard.as_raw());
+        registration.hazards_cached.store(hazards.cached.len(), atomic::Ordering::Relaxed);
+
+        hazard
+    }
+
+    /// Free a hazard, which was got through `get_foreign_hazard()`.
+    fn free_foreign_hazard(&mut self, hazard: hazard::Writer) {
+        if let Some(ref registration) = self.registration {
+            let foreign = registration.foreign.as_ref().unwrap();
+            let _critical = global::Critical::new();
+            let mut hazards = foreign.hazards.lock();
+            if let Some(n) = hazards.held.iter().position(|&x| ptr::eq(x, hazard.as_raw())) {
+                hazards.held.swap_remove(n);
+                hazard.free();
+                hazards.cached.push(hazard);
+                registration.hazards_cached.store(hazards.cached.len(), atomic::Ordering::Relaxed);
+                return;
+            }
+        }
+
+        // The hazard was got before the state was torn down.
+        hazard.kill();
+    }
+
     /// See `free_hazard()`.
     fn free_hazard(&mut self, hazard: hazard::Writer) {
+        if self.foreign.is_some() {
+            return self.free_foreign_hazard(hazard);
+        }
+
         // FIXME: This can lead to some subtle bugs, since the dtor is unpredictable as there is no
         //        way of predicting when the hazard is cleared.
 
         // Push the given hazard to the cache.
         self.available_hazards.push(hazard);
+        self.update_hazards_cached();
 
         // Check if we exceeded the limit.
         if self.non_free_hazards() > settings::get().max_non_free_hazards {
             // We did; we must now set the non-free hazards to "free".
-            for i in &self.available_hazards[self.available_hazards_free_before..] {
+            self.free_hazards();
+        }
+    }
+
+    /// See `free_hazards()`.
+    fn free_hazards(&mut self) {
+        for i in &self.available_hazards[self.available_hazards_free_before..] {
+            i.free();
+        }
+
+        // Update the counter such that we mark the new hazards set to "free".
+        self.available_hazards_free_before = self.available_hazards.len();
+    }
+
+    /// See `kill_hazards()`.
+    fn kill_hazards(&mut self) {
+        // Clear every hazard to "dead" state.
+        for hazard in self.available_hazards.drain(..) {
+            hazard.kill();
+        }
+        self.available_hazards_free_before = 0;
+        self.update_hazards_cached();
+    }
+
+    /// See `LocalState::detach()`.
+    fn detach(&mut self) -> LocalState {
+        self.free_hazards();
+        self.available_hazards_free_before = 0;
+        self.scanned = 0;
+
+        let state = LocalState {
+            garbage: mem::replace(&mut self.garbage, Vec::new()),
+            hazards: mem::replace(&mut self.available_hazards, Vec::new()),
+            hazard_batch_size: self.hazard_batch_size,
+        };
+        self.update_cached();
+        self.update_hazards_cached();
+
+        state
+    }
+
+    /// See `LocalState::adopt()`.
+    ///
+    /// If garbage was exported, `true` is returned.
+    fn adopt(&mut self, mut state: LocalState) -> bool {
+        self.arm();
+        if self.foreign.is_some() {
+            // Nothing is cached, so the state is dropped, which exports the garbage.
+            let exported = !state.garbage.is_empty();
+            drop(state);
+            return exported;
+        }
+
+        // The hazards are free, so they are put below the "free" mark, like in `warm_up()`.
+        let adopted = state.hazards.len();
+        let mut hazards = mem::replace(&mut state.hazards, Vec::new());
+        hazards.extend(self.available_hazards.drain(..));
+        self.available_hazards = hazards;
+        self.available_hazards_free_before += adopted;
+        self.hazard_batch_size = cmp::max(self.hazard_batch_size, state.hazard_batch_size);
+        self.update_hazards_cached();
+
+        // Keep the larger queue, as it is the one most likely to have enough capacity.
+        if self.garbage.capacity() < state.garbage.capacity() {
+            mem::swap(&mut self.garbage, &mut state.garbage);
+        }
+        self.garbage.append(&mut state.garbage);
+        if !self.garbage.is_empty() {
+            self.register();
+        }
+
+        if self.garbage.len() > settings::get().max_garbage_before_export {
+            self.export_garbage()
+        } else {
+            self.update_cached();
+            false
+        }
+    }
+
+    /// See `warm_up()`.
+    fn warm_up(&mut self, hazards: usize, garbage_capacity: usize) {
+        self.register();
+        if self.foreign.is_some() {
+            // Nothing is cached.
+            return;
+        }
+
+        let missing = hazards.saturating_sub(self.available_hazards.len());
+        if missing > 0 {
+            // The new hazards are blocked, so we set them to "free" before caching them. They are
+            // put below the "free" mark, so the other cached hazards remain above it.
+            let mut new = global::create_hazards(missing);
+            for i in &new {
                 i.free();
             }
+            let free_before = self.available_hazards_free_before;
+            new.extend(self.available_hazards.drain(..));
+            self.available_hazards = new;
+            self.available_hazards_free_before = free_before + missing;
 
-            // Update the counter such that we mark the new hazards set to "free".
-            self.available_hazards_free_before = self.available_hazards.len();
+            self.created_hazards(missing);
+            self.update_hazards_cached();
         }
+
+        let len = self.garbage.len();
+        self.garbage.reserve(garbage_capacity.saturating_sub(len));
     }
 
     /// Queues garbage to destroy.
@@ -173,33 +859,141 @@ impl State {
     /// When this happens (i.e. the global state gets the garbage), it returns `true`. Otherwise,
     /// it returns `false`.
     fn add_garbage(&mut self, garbage: Garbage) -> bool {
+        bump(&self.register().retired, 1);
+
         // Push the garbage to the cache of garbage.
+        let urgent = garbage.is_urgent();
         self.garbage.push(garbage);
 
         // Export the garbage if it exceeds the limit.
         // TODO: use memory instead of items as a metric.
-        if self.garbage.len() > settings::get().max_garbage_before_export {
+        // Foreign threads export right away, as their garbage would be lost with the thread, and so
+        // does urgent garbage, which is collected right away.
+        let max = settings::get().max_garbage_before_export;
+        if self.foreign.is_some() || urgent || self.garbage.len() > max {
             self.export_garbage();
             true
-        } else { false }
+        } else {
+            self.update_cached();
+            false
+        }
+    }
+
+    /// Take the garbage out for a scan, if it grew enough since the last scan.
+    ///
+    /// The garbage left by the scan must be put back through `put_back()`.
+    fn take_scan(&mut self) -> Option<Vec<Garbage>> {
+        let threshold = settings::get().scan_threshold;
+        if threshold == 0 || self.garbage.len() < self.scanned.saturating_add(threshold) {
+            return None;
+        }
+
+        Some(mem::replace(&mut self.garbage, Vec::new()))
+    }
+
+    /// Put back the garbage left by a scan.
+    ///
+    /// The garbage added during the scan is kept after it.
+    fn put_back(&mut self, mut garbage: Vec<Garbage>) {
+        self.scanned = garbage.len();
+        garbage.append(&mut self.garbage);
+        self.garbage = garbage;
+        self.update_cached();
+    }
+
+    /// Update the number of cached garbage objects in the registration.
+    fn update_cached(&self) {
+        if let Some(ref registration) = self.registration {
+            registration.cached.store(self.garbage.len(), atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Update the number of cached hazards in the registration.
+    fn update_hazards_cached(&self) {
+        if let Some(ref registration) = self.registration {
+            let len = self.available_hazards.len();
+            registration.hazards_cached.store(len, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Count `n` hazards as created by this state.
+    fn created_hazards(&mut self, n: usize) {
+        self.hazards_created += n;
+        if let Some(ref registration) = self.registration {
+            registration.hazards_created.store(self.hazards_created, atomic::Ordering::Relaxed);
+        }
+
+        // The hazards must be killed, when the thread exits.
+        self.arm();
+    }
+
+    /// Make sure the state is torn down, when the thread exits.
+    fn arm(&mut self) {
+        if !self.armed {
+            self.armed = true;
+            // The thread-local destructor of the state might run late or not at all, so the
+            // thread tears the state down on exit as well.
+            exit::at_exit(on_exit);
+        }
+    }
+
+    /// Register this state, unless already done, and return the registration.
+    fn register(&mut self) -> &Registration {
+        if self.registration.is_none() {
+            let thread = thread::current();
+            let registration = Arc::new(Registration {
+                id: thread.id(),
+                name: thread.name().map(ToOwned::to_owned),
+                retired: AtomicUsize::new(0),
+                exported: AtomicUsize::new(0),
+                cached: AtomicUsize::new(self.garbage.len()),
+                hazards_created: AtomicUsize::new(self.hazards_created),
+                hazards_cached: AtomicUsize::new(self.available_hazards.len()),
+                foreign: self.foreign.map(|os_thread| Foreign {
+                    os_thread: os_thread,
+                    hazards: Mutex::new(ForeignHazards::default()),
+                }),
+            });
+
+            let _critical = global::Critical::new();
+            THREADS.lock().push(registration.clone());
+            self.registration = Some(registration);
+            self.arm();
+        }
+
+        self.registration.as_ref().unwrap()
     }
 
     /// See `export_garbage()` for more information.
-    fn export_garbage(&mut self) {
+    ///
+    /// If there was no garbage to export, the global state is left untouched, and `false` is
+    /// returned. Otherwise, `true` is returned.
+    fn export_garbage(&mut self) -> bool {
+        if self.garbage.is_empty() {
+            return false;
+        }
+
         // Print message in debug mode.
         debug::exec(|| println!("Exporting garbage."));
 
-        // Clear the vector and export the garbage.
-        global::export_garbage(mem::replace(&mut self.garbage, Vec::new()));
+        if let Some(ref registration) = self.registration {
+            bump(&registration.exported, self.garbage.len());
+        }
+
+        // Replace the vector by an empty segment and export the garbage.
+        global::export_garbage(mem::replace(&mut self.garbage, global::segment()));
+        self.scanned = 0;
+        self.update_cached();
+
+        true
     }
-}
 
-impl Drop for State {
-    fn drop(&mut self) {
-        // Clear every hazard to "dead" state.
-        for hazard in self.available_hazards.drain(..) {
-            hazard.kill();
-        }
+    /// Tear the state down, as the thread is exiting.
+    ///
+    /// The hazards are killed, the garbage is exported, and the registration is removed. If the
+    /// state is used afterwards, it is set up anew.
+    fn exit(&mut self) {
+        self.kill_hazards();
 
         // The thread is exiting, thus we must export the garbage to the global state to avoid
         // memory leaks. It is very important that this does indeed not tick, as causing garbage
@@ -207,6 +1001,40 @@ impl Drop for State {
         // here, after it has deinitialized.
         // TODO: Figure out a way we can tick anyway.
         self.export_garbage();
+        // The exit hook is consumed, so it is set anew, if the state is used afterwards.
+        self.armed = false;
+
+        if let Some(registration) = self.registration.take() {
+            let _critical = global::Critical::new();
+            THREADS.lock().retain(|x| !Arc::ptr_eq(x, &registration));
+
+            // The hazards held by guards are killed, as the guards free them.
+            if let Some(ref foreign) = registration.foreign {
+                for hazard in foreign.hazards.lock().cached.drain(..) {
+                    hazard.kill();
+                }
+            }
+        }
+    }
+}
+
+impl Drop for State {
+    fn drop(&mut self) {
+        self.exit();
+    }
+}
+
+/// Tear down the local state, unless its destructor did so already.
+///
+/// This is run through `exit::at_exit()`, when the thread exits.
+fn on_exit() {
+    if STATE.state() != thread::LocalKeyState::Destroyed {
+        STATE.with(|s| {
+            // The thread is exiting, so nothing else can hold the state.
+            if let Ok(mut state) = s.try_borrow_mut() {
+                state.exit();
+            }
+        });
     }
 }
 
@@ -215,7 +1043,116 @@ mod tests {
     use super::*;
     use garbage::Garbage;
     use hazard;
-    use std::thread;
+    use std::{ptr, thread};
+
+    #[test]
+    fn seeded_random() {
+        seed(42);
+        let a: Vec<_> = (0..16).map(|_| random()).collect();
+        seed(42);
+        let b: Vec<_> = (0..16).map(|_| random()).collect();
+        assert_eq!(a, b);
+
+        seed(43);
+        let c: Vec<_> = (0..16).map(|_| random()).collect();
+        assert!(a != c);
+    }
+
+    #[test]
+    fn distinct_thread_seeds() {
+        let a = thread::spawn(|| random()).join().unwrap();
+        let b = thread::spawn(|| random()).join().unwrap();
+        assert!(a != b);
+    }
+
+    #[test]
+    fn random_distribution() {
+        // Roughly half of the numbers should be below the middle.
+        let n = (0..10000).filter(|_| random() < !0 / 2).count();
+        assert!(n > 4000 && n < 6000);
+    }
+
+    #[test]
+    fn splitmix_nonzero() {
+        for i in 0..1000 {
+            assert!(splitmix(i) != 0);
+        }
+    }
+
+    #[test]
+    fn thread_stats() {
+        let id = thread::Builder::new().name("counted".to_owned()).spawn(|| {
+            let id = thread::current().id();
+            add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {}));
+            add_garbage(Garbage::new(ptr::without_provenance(0x2), |_| {}));
+
+            let me = stats_of(id).unwrap();
+            assert_eq!(me.name.as_ref().unwrap(), "counted");
+            assert_eq!(me.retired, 2);
+            assert_eq!(me.exported, 0);
+            assert_eq!(me.cached, 2);
+
+            export_garbage();
+            let me = stats_of(id).unwrap();
+            assert_eq!(me.exported, 2);
+            assert_eq!(me.cached, 0);
+
+            let h = get_hazard();
+            let me = stats_of(id).unwrap();
+            assert!(me.hazards_created >= 1);
+            assert_eq!(me.hazards_cached, me.hazards_created - 1);
+            h.free();
+            free_hazard(h);
+            let me = stats_of(id).unwrap();
+            assert_eq!(me.hazards_cached, me.hazards_created);
+
+            id
+        }).unwrap().join().unwrap();
+
+        // The registration is gone with the thread.
+        assert!(stats_of(id).is_none());
+    }
+
+    #[test]
+    fn readers_not_registered() {
+        thread::spawn(|| {
+            let id = thread::current().id();
+            let h = get_hazard();
+            h.free();
+            free_hazard(h);
+            assert!(stats_of(id).is_none());
+
+            // The hazards created so far are counted, once the thread registers.
+            add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {}));
+            let me = stats_of(id).unwrap();
+            assert!(me.hazards_created >= 1);
+            assert_eq!(me.hazards_cached, me.hazards_created);
+            assert_eq!(me.cached, 1);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn exit_notification() {
+        thread::spawn(|| {
+            let id = thread::current().id();
+            add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {}));
+            assert!(has_garbage());
+
+            // The notification tears the state down, if its destructor hasn't run yet.
+            on_exit();
+            assert!(!has_garbage());
+            assert!(stats_of(id).is_none());
+
+            // The state is set up anew, when it is used afterwards.
+            add_garbage(Garbage::new(ptr::without_provenance(0x2), |_| {}));
+            assert_eq!(stats_of(id).unwrap().retired, 1);
+        }).join().unwrap();
+    }
+
+    /// Get the statistics of a thread.
+    fn stats_of(id: thread::ThreadId) -> Option<stats::ThreadStats> {
+        stats().into_iter().find(|x| x.id == id)
+    }
 
     #[test]
     fn dtor_runs() {
@@ -230,11 +1167,11 @@ mod tests {
             let h = get_hazard();
             h.protect(&*b);
             add_garbage(Garbage::new(&*b, dtor));
-            ::gc();
+            ::gc().unwrap();
             assert_eq!(*b, 0);
-            ::gc();
+            ::gc().unwrap();
             h.free();
-            ::gc();
+            ::gc().unwrap();
             assert_eq!(*b, 1);
         }
     }
@@ -249,29 +1186,87 @@ mod tests {
 
         for _ in 0..1000 {
             let b = Box::new(0);
-            let bptr = &*b as *const _ as usize;
+            let bptr = (&*b as *const u8).addr();
             let h = thread::spawn(move || {
                 let h = get_hazard();
-                h.protect(bptr as *const u8);
+                h.protect(ptr::without_provenance(bptr));
                 h
             }).join().unwrap();
             add_garbage(Garbage::new(&*b, dtor));
-            ::gc();
+            ::gc().unwrap();
             assert_eq!(*b, 0);
-            ::gc();
+            ::gc().unwrap();
             h.free();
-            ::gc();
+            ::gc().unwrap();
             assert_eq!(*b, 1);
         }
     }
 
+    #[test]
+    fn run_exit_gc() {
+        use std::sync::atomic::{self, AtomicUsize};
+
+        static OUTER: AtomicUsize = AtomicUsize::new(0);
+        static INNER: AtomicUsize = AtomicUsize::new(0);
+
+        fn inner(_: *const u8) {
+            INNER.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        fn outer(_: *const u8) {
+            OUTER.fetch_add(1, atomic::Ordering::Relaxed);
+            // Retire more garbage from the destructor, like nested structures do.
+            add_garbage(Garbage::new(ptr::without_provenance(0x2), inner));
+        }
+
+        thread::spawn(|| {
+            add_garbage(Garbage::new(ptr::without_provenance(0x1), outer));
+            ::run_exit_gc().unwrap();
+
+            assert!(!has_garbage());
+            assert_eq!(OUTER.load(atomic::Ordering::Relaxed), 1);
+            assert_eq!(INNER.load(atomic::Ordering::Relaxed), 1);
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn drain_waits_for_guards() {
+        use std::sync::atomic;
+        use std::time::Duration;
+        use testing::{Counter, Tracked};
+        use Atomic;
+
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Box::new(Tracked::with_counter(0, &COUNTER))));
+        let guard = a.load(atomic::Ordering::Acquire).unwrap();
+        a.store(None, atomic::Ordering::Release);
+
+        // The garbage stays protected until the timeout.
+        match ::drain(Duration::from_millis(10)) {
+            Err(::GcError::Unreclaimed(n)) => assert!(n > 0),
+            res => panic!("Unexpected result: {:?}", res),
+        }
+        assert_eq!(COUNTER.destroyed(), 0);
+
+        // Drop the guard while draining.
+        let j = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(10));
+            drop(guard);
+        });
+        // Other tests might keep their garbage protected, so we don't check the result.
+        let _ = ::drain(Duration::from_secs(10));
+        j.join().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
     #[test]
     fn clear_hazards() {
         let mut s = State::default();
         let mut v = Vec::new();
         for _ in 0..100 {
             let (w, r) = hazard::create();
-            w.protect(0x1 as *const u8);
+            w.protect(ptr::without_provenance(0x1));
             v.push(r);
             s.free_hazard(w);
         }
@@ -283,6 +1278,190 @@ mod tests {
         mem::forget(v);
     }
 
+    #[test]
+    fn batch_hazards() {
+        let mut s = State::default();
+        let batch = settings::get().hazard_batch_size;
+
+        // The first hazard is registered alone.
+        let mut v = vec![s.get_hazard()];
+        assert!(v[0].is_blocked());
+        assert!(s.available_hazards.is_empty());
+
+        // Then the batches double in size until they reach the limit.
+        let mut size = 1;
+        while size < batch {
+            size *= 2;
+            v.push(s.get_hazard());
+            assert_eq!(s.available_hazards.len(), size - 1);
+            assert_eq!(s.non_free_hazards(), 0);
+
+            // Exhaust the cache.
+            for _ in 1..size {
+                v.push(s.get_hazard());
+            }
+        }
+
+        v.push(s.get_hazard());
+        assert_eq!(s.available_hazards.len(), batch - 1);
+
+        for h in v {
+            h.free();
+            s.free_hazard(h);
+        }
+    }
+
+    #[test]
+    fn warm_up_state() {
+        let mut s = State::default();
+        let h = s.get_hazard();
+        h.protect(ptr::without_provenance(0x1));
+        s.free_hazard(h);
+        assert_eq!(s.non_free_hazards(), 1);
+
+        s.warm_up(8, 100);
+        assert_eq!(s.available_hazards.len(), 8);
+        // The cached hazard is still protecting, while the new ones are free.
+        assert_eq!(s.non_free_hazards(), 1);
+        assert!(s.available_hazards[..7].iter().all(|h| !h.is_blocked()));
+        assert!(s.garbage.capacity() >= 100);
+        assert!(s.registration.is_some());
+
+        // The cache is full already.
+        s.warm_up(4, 0);
+        assert_eq!(s.available_hazards.len(), 8);
+
+        let v: Vec<_> = (0..8).map(|_| s.get_hazard()).collect();
+        assert!(s.available_hazards.is_empty());
+        for h in v {
+            h.free();
+            s.free_hazard(h);
+        }
+    }
+
+    #[test]
+    fn no_empty_export() {
+        let mut s = State::default();
+        assert!(!s.export_garbage());
+
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {}));
+        assert!(s.export_garbage());
+        assert!(!s.export_garbage());
+    }
+
+    #[test]
+    fn urgent_export() {
+        let mut s = State::default();
+        assert!(!s.add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {})));
+        assert!(s.add_garbage(Garbage::new(ptr::without_provenance(0x2), |_| {}).urgent()));
+        assert!(s.garbage.is_empty());
+    }
+
+    #[test]
+    fn detach_adopt() {
+        let mut s = State::default();
+        s.warm_up(4, 16);
+        let h = s.get_hazard();
+        h.protect(ptr::without_provenance(0x1));
+        s.free_hazard(h);
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x2), |_| {}));
+
+        let state = s.detach();
+        assert_eq!(state.cached_garbage(), 1);
+        assert_eq!(state.cached_hazards(), 4);
+        assert!(s.garbage.is_empty());
+        assert!(s.available_hazards.is_empty());
+
+        let state = thread::spawn(move || {
+            let mut s = State::default();
+            s.add_garbage(Garbage::new(ptr::without_provenance(0x3), |_| {}));
+            assert!(!s.adopt(state));
+            assert_eq!(s.garbage.len(), 2);
+            assert_eq!(s.available_hazards.len(), 4);
+            assert_eq!(s.non_free_hazards(), 0);
+
+            s.detach()
+        }).join().unwrap();
+
+        // Parking exports the garbage.
+        state.park();
+        let state = LocalState::unpark().unwrap();
+        assert_eq!(state.cached_garbage(), 0);
+        s.adopt(state);
+        assert!(s.available_hazards.len() > 0);
+    }
+
+    #[test]
+    fn take_scan() {
+        settings::set_local(settings::Settings {
+            scan_threshold: 2,
+            ..Default::default()
+        });
+
+        let mut s = State::default();
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x1), |_| {}));
+        assert!(s.take_scan().is_none());
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x2), |_| {}));
+        let mut garbage = s.take_scan().unwrap();
+        assert_eq!(garbage.len(), 2);
+        assert!(s.garbage.is_empty());
+
+        // One object survived the scan, so the next scan waits for two more.
+        garbage.pop();
+        s.put_back(garbage);
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x3), |_| {}));
+        assert!(s.take_scan().is_none());
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x4), |_| {}));
+        let garbage = s.take_scan().unwrap();
+        assert_eq!(garbage.len(), 3);
+        s.put_back(garbage);
+
+        // Exporting starts over.
+        assert!(s.export_garbage());
+        s.add_garbage(Garbage::new(ptr::without_provenance(0x5), |_| {}));
+        assert!(s.take_scan().is_none());
+        s.export_garbage();
+
+        // Avoid messing with other tests.
+        settings::set_local(settings::Settings::default());
+    }
+
+    #[test]
+    fn scan_on_retire() {
+        fn dtor(x: *const u8) {
+            unsafe {
+                *(x as *mut u8) = 1;
+            }
+        }
+
+        thread::spawn(|| {
+            settings::set_local(settings::Settings {
+                scan_threshold: 4,
+                ..Default::default()
+            });
+
+            let protected = Box::new(0u8);
+            let h = get_hazard();
+            h.protect(&*protected);
+            add_garbage(Garbage::new(&*protected, dtor));
+
+            // The scans are skipped while hazards of other threads are blocked, so retire until one
+            // got through.
+            let boxes: Vec<_> = (0..1000).map(|_| Box::new(0u8)).collect();
+            for b in &boxes {
+                add_garbage(Garbage::new(&**b, dtor));
+            }
+            assert!(boxes.iter().any(|b| **b == 1));
+            assert_eq!(*protected, 0);
+
+            h.free();
+            free_hazard(h);
+            export_garbage();
+            ::gc().unwrap();
+            assert_eq!(*protected, 1);
+        }).join().unwrap();
+    }
+
     #[test]
     fn kill_hazards() {
         fn dtor(x: *const u8) {
@@ -297,11 +1476,11 @@ mod tests {
                 let h = get_hazard();
                 h.protect(&*b);
                 add_garbage(Garbage::new(&*b, dtor));
-                ::gc();
+                ::gc().unwrap();
                 assert_eq!(*b, 0);
                 b
             }).join().unwrap();
-            ::gc();
+            ::gc().unwrap();
             assert_eq!(*b, 1);
         }
     }
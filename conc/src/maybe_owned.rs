@@ -0,0 +1,148 @@
+//! Values, which are either guarded or owned.
+
+use std::borrow::Borrow;
+use std::ops;
+use Guard;
+
+/// A value, which is either protected by a guard or owned.
+///
+/// This is like `Cow`, with a guard in place of the reference: APIs reading `conc`-backed data can
+/// return the protected object, when it is cheap to hold on to it, and an owned copy otherwise
+/// (e.g. when the guard would have to outlive the structure, or it is computed on the fly). Either
+/// way, it dereferences to the value.
+///
+/// A guarded value keeps its object from being destroyed, like the guard does. Use `into_owned()`
+/// to release the guard, when the value is to be held on to for long.
+///
+/// # Example
+///
+/// ```rust
+/// use conc::{Atomic, MaybeOwned};
+/// use std::sync::atomic::Ordering;
+///
+/// fn get(a: &Atomic<String>) -> MaybeOwned<String> {
+///     match a.load(Ordering::Acquire) {
+///         Some(guard) => MaybeOwned::from(guard),
+///         None => MaybeOwned::from(String::from("default")),
+///     }
+/// }
+///
+/// let a = Atomic::new(None);
+/// assert_eq!(*get(&a), "default");
+/// a.store(Some(Box::new(String::from("stored"))), Ordering::Release);
+/// assert_eq!(get(&a).into_owned(), "stored");
+/// ```
+#[derive(Debug)]
+pub enum MaybeOwned<T: 'static> {
+    /// An object protected by a guard.
+    Guarded(Guard<T>),
+    /// An owned value.
+    Owned(T),
+}
+
+impl<T> MaybeOwned<T> {
+    /// Is the value owned?
+    pub fn is_owned(&self) -> bool {
+        match *self {
+            MaybeOwned::Guarded(_) => false,
+            MaybeOwned::Owned(_) => true,
+        }
+    }
+
+    /// Get an owned copy of the value.
+    pub fn to_owned(&self) -> T where T: Clone {
+        (**self).clone()
+    }
+
+    /// Get the owned value, copying it out of the object, if it is guarded.
+    ///
+    /// The guard is dropped afterwards, so the object is no longer protected.
+    pub fn into_owned(self) -> T where T: Clone {
+        match self {
+            MaybeOwned::Guarded(guard) => (*guard).clone(),
+            MaybeOwned::Owned(x) => x,
+        }
+    }
+}
+
+impl<T> ops::Deref for MaybeOwned<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        match *self {
+            MaybeOwned::Guarded(ref guard) => guard,
+            MaybeOwned::Owned(ref x) => x,
+        }
+    }
+}
+
+impl<T> AsRef<T> for MaybeOwned<T> {
+    fn as_ref(&self) -> &T {
+        self
+    }
+}
+
+impl<T> Borrow<T> for MaybeOwned<T> {
+    fn borrow(&self) -> &T {
+        self
+    }
+}
+
+impl<T> From<Guard<T>> for MaybeOwned<T> {
+    fn from(guard: Guard<T>) -> MaybeOwned<T> {
+        MaybeOwned::Guarded(guard)
+    }
+}
+
+impl<T> From<T> for MaybeOwned<T> {
+    fn from(x: T) -> MaybeOwned<T> {
+        MaybeOwned::Owned(x)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic;
+    use testing::{Counter, Tracked};
+    use Atomic;
+
+    #[test]
+    fn guarded() {
+        let a = Atomic::new(Some(Box::new(vec![1, 2])));
+        let x = MaybeOwned::Guarded(a.load(atomic::Ordering::Acquire).unwrap());
+        assert!(!x.is_owned());
+        assert_eq!(*x, [1, 2]);
+
+        // The object stays valid, while it is guarded.
+        a.store(None, atomic::Ordering::Release);
+        ::gc().unwrap();
+        assert_eq!(x.to_owned(), [1, 2]);
+        assert_eq!(x.into_owned(), [1, 2]);
+    }
+
+    #[test]
+    fn owned() {
+        let x: MaybeOwned<Vec<i32>> = MaybeOwned::from(vec![1, 2]);
+        assert!(x.is_owned());
+        assert_eq!(x.len(), 2);
+        assert_eq!(x.as_ref(), &[1, 2]);
+        assert_eq!(x.into_owned(), [1, 2]);
+    }
+
+    #[test]
+    fn into_owned_releases() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Box::new(Tracked::with_counter(1, &COUNTER))));
+        let x = MaybeOwned::Guarded(a.load(atomic::Ordering::Acquire).unwrap());
+        a.store(None, atomic::Ordering::Release);
+
+        let owned = x.into_owned();
+        assert_eq!(*owned, 1);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        // Only the copy is alive.
+        assert_eq!(COUNTER.live(), 1);
+    }
+}
@@ -1,57 +1,48 @@
 //! Multi-producer single-consumer queues.
 //!
-//! Since the standard library's implementation of `mpsc` requires us to clone the senders in
-//! advance, such that we cannot store them in our global state outside a lock, we must implement
+//! Since the standard library's implementation of `mpsc` cannot be constructed at compile time,
+//! such that we cannot store it in our global state without lazy initialization, we must implement
 //! our own `mpsc` queue.
 //!
 //! Right now, the implementation is really nothing but a wrapper around `Mutex<Vec<T>>`, and
 //! although this is reasonably fast as the lock is only held for very short time, it is
 //! sub-optimal, and blocking.
 
-use parking_lot::Mutex;
-use std::sync::Arc;
+use parking_lot::{self, Mutex};
 use std::mem;
+use global;
 
-/// Create a MPSC pair.
+/// A MPSC queue.
 ///
-/// This creates a "channel", i.e. a pair of sender and receiver connected to each other.
-pub fn channel<T>() -> (Sender<T>, Receiver<T>) {
-    // Create a new ARC.
-    let end = Arc::new(Mutex::new(Vec::new()));
-
-    (Sender {
-        inner: end.clone(),
-    }, Receiver {
-        inner: end,
-    })
+/// Contrary to ordinary channels, this is not split into a sender and a receiver. Instead, the
+/// owner of the queue must ensure that only one thread receives at a time.
+pub struct Queue<T> {
+    /// The inner buffer.
+    inner: Mutex<Vec<T>>,
 }
 
-/// The sender of a MPSC channel.
-pub struct Sender<T> {
-    /// The wrapped end.
-    inner: Arc<Mutex<Vec<T>>>,
-}
+impl<T> Queue<T> {
+    /// Create a new, empty queue.
+    pub const fn new() -> Queue<T> {
+        Queue {
+            inner: parking_lot::const_mutex(Vec::new()),
+        }
+    }
 
-impl<T> Sender<T> {
-    /// Send an item to this channel.
+    /// Send an item to this queue.
     pub fn send(&self, item: T) {
+        // The push might allocate, and a failing allocation might collect garbage (see `oom`), which
+        // takes this lock.
+        let _critical = global::Critical::new();
         // Lock the vector, and push.
         self.inner.lock().push(item);
     }
-}
-
-/// The receiver of a MPSC channel.
-pub struct Receiver<T> {
-    /// The wrapped end.
-    inner: Arc<Mutex<Vec<T>>>,
-}
 
-impl<T> Receiver<T> {
     /// Receive all the elements in the queue.
     ///
-    /// This takes all the elements and applies the given closure to them in an unspecified order.
+    /// This takes all the elements and returns them in an unspecified order.
     pub fn recv_all(&self) -> Vec<T> {
-        // Lock the vector, and replace it by an empty vector, then iterate.
+        // Lock the vector, and replace it by an empty vector.
         mem::replace(&mut *self.inner.lock(), Vec::new())
     }
 }
@@ -0,0 +1,291 @@
+//! Recursive retirement of nested structures.
+//!
+//! When an object is retired, the children it owns must be retired as well, rather than dropped
+//! right away, as concurrent readers might still reach them through guards of the parent. Values
+//! of `Atomic<T>` are retired, when the container is dropped, but children behind raw pointers
+//! (e.g. `AtomicPtr<T>`) must be retired by hand, which is easy to get wrong.
+//!
+//! The `Retire` trait walks the fields of an object and retires its children. It is implemented
+//! for `Atomic<T>` and the common containers of it, and `retire_fields!` implements it for
+//! structures by listing their fields. Children behind raw pointers are retired through
+//! `retire_ptr()`, and `add_garbage_tree()` retires an object along with its children.
+//!
+//! # Example
+//!
+//! ```rust
+//! #[macro_use]
+//! extern crate conc;
+//!
+//! use conc::Atomic;
+//! use conc::nested::{self, Retire};
+//! use std::sync::atomic::AtomicPtr;
+//!
+//! struct Node {
+//!     value: u32,
+//!     left: Atomic<Node>,
+//!     right: AtomicPtr<Node>,
+//! }
+//!
+//! impl Retire for Node {
+//!     fn retire_children(&mut self) {
+//!         self.left.retire_children();
+//!         unsafe { nested::retire_ptr(&mut self.right); }
+//!     }
+//! }
+//!
+//! // The nodes are retired through `Atomic<Node>` as well, which drops them.
+//! impl Drop for Node {
+//!     fn drop(&mut self) {
+//!         self.retire_children();
+//!     }
+//! }
+//!
+//! struct Tree {
+//!     root: Atomic<Node>,
+//!     size: Option<Box<Atomic<usize>>>,
+//! }
+//!
+//! retire_fields!(Tree { root, size });
+//!
+//! fn main() {
+//!     let leaf = Box::into_raw(Box::new(Node {
+//!         value: 2,
+//!         left: Atomic::default(),
+//!         right: AtomicPtr::default(),
+//!     }));
+//!     let tree = Box::new(Tree {
+//!         root: Atomic::new(Some(Box::new(Node {
+//!             value: 1,
+//!             left: Atomic::default(),
+//!             right: AtomicPtr::new(leaf),
+//!         }))),
+//!         size: Some(Box::new(Atomic::new(Some(Box::new(2))))),
+//!     });
+//!
+//!     // Retire the tree along with every node.
+//!     unsafe { nested::add_garbage_tree(Box::into_raw(tree)); }
+//!     conc::gc().unwrap();
+//! }
+//! ```
+
+use std::{mem, ptr};
+use std::sync::atomic::AtomicPtr;
+use add_garbage;
+
+/// An object owning children, which are reclaimed through `conc`.
+///
+/// Implementations shall retire every child, which `self` owns (e.g. through
+/// `Retire::retire_children()` of their fields, or `retire_ptr()`), and leave `self` without
+/// children, such that dropping it afterwards doesn't retire them again.
+///
+/// Types with children behind raw pointers should call `retire_children()` in their `Drop`, so
+/// they are retired recursively, when they are retired themselves (e.g. through `Atomic<T>`).
+pub trait Retire {
+    /// Retire the children of `self`.
+    ///
+    /// This is called, when `self` has become unreachable, but the children might still be
+    /// protected through guards created before.
+    fn retire_children(&mut self);
+}
+
+impl<R: Retire> Retire for Option<R> {
+    fn retire_children(&mut self) {
+        if let Some(ref mut x) = *self {
+            x.retire_children();
+        }
+    }
+}
+
+impl<R: Retire + ?Sized> Retire for Box<R> {
+    fn retire_children(&mut self) {
+        (**self).retire_children();
+    }
+}
+
+impl<R: Retire> Retire for [R] {
+    fn retire_children(&mut self) {
+        for x in self {
+            x.retire_children();
+        }
+    }
+}
+
+impl<R: Retire, const N: usize> Retire for [R; N] {
+    fn retire_children(&mut self) {
+        self[..].retire_children();
+    }
+}
+
+impl<R: Retire> Retire for Vec<R> {
+    fn retire_children(&mut self) {
+        self[..].retire_children();
+    }
+}
+
+/// Retire the box, which `ptr` owns, along with its children, and set `ptr` to null.
+///
+/// Nothing is done, if `ptr` is null.
+///
+/// # Safety
+///
+/// `ptr` must own the box, which must have been allocated through `Box`, and it must be
+/// unreachable, except from `ptr` (see `add_garbage_box()`).
+pub unsafe fn retire_ptr<T: Retire + Send + Sync + 'static>(ptr: &mut AtomicPtr<T>) {
+    let ptr = mem::replace(ptr.get_mut(), ptr::null_mut());
+    if !ptr.is_null() {
+        add_garbage_tree(ptr);
+    }
+}
+
+/// Retire a heap-allocated `Box<T>` along with its children.
+///
+/// Once the box is no longer protected, its children are retired (through `retire_children()`),
+/// and then it is dropped. Hence, the children are destroyed by later collections.
+///
+/// # Safety
+///
+/// This is unsafe for the same reasons as `add_garbage_box()`.
+pub unsafe fn add_garbage_tree<T: Retire + Send + Sync + 'static>(ptr: *const T) {
+    fn destroy<T: Retire>(ptr: &'static T) {
+        // The garbage is unreachable and unprotected, so we own it.
+        let mut item = unsafe { Box::from_raw(ptr as *const T as *mut T) };
+        item.retire_children();
+    }
+
+    add_garbage(&*ptr, destroy::<T>);
+}
+
+/// Implement `Retire` for a structure by retiring the children of the given fields.
+///
+/// Every field listed must implement `Retire`. The generic parameters of the structure (if any)
+/// are given in brackets before the type.
+///
+/// # Example
+///
+/// ```rust
+/// #[macro_use]
+/// extern crate conc;
+///
+/// use conc::Atomic;
+///
+/// struct Pair<T> {
+///     first: Atomic<T>,
+///     second: Atomic<T>,
+///     label: &'static str,
+/// }
+///
+/// retire_fields!([T] Pair<T> { first, second });
+///
+/// fn main() {}
+/// ```
+#[macro_export]
+macro_rules! retire_fields {
+    ([$($generics:tt)*] $ty:ty { $($field:tt),* $(,)* }) => {
+        impl<$($generics)*> $crate::nested::Retire for $ty {
+            fn retire_children(&mut self) {
+                $($crate::nested::Retire::retire_children(&mut self.$field);)*
+            }
+        }
+    };
+    ($ty:ty { $($field:tt),* $(,)* }) => {
+        retire_fields!([] $ty { $($field),* });
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicUsize};
+    use Atomic;
+
+    static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+    /// A node of a binary tree, which is only reachable through raw pointers.
+    struct Node {
+        left: AtomicPtr<Node>,
+        right: AtomicPtr<Node>,
+    }
+
+    impl Retire for Node {
+        fn retire_children(&mut self) {
+            unsafe {
+                retire_ptr(&mut self.left);
+                retire_ptr(&mut self.right);
+            }
+        }
+    }
+
+    impl Drop for Node {
+        fn drop(&mut self) {
+            DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            // The children are retired already, unless the node was dropped directly.
+            self.retire_children();
+        }
+    }
+
+    /// Build a full tree of depth `depth`.
+    fn tree(depth: usize) -> *mut Node {
+        if depth == 0 {
+            ptr::null_mut()
+        } else {
+            Box::into_raw(Box::new(Node {
+                left: AtomicPtr::new(tree(depth - 1)),
+                right: AtomicPtr::new(tree(depth - 1)),
+            }))
+        }
+    }
+
+    struct Fields {
+        a: Atomic<Node>,
+        b: Vec<Atomic<Node>>,
+        c: [Option<Atomic<Node>>; 2],
+        d: u8,
+    }
+
+    retire_fields!(Fields { a, b, c });
+
+    struct Generic<T> {
+        a: Box<Atomic<T>>,
+    }
+
+    retire_fields!([T] Generic<T> { a, });
+
+    #[test]
+    fn whole_tree() {
+        let before = DROPS.load(atomic::Ordering::Relaxed);
+        unsafe { add_garbage_tree(tree(5)); }
+
+        // Every level is destroyed by a collection of its own, and the children might be retired
+        // by other threads collecting garbage, which export them later.
+        for _ in 0..10000 {
+            ::gc().unwrap();
+            if DROPS.load(atomic::Ordering::Relaxed) - before >= 31 {
+                return;
+            }
+            ::std::thread::sleep(::std::time::Duration::from_millis(1));
+        }
+        panic!("The tree wasn't destroyed.");
+    }
+
+    #[test]
+    fn fields() {
+        let atomic = |depth| Atomic::new(Some(unsafe { Box::from_raw(tree(depth)) }));
+        let mut x = Fields {
+            a: atomic(1),
+            b: vec![atomic(1), atomic(2)],
+            c: [None, Some(atomic(1))],
+            d: 0,
+        };
+        x.retire_children();
+        assert!(x.a.load(atomic::Ordering::Relaxed).is_none());
+        assert!(x.b.iter().all(|x| x.load(atomic::Ordering::Relaxed).is_none()));
+        assert!(x.c[1].as_ref().unwrap().load(atomic::Ordering::Relaxed).is_none());
+        assert_eq!(x.d, 0);
+
+        let mut y = Generic {
+            a: Box::new(Atomic::new(Some(Box::new(1)))),
+        };
+        y.retire_children();
+        assert!(y.a.load(atomic::Ordering::Relaxed).is_none());
+    }
+}
@@ -0,0 +1,102 @@
+//! NUMA topology.
+//!
+//! On multi-socket machines, moving cache lines between the sockets is considerably more expensive
+//! than moving them within a socket. To avoid that, parts of the global state are sharded by the
+//! NUMA node of the accessing thread.
+
+use std::cell::Cell;
+use std::thread;
+
+/// The number of lookups of the node, after which the cached node is refreshed.
+///
+/// Querying the kernel is a real system call (`getcpu` has no vDSO entry through `syscall`), so
+/// it is only done occasionally. A thread, which migrated to another node, is noticed after at most
+/// this many lookups.
+const REFRESH_INTERVAL: u32 = 1024;
+
+thread_local! {
+    /// The cached node of this thread and the number of lookups left, before it is refreshed.
+    static CACHED: Cell<(usize, u32)> = Cell::new((0, 0));
+}
+
+/// Get the NUMA node the current thread runs on.
+///
+/// Since threads can migrate between nodes at any time, this is merely a hint. The node is cached
+/// by the thread, and only refreshed every `REFRESH_INTERVAL` calls. If the node cannot be
+/// determined, `0` is returned.
+pub fn current_node() -> usize {
+    if CACHED.state() == thread::LocalKeyState::Destroyed {
+        // The cache is gone, as the thread is exiting, so we query the node directly.
+        return query_node();
+    }
+
+    CACHED.with(|cached| {
+        let (node, left) = cached.get();
+        if left == 0 {
+            let node = query_node();
+            cached.set((node, REFRESH_INTERVAL - 1));
+            node
+        } else {
+            cached.set((node, left - 1));
+            node
+        }
+    })
+}
+
+/// Query the NUMA node the current thread runs on.
+///
+/// If the node cannot be determined, `0` is returned.
+#[cfg(target_os = "linux")]
+fn query_node() -> usize {
+    use libc;
+    use std::ptr;
+
+    let mut cpu: libc::c_uint = 0;
+    let mut node: libc::c_uint = 0;
+
+    // `getcpu(2)` gives us both the CPU and its node.
+    if unsafe {
+        libc::syscall(libc::SYS_getcpu, &mut cpu, &mut node, ptr::null_mut::<libc::c_void>())
+    } == 0 {
+        node as usize
+    } else {
+        0
+    }
+}
+
+/// Query the NUMA node the current thread runs on.
+///
+/// The node cannot be determined on this platform, so this is always `0`.
+#[cfg(not(target_os = "linux"))]
+fn query_node() -> usize {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn current_node_cross_thread() {
+        let mut j = Vec::new();
+        for _ in 0..8 {
+            j.push(thread::spawn(|| current_node()));
+        }
+
+        for i in j {
+            // There are no machines with this many nodes.
+            assert!(i.join().unwrap() < 1 << 16);
+        }
+    }
+
+    #[test]
+    fn cached() {
+        let node = current_node();
+        for _ in 0..REFRESH_INTERVAL - 1 {
+            // The node isn't refreshed until the cached one was used enough times.
+            assert_eq!(current_node(), node);
+        }
+
+        assert!(current_node() < 1 << 16);
+    }
+}
@@ -0,0 +1,169 @@
+//! Garbage collection on allocation failure.
+//!
+//! `Reclaiming` wraps an allocator, such that when an allocation fails, a last-ditch garbage
+//! collection is run, and the allocation is retried once before giving up (and usually aborting).
+//! If a lot of garbage is pending, this can recover enough memory to keep the program alive.
+//!
+//! This is opt-in, through using `Reclaiming` as the global allocator:
+//!
+//! ```rust
+//! use std::alloc::System;
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: conc::oom::Reclaiming<System> = conc::oom::Reclaiming::new(System);
+//! # fn main() {}
+//! ```
+//!
+//! The allocation error hook (`std::alloc::set_alloc_error_hook`) isn't used for this, as it is
+//! called after the allocation has failed for good, so it cannot retry it.
+//!
+//! # Limitations
+//!
+//! The collection only destroys garbage, whose destruction merely frees memory (boxes of types
+//! without drop glue). Other destructors run user code, which might allocate (failing again) or
+//! take a lock held by the failing allocation, so that garbage is left to the next regular
+//! collection (see `global::reclaim_plain()`).
+//!
+//! The collection is skipped if the failing allocation is made while the thread is collecting
+//! garbage (e.g. by a destructor) or holding a lock, which the collector takes, as it would
+//! deadlock. Neither can it collect the garbage, which other threads haven't exported yet.
+
+use std::alloc::{GlobalAlloc, Layout};
+use std::cell::Cell;
+use std::{panic, thread};
+use {global, local};
+
+thread_local! {
+    /// Is the current thread running a last-ditch collection?
+    static RECLAIMING: Cell<bool> = Cell::new(false);
+}
+
+/// An allocator collecting garbage, when allocation fails.
+///
+/// See the module documentation for details.
+#[derive(Debug, Default)]
+pub struct Reclaiming<A> {
+    /// The inner allocator.
+    inner: A,
+}
+
+impl<A> Reclaiming<A> {
+    /// Wrap an allocator.
+    pub const fn new(inner: A) -> Reclaiming<A> {
+        Reclaiming {
+            inner: inner,
+        }
+    }
+}
+
+unsafe impl<A: GlobalAlloc> GlobalAlloc for Reclaiming<A> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        retry(|| self.inner.alloc(layout))
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        retry(|| self.inner.alloc_zeroed(layout))
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        self.inner.dealloc(ptr, layout);
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        // On failure, the old block is left untouched, so we can retry with the same arguments.
+        retry(|| self.inner.realloc(ptr, layout, new_size))
+    }
+}
+
+/// Run an allocation, collecting garbage and retrying once if it fails.
+fn retry<F: Fn() -> *mut u8>(alloc: F) -> *mut u8 {
+    let ptr = alloc();
+    if ptr.is_null() && reclaim() {
+        alloc()
+    } else {
+        ptr
+    }
+}
+
+/// Run a last-ditch garbage collection.
+///
+/// This returns `true` if garbage was collected.
+fn reclaim() -> bool {
+    // Collecting here might deadlock. The allocations of the collection itself might fail as well,
+    // in which case we don't recurse.
+    if global::in_critical()
+        || RECLAIMING.state() == thread::LocalKeyState::Destroyed
+        || RECLAIMING.with(|x| x.replace(true)) {
+        return false;
+    }
+
+    // Allocators must not unwind, so panics (e.g. of the debug assertions of the scan) are
+    // caught. The panic still poisons the collector.
+    let res = panic::catch_unwind(|| {
+        // The local state might be in use by the failing allocation, in which case its garbage
+        // isn't exported.
+        local::try_export_garbage();
+        global::reclaim_plain()
+    });
+
+    RECLAIMING.with(|x| x.set(false));
+
+    match res {
+        Ok(Ok(_)) => true,
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::alloc::System;
+    use std::ptr;
+    use std::sync::atomic::{self, AtomicUsize};
+    use Atomic;
+
+    /// An allocator, which fails a given number of times.
+    struct Flaky {
+        /// The number of allocations left to fail.
+        failures: AtomicUsize,
+    }
+
+    unsafe impl GlobalAlloc for Flaky {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            if self.failures.load(atomic::Ordering::SeqCst) > 0 {
+                self.failures.fetch_sub(1, atomic::Ordering::SeqCst);
+                return ptr::null_mut();
+            }
+
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn retry_after_gc() {
+        // Plain boxes are destroyed by the last-ditch collection.
+        let a = Atomic::new(Some(Box::new([0u64; 16])));
+        a.store(None, atomic::Ordering::Release);
+
+        let alloc = Reclaiming::new(Flaky { failures: AtomicUsize::new(1) });
+        let layout = Layout::new::<u64>();
+        unsafe {
+            // The retired object was collected before retrying.
+            let ptr = alloc.alloc(layout);
+            assert!(!ptr.is_null());
+            alloc.dealloc(ptr, layout);
+        }
+    }
+
+    #[test]
+    fn retry_once() {
+        let alloc = Reclaiming::new(Flaky { failures: AtomicUsize::new(2) });
+        unsafe {
+            assert!(alloc.alloc(Layout::new::<u64>()).is_null());
+        }
+    }
+}
@@ -0,0 +1,157 @@
+//! Long-lived protection through shared hazards.
+//!
+//! A dropped guard caches its hazard in the current thread, and every clone of a guard takes a
+//! hazard of its own, so holding on to an object for long (e.g. while a page is written to disk)
+//! through guards ties up hazards. A long guard instead shares its hazard with its clones through
+//! a reference count stored next to the hazard, and frees the hazard right away, when the last of
+//! them is dropped.
+
+use std::sync::atomic::{self, AtomicUsize};
+use std::{fmt, ops};
+use std::ptr::NonNull;
+use hazard;
+
+/// The hazard shared by a long guard and its clones.
+struct Protection {
+    /// The hazard protecting the object.
+    hazard: hazard::Writer,
+    /// The number of long guards sharing the hazard.
+    count: AtomicUsize,
+}
+
+/// A long-lived protection of an object.
+///
+/// This acts like `Guard<T>`, but is meant for holding on to objects for long. See
+/// `Guard::pin_long()`.
+///
+/// Cloning a long guard only increments the reference count of its hazard, so the clones are
+/// protected by a single hazard. When the last clone drops, the hazard is freed right away, rather
+/// than keeping the object protected until the cache of the thread is full.
+pub struct LongGuard<T: 'static + ?Sized> {
+    /// The pointer to the protected object.
+    pointer: &'static T,
+    /// The hazard protecting the object, shared with the clones.
+    protection: NonNull<Protection>,
+}
+
+impl<T: ?Sized> LongGuard<T> {
+    /// Create a long guard of the object `pointer` refers to, which is protected by `hazard`.
+    pub(crate) fn new(hazard: hazard::Writer, pointer: &'static T) -> LongGuard<T> {
+        let protection = Box::new(Protection {
+            hazard: hazard,
+            count: AtomicUsize::new(1),
+        });
+
+        LongGuard {
+            pointer: pointer,
+            protection: NonNull::from(Box::leak(protection)),
+        }
+    }
+
+    /// Get the raw pointer of this long guard.
+    pub fn as_ptr(&self) -> *const T {
+        self.pointer
+    }
+
+    /// Get the hazard shared by this long guard and its clones.
+    fn protection(&self) -> &Protection {
+        // The protection lives as long as any of the long guards sharing it.
+        unsafe { self.protection.as_ref() }
+    }
+}
+
+impl<T: ?Sized> Clone for LongGuard<T> {
+    fn clone(&self) -> LongGuard<T> {
+        // The object is protected by `self`, so the count is at least one already.
+        self.protection().count.fetch_add(1, atomic::Ordering::Relaxed);
+
+        LongGuard {
+            pointer: self.pointer,
+            protection: self.protection,
+        }
+    }
+}
+
+impl<T: ?Sized> ops::Deref for LongGuard<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+impl<T: ?Sized> Drop for LongGuard<T> {
+    fn drop(&mut self) {
+        if self.protection().count.fetch_sub(1, atomic::Ordering::Release) == 1 {
+            // Order the accesses through the other clones before freeing the hazard.
+            atomic::fence(atomic::Ordering::Acquire);
+
+            let protection = unsafe { Box::from_raw(self.protection.as_ptr()) };
+            protection.hazard.free();
+        }
+    }
+}
+
+// The pointer to the protection makes `LongGuard` neither `Send` nor `Sync`, but it acts like a
+// reference-counted `Guard<T>`.
+unsafe impl<T: ?Sized + Sync> Send for LongGuard<T> {}
+unsafe impl<T: ?Sized + Sync> Sync for LongGuard<T> {}
+
+impl<T: ?Sized + fmt::Debug> fmt::Debug for LongGuard<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("LongGuard").field("pointer", &self.pointer).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic;
+    use std::thread;
+    use testing::{Counter, Tracked};
+    use Atomic;
+
+    #[test]
+    fn protects() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Box::new(Tracked::with_counter(1, &COUNTER))));
+
+        let long = a.load(atomic::Ordering::Acquire).unwrap().pin_long();
+        let long2 = long.clone();
+        a.store(None, atomic::Ordering::Release);
+
+        ::gc().unwrap();
+        drop(long);
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 0);
+
+        // The hazard is freed along with the last clone.
+        drop(long2);
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
+    #[test]
+    fn clones_in_threads() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Atomic::new(Some(Box::new(Tracked::with_counter(2, &COUNTER))));
+        let long = a.load(atomic::Ordering::Acquire).unwrap().pin_long();
+        a.store(None, atomic::Ordering::Release);
+
+        let threads: Vec<_> = (0..4).map(|_| {
+            let long = long.clone();
+            thread::spawn(move || assert_eq!(**long, 2))
+        }).collect();
+        for thread in threads {
+            thread.join().unwrap();
+        }
+
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 0);
+
+        drop(long);
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+}
@@ -0,0 +1,38 @@
+//! The commonly used items of `conc`.
+//!
+//! This exports the containers, the guards, the functions for retiring garbage, and the main data
+//! structures of `sync`, so they can be imported at once.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::prelude::*;
+//! use std::sync::atomic::Ordering;
+//!
+//! let atomic = Atomic::new(Some(Box::new(1)));
+//! let guard: Guard<i32> = atomic.load(Ordering::Acquire).unwrap();
+//! atomic.store(Some(Box::new(2)), Ordering::Release);
+//! assert_eq!(*guard, 1);
+//!
+//! let stack = Treiber::new();
+//! stack.push(3);
+//! assert_eq!(*stack.pop().unwrap(), 3);
+//! ```
+
+pub use {Atomic, AtomicCell, Guard, LongGuard, MaybeOwned, Shared};
+pub use {add_garbage, add_garbage_box, add_garbage_box_parallel, add_garbage_parallel};
+pub use {add_garbage_box_urgent, add_garbage_urgent};
+pub use defer;
+pub use sync::{
+    BloomFilter,
+    ClockCache,
+    Deque,
+    DirtyMap,
+    DisjointSet,
+    HashMultiMap,
+    IdAllocator,
+    PageCache,
+    Stm,
+    Treiber,
+    Versioned,
+};
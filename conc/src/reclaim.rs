@@ -0,0 +1,126 @@
+//! Abstraction over reclamation schemes.
+//!
+//! Data structures written against the `Protect` trait rather than this crate's API directly can
+//! be used with any reclamation scheme implementing it, which is chosen by the user of the
+//! structure at compile time. `Conc` implements it through this crate.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::reclaim::{Conc, Protect};
+//! use std::marker::PhantomData;
+//! use std::sync::atomic::{AtomicPtr, Ordering};
+//!
+//! /// A concurrently replaceable value, generic over the reclamation scheme.
+//! struct Slot<P: Protect, T: Send + 'static> {
+//!     ptr: AtomicPtr<T>,
+//!     _scheme: PhantomData<P>,
+//! }
+//!
+//! impl<P: Protect, T: Send + 'static> Slot<P, T> {
+//!     fn get(&self) -> Option<P::Guard<T>> {
+//!         P::protect(&self.ptr, Ordering::Acquire)
+//!     }
+//!
+//!     fn set(&self, value: T) {
+//!         let old = self.ptr.swap(Box::into_raw(Box::new(value)), Ordering::AcqRel);
+//!         if !old.is_null() {
+//!             unsafe { P::retire(old); }
+//!         }
+//!     }
+//! }
+//!
+//! let slot: Slot<Conc, u32> = Slot {
+//!     ptr: AtomicPtr::default(),
+//!     _scheme: PhantomData,
+//! };
+//! slot.set(42);
+//! Conc::scope(|| assert_eq!(*slot.get().unwrap(), 42));
+//! ```
+
+use std::ops;
+use std::sync::atomic::{self, AtomicPtr};
+use {add_garbage_box, Guard};
+
+/// A memory reclamation scheme.
+pub trait Protect {
+    /// A guard protecting an object of type `T` from being reclaimed.
+    type Guard<T: 'static>: ops::Deref<Target = T>;
+
+    /// Load a pointer and protect the object it points to.
+    ///
+    /// `None` is returned, if the pointer is null. The `ordering` is the ordering of the load.
+    fn protect<T: 'static>(ptr: &AtomicPtr<T>, ordering: atomic::Ordering)
+    -> Option<Self::Guard<T>>;
+
+    /// Retire a heap-allocated `Box<T>`, which has become unreachable.
+    ///
+    /// The box is dropped, once it is no longer protected.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must be allocated through `Box`, and must be unreachable, such that it can't be
+    /// protected anew (see `conc::add_garbage_box()`).
+    unsafe fn retire<T: Send + 'static>(ptr: *mut T);
+
+    /// Run `f` in a protection scope.
+    ///
+    /// Schemes protecting the objects by region (e.g. epochs) need guards to be created and
+    /// dropped in a scope, whereas others simply run `f`.
+    fn scope<R, F: FnOnce() -> R>(f: F) -> R;
+}
+
+/// The reclamation scheme of this crate.
+///
+/// Objects are protected by hazards (through `Guard<T>`) individually, so no scope is needed.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Conc;
+
+impl Protect for Conc {
+    type Guard<T: 'static> = Guard<T>;
+
+    fn protect<T: 'static>(ptr: &AtomicPtr<T>, ordering: atomic::Ordering) -> Option<Guard<T>> {
+        Guard::maybe_new(|| unsafe { ptr.load(ordering).as_ref() })
+    }
+
+    unsafe fn retire<T: Send + 'static>(ptr: *mut T) {
+        add_garbage_box(ptr);
+    }
+
+    fn scope<R, F: FnOnce() -> R>(f: F) -> R {
+        f()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use testing::{Counter, Tracked};
+
+    /// Replace the value of `ptr` through the scheme `P`, returning the value replaced.
+    fn replace<P: Protect>(ptr: &AtomicPtr<Tracked<u32>>, new: Tracked<u32>) -> u32 {
+        P::scope(|| {
+            let guard = P::protect(ptr, atomic::Ordering::Acquire).unwrap();
+            let old = ptr.swap(Box::into_raw(Box::new(new)), atomic::Ordering::AcqRel);
+            unsafe { P::retire(old); }
+
+            // The old value is protected until the guard is dropped.
+            **guard
+        })
+    }
+
+    #[test]
+    fn generic() {
+        static COUNTER: Counter = Counter::new();
+
+        let ptr = AtomicPtr::new(Box::into_raw(Box::new(Tracked::with_counter(1, &COUNTER))));
+
+        assert_eq!(replace::<Conc>(&ptr, Tracked::with_counter(2, &COUNTER)), 1);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+
+        unsafe { Conc::retire(ptr.load(atomic::Ordering::Relaxed)); }
+        COUNTER.assert_balanced();
+    }
+}
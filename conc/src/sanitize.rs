@@ -0,0 +1,135 @@
+//! Annotations of reclaimed memory for memory checkers.
+//!
+//! Memory checkers (Valgrind's memcheck and AddressSanitizer) only know about the allocator, so
+//! they can't tell memory held by `conc` apart from live memory. With the `sanitize-hooks`
+//! feature, the reclaimed allocations in the quarantine (see `debug`) are marked inaccessible
+//! through the client requests of the checker, such that an access to a reclaimed object is
+//! reported where it happens, rather than when the memory is released and reused by an unrelated
+//! allocation.
+//!
+//! Retired objects, which are yet to be reclaimed, are left accessible, as the guards created
+//! before the retirement may still read them.
+//!
+//! The memcheck requests are magic instruction sequences, which do nothing outside of Valgrind.
+//! They are issued on x86-64 and AArch64. The AddressSanitizer requests are only issued, when
+//! compiled with `-Z sanitizer=address`.
+
+/// Mark `size` bytes at `ptr` as inaccessible.
+///
+/// Any access to the memory is reported by the memory checker, until it is made accessible again.
+#[cfg(feature = "sanitize-hooks")]
+pub fn poison(ptr: *const u8, size: usize) {
+    memcheck::request(memcheck::MAKE_MEM_NOACCESS, ptr, size);
+    #[cfg(sanitize = "address")]
+    unsafe { asan::__asan_poison_memory_region(ptr, size); }
+}
+
+/// Mark `size` bytes at `ptr` as accessible and initialized.
+///
+/// This must be called before the memory is released to the allocator.
+#[cfg(feature = "sanitize-hooks")]
+pub fn unpoison(ptr: *const u8, size: usize) {
+    #[cfg(sanitize = "address")]
+    unsafe { asan::__asan_unpoison_memory_region(ptr, size); }
+    memcheck::request(memcheck::MAKE_MEM_DEFINED, ptr, size);
+}
+
+/// Do nothing.
+///
+/// With `sanitize-hooks`, this marks the memory as inaccessible.
+#[inline]
+#[cfg(not(feature = "sanitize-hooks"))]
+pub fn poison(_: *const u8, _: usize) {}
+
+/// Do nothing.
+///
+/// With `sanitize-hooks`, this marks the memory as accessible.
+#[inline]
+#[cfg(not(feature = "sanitize-hooks"))]
+pub fn unpoison(_: *const u8, _: usize) {}
+
+/// The interface of AddressSanitizer.
+#[cfg(all(feature = "sanitize-hooks", sanitize = "address"))]
+mod asan {
+    extern "C" {
+        pub fn __asan_poison_memory_region(addr: *const u8, size: usize);
+        pub fn __asan_unpoison_memory_region(addr: *const u8, size: usize);
+    }
+}
+
+/// The client requests of memcheck.
+///
+/// See `valgrind.h` and `memcheck.h` for the protocol.
+#[cfg(feature = "sanitize-hooks")]
+mod memcheck {
+    /// The base of the memcheck requests (`'M' << 24 | 'C' << 16`).
+    const BASE: usize = 0x4D43_0000;
+    /// Mark memory as inaccessible.
+    pub const MAKE_MEM_NOACCESS: usize = BASE;
+    /// Mark memory as accessible and initialized.
+    pub const MAKE_MEM_DEFINED: usize = BASE + 2;
+
+    /// Issue the client request `req` for `size` bytes at `ptr`.
+    #[cfg(target_arch = "x86_64")]
+    pub fn request(req: usize, ptr: *const u8, size: usize) {
+        use std::arch::asm;
+
+        let args = [req, ptr.addr(), size, 0, 0, 0];
+        // The rotations add up to 128 bits, so they leave `rdi` as is. Outside of Valgrind, the
+        // sequence does nothing but that.
+        unsafe {
+            asm!(
+                "rol rdi, 3",
+                "rol rdi, 13",
+                "rol rdi, 61",
+                "rol rdi, 51",
+                "xchg rbx, rbx",
+                in("rax") args.as_ptr(),
+                inout("rdx") 0usize => _,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Issue the client request `req` for `size` bytes at `ptr`.
+    #[cfg(target_arch = "aarch64")]
+    pub fn request(req: usize, ptr: *const u8, size: usize) {
+        use std::arch::asm;
+
+        let args = [req, ptr.addr(), size, 0, 0, 0];
+        // The rotations add up to 128 bits, so they leave `x12` as is.
+        unsafe {
+            asm!(
+                "ror x12, x12, #3",
+                "ror x12, x12, #13",
+                "ror x12, x12, #51",
+                "ror x12, x12, #61",
+                "orr x10, x10, x10",
+                in("x4") args.as_ptr(),
+                inout("x3") 0usize => _,
+                options(nostack, preserves_flags),
+            );
+        }
+    }
+
+    /// Do nothing.
+    ///
+    /// Valgrind's client requests aren't supported on this architecture.
+    #[cfg(not(any(target_arch = "x86_64", target_arch = "aarch64")))]
+    pub fn request(_: usize, _: *const u8, _: usize) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn poison_unpoison() {
+        // Outside of a memory checker, the annotations have no effect.
+        let mut x = Box::new([1u8; 16]);
+        poison(x.as_ptr(), 16);
+        unpoison(x.as_ptr(), 16);
+        x[3] = 2;
+        assert_eq!(x[3], 2);
+    }
+}
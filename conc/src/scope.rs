@@ -0,0 +1,317 @@
+//! Scoped reclamation of non-`'static` data.
+//!
+//! `conc::add_garbage` requires the garbage to be `'static`, as it might be destroyed at any time
+//! later, possibly by another thread. A scope lifts this restriction: The garbage retired into the
+//! scope can borrow from the environment of the scope, as the scope destroys all of it before it
+//! ends. The guards protecting scoped data can't outlive the scope either.
+//!
+//! The garbage of a scope isn't exported to the global state, but it is protected by hazards like
+//! any other garbage. Unlike the hazards of `Guard`s, which a thread caches while they keep
+//! protecting their pointer, the hazard of a `ScopedGuard` is freed as soon as the guard drops.
+//! Hence, no thread (including long-lived ones, e.g. of a thread pool) keeps protecting scoped
+//! garbage, after its guards are gone, and the scope doesn't wait for it to cache more guards.
+//!
+//! When the scope ends, no `ScopedGuard` can be alive anymore, unless it was forgotten through
+//! `mem::forget`. Such a guard protects its garbage forever, so the scope only waits a bounded
+//! number of rounds for protected garbage and leaks the rest, instead of hanging.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::atomic::{AtomicPtr, Ordering};
+//!
+//! struct Token<'a> {
+//!     text: &'a str,
+//! }
+//!
+//! let text = String::from("hello world");
+//!
+//! conc::scope(|s| {
+//!     let current = AtomicPtr::new(Box::into_raw(Box::new(Token { text: &text[..5] })));
+//!
+//!     let guard = s.guard(|| unsafe { current.load(Ordering::Acquire).as_ref() }).unwrap();
+//!     let old = current.swap(Box::into_raw(Box::new(Token { text: &text[6..] })), Ordering::AcqRel);
+//!     unsafe { s.add_garbage_box(old); }
+//!
+//!     // The old token is protected by the guard.
+//!     assert_eq!(guard.text, "hello");
+//!
+//!     drop(guard);
+//!     unsafe { s.add_garbage_box(current.load(Ordering::Acquire)); }
+//! });
+//! ```
+
+use parking_lot::Mutex;
+use std::marker::PhantomData;
+use std::{mem, ops, thread};
+use garbage::Garbage;
+use {debug, global, guard, hazard, local};
+
+/// The number of rounds without progress, after which a dropping scope leaks its garbage.
+const PATIENCE: usize = 100;
+
+/// Run `f` in a new scope.
+///
+/// When `f` returns (or panics), the scope waits for all the garbage retired into it to become
+/// unprotected, and destroys it. Garbage which stays protected (e.g. by a forgotten guard) is
+/// leaked after a while.
+///
+/// See the module documentation for details.
+pub fn scope<'env, F, R>(f: F) -> R
+where F: FnOnce(&Scope<'env>) -> R {
+    let scope = Scope {
+        garbage: Mutex::new(Vec::new()),
+        _env: PhantomData,
+    };
+
+    f(&scope)
+}
+
+/// A scope for reclaiming data borrowing from the environment.
+///
+/// `'env` is the lifetime of the data borrowed by the garbage.
+pub struct Scope<'env> {
+    /// The garbage retired into the scope.
+    garbage: Mutex<Vec<Garbage>>,
+    /// Make the scope invariant over `'env`.
+    _env: PhantomData<&'env mut &'env ()>,
+}
+
+impl<'env> Scope<'env> {
+    /// Declare a pointer unreachable garbage to be deleted eventually.
+    ///
+    /// This acts like `conc::add_garbage`, except that `ptr` only has to live as long as the
+    /// scope, and that it is destroyed before the scope ends.
+    pub fn add_garbage<T: Sync>(&self, ptr: &'env T, dtor: fn(&'env T)) {
+        ::retire::<T>(ptr);
+        // The garbage is destroyed before the scope ends, so `ptr` outlives it.
+        self.garbage.lock().push(unsafe { Garbage::new_ref(ptr, dtor) });
+    }
+
+    /// Add a heap-allocated `Box<T>` as garbage.
+    ///
+    /// This acts like `conc::add_garbage_box`, except that `T` only has to live as long as the
+    /// scope, and that it is destroyed before the scope ends.
+    ///
+    /// # Safety
+    ///
+    /// This is unsafe for the same reasons as `conc::add_garbage_box`.
+    pub unsafe fn add_garbage_box<T: 'env>(&self, ptr: *const T) {
+        ::retire::<T>(ptr);
+        self.garbage.lock().push(Garbage::new_box(ptr));
+    }
+
+    /// Create a guard protecting a pointer from being destroyed.
+    ///
+    /// This acts like `Guard::maybe_new`, except that the pointer only has to live as long as the
+    /// scope, and so does the guard.
+    pub fn guard<'s, T: 'env + ?Sized, F>(&'s self, ptr: F) -> Option<ScopedGuard<'s, T>>
+    where F: FnOnce() -> Option<&'env T> {
+        guard::protect(|| ptr().ok_or(())).ok().map(|(hazard, ptr)| ScopedGuard {
+            hazard: hazard,
+            pointer: ptr,
+            _held: debug::Held::new(ptr as *const T as *const u8),
+        })
+    }
+
+    /// Destroy the unprotected garbage of the scope.
+    ///
+    /// This doesn't wait for the protected garbage to become unprotected. It is useful for keeping
+    /// the memory usage down in long-running scopes.
+    pub fn gc(&self) {
+        // The hazards of the guards dropped in this thread might still protect the garbage.
+        local::free_hazards();
+
+        // Take out the garbage, such that destructors can retire new garbage into the scope.
+        let mut garbage = mem::replace(&mut *self.garbage.lock(), Vec::new());
+        global::destroy_unprotected(&mut garbage);
+        self.garbage.lock().append(&mut garbage);
+    }
+}
+
+impl<'env> Drop for Scope<'env> {
+    fn drop(&mut self) {
+        // Destroy all the garbage, waiting for it to become unprotected. Destructors might retire
+        // new garbage, so we go on until the scope is empty, or until we stop making progress.
+        let mut idle = 0;
+        loop {
+            let before = self.garbage.get_mut().len();
+            self.gc();
+            let after = self.garbage.get_mut().len();
+
+            if after == 0 {
+                break;
+            }
+
+            if after < before {
+                idle = 0;
+            } else {
+                idle += 1;
+            }
+
+            if idle == PATIENCE {
+                // The remaining garbage is most likely protected by a forgotten guard, so we
+                // can't destroy it. Leaking it is safe, as no destructor will ever touch the
+                // environment.
+                debug::exec(|| println!("Leaking {} protected objects of a scope.", after));
+                mem::forget(mem::replace(self.garbage.get_mut(), Vec::new()));
+                break;
+            }
+
+            thread::yield_now();
+        }
+    }
+}
+
+/// A guard protecting scoped data from being destroyed.
+///
+/// This is the scoped counterpart of `Guard<T>`.
+#[must_use]
+pub struct ScopedGuard<'s, T: 's + ?Sized> {
+    /// The inner hazard.
+    hazard: hazard::Writer,
+    /// The pointer to the protected object.
+    pointer: &'s T,
+    /// The registration of the guard as held by the thread, which created it.
+    _held: debug::Held,
+}
+
+impl<'s, T: ?Sized> ScopedGuard<'s, T> {
+    /// Get the raw pointer of this guard.
+    pub fn as_ptr(&self) -> *const T {
+        self.pointer
+    }
+}
+
+impl<'s, T: ?Sized> Drop for ScopedGuard<'s, T> {
+    fn drop(&mut self) {
+        // The scope waits for its garbage to become unprotected before it ends, so the hazard
+        // must not keep protecting the pointer in the cache of this thread.
+        self.hazard.free();
+    }
+}
+
+impl<'s, T: ?Sized> ops::Deref for ScopedGuard<'s, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.pointer
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+
+    struct Counted<'a> {
+        drops: &'a AtomicUsize,
+    }
+
+    impl<'a> Drop for Counted<'a> {
+        fn drop(&mut self) {
+            self.drops.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn destroyed_at_end() {
+        let drops = AtomicUsize::new(0);
+
+        scope(|s| {
+            for _ in 0..100 {
+                unsafe { s.add_garbage_box(Box::into_raw(Box::new(Counted { drops: &drops }))); }
+            }
+        });
+
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 100);
+    }
+
+    #[test]
+    fn guard_protects() {
+        let drops = AtomicUsize::new(0);
+
+        scope(|s| {
+            let ptr = AtomicPtr::new(Box::into_raw(Box::new(Counted { drops: &drops })));
+            let guard = s.guard(|| unsafe { ptr.load(atomic::Ordering::Acquire).as_ref() }).unwrap();
+            unsafe { s.add_garbage_box(ptr.load(atomic::Ordering::Acquire)); }
+
+            s.gc();
+            assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+            assert_eq!(guard.drops.load(atomic::Ordering::Relaxed), 0);
+
+            drop(guard);
+            s.gc();
+            assert_eq!(drops.load(atomic::Ordering::Relaxed), 1);
+        });
+    }
+
+    #[test]
+    fn add_garbage() {
+        fn dtor(x: &AtomicUsize) {
+            x.fetch_add(1, atomic::Ordering::Relaxed);
+        }
+
+        let n = AtomicUsize::new(0);
+        scope(|s| s.add_garbage(&n, dtor));
+        assert_eq!(n.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn threads() {
+        let drops = AtomicUsize::new(0);
+
+        scope(|s| thread::scope(|t| {
+            for _ in 0..4 {
+                t.spawn(|| for _ in 0..100 {
+                    unsafe { s.add_garbage_box(Box::into_raw(Box::new(Counted { drops: &drops }))); }
+                });
+            }
+        }));
+
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 400);
+    }
+
+    #[test]
+    fn dropped_guard_of_live_thread() {
+        use std::sync::Barrier;
+
+        let drops = AtomicUsize::new(0);
+        let barrier = Barrier::new(2);
+
+        scope(|s| {
+            let ptr = AtomicPtr::new(Box::into_raw(Box::new(Counted { drops: &drops })));
+
+            thread::scope(|t| {
+                t.spawn(|| {
+                    let guard = s.guard(|| unsafe { ptr.load(atomic::Ordering::Acquire).as_ref() });
+                    drop(guard);
+                    barrier.wait();
+                    // Stay alive until the garbage was collected.
+                    barrier.wait();
+                });
+
+                barrier.wait();
+                unsafe { s.add_garbage_box(ptr.load(atomic::Ordering::Acquire)); }
+                s.gc();
+                let destroyed = drops.load(atomic::Ordering::Relaxed);
+                barrier.wait();
+
+                assert_eq!(destroyed, 1);
+            });
+        });
+    }
+
+    #[test]
+    fn forgotten_guard_leaks() {
+        let drops = AtomicUsize::new(0);
+
+        scope(|s| {
+            let ptr = Box::into_raw(Box::new(Counted { drops: &drops }));
+            mem::forget(s.guard(|| unsafe { ptr.as_ref() }));
+            unsafe { s.add_garbage_box(ptr); }
+        });
+
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 0);
+    }
+}
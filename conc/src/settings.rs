@@ -1,12 +1,34 @@
 //! Settings and presets.
 
 use std::cell::Cell;
+use std::thread;
 
 thread_local! {
     /// The settings for the current thread.
     static LOCAL_SETTINGS: Cell<Settings> = Cell::new(Settings::default())
 }
 
+/// What to do when a destructor panics during garbage collection.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum PanicPolicy {
+    /// Abort the process.
+    ///
+    /// Unwinding out of a collection leaves it half-finished, so for some applications, it is
+    /// safer to abort right away.
+    Abort,
+    /// Propagate the panic to the thread collecting the garbage.
+    ///
+    /// The collection is stopped, and the collector is poisoned (see `conc::clear_poison()`). The
+    /// rest of the unprotected garbage is destroyed by the first collection after the poison is
+    /// cleared.
+    Propagate,
+    /// Catch the panic and continue the collection.
+    ///
+    /// The panicking destructor is considered run, and the rest of the garbage is destroyed as
+    /// usual.
+    Isolate,
+}
+
 /// Settings for the system.
 #[derive(Copy, Clone, PartialEq, Eq, Debug)]
 pub struct Settings {
@@ -23,12 +45,72 @@ pub struct Settings {
     /// When the local state's garbage queue exceeds this limit, it exports it to the global
     /// garbage queue.
     pub max_garbage_before_export: usize,
+    /// The amount of garbage cached by a thread, at which retiring scans the hazards.
+    ///
+    /// When the local garbage queue grows by this much since the last scan, retiring takes a
+    /// snapshot of the hazards and destroys the garbage, which nothing protects, right away. Only
+    /// the protected garbage stays queued, so the garbage pending is kept near the number of
+    /// active hazards. The hazards are read without waiting for the collector, and if one of them
+    /// is blocked, nothing is destroyed.
+    ///
+    /// `0` disables the scans, which is the default, as each scan reads every hazard slot.
+    pub scan_threshold: usize,
     /// The maximal amount of non-free hazards in the thread-local cache.
     ///
     /// When it exceeds this limit, it will clean up the cached hazards. With "cleaning up" we mean
     /// setting the state of the hazards to "free" in order to allow garbage collection of the
     /// object it is currently protecting.
     pub max_non_free_hazards: usize,
+    /// The maximal number of hazards to create at once.
+    ///
+    /// When the thread-local cache of hazards is empty, a batch of new hazards is created and
+    /// registered in the global state through a single operation, and the surplus is put in the
+    /// cache. This amortizes the cost of the registration.
+    ///
+    /// The batches start out with a single hazard and double in size until they reach this
+    /// limit.
+    pub hazard_batch_size: usize,
+    /// The number of rounds of spinning before yielding in spin loops.
+    ///
+    /// Internal spin loops (e.g. waiting for a blocked hazard or for another thread's garbage
+    /// collection) back off exponentially, spinning `2^n` times in round `n`. After this many
+    /// rounds, they yield the thread's time slice instead, which is considerably friendlier to
+    /// oversubscribed systems.
+    ///
+    /// `0` means that spin loops yield immediately.
+    pub spin_rounds_before_yield: u32,
+    /// The number of threads destroying the garbage flagged as parallel.
+    ///
+    /// When a collection has lots of garbage, whose destructors are safe to run concurrently (see
+    /// `conc::add_garbage_parallel()`), it is split among this many threads (including the
+    /// collecting thread itself).
+    ///
+    /// `0` and `1` mean that all the garbage is destroyed by the collecting thread.
+    pub destructor_threads: usize,
+    /// Destroy the values replaced by `Atomic::store()` right away, if they're unprotected.
+    ///
+    /// Rather than queuing the old value as garbage, the hazards are checked for it, and if none
+    /// protects it, it is destroyed in the storing thread. This keeps the garbage near zero when
+    /// values are rarely protected (e.g. with a single writer and few readers), but checking the
+    /// hazards is costly: It scans every hazard and issues the collector side of the fence.
+    ///
+    /// This applies to `store()` and `compare_and_store()`, whereas the values replaced by the
+    /// swapping operations are protected by the guards returned.
+    pub reclaim_on_store: bool,
+    /// What to do when a destructor panics.
+    ///
+    /// The policy of the thread collecting the garbage applies, regardless of which thread added
+    /// the garbage.
+    pub on_dtor_panic: PanicPolicy,
+    /// The number of collections, after which protected garbage is considered stuck.
+    ///
+    /// The collector counts the collections, which each garbage object survived, because it was
+    /// protected. When it reaches this, the collector looks up what protects it, and reports it
+    /// (see `stall::stuck()`). As with `on_dtor_panic`, the setting of the collecting thread
+    /// applies.
+    ///
+    /// `0` disables the aging of garbage.
+    pub stuck_after: usize,
 }
 
 impl Default for Settings {
@@ -36,7 +118,14 @@ impl Default for Settings {
         Settings {
             gc_probability: (!0) / 128,
             max_garbage_before_export: 64,
+            scan_threshold: 0,
             max_non_free_hazards: 16,
+            hazard_batch_size: 8,
+            spin_rounds_before_yield: 6,
+            destructor_threads: 1,
+            reclaim_on_store: false,
+            on_dtor_panic: PanicPolicy::Propagate,
+            stuck_after: 64,
         }
     }
 }
@@ -47,7 +136,14 @@ impl Settings {
         Settings {
             gc_probability: (!0) / 32,
             max_garbage_before_export: 16,
+            scan_threshold: 8,
             max_non_free_hazards: 4,
+            hazard_batch_size: 2,
+            spin_rounds_before_yield: 10,
+            destructor_threads: 1,
+            reclaim_on_store: false,
+            on_dtor_panic: PanicPolicy::Propagate,
+            stuck_after: 256,
         }
     }
 
@@ -56,7 +152,14 @@ impl Settings {
         Settings {
             gc_probability: (!0) / 256,
             max_garbage_before_export: 128,
+            scan_threshold: 0,
             max_non_free_hazards: 32,
+            hazard_batch_size: 16,
+            spin_rounds_before_yield: 4,
+            destructor_threads: 1,
+            reclaim_on_store: false,
+            on_dtor_panic: PanicPolicy::Propagate,
+            stuck_after: 32,
         }
     }
 
@@ -66,6 +169,7 @@ impl Settings {
     /// can still be propagated and destroyed, it will just not happen in this thread.
     pub fn disable_automatic_gc(&mut self) {
         self.gc_probability = 0;
+        self.scan_threshold = 0;
     }
 
     /// Disable automatic exportation.
@@ -77,12 +181,129 @@ impl Settings {
         // than one byte) queue would have to fill more than the whole memory space, which is
         // obviously impossible.
         self.max_garbage_before_export = !0;
+        // Scans destroy the garbage locally, so they are disabled as well.
+        self.scan_threshold = 0;
+    }
+
+    /// Start building settings from the defaults.
+    ///
+    /// This is an alternative to struct update syntax, which names the tunables in terms of their
+    /// effect (e.g. the GC interval rather than the GC probability).
+    pub fn builder() -> Builder {
+        Builder {
+            settings: Settings::default(),
+        }
+    }
+}
+
+/// A builder of `Settings`.
+///
+/// The settings are built all at once, so they can be installed with a single `set_local()`, e.g.
+/// at the start of every thread of a pool. There is a single reclamation domain, so the settings
+/// are still per thread, and the memory budget is set through `budget`.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Builder {
+    /// The settings built so far.
+    settings: Settings,
+}
+
+impl Builder {
+    /// Collect garbage about once in `ticks` ticks.
+    ///
+    /// `0` disables automatic garbage collection. This sets `gc_probability`.
+    pub fn gc_interval(mut self, ticks: usize) -> Builder {
+        self.settings.gc_probability = if ticks == 0 { 0 } else { !0 / ticks };
+        self
+    }
+
+    /// Set the maximal amount of garbage before exportation.
+    ///
+    /// This sets `max_garbage_before_export`.
+    pub fn max_garbage_before_export(mut self, max: usize) -> Builder {
+        self.settings.max_garbage_before_export = max;
+        self
+    }
+
+    /// Scan the hazards on retirement every `threshold` garbage objects.
+    ///
+    /// `0` disables the scans. This sets `scan_threshold`.
+    pub fn scan_threshold(mut self, threshold: usize) -> Builder {
+        self.settings.scan_threshold = threshold;
+        self
+    }
+
+    /// Set the maximal number of non-free hazards in the thread-local cache.
+    ///
+    /// This sets `max_non_free_hazards`.
+    pub fn hazard_cap(mut self, cap: usize) -> Builder {
+        self.settings.max_non_free_hazards = cap;
+        self
+    }
+
+    /// Set the maximal number of hazards created at once.
+    ///
+    /// This sets `hazard_batch_size`.
+    pub fn hazard_batch_size(mut self, size: usize) -> Builder {
+        self.settings.hazard_batch_size = size;
+        self
+    }
+
+    /// Set the number of rounds of spinning before yielding.
+    ///
+    /// This sets `spin_rounds_before_yield`.
+    pub fn spin_rounds_before_yield(mut self, rounds: u32) -> Builder {
+        self.settings.spin_rounds_before_yield = rounds;
+        self
+    }
+
+    /// Set the number of threads destroying parallel garbage.
+    ///
+    /// This sets `destructor_threads`.
+    pub fn destructor_threads(mut self, threads: usize) -> Builder {
+        self.settings.destructor_threads = threads;
+        self
+    }
+
+    /// Set whether unprotected values replaced by `Atomic::store()` are destroyed right away.
+    ///
+    /// This sets `reclaim_on_store`.
+    pub fn reclaim_on_store(mut self, reclaim: bool) -> Builder {
+        self.settings.reclaim_on_store = reclaim;
+        self
+    }
+
+    /// Set what to do when a destructor panics.
+    ///
+    /// This sets `on_dtor_panic`.
+    pub fn on_dtor_panic(mut self, policy: PanicPolicy) -> Builder {
+        self.settings.on_dtor_panic = policy;
+        self
+    }
+
+    /// Set the number of collections, after which protected garbage is considered stuck.
+    ///
+    /// This sets `stuck_after`.
+    pub fn stuck_after(mut self, collections: usize) -> Builder {
+        self.settings.stuck_after = collections;
+        self
+    }
+
+    /// Get the settings built.
+    pub fn build(self) -> Settings {
+        self.settings
     }
 }
 
 /// Get the settings of the current thread.
+///
+/// If the thread-local settings were deinitialized (i.e. the thread is exiting), the default
+/// settings are returned.
 pub fn get() -> Settings {
-    LOCAL_SETTINGS.with(|x| x.get())
+    if LOCAL_SETTINGS.state() == thread::LocalKeyState::Destroyed {
+        Settings::default()
+    } else {
+        LOCAL_SETTINGS.with(|x| x.get())
+    }
 }
 
 /// Set the settings for the current thread.
@@ -99,7 +320,7 @@ pub fn set_local(settings: Settings) {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::thread;
+    use std::{ptr, thread};
     use {Garbage, local};
 
     #[test]
@@ -133,7 +354,7 @@ mod tests {
         set_local(settings);
 
         for _ in 0..100000 {
-            local::add_garbage(Garbage::new(0x1 as *const u8, dtor));
+            local::add_garbage(Garbage::new(ptr::without_provenance(0x1), dtor));
             assert!(!X.with(|x| x.get()));
         }
 
@@ -163,6 +384,27 @@ mod tests {
         set_local(Settings::default());
     }
 
+    #[test]
+    fn builder() {
+        assert_eq!(Settings::builder().build(), Settings::default());
+
+        let settings = Settings::builder()
+            .gc_interval(32)
+            .max_garbage_before_export(16)
+            .scan_threshold(4)
+            .hazard_cap(4)
+            .on_dtor_panic(PanicPolicy::Isolate)
+            .build();
+        assert_eq!(settings.gc_probability, (!0) / 32);
+        assert_eq!(settings.max_garbage_before_export, 16);
+        assert_eq!(settings.scan_threshold, 4);
+        assert_eq!(settings.max_non_free_hazards, 4);
+        assert_eq!(settings.on_dtor_panic, PanicPolicy::Isolate);
+        assert_eq!(settings.hazard_batch_size, Settings::default().hazard_batch_size);
+
+        assert_eq!(Settings::builder().gc_interval(0).build().gc_probability, 0);
+    }
+
     #[test]
     fn compare_presets() {
         let low = Settings::low_memory();
@@ -171,5 +413,7 @@ mod tests {
         assert!(low.gc_probability > high.gc_probability);
         assert!(high.max_garbage_before_export > low.max_garbage_before_export);
         assert!(high.max_non_free_hazards > low.max_non_free_hazards);
+        assert!(high.hazard_batch_size > low.hazard_batch_size);
+        assert!(low.spin_rounds_before_yield > high.spin_rounds_before_yield);
     }
 }
@@ -0,0 +1,260 @@
+//! Tagged pointers bound to a protection.
+//!
+//! # Tag bits
+//!
+//! The tag is stored in the low bits of the pointer, which are zero due to the alignment of the
+//! pointee, so a pointer to `T` has `Shared::<T>::tag_bits()` bits of tag, which is the base-2
+//! logarithm of the alignment of `T`. Note that the alignment of many types depends on the target:
+//! For example, `usize` has 3 bits on 64-bit targets, but only 2 bits on 32-bit targets.
+//!
+//! If more tag bits are needed, raise the alignment of the pointee with `#[repr(align(N))]`, or
+//! wrap it in `Align8` or `Align16`. As `tag_bits()` is a `const fn`, the requirement can be
+//! checked at compile time:
+//!
+//! ```rust
+//! #[repr(align(8))]
+//! struct Node {
+//!     value: u32,
+//! }
+//!
+//! const _: () = assert!(conc::Shared::<Node>::tag_bits() >= 3);
+//! ```
+
+use std::marker::PhantomData;
+use std::{fmt, mem, ops, ptr};
+
+/// A value aligned to 8 bytes, such that pointers to it have (at least) 3 tag bits.
+#[repr(align(8))]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+pub struct Align8<T>(pub T);
+
+/// A value aligned to 16 bytes, such that pointers to it have (at least) 4 tag bits.
+#[repr(align(16))]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Default, Debug)]
+pub struct Align16<T>(pub T);
+
+impl<T> ops::Deref for Align8<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Align8<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+impl<T> ops::Deref for Align16<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T> ops::DerefMut for Align16<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        &mut self.0
+    }
+}
+
+/// Get the mask of the tag bits of pointers to `T`.
+///
+/// The tag is stored in the low bits, which are always zero due to the alignment of `T`.
+fn tag_mask<T>() -> usize {
+    Shared::<T>::max_tag()
+}
+
+/// Strip the tag off a pointer.
+///
+/// Only the address is changed, so the result keeps the provenance of `ptr`.
+pub(crate) fn untagged<T>(ptr: *mut T) -> *mut T {
+    ptr.map_addr(|addr| addr & !tag_mask::<T>())
+}
+
+/// A tagged, possibly null pointer, whose pointee is protected for `'g`.
+///
+/// This is a plain pointer, which can be copied and compared without dereferencing it (or creating
+/// guards), making it suitable for juggling pointers between loads and compare-and-swaps in
+/// algorithms like lock-free lists, which mark pointers through their tags.
+///
+/// The tag is stored in the unused low bits of the pointer, so it can take values up to
+/// `max_tag()` (see the module documentation). The pointer is protected, as long as the source it
+/// was obtained from (see `Atomic::load_shared()` and `Guard::shared()`) is borrowed.
+pub struct Shared<'g, T: 'g> {
+    /// The pointer with the tag in its low bits.
+    ///
+    /// This is kept as a pointer rather than an integer, such that the provenance isn't lost.
+    data: *mut T,
+    /// Bind the pointer to the protection and make it act like a reference to `T`.
+    _marker: PhantomData<&'g T>,
+}
+
+impl<'g, T> Shared<'g, T> {
+    /// Get the number of tag bits of pointers to `T`.
+    ///
+    /// This is the base-2 logarithm of the alignment of `T`.
+    pub const fn tag_bits() -> u32 {
+        mem::align_of::<T>().trailing_zeros()
+    }
+
+    /// Get the maximal tag of pointers to `T`.
+    pub const fn max_tag() -> usize {
+        mem::align_of::<T>() - 1
+    }
+
+    /// Create a null pointer with tag `0`.
+    pub fn null() -> Shared<'g, T> {
+        Shared::from_raw(ptr::null_mut())
+    }
+
+    /// Create a pointer from a raw (possibly tagged) pointer.
+    pub(crate) fn from_raw(ptr: *mut T) -> Shared<'g, T> {
+        Shared {
+            data: ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    /// Move a box into a new, unprotected pointer.
+    ///
+    /// This is meant for inserting new values through `Atomic::compare_and_set_shared()`. The box
+    /// is leaked, unless it is stored or taken back with `into_box()`. As the pointer isn't
+    /// protected, `'g` is unbounded, and dereferencing it through `as_ref()` is up to the caller.
+    pub fn from_box(value: Box<T>) -> Shared<'g, T> {
+        Shared::from_raw(Box::into_raw(value))
+    }
+
+    /// Take back the box of a pointer created through `from_box()`.
+    ///
+    /// # Safety
+    ///
+    /// The pointer must be created through `from_box()` and must not have been stored in an
+    /// `Atomic` or taken back already.
+    pub unsafe fn into_box(self) -> Box<T> {
+        Box::from_raw(self.as_raw() as *mut T)
+    }
+
+    /// Get the raw pointer without the tag.
+    pub fn as_raw(&self) -> *const T {
+        untagged(self.data)
+    }
+
+    /// Get the raw pointer with the tag.
+    pub(crate) fn as_tagged(&self) -> *mut T {
+        self.data
+    }
+
+    /// Is the pointer null?
+    ///
+    /// The tag is ignored, so a null pointer with a tag is null as well.
+    pub fn is_null(&self) -> bool {
+        self.as_raw().is_null()
+    }
+
+    /// Get the tag.
+    pub fn tag(&self) -> usize {
+        self.data.addr() & tag_mask::<T>()
+    }
+
+    /// Get the same pointer with another tag.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `tag` exceeds `max_tag()`, as it would otherwise corrupt the pointer.
+    pub fn with_tag(&self, tag: usize) -> Shared<'g, T> {
+        assert!(
+            tag <= Shared::<T>::max_tag(),
+            "Tag {} doesn't fit in the {} tag bit(s) of the pointer.",
+            tag,
+            Shared::<T>::tag_bits()
+        );
+
+        Shared::from_raw(untagged(self.data).map_addr(|addr| addr | tag))
+    }
+
+    /// Get a reference to the pointee, or `None` if the pointer is null.
+    ///
+    /// # Safety
+    ///
+    /// The pointee must be valid for `'g`. This holds for pointers obtained from a protection
+    /// (e.g. `Atomic::load_shared()`), but not for pointers created through `from_box()`, which
+    /// aren't bound to anything, and might be destroyed as soon as they are stored.
+    pub unsafe fn as_ref(&self) -> Option<&'g T> {
+        self.as_raw().as_ref()
+    }
+}
+
+// The raw pointer makes `Shared` neither `Send` nor `Sync`, but it acts like `&'g T`.
+unsafe impl<'g, T: Sync> Send for Shared<'g, T> {}
+unsafe impl<'g, T: Sync> Sync for Shared<'g, T> {}
+
+impl<'g, T> Clone for Shared<'g, T> {
+    fn clone(&self) -> Shared<'g, T> {
+        *self
+    }
+}
+
+impl<'g, T> Copy for Shared<'g, T> {}
+
+impl<'g, T> PartialEq for Shared<'g, T> {
+    fn eq(&self, other: &Shared<'g, T>) -> bool {
+        self.data == other.data
+    }
+}
+
+impl<'g, T> Eq for Shared<'g, T> {}
+
+impl<'g, T> fmt::Debug for Shared<'g, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Shared").field("ptr", &self.as_raw()).field("tag", &self.tag()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tags() {
+        let value = Align8(42u64);
+        let ptr = Shared::from_raw(&value as *const Align8<u64> as *mut Align8<u64>);
+        assert_eq!(ptr.tag(), 0);
+
+        let tagged = ptr.with_tag(5);
+        assert_eq!(tagged.tag(), 5);
+        assert_eq!(tagged.as_raw(), ptr.as_raw());
+        assert_eq!(**unsafe { tagged.as_ref() }.unwrap(), 42);
+        assert!(tagged != ptr);
+        assert_eq!(tagged.with_tag(0), ptr);
+
+        assert_eq!(ptr.with_tag(7).tag(), 7);
+    }
+
+    #[test]
+    fn tag_bits() {
+        assert_eq!(Shared::<u8>::tag_bits(), 0);
+        assert_eq!(Shared::<u32>::tag_bits(), 2);
+        assert_eq!(Shared::<Align8<u8>>::tag_bits(), 3);
+        assert_eq!(Shared::<Align16<u8>>::max_tag(), 15);
+        assert_eq!(Shared::<usize>::tag_bits(), mem::size_of::<usize>().trailing_zeros());
+    }
+
+    #[test]
+    #[should_panic]
+    fn tag_overflow() {
+        let value = Align8(42u8);
+        Shared::from_raw(&value as *const Align8<u8> as *mut Align8<u8>).with_tag(8);
+    }
+
+    #[test]
+    fn null() {
+        let null = Shared::<Align8<u64>>::null();
+        assert!(null.is_null());
+        assert!(null.with_tag(3).is_null());
+        assert!(unsafe { null.as_ref() }.is_none());
+    }
+}
@@ -0,0 +1,284 @@
+//! Detection of stalled reclamation.
+//!
+//! Garbage, which is never reclaimed, is silent: The memory use grows, until the process runs out
+//! of it. The usual causes are a leaked guard, a long-lived guard protecting the object that
+//! everything else hangs off, or garbage collections, which never run.
+//!
+//! The watchdog of this module watches the number of garbage objects pending in the global state.
+//! If it has grown without ever decreasing for longer than a window, it invokes a callback, which
+//! can then e.g. log `debug::dump()` or the statistics of `stats::destructors()`.
+//!
+//! The watchdog piggybacks on the operations: The pending garbage is sampled whenever garbage is
+//! exported, or a collection finishes. Hence, no timer is needed, and a stall is only reported,
+//! while garbage is being retired.
+//!
+//! A single object can be stuck as well, while the rest of the garbage is reclaimed. Hence, the
+//! collector counts the collections, which each garbage object survived. When an object reaches
+//! `stuck_after` (see `Settings`) collections, the collector looks up what protects it, and reports
+//! it as a debug event, and to the watchdog, which then doesn't wait for the window. `stuck()`
+//! lists the stuck garbage, along with the hazards, pins and (with `debug-tools`) guards
+//! protecting it.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::time::Duration;
+//!
+//! fn alarm(stall: conc::stall::Stall) {
+//!     eprintln!("{} garbage objects piled up in {:?}.", stall.growth, stall.duration);
+//! }
+//!
+//! conc::stall::set_watchdog(Duration::from_secs(60), alarm);
+//!
+//! // ...
+//!
+//! conc::stall::remove_watchdog();
+//! ```
+
+use parking_lot::{self, Mutex};
+use std::sync::atomic::{self, AtomicBool};
+use std::time::{Duration, Instant};
+use global;
+
+/// Is a watchdog set?
+///
+/// This allows for sampling without taking the lock.
+static WATCHING: AtomicBool = AtomicBool::new(false);
+/// The watchdog.
+static WATCHDOG: Mutex<Option<Watchdog>> = parking_lot::const_mutex(None);
+
+/// A stall of the reclamation.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct Stall {
+    /// For how long the pending garbage has grown.
+    pub duration: Duration,
+    /// The number of garbage objects, by which the pending garbage has grown meanwhile.
+    pub growth: usize,
+    /// The number of garbage objects pending.
+    pub pending: usize,
+    /// The number of garbage objects, which got stuck since the last invocation of the callback.
+    ///
+    /// See `stuck()`.
+    pub stuck: usize,
+}
+
+/// Garbage, which stayed protected for many collections.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct Stuck {
+    /// The address of the garbage.
+    pub ptr: usize,
+    /// The number of collections, which the garbage survived.
+    pub collections: usize,
+    /// The number of hazards protecting the garbage.
+    ///
+    /// The clones of a long guard (see `Guard::pin_long()`) share a single hazard.
+    pub hazards: usize,
+    /// Descriptions of the guards protecting the garbage.
+    ///
+    /// Each guard is described by the thread, which created it, and the backtrace of its creation,
+    /// if `CONC_DEBUG_STACKTRACE` is set. The guards are only tracked, when compiled with
+    /// `debug-tools`, so this is empty otherwise.
+    pub guards: Vec<String>,
+}
+
+/// A watchdog of the pending garbage.
+struct Watchdog {
+    /// The time, for which the pending garbage must grow to be considered stalled.
+    window: Duration,
+    /// The callback invoked, when the reclamation is stalled.
+    callback: fn(Stall),
+    /// Since when the pending garbage has grown.
+    since: Instant,
+    /// The number of garbage objects pending at `since`.
+    base: usize,
+    /// The number of garbage objects pending at the last sample.
+    last: usize,
+    /// When the callback was last invoked.
+    last_alarm: Option<Instant>,
+    /// The number of garbage objects, which got stuck since start, at the last invocation.
+    stuck: usize,
+}
+
+impl Watchdog {
+    /// Sample `pending` at `now`.
+    ///
+    /// If the pending garbage decreased since the last sample, the growth starts over.
+    fn sample(&mut self, now: Instant, pending: usize) {
+        if pending < self.last {
+            self.since = now;
+            self.base = pending;
+            self.last_alarm = None;
+        }
+
+        self.last = pending;
+    }
+
+    /// Sample `pending` at `now`, returning the stall, if the callback is to be invoked.
+    ///
+    /// `stuck` is the number of garbage objects, which got stuck since start. The callback is
+    /// invoked at most once every window, while the reclamation is stalled, and right away, when
+    /// garbage got stuck.
+    fn poll(&mut self, now: Instant, pending: usize, stuck: usize) -> Option<Stall> {
+        self.sample(now, pending);
+
+        let duration = now.saturating_duration_since(self.since);
+        let stalled = duration > self.window && pending > self.base;
+        if !stalled && stuck <= self.stuck {
+            return None;
+        }
+
+        if let Some(last_alarm) = self.last_alarm {
+            if now.saturating_duration_since(last_alarm) < self.window && stuck <= self.stuck {
+                return None;
+            }
+        }
+
+        self.last_alarm = Some(now);
+        let new_stuck = stuck - self.stuck;
+        self.stuck = stuck;
+        Some(Stall {
+            duration: duration,
+            growth: pending.saturating_sub(self.base),
+            pending: pending,
+            stuck: new_stuck,
+        })
+    }
+}
+
+/// Set the watchdog, replacing the previous one.
+///
+/// `callback` is invoked, when the number of garbage objects pending has grown without decreasing
+/// for longer than `window`. It runs in the thread exporting garbage, at most once every `window`,
+/// while the reclamation is stalled.
+///
+/// `callback` is also invoked, when garbage got stuck (see `stuck()`), regardless of the window.
+///
+/// Only the garbage exported to the global state is watched, so a thread, which never exports its
+/// garbage, goes unnoticed (see `stats::threads()` for that).
+pub fn set_watchdog(window: Duration, callback: fn(Stall)) {
+    let pending = global::pending_items();
+    let stuck = global::stuck_items();
+    let _critical = global::Critical::new();
+    *WATCHDOG.lock() = Some(Watchdog {
+        window: window,
+        callback: callback,
+        since: Instant::now(),
+        base: pending,
+        last: pending,
+        last_alarm: None,
+        stuck: stuck,
+    });
+    WATCHING.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Remove the watchdog.
+pub fn remove_watchdog() {
+    WATCHING.store(false, atomic::Ordering::Relaxed);
+    let _critical = global::Critical::new();
+    *WATCHDOG.lock() = None;
+}
+
+/// Get the garbage, which is stuck.
+///
+/// Garbage is stuck, if it stayed protected for `stuck_after` (see `Settings`) collections. It is
+/// listed from the oldest to the youngest, along with what protects it.
+///
+/// This blocks until any ongoing garbage collection is done.
+pub fn stuck() -> Vec<Stuck> {
+    global::stuck_garbage()
+}
+
+/// Sample the pending garbage.
+///
+/// This shall be called when a collection finishes, such that decreases aren't missed.
+pub(crate) fn sample() {
+    if WATCHING.load(atomic::Ordering::Relaxed) {
+        let pending = global::pending_items();
+        let _critical = global::Critical::new();
+        if let Some(ref mut watchdog) = *WATCHDOG.lock() {
+            watchdog.sample(Instant::now(), pending);
+        }
+    }
+}
+
+/// Sample the pending garbage, invoking the callback if the reclamation is stalled.
+///
+/// This shall be called when garbage is exported.
+pub(crate) fn check() {
+    if !WATCHING.load(atomic::Ordering::Relaxed) || global::in_critical() {
+        return;
+    }
+
+    // Copy the callback out, such that it can set a new watchdog without deadlocking.
+    let pending = global::pending_items();
+    let stuck = global::stuck_items();
+    let alarm = {
+        let _critical = global::Critical::new();
+        let mut watchdog = WATCHDOG.lock();
+        watchdog.as_mut()
+            .and_then(|x| x.poll(Instant::now(), pending, stuck).map(|s| (x.callback, s)))
+    };
+
+    if let Some((callback, stall)) = alarm {
+        callback(stall);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn watchdog() {
+        fn callback(_: Stall) {}
+
+        let start = Instant::now();
+        let mut watchdog = Watchdog {
+            window: Duration::from_millis(10),
+            callback: callback,
+            since: start,
+            base: 100,
+            last: 100,
+            last_alarm: None,
+            stuck: 0,
+        };
+        let ms = |n| start + Duration::from_millis(n);
+
+        assert_eq!(watchdog.poll(ms(5), 110, 0), None);
+        assert_eq!(watchdog.poll(ms(15), 120, 0), Some(Stall {
+            duration: Duration::from_millis(15),
+            growth: 20,
+            pending: 120,
+            stuck: 0,
+        }));
+        // The alarm isn't raised again right away.
+        assert_eq!(watchdog.poll(ms(20), 130, 0), None);
+        assert_eq!(watchdog.poll(ms(25), 130, 0).map(|x| x.growth), Some(30));
+
+        // A decrease starts the growth over.
+        watchdog.sample(ms(30), 50);
+        assert_eq!(watchdog.poll(ms(35), 60, 0), None);
+        assert_eq!(watchdog.poll(ms(45), 60, 0).map(|x| x.growth), Some(10));
+
+        // Garbage, which doesn't grow, isn't a stall.
+        watchdog.sample(ms(50), 0);
+        assert_eq!(watchdog.poll(ms(100), 0, 0), None);
+
+        // Stuck garbage is reported right away, and only once.
+        assert_eq!(watchdog.poll(ms(101), 0, 2).map(|x| x.stuck), Some(2));
+        assert_eq!(watchdog.poll(ms(102), 0, 2), None);
+        assert_eq!(watchdog.poll(ms(103), 0, 3).map(|x| x.stuck), Some(1));
+    }
+
+    #[test]
+    fn set_remove() {
+        fn callback(_: Stall) {}
+
+        // This test touches the global watchdog, so we only use a window, which never triggers.
+        set_watchdog(Duration::from_secs(1 << 40), callback);
+        check();
+        sample();
+        remove_watchdog();
+        assert!(WATCHDOG.lock().is_none());
+    }
+}
@@ -0,0 +1,239 @@
+//! Runtime statistics.
+//!
+//! These are cheap to query, such that they can be sampled frequently (e.g. by a monitoring
+//! system or an admission controller). For a detailed, but more expensive, snapshot of the
+//! internal state, see `debug::dump()`.
+
+use std::thread::ThreadId;
+use {garbage, global, hazard, local};
+
+/// Get an estimate of the number of bytes of garbage awaiting reclamation.
+///
+/// This is maintained incrementally from the size hints of the garbage, so it is `O(1)`. It is
+/// accounted the same way as the memory budget (see `budget`): Garbage of unknown size isn't
+/// counted, and garbage is counted once it is exported from the local state of the thread, which
+/// retired it.
+pub fn pending_bytes() -> usize {
+    global::pending_bytes()
+}
+
+/// The highest amount of garbage pending at once.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct HighWater {
+    /// The highest number of garbage objects pending.
+    pub items: usize,
+    /// The highest number of bytes of garbage pending.
+    pub bytes: usize,
+}
+
+/// Get the highest amount of garbage pending since start or the last `reset_high_water()`.
+///
+/// The garbage is accounted like in `pending_bytes()`, and the peaks of the items and bytes are
+/// tracked independently. The marks are updated whenever garbage is exported, so short spikes
+/// aren't missed.
+pub fn high_water() -> HighWater {
+    let (items, bytes) = global::high_water();
+    HighWater {
+        items: items,
+        bytes: bytes,
+    }
+}
+
+/// Reset the high-water marks to the amount of garbage currently pending.
+pub fn reset_high_water() {
+    global::reset_high_water();
+}
+
+/// The numbers of hazards in each state.
+///
+/// A hazard is what a guard uses to protect its object from being destroyed.
+#[derive(Copy, Clone, PartialEq, Eq, Debug, Default)]
+pub struct HazardStats {
+    /// The number of hazards protecting an object.
+    ///
+    /// The hazards of dropped guards are cached by their thread, and keep protecting their object
+    /// until the cache is cleaned up (see `Settings::max_non_free_hazards`), so this overestimates
+    /// the number of guards alive by a bounded amount. A steadily climbing number usually means
+    /// that guards are leaked.
+    pub protecting: usize,
+    /// The number of hazards in use, which is the protecting ones plus the ones being created or
+    /// waiting in the caches of the threads.
+    pub live: usize,
+    /// The number of hazards allocated, including the ones not in use.
+    ///
+    /// The memory of the hazards is reused, but never released.
+    pub allocated: usize,
+}
+
+/// Get the numbers of hazards in each state.
+///
+/// There is a single reclamation domain, so this covers every hazard of the process. It doesn't
+/// wait for garbage collections, but reads every hazard allocated, so it is linear in the peak
+/// number of hazards.
+pub fn active_hazards() -> HazardStats {
+    hazard::count()
+}
+
+/// The statistics of a thread.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub struct ThreadStats {
+    /// The identifier of the thread.
+    pub id: ThreadId,
+    /// The name of the thread.
+    pub name: Option<String>,
+    /// The number of garbage objects retired by the thread.
+    pub retired: usize,
+    /// The number of garbage objects exported to the global state by the thread.
+    ///
+    /// The garbage is exported in batches, so this lags behind `retired`.
+    pub exported: usize,
+    /// The number of garbage objects cached by the thread, which aren't exported yet.
+    pub cached: usize,
+    /// The number of hazards created by the thread.
+    ///
+    /// Hazards are reused and created in batches, so this is roughly the peak number of guards,
+    /// which the thread held at once.
+    pub hazards_created: usize,
+    /// The number of hazards in the cache of the thread.
+    ///
+    /// The hazards created by the thread, but not cached, are used by guards. The hazards of the
+    /// guards sent to, and dropped by, other threads end up in the caches of those.
+    pub hazards_cached: usize,
+}
+
+/// Get the statistics of every running thread, which has retired garbage.
+///
+/// Threads, which only take guards, are never registered, so they are not included. This locks a
+/// global registry, so it is more expensive than the other statistics.
+pub fn threads() -> Vec<ThreadStats> {
+    local::stats()
+}
+
+/// The statistics of the garbage destroyed by a given destructor.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub struct DestructorStats {
+    /// The address of the destructor.
+    ///
+    /// Every type of garbage retired through the typed API (e.g. `Atomic<T>` or
+    /// `add_garbage_box()`) has a destructor of its own. Other garbage is keyed by the destructor
+    /// given when it was added. Destructors compiling to the same code (e.g. of two types without
+    /// drop glue) might be merged by the compiler, and share their statistics.
+    pub dtor: usize,
+    /// The name of the type of the garbage, if it was retired through the typed API (including
+    /// `add_garbage()`, where it names the type of the reference).
+    pub type_name: Option<&'static str>,
+    /// The number of garbage objects exported to the global state.
+    pub exported: usize,
+    /// The number of garbage objects destroyed.
+    pub destroyed: usize,
+}
+
+impl DestructorStats {
+    /// Get the number of garbage objects exported, but not yet destroyed.
+    ///
+    /// Garbage destroyed without being exported (e.g. by a `scope()`) is subtracted as well, so
+    /// this is a lower bound.
+    pub fn pending(&self) -> usize {
+        self.exported.saturating_sub(self.destroyed)
+    }
+}
+
+/// Get the statistics of every destructor, which garbage was exported or destroyed with, the most
+/// pending first.
+///
+/// When the pending garbage grows, this tells which kind of object is piling up. The garbage is
+/// counted in batches, once it is exported from the local state of the thread, which retired it,
+/// and when it is destroyed. This locks a global table, so it is more expensive than the other
+/// statistics.
+pub fn destructors() -> Vec<DestructorStats> {
+    let mut stats = garbage::destructors();
+    stats.sort_by(|a, b| b.pending().cmp(&a.pending()));
+    stats
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{self, AtomicUsize};
+    use Atomic;
+
+    #[test]
+    fn pending_bytes_counts_garbage() {
+        let a = Atomic::new(Some(Box::new([0u8; 4096])));
+        // Protect the old object, such that other collections can't reclaim it.
+        let guard = a.load(atomic::Ordering::Relaxed).unwrap();
+        a.store(None, atomic::Ordering::Relaxed);
+        local::export_garbage();
+
+        assert!(pending_bytes() >= 4096);
+        drop(guard);
+    }
+
+    #[test]
+    fn high_water_marks() {
+        let a = Atomic::new(Some(Box::new([0u8; 4096])));
+        let guard = a.load(atomic::Ordering::Relaxed).unwrap();
+        a.store(None, atomic::Ordering::Relaxed);
+        local::export_garbage();
+
+        let high = high_water();
+        assert!(high.items >= 1);
+        assert!(high.bytes >= 4096);
+
+        // The mark stays after the garbage is destroyed.
+        drop(guard);
+        ::gc().unwrap();
+        let after = high_water();
+        assert!(after.bytes >= 4096);
+
+        // Other tests might export garbage meanwhile, so the reset value can't be checked exactly.
+        reset_high_water();
+    }
+
+    #[test]
+    fn destructors_by_type() {
+        static DROPS: AtomicUsize = AtomicUsize::new(0);
+
+        // The destructor must do something of its own, as otherwise it might be merged with the
+        // destructors of other types.
+        struct Marker;
+
+        impl Drop for Marker {
+            fn drop(&mut self) {
+                DROPS.fetch_add(1, atomic::Ordering::Relaxed);
+            }
+        }
+
+        let a = Atomic::new(Some(Box::new(Marker)));
+        let guard = a.load(atomic::Ordering::Relaxed).unwrap();
+        a.store(None, atomic::Ordering::Relaxed);
+        local::export_garbage();
+
+        let find = || destructors().into_iter()
+            .find(|x| x.type_name.map_or(false, |name| name.ends_with("::Marker")))
+            .unwrap();
+        let stats = find();
+        assert_eq!(stats.exported, 1);
+        assert_eq!(stats.pending(), 1);
+
+        drop(guard);
+        local::free_hazards();
+        ::gc().unwrap();
+        let stats = find();
+        assert_eq!(stats.destroyed, 1);
+        assert_eq!(stats.pending(), 0);
+        assert_eq!(DROPS.load(atomic::Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn active_hazards_counts_guards() {
+        let a = Atomic::new(Some(Box::new(0)));
+        let guards: Vec<_> = (0..64).map(|_| a.load(atomic::Ordering::Relaxed).unwrap()).collect();
+
+        let hazards = active_hazards();
+        assert!(hazards.protecting >= 64);
+        assert!(hazards.live >= hazards.protecting);
+        assert!(hazards.allocated >= hazards.live);
+        drop(guards);
+    }
+}
@@ -0,0 +1,307 @@
+//! Bloom filters with growable bit arrays.
+
+use parking_lot::{Mutex, MutexGuard};
+use std::collections::hash_map::DefaultHasher;
+use std::hash::{Hash, Hasher};
+use std::{mem, ptr};
+use std::sync::atomic::{self, AtomicPtr, AtomicU64};
+use {Atomic, Guard, add_garbage_box};
+
+/// A lock-free Bloom filter.
+///
+/// This answers whether some item is possibly in a set (e.g. of cached blocks): there are no false
+/// negatives, but there are false positives, which become more likely as the bit array fills up.
+///
+/// To reduce them, the bit array can be replaced by a larger one, which is rebuilt from the items
+/// (e.g. by another thread) while the filter is in use. Items inserted in the meantime are added
+/// to both arrays, so none of them are lost, when the new array is swapped in, and the old array
+/// is retired through `conc`.
+pub struct BloomFilter {
+    /// The current bit array.
+    ///
+    /// This is never null.
+    bits: Atomic<Bits>,
+    /// The bit array being rebuilt, or null.
+    ///
+    /// This is owned by the `Rebuild` while it is alive, and afterwards by `self.bits`.
+    next: AtomicPtr<Bits>,
+    /// The number of hash functions.
+    hashes: u32,
+    /// The lock serializing rebuilds.
+    rebuild: Mutex<()>,
+}
+
+/// The bit array of a Bloom filter.
+struct Bits {
+    /// The words of the array.
+    words: Box<[AtomicU64]>,
+    /// The number of bits of the array.
+    len: u64,
+}
+
+impl Bits {
+    /// Create a new, clear bit array of `len` bits.
+    fn new(len: usize) -> Bits {
+        assert!(len > 0, "Creating an empty bit array.");
+
+        Bits {
+            words: (0..(len + 63) / 64).map(|_| AtomicU64::new(0)).collect::<Vec<_>>()
+                .into_boxed_slice(),
+            len: len as u64,
+        }
+    }
+
+    /// Get the positions of the bits of the item with hash `hash`.
+    fn positions(&self, hash: u64, hashes: u32) -> impl Iterator<Item = u64> {
+        // Double hashing: the positions are derived from the two halves of the hash.
+        let len = self.len;
+        let step = hash.rotate_left(32) | 1;
+        (0..hashes as u64).map(move |i| hash.wrapping_add(i.wrapping_mul(step)) % len)
+    }
+
+    /// Set the bits of the item with hash `hash`.
+    fn set(&self, hash: u64, hashes: u32) {
+        for pos in self.positions(hash, hashes) {
+            self.words[(pos / 64) as usize].fetch_or(1 << (pos % 64), atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Are the bits of the item with hash `hash` set?
+    fn test(&self, hash: u64, hashes: u32) -> bool {
+        self.positions(hash, hashes).all(|pos| {
+            self.words[(pos / 64) as usize].load(atomic::Ordering::Relaxed) & 1 << (pos % 64) != 0
+        })
+    }
+}
+
+/// Hash an item.
+fn hash<T: ?Sized + Hash>(item: &T) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    item.hash(&mut hasher);
+    hasher.finish()
+}
+
+impl BloomFilter {
+    /// Create a new, empty filter of `bits` bits using `hashes` hash functions.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `bits` or `hashes` is zero.
+    pub fn new(bits: usize, hashes: u32) -> BloomFilter {
+        assert!(hashes > 0, "Creating a Bloom filter without hash functions.");
+
+        BloomFilter {
+            bits: Atomic::new(Some(Box::new(Bits::new(bits)))),
+            next: AtomicPtr::default(),
+            hashes: hashes,
+            rebuild: Mutex::new(()),
+        }
+    }
+
+    /// Get the number of bits of the current bit array.
+    pub fn bits(&self) -> usize {
+        self.load().len as usize
+    }
+
+    /// Get the number of hash functions.
+    pub fn hashes(&self) -> u32 {
+        self.hashes
+    }
+
+    /// Insert an item.
+    pub fn insert<T: ?Sized + Hash>(&self, item: &T) {
+        let hash = hash(item);
+
+        loop {
+            let bits = self.load();
+            bits.set(hash, self.hashes);
+
+            // Add the item to the array being rebuilt as well, as the rebuild might miss it.
+            if let Some(next) = Guard::maybe_new(|| unsafe {
+                self.next.load(atomic::Ordering::SeqCst).as_ref()
+            }) {
+                next.set(hash, self.hashes);
+            }
+
+            // If the array was swapped since it was loaded, the item might have been set in
+            // neither the old nor the new array, so we retry.
+            if self.bits.load_raw(atomic::Ordering::SeqCst) as *const Bits == bits.as_ptr() {
+                return;
+            }
+        }
+    }
+
+    /// Is the item possibly in the filter?
+    ///
+    /// `false` is returned only if the item was never inserted.
+    pub fn contains<T: ?Sized + Hash>(&self, item: &T) -> bool {
+        self.load().test(hash(item), self.hashes)
+    }
+
+    /// Start rebuilding the filter with a new bit array of `bits` bits.
+    ///
+    /// The items of the filter must be inserted into the returned `Rebuild`, which then swaps the
+    /// new array in, when it is finished. Only items inserted into the filter before this was
+    /// called need to be, so they can be taken from an index, as long as items are added to the
+    /// index before being inserted into the filter. Items inserted concurrently are added to both
+    /// arrays.
+    ///
+    /// If another rebuild is in progress, this blocks until it is finished or abandoned.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `bits` is zero.
+    pub fn rebuild(&self, bits: usize) -> Rebuild {
+        let lock = self.rebuild.lock();
+        let next = Box::into_raw(Box::new(Bits::new(bits)));
+        self.next.store(next, atomic::Ordering::SeqCst);
+
+        Rebuild {
+            filter: self,
+            next: next,
+            _lock: lock,
+        }
+    }
+
+    /// Get the current bit array.
+    fn load(&self) -> Guard<Bits> {
+        self.bits.load(atomic::Ordering::SeqCst).expect("The bit array is never null.")
+    }
+}
+
+/// A rebuild of the bit array of a `BloomFilter` in progress.
+///
+/// This is created by `BloomFilter::rebuild()`. Dropping it without finishing it abandons the
+/// rebuild.
+pub struct Rebuild<'a> {
+    /// The filter being rebuilt.
+    filter: &'a BloomFilter,
+    /// The new bit array.
+    next: *mut Bits,
+    /// The lock excluding other rebuilds.
+    _lock: MutexGuard<'a, ()>,
+}
+
+impl<'a> Rebuild<'a> {
+    /// Insert an item into the new bit array.
+    pub fn insert<T: ?Sized + Hash>(&self, item: &T) {
+        unsafe { (*self.next).set(hash(item), self.filter.hashes) }
+    }
+
+    /// Swap the new bit array in, retiring the old one.
+    pub fn finish(mut self) {
+        // The array must only be unpublished after it is swapped in, as inserts, which missed it,
+        // only retry, if they see the swap.
+        self.filter.bits.store(Some(unsafe { Box::from_raw(self.next) }), atomic::Ordering::SeqCst);
+        self.unpublish();
+    }
+
+    /// Stop inserting concurrently inserted items into the new array, and take it.
+    fn unpublish(&mut self) -> *mut Bits {
+        self.filter.next.store(ptr::null_mut(), atomic::Ordering::SeqCst);
+        mem::replace(&mut self.next, ptr::null_mut())
+    }
+}
+
+impl<'a> Drop for Rebuild<'a> {
+    fn drop(&mut self) {
+        if !self.next.is_null() {
+            // The rebuild is abandoned. The array might still be guarded by inserts.
+            let next = self.unpublish();
+            unsafe { add_garbage_box(next) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn insert_contains() {
+        let filter = BloomFilter::new(1 << 16, 4);
+        for i in 0..1000 {
+            filter.insert(&i);
+        }
+        for i in 0..1000 {
+            assert!(filter.contains(&i));
+        }
+
+        // With ~6% of the bits set, false positives are rare.
+        let false_positives = (1000..11000).filter(|i| filter.contains(i)).count();
+        assert!(false_positives < 100, "{} false positives.", false_positives);
+    }
+
+    #[test]
+    fn rebuild() {
+        let filter = BloomFilter::new(64, 3);
+        for i in 0..100 {
+            filter.insert(&i);
+        }
+
+        let rebuild = filter.rebuild(1 << 16);
+        for i in 0..50 {
+            rebuild.insert(&i);
+        }
+        // Items inserted during the rebuild are kept.
+        filter.insert(&"hello");
+        rebuild.finish();
+
+        assert_eq!(filter.bits(), 1 << 16);
+        assert!(filter.contains(&"hello"));
+        assert!(filter.contains(&0));
+        assert!(filter.contains(&49));
+        assert!((50..100).filter(|i| filter.contains(i)).count() < 10);
+    }
+
+    #[test]
+    fn abandon() {
+        let filter = BloomFilter::new(128, 2);
+        filter.insert(&1);
+        drop(filter.rebuild(1 << 10));
+
+        assert_eq!(filter.bits(), 128);
+        assert!(filter.contains(&1));
+        ::gc().unwrap();
+    }
+
+    #[test]
+    fn grow_concurrently() {
+        let filter = Arc::new(BloomFilter::new(64, 3));
+        let index = Arc::new(Mutex::new(Vec::new()));
+
+        let mut j = Vec::new();
+        for t in 0..4u64 {
+            let filter = filter.clone();
+            let index = index.clone();
+            j.push(thread::spawn(move || for i in 0..2000 {
+                let item = i * 4 + t;
+                index.lock().push(item);
+                filter.insert(&item);
+                assert!(filter.contains(&item));
+            }));
+        }
+        {
+            let filter = filter.clone();
+            let index = index.clone();
+            j.push(thread::spawn(move || for n in 1..8 {
+                let rebuild = filter.rebuild(64 << n);
+                let items = index.lock().clone();
+                for item in items {
+                    rebuild.insert(&item);
+                }
+                rebuild.finish();
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert_eq!(filter.bits(), 64 << 7);
+        for i in 0..8000u64 {
+            assert!(filter.contains(&i));
+        }
+    }
+}
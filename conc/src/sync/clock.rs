@@ -0,0 +1,289 @@
+//! Caches with clock (second-chance) eviction.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic::{self, AtomicBool};
+use {Atomic, Guard};
+use super::{MapPrefix, Prefix};
+
+/// A concurrent cache of a fixed number of entries, evicted by the clock algorithm.
+///
+/// The entries are stored in a fixed table of slots, each having a reference bit, which is set
+/// when the entry is read. To make room for a new entry, a "clock hand" sweeps over the slots,
+/// clearing the reference bits, until it finds an entry, which wasn't referenced since the hand
+/// last passed it. This entry is evicted, whereas the referenced entries get a second chance.
+///
+/// Unlike LRU, a lookup doesn't reorder anything, but only sets a bit, so lookups don't contend
+/// with each other. They return guards, which pin the entry in memory: An entry can be evicted or
+/// removed while it is guarded, but it isn't destroyed until the guards are gone.
+pub struct ClockCache<K, V> {
+    /// The slots of the entries.
+    slots: Box<[Slot<K, V>]>,
+    /// The index of the slots.
+    ///
+    /// This is read-locked by lookups and write-locked by insertions and removals, so the slot of a
+    /// key doesn't change while it is looked up.
+    index: RwLock<Index<K>>,
+}
+
+/// A slot of the cache.
+struct Slot<K, V> {
+    /// The entry in the slot, if any.
+    entry: Atomic<Entry<K, V>>,
+    /// Was the entry read since the clock hand passed it?
+    referenced: AtomicBool,
+}
+
+/// An entry of the cache.
+struct Entry<K, V> {
+    /// The key of the entry.
+    ///
+    /// This is needed to remove the entry from the index, when it is evicted.
+    key: K,
+    /// The cached value.
+    value: V,
+}
+
+/// The index of the slots of a cache.
+struct Index<K> {
+    /// Map keys to the slots of their entries.
+    slots: HashMap<K, usize>,
+    /// The empty slots.
+    free: Vec<usize>,
+    /// The slot, which the clock hand points to.
+    hand: usize,
+}
+
+impl<K, V> ClockCache<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// Create a new, empty cache holding at most `capacity` entries.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `capacity` is zero.
+    pub fn new(capacity: usize) -> ClockCache<K, V> {
+        assert!(capacity > 0, "Creating a cache without capacity.");
+
+        ClockCache {
+            slots: (0..capacity).map(|_| Slot {
+                entry: Atomic::default(),
+                referenced: AtomicBool::new(false),
+            }).collect::<Vec<_>>().into_boxed_slice(),
+            index: RwLock::new(Index {
+                slots: HashMap::with_capacity(capacity),
+                // Fill the slots from the start.
+                free: (0..capacity).rev().collect(),
+                hand: 0,
+            }),
+        }
+    }
+
+    /// Get the maximal number of entries of the cache.
+    pub fn capacity(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Get the number of entries in the cache.
+    pub fn len(&self) -> usize {
+        self.index.read().slots.len()
+    }
+
+    /// Is the cache empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Look up the value of `key`, marking it as referenced.
+    ///
+    /// The value is protected by the returned guard, even if the entry is evicted in the meantime.
+    pub fn get(&self, key: &K) -> Option<Guard<V>> {
+        let index = self.index.read();
+        let slot = &self.slots[*index.slots.get(key)?];
+
+        slot.referenced.store(true, atomic::Ordering::Relaxed);
+        slot.entry.load(atomic::Ordering::Acquire).map(|entry| entry.map(|entry| &entry.value))
+    }
+
+    /// Insert `value` under `key`, evicting another entry, if the cache is full.
+    ///
+    /// If `key` already has an entry, its value is replaced and the old value is returned.
+    pub fn insert(&self, key: K, value: V) -> Option<Guard<V>> {
+        let mut index = self.index.write();
+
+        let (i, replacing) = match index.slots.get(&key) {
+            Some(&i) => (i, true),
+            None => (self.victim(&mut index), false),
+        };
+        if !replacing {
+            // Evict the entry in the slot (if any) by unlinking it from the index.
+            if let Some(old) = self.slots[i].entry.load(atomic::Ordering::Relaxed) {
+                index.slots.remove(&old.key);
+            }
+            index.slots.insert(key.clone(), i);
+            self.slots[i].referenced.store(false, atomic::Ordering::Relaxed);
+        }
+
+        let old = self.slots[i].entry.swap(Some(Box::new(Entry {
+            key: key,
+            value: value,
+        })), atomic::Ordering::Release);
+
+        // An evicted entry has another key, so it isn't returned.
+        if replacing {
+            old.map(|entry| entry.map(|entry| &entry.value))
+        } else {
+            None
+        }
+    }
+
+    /// Remove the entry of `key` from the cache, returning its value.
+    pub fn remove(&self, key: &K) -> Option<Guard<V>> {
+        let mut index = self.index.write();
+        let i = index.slots.remove(key)?;
+        index.free.push(i);
+
+        self.slots[i].entry.swap(None, atomic::Ordering::Release)
+            .map(|entry| entry.map(|entry| &entry.value))
+    }
+
+    /// Pick the slot of a new entry.
+    ///
+    /// This is an empty slot, if any. Otherwise, the clock hand is advanced until it finds an
+    /// entry, which wasn't referenced since it was last passed, and its slot is returned.
+    fn victim(&self, index: &mut Index<K>) -> usize {
+        if let Some(i) = index.free.pop() {
+            return i;
+        }
+
+        // As lookups are excluded by the write lock, no reference bits are set in the meantime,
+        // so this ends within a single revolution.
+        loop {
+            let i = index.hand;
+            index.hand = (i + 1) % self.slots.len();
+
+            // Give the entry a second chance, if it was referenced.
+            if !self.slots[i].referenced.swap(false, atomic::Ordering::Relaxed) {
+                return i;
+            }
+        }
+    }
+}
+
+impl<K: fmt::Debug + 'static, V: fmt::Debug + 'static> fmt::Debug for ClockCache<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // The entries hold their keys, so they can be read from the slots without the lock.
+        let entries = Prefix::collect(self.slots.iter().filter_map(|slot| {
+            slot.entry.load(atomic::Ordering::Acquire)
+        }));
+
+        f.debug_struct("ClockCache")
+            .field("len", &self.index.read().slots.len())
+            .field("capacity", &self.slots.len())
+            .field("entries", &MapPrefix(entries.map(|entry| (&entry.key, &entry.value))))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use testing::{Counter, Tracked};
+
+    #[test]
+    fn insert_get_remove() {
+        let cache = ClockCache::new(4);
+        assert!(cache.is_empty());
+        assert!(cache.insert(1, "a").is_none());
+        assert!(cache.insert(2, "b").is_none());
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(*cache.get(&1).unwrap(), "a");
+        assert_eq!(*cache.insert(1, "c").unwrap(), "a");
+        assert_eq!(*cache.get(&1).unwrap(), "c");
+        assert_eq!(cache.len(), 2);
+
+        assert_eq!(*cache.remove(&2).unwrap(), "b");
+        assert!(cache.remove(&2).is_none());
+        assert!(cache.get(&2).is_none());
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn debug() {
+        let cache = ClockCache::new(4);
+        cache.insert(1, "a");
+        assert_eq!(format!("{:?}", cache),
+                   "ClockCache { len: 1, capacity: 4, entries: {1: \"a\"} }");
+    }
+
+    #[test]
+    fn second_chance() {
+        let cache = ClockCache::new(2);
+        cache.insert(1, 1);
+        cache.insert(2, 2);
+
+        // The referenced entry survives the eviction.
+        cache.get(&1);
+        cache.insert(3, 3);
+        assert!(cache.get(&2).is_none());
+        assert_eq!(cache.len(), 2);
+
+        // The reference bit was cleared by the sweep, so it is evicted next.
+        cache.insert(4, 4);
+        assert!(cache.get(&1).is_none());
+        assert_eq!(*cache.get(&3).unwrap(), 3);
+        assert_eq!(*cache.get(&4).unwrap(), 4);
+    }
+
+    #[test]
+    fn guard_pins_evicted() {
+        static COUNTER: Counter = Counter::new();
+
+        let cache = ClockCache::new(1);
+        cache.insert(1, Tracked::with_counter(1, &COUNTER));
+
+        let g = cache.get(&1).unwrap();
+        cache.insert(2, Tracked::with_counter(2, &COUNTER));
+        assert!(cache.get(&1).is_none());
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 0);
+        assert_eq!(**g, 1);
+
+        drop(g);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let cache = Arc::new(ClockCache::new(16));
+
+        let mut j = Vec::new();
+        for t in 0..8u64 {
+            let cache = cache.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..10000u64 {
+                    let key = (i * 7 + t) % 64;
+                    if let Some(value) = cache.get(&key) {
+                        assert_eq!(*value, key * 2);
+                    } else {
+                        cache.insert(key, key * 2);
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert_eq!(cache.len(), 16);
+    }
+}
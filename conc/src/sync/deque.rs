@@ -0,0 +1,632 @@
+//! Double-ended queues.
+
+use std::{fmt, mem, ptr};
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
+use {Atomic, Guard, add_garbage_box};
+use super::Prefix;
+
+/// A lock-free double-ended queue.
+///
+/// This implements Michael's deque ("CAS-Based Lock-Free Algorithm for Shared Deques", 2003): the
+/// nodes form a doubly linked list, whose ends are kept in an anchor together with a status. A
+/// push swings the anchor to the new node and marks it unstable, after which the link from the old
+/// end to the new node is set by whichever thread comes first. Pops only take place from stable
+/// anchors.
+///
+/// The anchor doesn't fit into a word, so it is kept in an immutable box, which is replaced
+/// through CAS and retired through `conc`, as are the popped nodes.
+pub struct Deque<T> {
+    /// The anchor of the deque.
+    ///
+    /// This is never null.
+    anchor: Atomic<Anchor<T>>,
+    /// The approximate number of items.
+    ///
+    /// This is incremented before an item is published and decremented after it is unlinked, so
+    /// it never underflows.
+    len: AtomicUsize,
+}
+
+/// An end of a deque.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Side {
+    /// The front (left) end.
+    Front,
+    /// The back (right) end.
+    Back,
+}
+
+/// The status of the anchor of a deque.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Status {
+    /// The nodes are fully linked.
+    Stable,
+    /// A node was pushed to the given end, but the old end doesn't link to it yet.
+    Pushed(Side),
+}
+
+/// The anchor of a deque.
+struct Anchor<T> {
+    /// The front node, or null, if the deque is empty.
+    front: *mut Node<T>,
+    /// The back node, or null, if the deque is empty.
+    back: *mut Node<T>,
+    /// The status of the deque.
+    status: Status,
+}
+
+// The anchor only refers to the nodes, which are shared like the anchor itself.
+unsafe impl<T: Send + Sync> Send for Anchor<T> {}
+unsafe impl<T: Send + Sync> Sync for Anchor<T> {}
+
+impl<T> Anchor<T> {
+    /// Get the node at end `side`.
+    fn end(&self, side: Side) -> *mut Node<T> {
+        match side {
+            Side::Front => self.front,
+            Side::Back => self.back,
+        }
+    }
+
+    /// Get a copy of the anchor with the node at end `side` replaced by `node`.
+    fn with_end(&self, side: Side, node: *mut Node<T>, status: Status) -> Anchor<T> {
+        match side {
+            Side::Front => Anchor {
+                front: node,
+                back: self.back,
+                status: status,
+            },
+            Side::Back => Anchor {
+                front: self.front,
+                back: node,
+                status: status,
+            },
+        }
+    }
+}
+
+/// A node of a deque.
+struct Node<T> {
+    /// The item of the node.
+    item: T,
+    /// The node in front of this one.
+    prev: AtomicPtr<Node<T>>,
+    /// The node behind this one.
+    next: AtomicPtr<Node<T>>,
+}
+
+impl<T> Node<T> {
+    /// Get the link towards end `side`.
+    fn outer(&self, side: Side) -> &AtomicPtr<Node<T>> {
+        match side {
+            Side::Front => &self.prev,
+            Side::Back => &self.next,
+        }
+    }
+
+    /// Get the link away from end `side`.
+    fn inner(&self, side: Side) -> &AtomicPtr<Node<T>> {
+        match side {
+            Side::Front => &self.next,
+            Side::Back => &self.prev,
+        }
+    }
+}
+
+impl<T: Send + Sync + 'static> Deque<T> {
+    /// Create a new, empty deque.
+    pub fn new() -> Deque<T> {
+        Deque {
+            anchor: Atomic::new(Some(Box::new(Anchor {
+                front: ptr::null_mut(),
+                back: ptr::null_mut(),
+                status: Status::Stable,
+            }))),
+            len: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the approximate number of items in the deque.
+    ///
+    /// This is a relaxed counter, so it is cheap, but it can be off while there are concurrent
+    /// pushes and pops. It is meant for monitoring and backpressure, not for synchronization.
+    pub fn len(&self) -> usize {
+        self.len.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Is the deque empty?
+    pub fn is_empty(&self) -> bool {
+        self.load().front.is_null()
+    }
+
+    /// Push an item to the front of the deque.
+    pub fn push_front(&self, item: T) {
+        self.push(Side::Front, item);
+    }
+
+    /// Push an item to the back of the deque.
+    pub fn push_back(&self, item: T) {
+        self.push(Side::Back, item);
+    }
+
+    /// Pop an item from the front of the deque.
+    pub fn pop_front(&self) -> Option<Guard<T>> {
+        self.pop(Side::Front)
+    }
+
+    /// Pop an item from the back of the deque.
+    pub fn pop_back(&self) -> Option<Guard<T>> {
+        self.pop(Side::Back)
+    }
+
+    /// Push an item to end `side`.
+    fn push(&self, side: Side, item: T) {
+        let node = Box::into_raw(Box::new(Node {
+            item: item,
+            prev: AtomicPtr::default(),
+            next: AtomicPtr::default(),
+        }));
+        // Count the item, before it can be popped.
+        self.len.fetch_add(1, atomic::Ordering::Relaxed);
+
+        loop {
+            let anchor = self.load();
+
+            let new = if anchor.front.is_null() {
+                Anchor {
+                    front: node,
+                    back: node,
+                    status: Status::Stable,
+                }
+            } else if anchor.status == Status::Stable {
+                // The node isn't published yet, so we can link it to the old end freely.
+                unsafe { (*node).inner(side).store(anchor.end(side), atomic::Ordering::Relaxed); }
+                anchor.with_end(side, node, Status::Pushed(side))
+            } else {
+                self.stabilize(&anchor);
+                continue;
+            };
+
+            if self.replace(&anchor, new) {
+                break;
+            }
+        }
+
+        // Link the old end to the node, unless another thread did so already.
+        let anchor = self.load();
+        if anchor.status != Status::Stable {
+            self.stabilize(&anchor);
+        }
+    }
+
+    /// Pop an item from end `side`.
+    fn pop(&self, side: Side) -> Option<Guard<T>> {
+        loop {
+            let anchor = self.load();
+            if anchor.front.is_null() {
+                return None;
+            }
+
+            if anchor.status != Status::Stable {
+                self.stabilize(&anchor);
+                continue;
+            }
+
+            // The end node can only be popped after the anchor changes.
+            let node = match self.protect(&anchor, anchor.end(side)) {
+                Some(node) => node,
+                None => continue,
+            };
+
+            let new = if anchor.front == anchor.back {
+                Anchor {
+                    front: ptr::null_mut(),
+                    back: ptr::null_mut(),
+                    status: Status::Stable,
+                }
+            } else {
+                anchor.with_end(side, node.inner(side).load(atomic::Ordering::Acquire),
+                                Status::Stable)
+            };
+
+            if self.replace(&anchor, new) {
+                unsafe { add_garbage_box(node.as_ptr()); }
+                self.len.fetch_sub(1, atomic::Ordering::Relaxed);
+                return Some(node.map(|node| &node.item));
+            }
+        }
+    }
+
+    /// Link the old end of the unstable `anchor` to the node pushed, and mark it stable.
+    fn stabilize(&self, anchor: &Guard<Anchor<T>>) {
+        let side = match anchor.status {
+            Status::Pushed(side) => side,
+            Status::Stable => return,
+        };
+
+        // No nodes are popped, while the anchor is unstable.
+        let node = match self.protect(anchor, anchor.end(side)) {
+            Some(node) => node,
+            None => return,
+        };
+        let old_end = match self.protect(anchor, node.inner(side).load(atomic::Ordering::Acquire)) {
+            Some(old_end) => old_end,
+            None => return,
+        };
+
+        let link = old_end.outer(side).load(atomic::Ordering::Acquire);
+        if link != node.as_ptr() as *mut Node<T> {
+            if !self.is_current(anchor) {
+                return;
+            }
+
+            // The link might refer to a popped node, but then it is only compared.
+            if old_end.outer(side).compare_exchange(
+                link,
+                node.as_ptr() as *mut Node<T>,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Relaxed,
+            ).is_err() {
+                return;
+            }
+        }
+
+        self.replace(anchor, Anchor {
+            front: anchor.front,
+            back: anchor.back,
+            status: Status::Stable,
+        });
+    }
+
+    /// Get the current anchor.
+    fn load(&self) -> Guard<Anchor<T>> {
+        self.anchor.load(atomic::Ordering::Acquire).expect("The anchor is never null.")
+    }
+
+    /// Is `anchor` still the current anchor?
+    fn is_current(&self, anchor: &Guard<Anchor<T>>) -> bool {
+        // The anchor is protected, so its address can't be reused.
+        self.anchor.load_raw(atomic::Ordering::Acquire) as *const Anchor<T> == anchor.as_ptr()
+    }
+
+    /// Protect the node `ptr`, which is linked from `anchor`, unless the anchor changed.
+    ///
+    /// The anchor is checked while garbage collection is blocked, so if it is current, the node
+    /// can't have been reclaimed yet. Otherwise, `None` is returned.
+    fn protect(&self, anchor: &Guard<Anchor<T>>, ptr: *mut Node<T>) -> Option<Guard<Node<T>>> {
+        Guard::try_new(|| if self.is_current(anchor) {
+            Ok(unsafe { &*ptr })
+        } else {
+            Err(())
+        }).ok()
+    }
+
+    /// Replace `old` by `new` as the anchor, unless it changed.
+    fn replace(&self, old: &Guard<Anchor<T>>, new: Anchor<T>) -> bool {
+        self.anchor.compare_and_store(Some(old.as_ptr()), Some(Box::new(new)),
+                                      atomic::Ordering::AcqRel).is_ok()
+    }
+}
+
+impl<T: Send + Sync + fmt::Debug + 'static> fmt::Debug for Deque<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Nodes are only popped by replacing the anchor, so as long as it is current, the nodes
+        // linked from its front are in the deque. Hence, we walk from the front, while it is
+        // current.
+        let anchor = self.load();
+        let mut nodes = Vec::new();
+        let mut truncated = false;
+        if !anchor.front.is_null() {
+            // The back node is only valid, if the anchor is current, when it is protected.
+            if let Some(back) = self.protect(&anchor, anchor.back) {
+                // If a node was pushed to the back, the link to it from the old back might be
+                // stale, so we stop at the old back, which the node links to.
+                let last = if anchor.status == Status::Pushed(Side::Back) {
+                    back.prev.load(atomic::Ordering::Acquire)
+                } else {
+                    anchor.back
+                };
+
+                let mut ptr = anchor.front;
+                loop {
+                    if nodes.len() == super::debug_limit() {
+                        truncated = true;
+                        break;
+                    }
+
+                    let node = match self.protect(&anchor, ptr) {
+                        Some(node) => node,
+                        None => break,
+                    };
+
+                    let next = node.next.load(atomic::Ordering::Acquire);
+                    nodes.push(node);
+                    if ptr == last {
+                        if last != anchor.back && nodes.len() < super::debug_limit() {
+                            nodes.push(back);
+                        }
+                        break;
+                    }
+                    ptr = next;
+                }
+            }
+
+            // If the anchor changed, the rest of the nodes are unknown.
+            truncated |= nodes.last().map_or(true, |node| node.as_ptr() != anchor.back);
+        }
+
+        f.debug_struct("Deque")
+            .field("len", &self.len())
+            .field("items", &Prefix {
+                items: nodes.iter().map(|node| &node.item).collect(),
+                truncated: truncated,
+            })
+            .finish()
+    }
+}
+
+impl<T: Send + Sync + 'static> Default for Deque<T> {
+    fn default() -> Deque<T> {
+        Deque::new()
+    }
+}
+
+impl<T> Drop for Deque<T> {
+    fn drop(&mut self) {
+        // There are no concurrent operations, so the anchor is stable, and no guards refer to the
+        // nodes left, as only popped nodes are handed out.
+        unsafe {
+            let anchor = self.anchor.get_inner_mut().get_mut();
+            if anchor.is_null() {
+                return;
+            }
+
+            let mut node = (**anchor).front;
+            let back = (**anchor).back;
+            while !node.is_null() {
+                let next = if node == back {
+                    ptr::null_mut()
+                } else {
+                    *(*node).next.get_mut()
+                };
+                drop(Box::from_raw(node));
+                node = next;
+            }
+        }
+    }
+}
+
+impl<T> IntoIterator for Deque<T> {
+    type Item = T;
+    type IntoIter = DequeIntoIter<T>;
+
+    /// Take the deque apart, yielding its items from the front.
+    ///
+    /// As the deque is owned, no other thread can access it, so this doesn't go through the
+    /// atomics or the garbage collector. The iterator can be reversed to yield from the back.
+    fn into_iter(mut self) -> DequeIntoIter<T> {
+        // There are no concurrent operations, so the anchor is stable, and no guards refer to it.
+        // A null anchor is left behind for the destructor.
+        let anchor = unsafe {
+            let anchor = self.anchor.get_inner_mut().get_mut();
+            Box::from_raw(mem::replace(anchor, ptr::null_mut()))
+        };
+
+        DequeIntoIter {
+            front: anchor.front,
+            back: anchor.back,
+        }
+    }
+}
+
+/// An iterator taking a deque apart.
+///
+/// See `Deque::into_iter()`.
+pub struct DequeIntoIter<T> {
+    /// The front node left, or null, if none are left.
+    front: *mut Node<T>,
+    /// The back node left, or null, if none are left.
+    back: *mut Node<T>,
+}
+
+// The iterator owns the nodes.
+unsafe impl<T: Send> Send for DequeIntoIter<T> {}
+unsafe impl<T: Sync> Sync for DequeIntoIter<T> {}
+
+impl<T> DequeIntoIter<T> {
+    /// Take the node at end `side`.
+    fn take(&mut self, side: Side) -> Option<T> {
+        let (end, other) = match side {
+            Side::Front => (&mut self.front, &mut self.back),
+            Side::Back => (&mut self.back, &mut self.front),
+        };
+        if end.is_null() {
+            return None;
+        }
+
+        let mut node = unsafe { Box::from_raw(*end) };
+        if *end == *other {
+            *end = ptr::null_mut();
+            *other = ptr::null_mut();
+        } else {
+            // Follow the link away from the end.
+            *end = match side {
+                Side::Front => *node.next.get_mut(),
+                Side::Back => *node.prev.get_mut(),
+            };
+        }
+
+        Some(node.item)
+    }
+}
+
+impl<T> Iterator for DequeIntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        self.take(Side::Front)
+    }
+}
+
+impl<T> DoubleEndedIterator for DequeIntoIter<T> {
+    fn next_back(&mut self) -> Option<T> {
+        self.take(Side::Back)
+    }
+}
+
+impl<T> Drop for DequeIntoIter<T> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+    use testing::{Counter, Tracked};
+
+    #[test]
+    fn both_ends() {
+        let deque = Deque::new();
+        assert!(deque.is_empty());
+        assert!(deque.pop_front().is_none());
+
+        deque.push_back(2);
+        deque.push_back(3);
+        deque.push_front(1);
+        deque.push_front(0);
+        assert!(!deque.is_empty());
+        assert_eq!(deque.len(), 4);
+
+        assert_eq!(*deque.pop_front().unwrap(), 0);
+        assert_eq!(*deque.pop_back().unwrap(), 3);
+        assert_eq!(*deque.pop_back().unwrap(), 2);
+        assert_eq!(*deque.pop_back().unwrap(), 1);
+        assert!(deque.pop_back().is_none());
+        assert!(deque.is_empty());
+        assert_eq!(deque.len(), 0);
+
+        // Items pushed to the front after being popped from it are popped first again.
+        deque.push_back(4);
+        deque.push_back(5);
+        let x = *deque.pop_front().unwrap();
+        deque.push_front(x);
+        assert_eq!(*deque.pop_front().unwrap(), 4);
+        assert_eq!(*deque.pop_front().unwrap(), 5);
+    }
+
+    #[test]
+    fn drop_items() {
+        static COUNTER: Counter = Counter::new();
+
+        let deque = Deque::new();
+        for i in 0..10 {
+            deque.push_back(Tracked::with_counter(i, &COUNTER));
+            deque.push_front(Tracked::with_counter(i, &COUNTER));
+        }
+        drop(deque.pop_front());
+        drop(deque.pop_back());
+        drop(deque);
+
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 20);
+    }
+
+    #[test]
+    fn debug() {
+        let deque = Deque::new();
+        assert_eq!(format!("{:?}", deque), "Deque { len: 0, items: [] }");
+
+        deque.push_back(1);
+        deque.push_back(2);
+        deque.push_front(0);
+        assert_eq!(format!("{:?}", deque), "Deque { len: 3, items: [0, 1, 2] }");
+
+        for i in 3..20 {
+            deque.push_back(i);
+        }
+        assert_eq!(format!("{:?}", deque), "Deque { len: 20, items: [0, 1, 2, 3, 4, 5, 6, 7, 8, \
+                                           9, 10, 11, 12, 13, 14, 15, ..] }");
+    }
+
+    #[test]
+    fn into_iter() {
+        let deque = Deque::new();
+        for i in 0..5 {
+            deque.push_back(i);
+        }
+        deque.push_front(5);
+
+        let mut iter = deque.into_iter();
+        assert_eq!(iter.next(), Some(5));
+        assert_eq!(iter.next_back(), Some(4));
+        assert_eq!(iter.collect::<Vec<_>>(), [0, 1, 2, 3]);
+
+        let deque = Deque::new();
+        deque.push_back(1);
+        deque.push_back(2);
+        assert_eq!(deque.into_iter().rev().collect::<Vec<_>>(), [2, 1]);
+        assert_eq!(Deque::<u8>::new().into_iter().next(), None);
+    }
+
+    #[test]
+    fn into_iter_drop() {
+        static COUNTER: Counter = Counter::new();
+
+        let deque = Deque::new();
+        for i in 0..10 {
+            deque.push_back(Tracked::with_counter(i, &COUNTER));
+        }
+
+        let mut iter = deque.into_iter();
+        drop(iter.next());
+        drop(iter.next_back());
+        // The items are destroyed right away, without going through the garbage.
+        assert_eq!(COUNTER.destroyed(), 2);
+        drop(iter);
+        assert_eq!(COUNTER.destroyed(), 10);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let deque = Arc::new(Deque::new());
+
+        let mut j = Vec::new();
+        for t in 0..4u64 {
+            let deque = deque.clone();
+            j.push(thread::spawn(move || {
+                let mut popped = Vec::new();
+                for i in 0..10000 {
+                    let item = t * 10000 + i;
+                    if i % 2 == 0 {
+                        deque.push_back(item);
+                    } else {
+                        deque.push_front(item);
+                    }
+
+                    let item = if i % 3 == 0 {
+                        deque.pop_front()
+                    } else {
+                        deque.pop_back()
+                    };
+                    popped.push(*item.expect("Popping from a non-empty deque failed."));
+                }
+                popped
+            }));
+        }
+
+        let mut all = HashSet::new();
+        for i in j {
+            for item in i.join().unwrap() {
+                assert!(all.insert(item), "Item {} was popped twice.", item);
+            }
+        }
+        assert_eq!(all.len(), 40000);
+        assert!(deque.is_empty());
+        assert_eq!(deque.len(), 0);
+    }
+}
@@ -0,0 +1,474 @@
+//! Dirty tracking for write-back caches.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::collections::hash_map;
+use std::fmt;
+use std::hash::Hash;
+use std::ops;
+use std::sync::atomic::{self, AtomicUsize};
+use {Atomic, Guard};
+use super::{MapPrefix, Prefix};
+
+/// The state bit of entries, which were changed since they were last flushed.
+const DIRTY: usize = 1;
+/// The state bit of entries, which are being flushed.
+const FLUSHING: usize = 2;
+
+/// A concurrent map tracking which values must be written back.
+///
+/// Every entry has a dirty flag and a flush-in-progress flag, which make up the following state
+/// machine:
+///
+/// - A clean entry becomes dirty, when it is written (`write()` and `mark_dirty()`).
+/// - A dirty entry is taken for flushing by `take_dirty_batch()`, clearing its dirty flag.
+/// - If the entry is written during the flush, it is dirty again, but not taken by another batch,
+///   so the writes back of an entry never overlap.
+/// - When the flush is completed, the entry becomes clean, unless it was written in the meantime,
+///   in which case it is dirty and is taken by the next batch. If the flush is dropped without
+///   being completed (e.g. because writing failed), the entry stays dirty.
+///
+/// Only clean entries can be evicted (see `evict()`), so no write is ever lost to an eviction,
+/// whereas `remove()` discards an entry regardless of its state.
+///
+/// The values are guarded, so a flush can read the value it writes back, while the entry is
+/// replaced concurrently.
+pub struct DirtyMap<K, V> {
+    /// The entries of the map.
+    ///
+    /// Entries are added and removed under the write lock, whereas their values and states are
+    /// changed under the read lock.
+    entries: RwLock<HashMap<K, Atomic<Entry<V>>>>,
+}
+
+/// An entry of a dirty map.
+struct Entry<V> {
+    /// The current value.
+    value: Atomic<V>,
+    /// The `DIRTY` and `FLUSHING` flags.
+    state: AtomicUsize,
+}
+
+impl<K, V> DirtyMap<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    /// Create a new, empty map.
+    pub fn new() -> DirtyMap<K, V> {
+        DirtyMap {
+            entries: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Get the number of entries in the map.
+    pub fn len(&self) -> usize {
+        self.entries.read().len()
+    }
+
+    /// Is the map empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the value of `key`.
+    pub fn get(&self, key: &K) -> Option<Guard<V>> {
+        self.entries.read().get(key)?.load(atomic::Ordering::Acquire)?.value
+            .load(atomic::Ordering::Acquire)
+    }
+
+    /// Does `key` have changes, which aren't flushed yet?
+    ///
+    /// This includes entries, which were written while being flushed.
+    pub fn is_dirty(&self, key: &K) -> bool {
+        self.state(key) & DIRTY != 0
+    }
+
+    /// Is `key` being flushed?
+    pub fn is_flushing(&self, key: &K) -> bool {
+        self.state(key) & FLUSHING != 0
+    }
+
+    /// Insert a clean value, e.g. one which was read from the backing store.
+    ///
+    /// If `key` already has an entry, its value is replaced without changing its state, and the old
+    /// value is returned.
+    pub fn insert(&self, key: K, value: V) -> Option<Guard<V>> {
+        self.put(key, value, false)
+    }
+
+    /// Write the value of `key`, marking it dirty.
+    ///
+    /// If `key` already has an entry, the old value is returned.
+    pub fn write(&self, key: K, value: V) -> Option<Guard<V>> {
+        self.put(key, value, true)
+    }
+
+    /// Mark the entry of `key` dirty, e.g. after its value was changed in place.
+    ///
+    /// This returns `false`, if `key` has no entry.
+    pub fn mark_dirty(&self, key: &K) -> bool {
+        match self.entries.read().get(key).and_then(|entry| entry.load(atomic::Ordering::Acquire)) {
+            Some(entry) => {
+                entry.state.fetch_or(DIRTY, atomic::Ordering::AcqRel);
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Take up to `max` dirty entries for flushing.
+    ///
+    /// The entries are marked as being flushed, and their dirty flags are cleared. Entries, which
+    /// are already being flushed, are skipped, even if they are dirty.
+    pub fn take_dirty_batch(&self, max: usize) -> Vec<Flush<K, V>> {
+        let entries = self.entries.read();
+        let mut batch = Vec::new();
+
+        for (key, entry) in entries.iter() {
+            if batch.len() >= max {
+                break;
+            }
+
+            let entry = match entry.load(atomic::Ordering::Acquire) {
+                Some(entry) => entry,
+                None => continue,
+            };
+            // Take the dirty flag, unless the entry is already being flushed.
+            if entry.state.compare_exchange(
+                DIRTY,
+                FLUSHING,
+                atomic::Ordering::AcqRel,
+                atomic::Ordering::Relaxed,
+            ).is_err() {
+                continue;
+            }
+
+            // As the dirty flag is set after the value is stored, this is at least as new as the
+            // write, which dirtied the entry. Newer writes set the dirty flag again.
+            let value = entry.value.load(atomic::Ordering::Acquire)
+                .expect("Entries always have a value.");
+            batch.push(Flush {
+                key: key.clone(),
+                value: value,
+                entry: entry,
+                completed: false,
+            });
+        }
+
+        batch
+    }
+
+    /// Evict the entry of `key`, if it is clean.
+    ///
+    /// This returns `true`, if the entry was removed, and `false`, if `key` has no entry, or the
+    /// entry is dirty or being flushed.
+    pub fn evict(&self, key: &K) -> bool {
+        let mut entries = self.entries.write();
+
+        // The state can only leave the clean state under the read lock, so it doesn't change
+        // until the entry is removed.
+        let clean = entries.get(key).and_then(|entry| entry.load(atomic::Ordering::Acquire))
+            .map_or(false, |entry| entry.state.load(atomic::Ordering::Acquire) == 0);
+        if clean {
+            entries.remove(key);
+        }
+
+        clean
+    }
+
+    /// Remove the entry of `key` regardless of its state, returning its value.
+    ///
+    /// Unflushed changes are discarded. Ongoing flushes of the entry can still be completed.
+    pub fn remove(&self, key: &K) -> Option<Guard<V>> {
+        let entry = self.entries.write().remove(key)?;
+        entry.load(atomic::Ordering::Acquire)?.value.load(atomic::Ordering::Acquire)
+    }
+
+    /// Get the state flags of `key`, or `0`, if it has no entry.
+    fn state(&self, key: &K) -> usize {
+        let entries = self.entries.read();
+        entries.get(key).and_then(|entry| entry.load(atomic::Ordering::Acquire))
+            .map_or(0, |entry| entry.state.load(atomic::Ordering::Acquire))
+    }
+
+    /// Store `value` under `key`, marking it dirty if `dirty` is set.
+    fn put(&self, key: K, value: V, dirty: bool) -> Option<Guard<V>> {
+        let value = Box::new(value);
+
+        {
+            // Try replacing the value of an existing entry, which only requires the read lock.
+            let entries = self.entries.read();
+            if let Some(entry) = entries.get(&key).and_then(|x| x.load(atomic::Ordering::Acquire)) {
+                return entry.replace(value, dirty);
+            }
+        }
+
+        let mut entries = self.entries.write();
+        match entries.entry(key) {
+            // The entry was inserted in the meantime.
+            hash_map::Entry::Occupied(entry) => {
+                let entry = entry.get().load(atomic::Ordering::Acquire)
+                    .expect("Entries are never null.");
+                entry.replace(value, dirty)
+            },
+            hash_map::Entry::Vacant(entry) => {
+                entry.insert(Atomic::new(Some(Box::new(Entry {
+                    value: Atomic::new(Some(value)),
+                    state: AtomicUsize::new(if dirty { DIRTY } else { 0 }),
+                }))));
+
+                None
+            },
+        }
+    }
+}
+
+impl<K, V> Default for DirtyMap<K, V>
+where
+    K: Hash + Eq + Clone + Send + Sync + 'static,
+    V: Send + Sync + 'static,
+{
+    fn default() -> DirtyMap<K, V> {
+        DirtyMap::new()
+    }
+}
+
+impl<K, V> fmt::Debug for DirtyMap<K, V>
+where
+    K: Clone + fmt::Debug,
+    V: fmt::Debug + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Copy the keys out, so the lock isn't held while formatting.
+        let (len, values) = {
+            let entries = self.entries.read();
+            (entries.len(), Prefix::collect(entries.iter().filter_map(|(key, entry)| {
+                let value = entry.load(atomic::Ordering::Acquire)?.value
+                    .load(atomic::Ordering::Acquire)?;
+                Some((key.clone(), value))
+            })))
+        };
+
+        f.debug_struct("DirtyMap")
+            .field("len", &len)
+            .field("entries", &MapPrefix(values.map(|&(ref key, ref value)| (key, &**value))))
+            .finish()
+    }
+}
+
+impl<V: 'static> Entry<V> {
+    /// Replace the value of the entry, marking it dirty if `dirty` is set.
+    fn replace(&self, value: Box<V>, dirty: bool) -> Option<Guard<V>> {
+        let old = self.value.swap(Some(value), atomic::Ordering::AcqRel);
+        // The flag is set after the value is stored, so the flush taking it writes this value
+        // (or a newer one).
+        if dirty {
+            self.state.fetch_or(DIRTY, atomic::Ordering::AcqRel);
+        }
+
+        old
+    }
+}
+
+/// A value taken for flushing by `DirtyMap::take_dirty_batch()`.
+///
+/// This guards the value, so it can be written back, even if the entry is changed, evicted or
+/// removed in the meantime. Dropping it without calling `complete()` leaves the entry dirty.
+pub struct Flush<K, V: 'static> {
+    /// The key of the entry.
+    key: K,
+    /// The value to write back.
+    value: Guard<V>,
+    /// The entry being flushed.
+    entry: Guard<Entry<V>>,
+    /// Was the flush completed?
+    completed: bool,
+}
+
+impl<K, V: 'static> Flush<K, V> {
+    /// Get the key of the entry being flushed.
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Mark the flush as completed.
+    ///
+    /// The entry becomes clean, unless it was written during the flush.
+    pub fn complete(mut self) {
+        self.completed = true;
+    }
+}
+
+impl<K, V: 'static> ops::Deref for Flush<K, V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.value
+    }
+}
+
+impl<K, V: 'static> Drop for Flush<K, V> {
+    fn drop(&mut self) {
+        if !self.completed {
+            // Set the dirty flag before the flushing flag is cleared, so the entry isn't
+            // considered clean in between.
+            self.entry.state.fetch_or(DIRTY, atomic::Ordering::AcqRel);
+        }
+
+        self.entry.state.fetch_and(!FLUSHING, atomic::Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn write_and_flush() {
+        let map = DirtyMap::new();
+        assert!(map.insert(1, "a").is_none());
+        assert!(map.write(2, "b").is_none());
+        assert!(!map.is_dirty(&1));
+        assert!(map.is_dirty(&2));
+
+        let batch = map.take_dirty_batch(16);
+        assert_eq!(batch.len(), 1);
+        assert_eq!(*batch[0].key(), 2);
+        assert_eq!(*batch[0], "b");
+        assert!(!map.is_dirty(&2));
+        assert!(map.is_flushing(&2));
+
+        for flush in batch {
+            flush.complete();
+        }
+        assert!(!map.is_flushing(&2));
+        assert!(map.take_dirty_batch(16).is_empty());
+    }
+
+    #[test]
+    fn debug() {
+        let map = DirtyMap::new();
+        assert_eq!(format!("{:?}", map), "DirtyMap { len: 0, entries: {} }");
+        map.write(1, "a");
+        assert_eq!(format!("{:?}", map), "DirtyMap { len: 1, entries: {1: \"a\"} }");
+    }
+
+    #[test]
+    fn write_during_flush() {
+        let map = DirtyMap::new();
+        map.write(1, 1);
+
+        let mut batch = map.take_dirty_batch(16);
+        assert_eq!(*map.write(1, 2).unwrap(), 1);
+        // The ongoing flush keeps its value, and no other flush is started.
+        assert_eq!(*batch[0], 1);
+        assert!(map.is_dirty(&1));
+        assert!(map.take_dirty_batch(16).is_empty());
+
+        batch.pop().unwrap().complete();
+        let batch = map.take_dirty_batch(16);
+        assert_eq!(*batch[0], 2);
+    }
+
+    #[test]
+    fn failed_flush() {
+        let map = DirtyMap::new();
+        map.insert(1, 1);
+        assert!(map.mark_dirty(&1));
+        assert!(!map.mark_dirty(&2));
+
+        drop(map.take_dirty_batch(16));
+        assert!(map.is_dirty(&1));
+        assert!(!map.is_flushing(&1));
+        assert_eq!(map.take_dirty_batch(16).len(), 1);
+    }
+
+    #[test]
+    fn evict_only_clean() {
+        let map = DirtyMap::new();
+        map.insert(1, 1);
+        map.write(2, 2);
+
+        assert!(!map.evict(&2));
+        let batch = map.take_dirty_batch(1);
+        assert!(!map.evict(&2));
+        for flush in batch {
+            flush.complete();
+        }
+
+        assert!(map.evict(&1));
+        assert!(map.evict(&2));
+        assert!(!map.evict(&3));
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn remove_during_flush() {
+        let map = DirtyMap::new();
+        map.write(1, 1);
+
+        let batch = map.take_dirty_batch(16);
+        assert_eq!(*map.remove(&1).unwrap(), 1);
+        ::gc().unwrap();
+        assert_eq!(*batch[0], 1);
+        drop(batch);
+        assert!(map.get(&1).is_none());
+    }
+
+    #[test]
+    fn batch_limit() {
+        let map = DirtyMap::new();
+        for i in 0..10 {
+            map.write(i, i);
+        }
+
+        assert_eq!(map.take_dirty_batch(4).len(), 4);
+        assert_eq!(map.take_dirty_batch(16).len(), 10);
+    }
+
+    #[test]
+    fn no_lost_writes() {
+        let map = Arc::new(DirtyMap::new());
+        let flushed = Arc::new(DirtyMap::new());
+
+        let mut j = Vec::new();
+        for t in 0..4u64 {
+            let map = map.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..1000u64 {
+                    map.write(i % 16, t * 1000 + i);
+                    map.evict(&((i + 8) % 16));
+                }
+            }));
+        }
+        for _ in 0..2 {
+            let map = map.clone();
+            let flushed = flushed.clone();
+            j.push(thread::spawn(move || {
+                for _ in 0..1000 {
+                    for flush in map.take_dirty_batch(4) {
+                        flushed.insert(*flush.key(), *flush);
+                        flush.complete();
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        // Flush the rest, after which every entry in the map is written back.
+        for flush in map.take_dirty_batch(16) {
+            flushed.insert(*flush.key(), *flush);
+            flush.complete();
+        }
+        for key in 0..16 {
+            if let Some(value) = map.get(&key) {
+                assert_eq!(*flushed.get(&key).unwrap(), *value);
+            }
+        }
+    }
+}
@@ -0,0 +1,219 @@
+//! Disjoint-set forests.
+
+use std::sync::atomic;
+use {Atomic, Guard};
+
+/// A lock-free disjoint-set (union-find) structure over the elements `0..len`.
+///
+/// Every element links to its parent, and the roots of the trees represent the sets. Sets are
+/// merged by linking the root of lower rank to the other one, and the paths are halved by the
+/// lookups.
+///
+/// The parent and the rank of an element are kept together in an immutable link, which is
+/// replaced through CAS, so they always change atomically. The links replaced are retired
+/// through `conc`, so they can't be reused while a concurrent operation still compares against
+/// them (which would lead to ABA).
+pub struct DisjointSet {
+    /// The links of the elements.
+    ///
+    /// These are never null.
+    links: Box<[Atomic<Link>]>,
+}
+
+/// The link of an element of a disjoint-set forest.
+struct Link {
+    /// The parent of the element, which is the element itself, if it is a root.
+    parent: usize,
+    /// The rank of the element.
+    ///
+    /// This is only meaningful for roots, and only increases.
+    rank: u32,
+}
+
+impl DisjointSet {
+    /// Create a new forest of `len` singleton sets.
+    pub fn new(len: usize) -> DisjointSet {
+        DisjointSet {
+            links: (0..len).map(|i| Atomic::new(Some(Box::new(Link {
+                parent: i,
+                rank: 0,
+            })))).collect::<Vec<_>>().into_boxed_slice(),
+        }
+    }
+
+    /// Get the number of elements.
+    pub fn len(&self) -> usize {
+        self.links.len()
+    }
+
+    /// Are there no elements?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Find the representative of the set of `x`.
+    ///
+    /// The representative might change, when sets are merged concurrently.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `x` is out of bounds.
+    pub fn find(&self, x: usize) -> usize {
+        self.find_root(x).0
+    }
+
+    /// Are `a` and `b` in the same set?
+    pub fn same_set(&self, a: usize, b: usize) -> bool {
+        loop {
+            let a = self.find(a);
+            let b = self.find(b);
+            if a == b {
+                return true;
+            }
+
+            // `a` might have been linked to `b` after it was found, so we must check that it is
+            // still a root.
+            if self.link(a).parent == a {
+                return false;
+            }
+        }
+    }
+
+    /// Merge the sets of `a` and `b`.
+    ///
+    /// `false` is returned, if they were in the same set already.
+    pub fn union(&self, a: usize, b: usize) -> bool {
+        loop {
+            let (a, link_a) = self.find_root(a);
+            let (b, link_b) = self.find_root(b);
+            if a == b {
+                return false;
+            }
+
+            // Link the lower ranked root to the other, breaking ties by index. As a root is only
+            // linked, if its link (and hence its rank) didn't change since it was compared, and
+            // ranks only increase, this can't create cycles.
+            let ((child, link_child), (parent, link_parent)) =
+                if (link_a.rank, a) < (link_b.rank, b) {
+                    ((a, link_a), (b, link_b))
+                } else {
+                    ((b, link_b), (a, link_a))
+                };
+
+            if self.links[child].compare_and_store(Some(link_child.as_ptr()), Some(Box::new(Link {
+                parent: parent,
+                rank: link_child.rank,
+            })), atomic::Ordering::AcqRel).is_err() {
+                // The child isn't a root anymore, or its rank changed.
+                continue;
+            }
+
+            if link_child.rank == link_parent.rank {
+                // The rank is only a heuristic, so it doesn't matter, if this fails because the
+                // parent was linked meanwhile.
+                let _ = self.links[parent].compare_and_store(
+                    Some(link_parent.as_ptr()),
+                    Some(Box::new(Link {
+                        parent: parent,
+                        rank: link_parent.rank + 1,
+                    })),
+                    atomic::Ordering::AcqRel,
+                );
+            }
+
+            return true;
+        }
+    }
+
+    /// Find the root of `x` and its link, halving the path on the way.
+    fn find_root(&self, mut x: usize) -> (usize, Guard<Link>) {
+        loop {
+            let link = self.link(x);
+            let parent = link.parent;
+            if parent == x {
+                return (x, link);
+            }
+
+            let grandparent = self.link(parent).parent;
+            if grandparent != parent {
+                // Skip the parent. If this fails, another thread changed the link already.
+                let _ = self.links[x].compare_and_store(Some(link.as_ptr()), Some(Box::new(Link {
+                    parent: grandparent,
+                    rank: link.rank,
+                })), atomic::Ordering::AcqRel);
+            }
+
+            x = grandparent;
+        }
+    }
+
+    /// Get the link of `x`.
+    fn link(&self, x: usize) -> Guard<Link> {
+        self.links[x].load(atomic::Ordering::Acquire).expect("Links are never null.")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn union_find() {
+        let set = DisjointSet::new(10);
+        assert_eq!(set.len(), 10);
+        assert!(!set.same_set(1, 2));
+
+        assert!(set.union(1, 2));
+        assert!(set.union(3, 4));
+        assert!(!set.union(2, 1));
+        assert!(set.same_set(1, 2));
+        assert!(!set.same_set(2, 3));
+
+        assert!(set.union(2, 4));
+        assert!(set.same_set(1, 3));
+        assert_eq!(set.find(1), set.find(4));
+        assert_eq!(set.find(0), 0);
+    }
+
+    #[test]
+    fn path_halving() {
+        let set = DisjointSet::new(100);
+        for i in 1..100 {
+            set.union(i - 1, i);
+        }
+
+        let root = set.find(0);
+        for i in 0..100 {
+            assert_eq!(set.find(i), root);
+        }
+        // Union by rank keeps the trees shallow.
+        assert!(set.link(root).rank <= 7);
+        ::gc().unwrap();
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let set = Arc::new(DisjointSet::new(1000));
+
+        let mut j = Vec::new();
+        for t in 0..4 {
+            let set = set.clone();
+            // Every thread joins the elements of the same residue modulo 10, in another order.
+            j.push(thread::spawn(move || for i in 0..990 {
+                let i = (i * 7 + t * 250) % 990;
+                set.union(i, i + 10);
+                assert!(set.same_set(i, i + 10));
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        for i in 0..1000 {
+            assert_eq!(set.find(i), set.find(i % 10));
+            assert_eq!(set.same_set(i, (i + 1) % 1000), false);
+        }
+    }
+}
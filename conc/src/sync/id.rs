@@ -0,0 +1,368 @@
+//! Allocators of integer IDs.
+
+use std::cmp;
+use std::sync::atomic::{self, AtomicU64, AtomicUsize};
+use {Atomic, Guard};
+
+/// The number of words of a chunk.
+const WORDS: usize = 64;
+/// The number of IDs of a chunk.
+const CHUNK_IDS: u64 = WORDS as u64 * 64;
+/// The `Chunk.used` value of chunks, which are being reclaimed.
+const DEAD: usize = !0;
+/// A free word of the bitmap.
+///
+/// This is used to initialize the arrays of words.
+const FREE_WORD: AtomicU64 = AtomicU64::new(0);
+
+/// A lock-free allocator of the IDs (e.g. block numbers) below some capacity.
+///
+/// The IDs are tracked by a bitmap split into chunks, which are allocated when they are first
+/// needed. Every chunk keeps the number of IDs reserved in it, so an allocation reserves its IDs,
+/// before it searches the bitmap for them, and doesn't touch full chunks. Chunks (other than the
+/// first), whose IDs are all freed, are reclaimed through `conc`.
+///
+/// The lowest free IDs are preferred, but as the search starts at a hint, which is updated
+/// concurrently, this isn't guaranteed.
+pub struct IdAllocator {
+    /// The chunks of the bitmap.
+    chunks: Box<[Atomic<Chunk>]>,
+    /// The number of IDs.
+    capacity: u64,
+    /// The chunk, which the search for free IDs starts at.
+    hint: AtomicUsize,
+}
+
+/// A chunk of the bitmap of an `IdAllocator`.
+struct Chunk {
+    /// The bitmap of allocated IDs.
+    words: [AtomicU64; WORDS],
+    /// The number of allocated (or reserved) IDs of the chunk, or `DEAD`.
+    ///
+    /// This includes the padding.
+    used: AtomicUsize,
+    /// The number of IDs at the end of the chunk, which are beyond the capacity.
+    ///
+    /// These are marked as allocated, when the chunk is created.
+    padding: usize,
+}
+
+impl Chunk {
+    /// Create a new chunk with `padding` IDs beyond the capacity.
+    fn new(padding: usize) -> Chunk {
+        let chunk = Chunk {
+            words: [FREE_WORD; WORDS],
+            used: AtomicUsize::new(padding),
+            padding: padding,
+        };
+
+        for id in CHUNK_IDS as usize - padding..CHUNK_IDS as usize {
+            chunk.words[id / 64].fetch_or(1 << (id % 64), atomic::Ordering::Relaxed);
+        }
+
+        chunk
+    }
+
+    /// Reserve up to `max` IDs of the chunk, returning the number reserved.
+    fn reserve(&self, max: usize) -> usize {
+        let mut used = self.used.load(atomic::Ordering::Relaxed);
+        loop {
+            if used == DEAD || used == CHUNK_IDS as usize {
+                return 0;
+            }
+
+            let n = cmp::min(max, CHUNK_IDS as usize - used);
+            match self.used.compare_exchange_weak(
+                used,
+                used + n,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            ) {
+                Ok(_) => return n,
+                Err(x) => used = x,
+            }
+        }
+    }
+
+    /// Take `n` reserved IDs from the bitmap, calling `f` with their indexes in the chunk.
+    fn claim<F: FnMut(u64)>(&self, mut n: usize, f: &mut F) {
+        // IDs are freed in the bitmap before they are unreserved, so there are always at least as
+        // many free IDs as are reserved, and this terminates, even though other threads claim
+        // IDs concurrently.
+        while n > 0 {
+            for (i, word) in self.words.iter().enumerate() {
+                let mut cur = word.load(atomic::Ordering::Relaxed);
+                while n > 0 && cur != !0 {
+                    // Take the lowest free bits of the word.
+                    let mut take = 0;
+                    let mut free = !cur;
+                    for _ in 0..cmp::min(n, 64) {
+                        take |= free & free.wrapping_neg();
+                        free &= free - 1;
+                        if free == 0 {
+                            break;
+                        }
+                    }
+
+                    match word.compare_exchange_weak(
+                        cur,
+                        cur | take,
+                        atomic::Ordering::Acquire,
+                        atomic::Ordering::Relaxed,
+                    ) {
+                        Ok(_) => {
+                            n -= take.count_ones() as usize;
+                            while take != 0 {
+                                f(i as u64 * 64 + take.trailing_zeros() as u64);
+                                take &= take - 1;
+                            }
+                        },
+                        Err(x) => cur = x,
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl IdAllocator {
+    /// Create a new allocator of the IDs `0..capacity`.
+    pub fn new(capacity: u64) -> IdAllocator {
+        let chunks = (capacity + CHUNK_IDS - 1) / CHUNK_IDS;
+
+        IdAllocator {
+            chunks: (0..chunks).map(|_| Atomic::default()).collect::<Vec<_>>().into_boxed_slice(),
+            capacity: capacity,
+            hint: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the number of IDs of the allocator.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Allocate a free ID.
+    ///
+    /// `None` is returned, if all IDs are allocated.
+    pub fn alloc(&self) -> Option<u64> {
+        let mut ret = None;
+        self.take(1, &mut |id| ret = Some(id));
+        ret
+    }
+
+    /// Allocate up to `n` free IDs at once.
+    ///
+    /// This is cheaper than allocating the IDs one by one, as they are reserved together. Fewer
+    /// IDs are returned, if there aren't enough free ones.
+    pub fn alloc_bulk(&self, n: usize) -> Vec<u64> {
+        let mut ret = Vec::with_capacity(n);
+        self.take(n, &mut |id| ret.push(id));
+        ret
+    }
+
+    /// Free an allocated ID.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `id` isn't allocated.
+    pub fn free(&self, id: u64) {
+        assert!(id < self.capacity, "Freeing an ID beyond the capacity.");
+
+        let i = (id / CHUNK_IDS) as usize;
+        let bit = (id % CHUNK_IDS) as usize;
+        let chunk = self.chunks[i].load(atomic::Ordering::Acquire)
+            .expect("Freeing an ID, which isn't allocated.");
+
+        let mask = 1 << (bit % 64);
+        let old = chunk.words[bit / 64].fetch_and(!mask, atomic::Ordering::Release);
+        assert!(old & mask != 0, "Freeing an ID, which isn't allocated.");
+
+        // Unreserve the ID, reclaiming the chunk if it was the last one.
+        if chunk.used.fetch_sub(1, atomic::Ordering::AcqRel) - 1 == chunk.padding && i != 0
+            && chunk.used.compare_exchange(
+                chunk.padding,
+                DEAD,
+                atomic::Ordering::Acquire,
+                atomic::Ordering::Relaxed,
+            ).is_ok() {
+            // No IDs can be reserved in the dead chunk, so it can be unlinked. Allocations might
+            // have done so already.
+            let _ = self.chunks[i].compare_and_store(
+                Some(chunk.as_ptr()),
+                None,
+                atomic::Ordering::AcqRel,
+            );
+        }
+
+        self.hint.fetch_min(i, atomic::Ordering::Relaxed);
+    }
+
+    /// Is `id` allocated?
+    pub fn is_allocated(&self, id: u64) -> bool {
+        id < self.capacity && self.chunks[(id / CHUNK_IDS) as usize]
+            .load(atomic::Ordering::Acquire)
+            .map_or(false, |chunk| {
+                let bit = (id % CHUNK_IDS) as usize;
+                chunk.words[bit / 64].load(atomic::Ordering::Acquire) & 1 << (bit % 64) != 0
+            })
+    }
+
+    /// Allocate up to `n` IDs, calling `f` with each of them.
+    fn take<F: FnMut(u64)>(&self, mut n: usize, f: &mut F) {
+        let len = self.chunks.len();
+        let start = self.hint.load(atomic::Ordering::Relaxed);
+
+        for i in (start..len).chain(0..start) {
+            if n == 0 {
+                break;
+            }
+
+            let chunk = self.chunk(i);
+            let reserved = chunk.reserve(n);
+            if reserved == 0 {
+                continue;
+            }
+
+            let base = i as u64 * CHUNK_IDS;
+            chunk.claim(reserved, &mut |id| f(base + id));
+            n -= reserved;
+            self.hint.store(i, atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// Get the live chunk `i`, creating it if necessary.
+    fn chunk(&self, i: usize) -> Guard<Chunk> {
+        loop {
+            let chunk = match self.chunks[i].load(atomic::Ordering::Acquire) {
+                Some(chunk) => chunk,
+                None => {
+                    let padding = ((i as u64 + 1) * CHUNK_IDS).saturating_sub(self.capacity);
+                    self.chunks[i].set_if_null(
+                        Box::new(Chunk::new(padding as usize)),
+                        atomic::Ordering::AcqRel,
+                    )
+                },
+            };
+
+            if chunk.used.load(atomic::Ordering::Acquire) != DEAD {
+                return chunk;
+            }
+
+            // Help unlinking the dead chunk, so it can be replaced.
+            let _ = self.chunks[i].compare_and_store(
+                Some(chunk.as_ptr()),
+                None,
+                atomic::Ordering::AcqRel,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn alloc_free() {
+        let ids = IdAllocator::new(10000);
+        assert_eq!(ids.alloc(), Some(0));
+        assert_eq!(ids.alloc(), Some(1));
+        assert!(ids.is_allocated(1));
+
+        ids.free(0);
+        assert!(!ids.is_allocated(0));
+        assert_eq!(ids.alloc(), Some(0));
+        assert_eq!(ids.alloc(), Some(2));
+    }
+
+    #[test]
+    #[should_panic]
+    fn double_free() {
+        let ids = IdAllocator::new(100);
+        let id = ids.alloc().unwrap();
+        ids.free(id);
+        ids.free(id);
+    }
+
+    #[test]
+    fn capacity() {
+        let ids = IdAllocator::new(5000);
+        let all = ids.alloc_bulk(6000);
+        assert_eq!(all.len(), 5000);
+        assert_eq!(all.iter().cloned().collect::<HashSet<_>>().len(), 5000);
+        assert!(all.iter().all(|&id| id < 5000));
+        assert_eq!(ids.alloc(), None);
+
+        ids.free(4999);
+        assert_eq!(ids.alloc(), Some(4999));
+    }
+
+    #[test]
+    fn bulk() {
+        let ids = IdAllocator::new(100000);
+        let first = ids.alloc_bulk(5000);
+        assert_eq!(first, (0..5000).collect::<Vec<_>>());
+
+        for &id in &first[100..200] {
+            ids.free(id);
+        }
+        let mut second = ids.alloc_bulk(150);
+        second.sort();
+        assert_eq!(&second[..100], &first[100..200]);
+    }
+
+    #[test]
+    fn chunk_reclaimed() {
+        let ids = IdAllocator::new(3 * CHUNK_IDS);
+        let all = ids.alloc_bulk(2 * CHUNK_IDS as usize);
+        assert!(ids.chunks[1].load(atomic::Ordering::Relaxed).is_some());
+
+        for id in all {
+            ids.free(id);
+        }
+        // The first chunk is kept.
+        assert!(ids.chunks[0].load(atomic::Ordering::Relaxed).is_some());
+        assert!(ids.chunks[1].load(atomic::Ordering::Relaxed).is_none());
+
+        assert_eq!(ids.alloc_bulk(CHUNK_IDS as usize + 1).len(), CHUNK_IDS as usize + 1);
+        assert!(ids.is_allocated(CHUNK_IDS));
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let ids = Arc::new(IdAllocator::new(2 * CHUNK_IDS));
+
+        let mut j = Vec::new();
+        for _ in 0..8 {
+            let ids = ids.clone();
+            j.push(thread::spawn(move || {
+                let mut held = Vec::new();
+                for i in 0..10000 {
+                    if i % 3 == 2 {
+                        held.extend(ids.alloc_bulk(16));
+                    } else if let Some(id) = ids.alloc() {
+                        held.push(id);
+                    }
+                    if held.len() > 500 {
+                        for id in held.drain(..400) {
+                            ids.free(id);
+                        }
+                    }
+                }
+                held
+            }));
+        }
+
+        let mut all = HashSet::new();
+        for i in j {
+            for id in i.join().unwrap() {
+                assert!(ids.is_allocated(id));
+                assert!(all.insert(id), "ID {} was allocated twice.", id);
+            }
+        }
+    }
+}
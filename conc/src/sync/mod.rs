@@ -1,7 +1,103 @@
 //! Various simple lock-free data structures built on `conc`.
+//!
+//! The structures can be formatted through `Debug` from any thread, while they are changed
+//! concurrently. This prints a guarded snapshot of at most `debug_limit()` elements, so logging a
+//! large structure is cheap. The snapshot of a structure isn't necessarily consistent as a whole,
+//! but every element printed was in the structure at some point during the formatting.
 
+mod bloom;
+mod clock;
+mod deque;
+mod dirty;
+mod disjoint;
+mod id;
+mod multimap;
+mod page;
 mod stm;
 mod treiber;
+mod versioned;
 
+pub use self::bloom::{BloomFilter, Rebuild};
+pub use self::clock::ClockCache;
+pub use self::deque::{Deque, DequeIntoIter};
+pub use self::dirty::{DirtyMap, Flush};
+pub use self::disjoint::DisjointSet;
+pub use self::id::IdAllocator;
+pub use self::multimap::HashMultiMap;
+pub use self::page::{PageCache, Pinned};
 pub use self::stm::Stm;
-pub use self::treiber::Treiber;
+pub use self::treiber::{Peek, Treiber, TreiberIntoIter};
+pub use self::versioned::Versioned;
+
+use std::fmt;
+use std::sync::atomic::{self, AtomicUsize};
+
+/// The maximal number of elements printed by the `Debug` implementations.
+static DEBUG_LIMIT: AtomicUsize = AtomicUsize::new(16);
+
+/// Set the maximal number of elements printed by the `Debug` implementations.
+///
+/// The default is 16.
+pub fn set_debug_limit(limit: usize) {
+    DEBUG_LIMIT.store(limit, atomic::Ordering::Relaxed);
+}
+
+/// Get the maximal number of elements printed by the `Debug` implementations.
+pub fn debug_limit() -> usize {
+    DEBUG_LIMIT.load(atomic::Ordering::Relaxed)
+}
+
+/// A bounded prefix of the elements of a structure, formatted as a list.
+struct Prefix<T> {
+    /// The elements.
+    items: Vec<T>,
+    /// Were elements left out?
+    truncated: bool,
+}
+
+impl<T> Prefix<T> {
+    /// Take at most `debug_limit()` elements from `iter`.
+    fn collect<I: IntoIterator<Item = T>>(iter: I) -> Prefix<T> {
+        let mut iter = iter.into_iter();
+        let items: Vec<T> = iter.by_ref().take(debug_limit()).collect();
+        Prefix {
+            items: items,
+            truncated: iter.next().is_some(),
+        }
+    }
+
+    /// Map the elements through `f`.
+    fn map<'a, U, F: FnMut(&'a T) -> U>(&'a self, f: F) -> Prefix<U> {
+        Prefix {
+            items: self.items.iter().map(f).collect(),
+            truncated: self.truncated,
+        }
+    }
+}
+
+impl<T: fmt::Debug> fmt::Debug for Prefix<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut list = f.debug_list();
+        list.entries(&self.items);
+        if self.truncated {
+            list.finish_non_exhaustive()
+        } else {
+            list.finish()
+        }
+    }
+}
+
+/// A bounded prefix of the entries of a map, formatted as a map.
+struct MapPrefix<K, V>(Prefix<(K, V)>);
+
+impl<K: fmt::Debug, V: fmt::Debug> fmt::Debug for MapPrefix<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut map = f.debug_map();
+        map.entries(self.0.items.iter().map(|&(ref k, ref v)| (k, v)));
+        if self.0.truncated {
+            map.finish_non_exhaustive()
+        } else {
+            map.finish()
+        }
+    }
+}
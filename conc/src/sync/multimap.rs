@@ -0,0 +1,269 @@
+//! Concurrent multimaps.
+
+use parking_lot::RwLock;
+use std::collections::HashMap;
+use std::fmt;
+use std::hash::Hash;
+use std::sync::atomic;
+use {Atomic, Guard};
+use super::{MapPrefix, Prefix};
+
+/// A concurrent hash map from keys to bags of values.
+///
+/// The values of every key are kept in an immutable array, which is replaced through CAS (copying
+/// it), when a value is added or removed, and retired through `conc`. Hence, changes to the
+/// values of a key are lock-free, and readers get a guarded snapshot of them, which they can
+/// iterate over without blocking writers. As every change copies the array, this is meant for
+/// keys with a moderate number of values.
+///
+/// Keys without values are removed from the map.
+pub struct HashMultiMap<K, V> {
+    /// The values of the keys.
+    ///
+    /// Keys are added and removed under the write lock, whereas their values are changed under
+    /// the read lock. The values are never empty, unless the key is about to be removed.
+    bags: RwLock<HashMap<K, Atomic<Vec<V>>>>,
+}
+
+impl<K, V> HashMultiMap<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + PartialEq + Send + Sync + 'static,
+{
+    /// Create a new, empty multimap.
+    pub fn new() -> HashMultiMap<K, V> {
+        HashMultiMap {
+            bags: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Get the number of keys with values.
+    pub fn len(&self) -> usize {
+        self.bags.read().len()
+    }
+
+    /// Is the multimap empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Get the values of `key`.
+    ///
+    /// The snapshot isn't affected by later changes. `None` is returned, if `key` has no values.
+    pub fn get(&self, key: &K) -> Option<Guard<[V]>> {
+        let bag = self.bags.read().get(key)?.load(atomic::Ordering::Acquire)?;
+        if bag.is_empty() {
+            None
+        } else {
+            Some(bag.map(|bag| &bag[..]))
+        }
+    }
+
+    /// Add `value` to the values of `key`.
+    pub fn insert(&self, key: K, value: V) {
+        let push = |values: &[V]| {
+            let mut values = values.to_vec();
+            values.push(value.clone());
+            Some(values)
+        };
+
+        {
+            let bags = self.bags.read();
+            if let Some(bag) = bags.get(&key) {
+                // The key isn't removed, while we hold the read lock, so it is fine, if the bag is
+                // empty.
+                update(bag, push);
+                return;
+            }
+        }
+
+        let mut bags = self.bags.write();
+        update(bags.entry(key).or_insert_with(|| Atomic::new(Some(Box::new(Vec::new())))), push);
+    }
+
+    /// Remove one occurrence of `value` from the values of `key`.
+    ///
+    /// `false` is returned, if `key` doesn't have the value.
+    pub fn remove_one(&self, key: &K, value: &V) -> bool {
+        let emptied = {
+            let bags = self.bags.read();
+            let bag = match bags.get(key) {
+                Some(bag) => bag,
+                None => return false,
+            };
+
+            let mut emptied = false;
+            let removed = update(bag, |values| {
+                let pos = values.iter().position(|x| x == value)?;
+                let mut values = values.to_vec();
+                values.remove(pos);
+                emptied = values.is_empty();
+                Some(values)
+            });
+
+            if !removed {
+                return false;
+            }
+
+            emptied
+        };
+
+        if emptied {
+            // Remove the key, unless a value was added before we got the write lock.
+            let mut bags = self.bags.write();
+            if bags.get(key).and_then(|bag| bag.load(atomic::Ordering::Acquire))
+                .map_or(false, |values| values.is_empty()) {
+                bags.remove(key);
+            }
+        }
+
+        true
+    }
+
+    /// Remove all the values of `key`, returning them.
+    pub fn remove_all(&self, key: &K) -> Option<Guard<[V]>> {
+        let bag = self.bags.write().remove(key)?.load(atomic::Ordering::Acquire)?;
+        if bag.is_empty() {
+            None
+        } else {
+            Some(bag.map(|bag| &bag[..]))
+        }
+    }
+}
+
+impl<K, V> Default for HashMultiMap<K, V>
+where
+    K: Hash + Eq + Send + Sync + 'static,
+    V: Clone + PartialEq + Send + Sync + 'static,
+{
+    fn default() -> HashMultiMap<K, V> {
+        HashMultiMap::new()
+    }
+}
+
+impl<K, V> fmt::Debug for HashMultiMap<K, V>
+where
+    K: Clone + fmt::Debug,
+    V: fmt::Debug + 'static,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Copy the keys out, so the lock isn't held while formatting.
+        let (len, bags) = {
+            let bags = self.bags.read();
+            (bags.len(), Prefix::collect(bags.iter().filter_map(|(key, bag)| {
+                bag.load(atomic::Ordering::Acquire).map(|bag| (key.clone(), bag))
+            })))
+        };
+
+        f.debug_struct("HashMultiMap")
+            .field("len", &len)
+            .field("entries", &MapPrefix(bags.map(|&(ref key, ref bag)| (key, &**bag))))
+            .finish()
+    }
+}
+
+/// Replace the values of `bag` by `f` applied to them.
+///
+/// If `f` returns `None`, nothing is changed. If the values are changed concurrently, `f` is
+/// reevaluated. Whether the values were replaced is returned.
+fn update<V, F>(bag: &Atomic<Vec<V>>, mut f: F) -> bool
+where
+    V: Send + Sync + 'static,
+    F: FnMut(&[V]) -> Option<Vec<V>>,
+{
+    loop {
+        let old = bag.load(atomic::Ordering::Acquire).expect("Bags are never null.");
+        let new = match f(&old) {
+            Some(new) => new,
+            None => return false,
+        };
+
+        if bag.compare_and_store(Some(old.as_ptr()), Some(Box::new(new)), atomic::Ordering::AcqRel)
+            .is_ok() {
+            return true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn insert_remove() {
+        let map = HashMultiMap::new();
+        map.insert(1, "a");
+        map.insert(1, "b");
+        map.insert(1, "a");
+        map.insert(2, "c");
+        assert_eq!(map.len(), 2);
+        assert_eq!(&*map.get(&1).unwrap(), &["a", "b", "a"]);
+
+        assert!(map.remove_one(&1, &"a"));
+        assert!(!map.remove_one(&1, &"c"));
+        assert!(!map.remove_one(&3, &"a"));
+        assert_eq!(&*map.get(&1).unwrap(), &["b", "a"]);
+
+        assert_eq!(&*map.remove_all(&1).unwrap(), &["b", "a"]);
+        assert!(map.get(&1).is_none());
+        assert!(map.remove_all(&1).is_none());
+
+        // The key is removed with its last value.
+        assert!(map.remove_one(&2, &"c"));
+        assert!(map.get(&2).is_none());
+        assert!(map.is_empty());
+    }
+
+    #[test]
+    fn debug() {
+        let map = HashMultiMap::new();
+        map.insert(1, 'a');
+        map.insert(1, 'b');
+        assert_eq!(format!("{:?}", map), "HashMultiMap { len: 1, entries: {1: ['a', 'b']} }");
+    }
+
+    #[test]
+    fn snapshot() {
+        let map = HashMultiMap::new();
+        map.insert(1, 1);
+        map.insert(1, 2);
+
+        let values = map.get(&1).unwrap();
+        map.insert(1, 3);
+        map.remove_one(&1, &1);
+        ::gc().unwrap();
+
+        assert_eq!(values.iter().sum::<i32>(), 3);
+        assert_eq!(&*map.get(&1).unwrap(), &[2, 3]);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let map = Arc::new(HashMultiMap::new());
+
+        let mut j = Vec::new();
+        for t in 0..8u64 {
+            let map = map.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..1000u64 {
+                    map.insert(i % 16, t);
+                    if i % 2 == 1 {
+                        assert!(map.remove_one(&(i % 16), &t));
+                    }
+                    if let Some(values) = map.get(&(i % 16)) {
+                        assert!(values.iter().all(|&x| x < 8));
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        // Every thread added 500 values in total, spread evenly over the keys.
+        let total: usize = (0..16).map(|k| map.get(&k).map_or(0, |values| values.len())).sum();
+        assert_eq!(total, 4000);
+    }
+}
@@ -0,0 +1,354 @@
+//! Page caches with pinning and weighted capacity.
+
+use parking_lot::RwLock;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::ops;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use {Atomic, Guard};
+use super::{MapPrefix, Prefix};
+
+/// A concurrent cache of pages keyed by `u64`, bounded by the total weight of the pages.
+///
+/// Every page has a weight (e.g. its size in bytes), and the total weight of the cached pages is
+/// kept within the capacity given to `PageCache::new()` by evicting pages in clock (second-chance)
+/// order, like `ClockCache`.
+///
+/// Lookups return pins, which prevent the page from being evicted, as long as they live. Pinned
+/// pages can still be replaced or removed explicitly, in which case the pin keeps the old page
+/// alive. If the capacity can't be met without evicting pinned pages, the insertion fails.
+pub struct PageCache<V> {
+    /// The maximal total weight of the pages.
+    capacity: usize,
+    /// The pages and their eviction order.
+    ///
+    /// This is read-locked by lookups and write-locked by insertions and removals. Pins are only
+    /// taken under the read lock, so pages unpinned under the write lock stay unpinned.
+    index: RwLock<Index<V>>,
+}
+
+/// The index of a page cache.
+struct Index<V> {
+    /// Map keys to their pages.
+    pages: HashMap<u64, Atomic<Page<V>>>,
+    /// The keys in clock order, starting at the clock hand.
+    order: VecDeque<u64>,
+    /// The total weight of the pages.
+    weight: usize,
+}
+
+/// A page of a page cache.
+struct Page<V> {
+    /// The cached value.
+    value: V,
+    /// The weight of the page.
+    weight: usize,
+    /// The number of pins of the page.
+    pins: AtomicUsize,
+    /// Was the page looked up since the clock hand passed it?
+    referenced: AtomicBool,
+}
+
+impl<V: Send + Sync + 'static> PageCache<V> {
+    /// Create a new, empty cache with a total weight of at most `capacity`.
+    pub fn new(capacity: usize) -> PageCache<V> {
+        PageCache {
+            capacity: capacity,
+            index: RwLock::new(Index {
+                pages: HashMap::new(),
+                order: VecDeque::new(),
+                weight: 0,
+            }),
+        }
+    }
+
+    /// Get the maximal total weight of the cache.
+    pub fn capacity(&self) -> usize {
+        self.capacity
+    }
+
+    /// Get the total weight of the cached pages.
+    pub fn weight(&self) -> usize {
+        self.index.read().weight
+    }
+
+    /// Get the number of cached pages.
+    pub fn len(&self) -> usize {
+        self.index.read().pages.len()
+    }
+
+    /// Is the cache empty?
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Look up and pin the page of `key`.
+    ///
+    /// The page isn't evicted until the returned pin is dropped.
+    pub fn get(&self, key: u64) -> Option<Pinned<V>> {
+        let index = self.index.read();
+        let page = index.pages.get(&key)?.load(atomic::Ordering::Acquire)?;
+
+        page.referenced.store(true, atomic::Ordering::Relaxed);
+        page.pins.fetch_add(1, atomic::Ordering::Relaxed);
+
+        Some(Pinned {
+            page: page,
+        })
+    }
+
+    /// Insert `value` as the page of `key` with weight `weight`.
+    ///
+    /// If `key` already has a page, it is replaced. Unpinned pages are evicted until the total
+    /// weight is within the capacity.
+    ///
+    /// # Errors
+    ///
+    /// If the capacity can't be met, as too many pages are pinned, nothing is evicted, and the
+    /// value is given back.
+    pub fn insert(&self, key: u64, value: V, weight: usize) -> Result<(), V> {
+        let mut index = self.index.write();
+
+        // The weight of the page being replaced, if any.
+        let replaced = index.pages.get(&key)
+            .and_then(|page| page.load(atomic::Ordering::Acquire))
+            .map_or(0, |page| page.weight);
+        let excess = (index.weight - replaced).saturating_add(weight).saturating_sub(self.capacity);
+
+        if excess > 0 {
+            // Check that enough weight can be evicted, before anything is evicted.
+            let evictable: usize = index.pages.iter()
+                .filter(|&(&x, _)| x != key)
+                .filter_map(|(_, page)| page.load(atomic::Ordering::Acquire))
+                .filter(|page| page.pins.load(atomic::Ordering::Acquire) == 0)
+                .map(|page| page.weight)
+                .sum();
+            if evictable < excess {
+                return Err(value);
+            }
+
+            self.evict(&mut index, key, excess);
+        }
+
+        let page = Box::new(Page {
+            value: value,
+            weight: weight,
+            pins: AtomicUsize::new(0),
+            referenced: AtomicBool::new(false),
+        });
+        if let Some(old) = index.pages.get(&key) {
+            old.store(Some(page), atomic::Ordering::Release);
+        } else {
+            index.pages.insert(key, Atomic::new(Some(page)));
+            index.order.push_back(key);
+        }
+        index.weight = index.weight - replaced + weight;
+
+        Ok(())
+    }
+
+    /// Remove the page of `key`, returning its value.
+    ///
+    /// This removes the page, even if it is pinned.
+    pub fn remove(&self, key: u64) -> Option<Guard<V>> {
+        let mut index = self.index.write();
+        let slot = index.pages.remove(&key)?;
+        index.order.retain(|&x| x != key);
+
+        let page = slot.load(atomic::Ordering::Acquire)?;
+        index.weight -= page.weight;
+
+        Some(page.map(|page| &page.value))
+    }
+
+    /// Evict unpinned pages other than `keep` of at least `excess` weight in total.
+    ///
+    /// The caller must ensure that there are enough of them.
+    fn evict(&self, index: &mut Index<V>, keep: u64, mut excess: usize) {
+        while excess > 0 {
+            let key = index.order.pop_front().expect("Too few evictable pages.");
+            let page = index.pages[&key].load(atomic::Ordering::Acquire)
+                .expect("Pages are never null.");
+
+            // Skip pinned pages, and give referenced pages a second chance. As lookups are
+            // excluded by the write lock, no pins or reference bits are added in the meantime, so
+            // this ends within two revolutions.
+            if key == keep
+                || page.pins.load(atomic::Ordering::Acquire) != 0
+                || page.referenced.swap(false, atomic::Ordering::Relaxed) {
+                index.order.push_back(key);
+                continue;
+            }
+
+            index.pages.remove(&key);
+            index.weight -= page.weight;
+            excess = excess.saturating_sub(page.weight);
+        }
+    }
+}
+
+impl<V: fmt::Debug + 'static> fmt::Debug for PageCache<V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Copy the pages out, so the lock isn't held while formatting.
+        let (len, weight, pages) = {
+            let index = self.index.read();
+            (index.pages.len(), index.weight, Prefix::collect(index.pages.iter()
+                .filter_map(|(&key, page)| page.load(atomic::Ordering::Acquire).map(|page| {
+                    (key, page)
+                }))))
+        };
+
+        f.debug_struct("PageCache")
+            .field("len", &len)
+            .field("weight", &weight)
+            .field("capacity", &self.capacity)
+            .field("pages", &MapPrefix(pages.map(|&(key, ref page)| (key, &page.value))))
+            .finish()
+    }
+}
+
+/// A pinned page of a `PageCache`.
+///
+/// The page is protected from reclamation, and isn't evicted, until this is dropped.
+pub struct Pinned<V: 'static> {
+    /// The pinned page.
+    page: Guard<Page<V>>,
+}
+
+impl<V> Pinned<V> {
+    /// Get the weight of the page.
+    pub fn weight(&self) -> usize {
+        self.page.weight
+    }
+}
+
+impl<V> ops::Deref for Pinned<V> {
+    type Target = V;
+
+    fn deref(&self) -> &V {
+        &self.page.value
+    }
+}
+
+impl<V> Drop for Pinned<V> {
+    fn drop(&mut self) {
+        self.page.pins.fetch_sub(1, atomic::Ordering::Release);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+
+    #[test]
+    fn insert_get_remove() {
+        let cache = PageCache::new(100);
+        assert!(cache.is_empty());
+        cache.insert(1, "a", 10).unwrap();
+        cache.insert(2, "b", 20).unwrap();
+        assert_eq!(cache.weight(), 30);
+
+        assert_eq!(*cache.get(1).unwrap(), "a");
+        assert_eq!(cache.get(2).unwrap().weight(), 20);
+        assert!(cache.get(3).is_none());
+
+        // Replacing a page updates its weight.
+        cache.insert(1, "c", 5).unwrap();
+        assert_eq!(*cache.get(1).unwrap(), "c");
+        assert_eq!(cache.weight(), 25);
+
+        assert_eq!(*cache.remove(2).unwrap(), "b");
+        assert!(cache.remove(2).is_none());
+        assert_eq!(cache.weight(), 5);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn debug() {
+        let cache = PageCache::new(10);
+        cache.insert(1, "a", 3).unwrap();
+        assert_eq!(format!("{:?}", cache),
+                   "PageCache { len: 1, weight: 3, capacity: 10, pages: {1: \"a\"} }");
+    }
+
+    #[test]
+    fn weighted_eviction() {
+        let cache = PageCache::new(100);
+        cache.insert(1, 1, 40).unwrap();
+        cache.insert(2, 2, 40).unwrap();
+        cache.insert(3, 3, 10).unwrap();
+
+        // Both of the oldest pages must go to make room for this.
+        cache.insert(4, 4, 90).unwrap();
+        assert!(cache.get(1).is_none());
+        assert!(cache.get(2).is_none());
+        assert_eq!(*cache.get(3).unwrap(), 3);
+        assert_eq!(cache.weight(), 100);
+
+        assert_eq!(cache.insert(5, 5, 101), Err(5));
+        assert_eq!(cache.weight(), 100);
+    }
+
+    #[test]
+    fn second_chance() {
+        let cache = PageCache::new(2);
+        cache.insert(1, 1, 1).unwrap();
+        cache.insert(2, 2, 1).unwrap();
+
+        drop(cache.get(1));
+        cache.insert(3, 3, 1).unwrap();
+        assert!(cache.get(2).is_none());
+        assert!(cache.get(1).is_some());
+    }
+
+    #[test]
+    fn pins_prevent_eviction() {
+        let cache = PageCache::new(2);
+        cache.insert(1, 1, 1).unwrap();
+        cache.insert(2, 2, 1).unwrap();
+
+        let pin = cache.get(1).unwrap();
+        let pin2 = cache.get(2).unwrap();
+        assert_eq!(cache.insert(3, 3, 1), Err(3));
+        assert_eq!(cache.len(), 2);
+
+        // Only the unpinned page is evicted, even though the pinned one is older.
+        drop(pin2);
+        cache.insert(3, 3, 1).unwrap();
+        assert!(cache.get(2).is_none());
+        assert_eq!(*pin, 1);
+
+        // Removal ignores pins, but the pin keeps the page alive.
+        cache.remove(1);
+        ::gc().unwrap();
+        assert_eq!(*pin, 1);
+    }
+
+    #[test]
+    fn multi_threaded() {
+        let cache = Arc::new(PageCache::new(64));
+
+        let mut j = Vec::new();
+        for t in 0..8u64 {
+            let cache = cache.clone();
+            j.push(thread::spawn(move || {
+                for i in 0..10000u64 {
+                    let key = (i * 7 + t) % 128;
+                    match cache.get(key) {
+                        Some(page) => assert_eq!(*page, key * 2),
+                        None => {
+                            let _ = cache.insert(key, key * 2, (key % 4 + 1) as usize);
+                        },
+                    }
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert!(cache.weight() <= 64);
+    }
+}
@@ -1,6 +1,7 @@
 //! Software transactional memory.
 
 use {Atomic, Guard};
+use std::fmt;
 use std::sync::atomic;
 
 /// A software transactional memory container.
@@ -47,6 +48,14 @@ impl<T> Stm<T> {
     }
 }
 
+impl<T: fmt::Debug + 'static> fmt::Debug for Stm<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Stm")
+            .field("value", &self.inner.load(atomic::Ordering::Acquire).as_ref().map(|x| &**x))
+            .finish()
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -66,6 +75,14 @@ mod tests {
         assert!(stm.load().is_none());
     }
 
+    #[test]
+    fn debug() {
+        let stm = Stm::new(None);
+        assert_eq!(format!("{:?}", stm), "Stm { value: None }");
+        stm.update(|_| Some(Box::new(4)));
+        assert_eq!(format!("{:?}", stm), "Stm { value: Some(4) }");
+    }
+
     #[test]
     fn multi_threaded() {
         let stm = Arc::new(Stm::new(Some(Box::new(0))));
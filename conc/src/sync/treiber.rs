@@ -1,8 +1,10 @@
 //! Treiber stacks.
 
-use std::sync::atomic::{self, AtomicPtr};
+use std::sync::atomic::{self, AtomicPtr, AtomicUsize};
 use std::marker::PhantomData;
-use std::ptr;
+use std::ops;
+use std::{fmt, mem, ptr};
+use super::Prefix;
 use {Guard, add_garbage_box};
 
 /// A Treiber stack.
@@ -16,6 +18,11 @@ use {Guard, add_garbage_box};
 pub struct Treiber<T> {
     /// The head node.
     head: AtomicPtr<Node<T>>,
+    /// The approximate number of items.
+    ///
+    /// This is incremented before an item is published and decremented after it is unlinked, so
+    /// it never underflows.
+    len: AtomicUsize,
     /// Make the `Sync` and `Send` (and other OIBITs) transitive.
     _marker: PhantomData<T>,
 }
@@ -25,10 +32,44 @@ impl<T> Treiber<T> {
     pub fn new() -> Treiber<T> {
         Treiber {
             head: AtomicPtr::default(),
+            len: AtomicUsize::new(0),
             _marker: PhantomData,
         }
     }
 
+    /// Get the approximate number of items on the stack.
+    ///
+    /// This is a relaxed counter, so it is cheap, but it can be off while there are concurrent
+    /// pushes and pops. It is meant for monitoring and backpressure, not for synchronization.
+    pub fn len(&self) -> usize {
+        self.len.load(atomic::Ordering::Relaxed)
+    }
+
+    /// Is the stack empty?
+    ///
+    /// Like `len()`, this can be outdated by the time it returns, if there are concurrent pushes
+    /// or pops.
+    pub fn is_empty(&self) -> bool {
+        self.head.load(atomic::Ordering::Relaxed).is_null()
+    }
+
+    /// Get the top item of the stack without popping it.
+    ///
+    /// The item is protected, even if it is popped meanwhile. This allows for inspecting it before
+    /// deciding to pop, but another thread might pop it first, so the next `pop()` doesn't
+    /// necessarily return the same item.
+    ///
+    /// Unlike a popped item, the item might still be on the stack, when the stack is torn down.
+    /// Hence, the peek borrows the stack.
+    pub fn peek(&self) -> Option<Peek<'_, T>> {
+        Guard::maybe_new(|| unsafe {
+            self.head.load(atomic::Ordering::Acquire).as_ref()
+        }).map(|node| Peek {
+            item: node.map(|x| &x.item),
+            _stack: PhantomData,
+        })
+    }
+
     /// Pop an item from the stack.
     // TODO: Change this return type.
     pub fn pop(&self) -> Option<Guard<T>> {
@@ -56,6 +97,7 @@ impl<T> Treiber<T> {
                     // As we overwrote the old head (the CAS was successful), we must queue its
                     // deletion.
                     unsafe { add_garbage_box(old.as_ptr()); }
+                    self.len.fetch_sub(1, atomic::Ordering::Relaxed);
                     // Map the guard to refer the item.
                     return Some(old.map(|x| &x.item));
                 }
@@ -77,6 +119,9 @@ impl<T> Treiber<T> {
             self.head.load(atomic::Ordering::Relaxed).as_ref()
         });
 
+        // Count the item, before it can be popped.
+        self.len.fetch_add(1, atomic::Ordering::Relaxed);
+
         // TODO: Use `catch {}` here when it lands.
         // Construct a node, which will be the new head.
         let mut node = Box::into_raw(Box::new(Node {
@@ -129,6 +174,106 @@ impl<T> Drop for Treiber<T> {
     }
 }
 
+impl<T: fmt::Debug + 'static> fmt::Debug for Treiber<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // Items are only popped from the top, so as long as the head is unchanged, the nodes below
+        // it are on the stack. Hence, we walk down, while the head is unchanged.
+        let head = self.head.load(atomic::Ordering::Acquire);
+        let mut ptr = head;
+        let mut nodes = Vec::new();
+        while !ptr.is_null() && nodes.len() < super::debug_limit() {
+            // The head is checked while garbage collection is blocked, so if it is unchanged, the
+            // node can't have been reclaimed yet.
+            let node = Guard::try_new(|| if self.head.load(atomic::Ordering::Acquire) == head {
+                Ok(unsafe { &*ptr })
+            } else {
+                Err(())
+            });
+            let node = match node {
+                Ok(node) => node,
+                Err(()) => break,
+            };
+
+            ptr = node.next;
+            nodes.push(node);
+        }
+
+        f.debug_struct("Treiber")
+            .field("len", &self.len())
+            .field("items", &Prefix {
+                items: nodes.iter().map(|node| &node.item).collect(),
+                truncated: !ptr.is_null(),
+            })
+            .finish()
+    }
+}
+
+impl<T> IntoIterator for Treiber<T> {
+    type Item = T;
+    type IntoIter = TreiberIntoIter<T>;
+
+    /// Take the stack apart, yielding its items from the top.
+    ///
+    /// As the stack is owned, no other thread can access it, so this doesn't go through the
+    /// atomics or the garbage collector.
+    fn into_iter(mut self) -> TreiberIntoIter<T> {
+        // Leave an empty stack behind for the destructor.
+        TreiberIntoIter {
+            head: mem::replace(self.head.get_mut(), ptr::null_mut()),
+        }
+    }
+}
+
+/// An iterator taking a stack apart.
+///
+/// See `Treiber::into_iter()`.
+pub struct TreiberIntoIter<T> {
+    /// The nodes left.
+    head: *mut Node<T>,
+}
+
+// The iterator owns the nodes.
+unsafe impl<T: Send> Send for TreiberIntoIter<T> {}
+unsafe impl<T: Sync> Sync for TreiberIntoIter<T> {}
+
+impl<T> Iterator for TreiberIntoIter<T> {
+    type Item = T;
+
+    fn next(&mut self) -> Option<T> {
+        if self.head.is_null() {
+            return None;
+        }
+
+        let node = unsafe { Box::from_raw(self.head) };
+        self.head = node.next;
+        Some(node.item)
+    }
+}
+
+impl<T> Drop for TreiberIntoIter<T> {
+    fn drop(&mut self) {
+        for _ in self {}
+    }
+}
+
+/// The top item of a stack, protected from destruction.
+///
+/// See `Treiber::peek()`.
+pub struct Peek<'a, T: 'static> {
+    /// The guard of the item.
+    item: Guard<T>,
+    /// Borrow the stack, so it isn't torn down while the item is protected.
+    _stack: PhantomData<&'a Treiber<T>>,
+}
+
+impl<'a, T> ops::Deref for Peek<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.item
+    }
+}
+
 /// A node in the stack.
 struct Node<T> {
     /// The data this node holds.
@@ -188,6 +333,85 @@ mod tests {
         }
     }
 
+    #[test]
+    fn len() {
+        let stack = Treiber::new();
+        assert_eq!(stack.len(), 0);
+        assert!(stack.is_empty());
+
+        stack.push(1);
+        stack.push(2);
+        assert_eq!(stack.len(), 2);
+        assert!(!stack.is_empty());
+
+        stack.pop();
+        assert_eq!(stack.len(), 1);
+        stack.pop();
+        stack.pop();
+        assert_eq!(stack.len(), 0);
+        assert!(stack.is_empty());
+    }
+
+    #[test]
+    fn peek() {
+        let stack = Treiber::new();
+        assert!(stack.peek().is_none());
+
+        stack.push(1);
+        stack.push(2);
+        assert_eq!(*stack.peek().unwrap(), 2);
+        assert_eq!(stack.len(), 2);
+
+        // The peeked item outlives its popping.
+        let top = stack.peek().unwrap();
+        assert_eq!(*stack.pop().unwrap(), 2);
+        ::gc().unwrap();
+        assert_eq!(*top, 2);
+        assert_eq!(*stack.peek().unwrap(), 1);
+    }
+
+    #[test]
+    fn into_iter() {
+        let stack = Treiber::new();
+        for i in 0..10 {
+            stack.push(i);
+        }
+        stack.pop();
+
+        assert_eq!(stack.into_iter().collect::<Vec<_>>(), [8, 7, 6, 5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn into_iter_drop() {
+        let drops = Arc::new(AtomicUsize::new(0));
+        let stack = Treiber::new();
+        for _ in 0..10 {
+            stack.push(Dropper {
+                d: drops.clone(),
+            });
+        }
+
+        let mut iter = stack.into_iter();
+        drop(iter.next());
+        drop(iter.next());
+        // The items are destroyed right away, without going through the garbage.
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 2);
+        drop(iter);
+        assert_eq!(drops.load(atomic::Ordering::Relaxed), 10);
+    }
+
+    #[test]
+    fn debug() {
+        let stack = Treiber::new();
+        assert_eq!(format!("{:?}", stack), "Treiber { len: 0, items: [] }");
+
+        for i in 0..20 {
+            stack.push(i);
+        }
+        assert_eq!(format!("{:?}", stack), "Treiber { len: 20, items: [19, 18, 17, 16, 15, 14, \
+                                           13, 12, 11, 10, 9, 8, 7, 6, 5, 4, ..] }");
+    }
+
     #[test]
     fn just_push() {
         let stack = Treiber::new();
@@ -210,7 +434,7 @@ mod tests {
         assert_eq!(*stack.pop().unwrap(), 1);
         assert!(stack.pop().is_none());
 
-        ::gc();
+        ::gc().unwrap();
     }
 
     #[test]
@@ -235,7 +459,7 @@ mod tests {
             assert!(stack.pop().is_none());
         }
 
-        ::gc();
+        ::gc().unwrap();
     }
 
     #[test]
@@ -382,13 +606,13 @@ mod tests {
             i.join().unwrap();
         }
 
-        ::gc();
+        ::gc().unwrap();
         // The 16 are for the `d` variable in the loop above.
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 32 + 16);
 
         // Drop the last arc.
         drop(stack);
-        ::gc();
+        ::gc().unwrap();
 
         assert_eq!(drops.load(atomic::Ordering::Relaxed), 20 * 16 + 16);
     }
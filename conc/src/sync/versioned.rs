@@ -0,0 +1,222 @@
+//! Multi-version containers.
+
+use parking_lot::Mutex;
+use std::marker::PhantomData;
+use std::ptr;
+use std::sync::atomic::{self, AtomicPtr};
+use {Guard, add_garbage_box};
+
+/// A container keeping a bounded number of recent versions of its value.
+///
+/// Every store creates a new version with the next version number. Readers can get the latest
+/// version, or the version as of some earlier version number (i.e. the newest version not newer
+/// than it), as long as it is still kept, which gives them snapshot isolation without copying the
+/// value.
+///
+/// Versions are kept in a chain from the newest to the oldest. When the chain exceeds the depth
+/// given to `Versioned::new()`, the oldest version is unlinked and retired, so it is reclaimed
+/// once the guards reading it are gone.
+///
+/// Reads are lock-free, whereas stores exclude each other.
+pub struct Versioned<T> {
+    /// The newest version.
+    ///
+    /// This is never null.
+    head: AtomicPtr<Node<T>>,
+    /// The maximal number of versions kept.
+    depth: usize,
+    /// The lock serializing stores.
+    writer: Mutex<()>,
+    /// Make the `Sync` and `Send` (and other OIBITs) transitive.
+    _marker: PhantomData<T>,
+}
+
+/// A version of the value of a `Versioned<T>`.
+struct Node<T> {
+    /// The version number.
+    version: u64,
+    /// The value of the version.
+    value: T,
+    /// The previous version, or null, if it isn't kept.
+    prev: AtomicPtr<Node<T>>,
+}
+
+impl<T: Send + Sync + 'static> Versioned<T> {
+    /// Create a new container with `value` as version `0`, keeping up to `depth` versions.
+    ///
+    /// # Panics
+    ///
+    /// This panics if `depth` is zero.
+    pub fn new(value: T, depth: usize) -> Versioned<T> {
+        assert!(depth > 0, "Keeping no versions.");
+
+        Versioned {
+            head: AtomicPtr::new(Box::into_raw(Box::new(Node {
+                version: 0,
+                value: value,
+                prev: AtomicPtr::default(),
+            }))),
+            depth: depth,
+            writer: Mutex::new(()),
+            _marker: PhantomData,
+        }
+    }
+
+    /// Get the number of the latest version.
+    pub fn version(&self) -> u64 {
+        self.latest().0
+    }
+
+    /// Get the latest version and its number.
+    pub fn latest(&self) -> (u64, Guard<T>) {
+        let node = Guard::new(|| unsafe { &*self.head.load(atomic::Ordering::Acquire) });
+        (node.version, node.map(|node| &node.value))
+    }
+
+    /// Get the newest version not newer than `version`, and its number.
+    ///
+    /// `None` is returned, if the version isn't kept anymore.
+    pub fn as_of(&self, version: u64) -> Option<(u64, Guard<T>)> {
+        let mut node = Guard::new(|| unsafe { &*self.head.load(atomic::Ordering::Acquire) });
+
+        while node.version > version {
+            // If the previous version is unlinked meanwhile, it isn't reclaimed before we had the
+            // chance to protect it, as the hazard is blocked while it is loaded.
+            let prev = Guard::maybe_new(|| unsafe {
+                node.prev.load(atomic::Ordering::Acquire).as_ref()
+            })?;
+            node = prev;
+        }
+
+        Some((node.version, node.map(|node| &node.value)))
+    }
+
+    /// Store a new version of the value, returning its number.
+    ///
+    /// If more than the maximal number of versions are kept afterwards, the oldest version is
+    /// retired.
+    pub fn store(&self, value: T) -> u64 {
+        let _writer = self.writer.lock();
+
+        // Only stores unlink versions, so the versions can be accessed, while the lock is held.
+        unsafe {
+            let head = self.head.load(atomic::Ordering::Relaxed);
+            let version = (*head).version + 1;
+            let node = Box::into_raw(Box::new(Node {
+                version: version,
+                value: value,
+                prev: AtomicPtr::new(head),
+            }));
+            self.head.store(node, atomic::Ordering::Release);
+
+            // Find the oldest version to keep.
+            let mut last = node;
+            for _ in 1..self.depth {
+                last = (*last).prev.load(atomic::Ordering::Relaxed);
+                if last.is_null() {
+                    return version;
+                }
+            }
+
+            // Unlink the versions beyond it. As every store does this, it is at most one.
+            let old = (*last).prev.swap(ptr::null_mut(), atomic::Ordering::Release);
+            if !old.is_null() {
+                add_garbage_box(old);
+            }
+
+            version
+        }
+    }
+}
+
+impl<T> Drop for Versioned<T> {
+    fn drop(&mut self) {
+        let mut node = *self.head.get_mut();
+        while !node.is_null() {
+            // The versions might still be guarded, so they're retired rather than dropped.
+            unsafe {
+                let prev = (*node).prev.load(atomic::Ordering::Relaxed);
+                add_garbage_box(node);
+                node = prev;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::thread;
+    use testing::{Counter, Tracked};
+
+    #[test]
+    fn latest_and_as_of() {
+        let v = Versioned::new("a", 3);
+        assert_eq!(v.version(), 0);
+        assert_eq!(v.store("b"), 1);
+        assert_eq!(v.store("c"), 2);
+
+        let (version, value) = v.latest();
+        assert_eq!((version, *value), (2, "c"));
+        let (version, value) = v.as_of(1).unwrap();
+        assert_eq!((version, *value), (1, "b"));
+        let (version, value) = v.as_of(0).unwrap();
+        assert_eq!((version, *value), (0, "a"));
+        let (version, value) = v.as_of(10).unwrap();
+        assert_eq!((version, *value), (2, "c"));
+    }
+
+    #[test]
+    fn bounded() {
+        static COUNTER: Counter = Counter::new();
+
+        let v = Versioned::new(Tracked::with_counter(0, &COUNTER), 2);
+        let oldest = v.latest().1;
+
+        v.store(Tracked::with_counter(1, &COUNTER));
+        v.store(Tracked::with_counter(2, &COUNTER));
+        v.store(Tracked::with_counter(3, &COUNTER));
+        assert!(v.as_of(1).is_none());
+        assert_eq!(v.as_of(2).unwrap().0, 2);
+
+        // The first version is retired, but still guarded.
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 1);
+        assert_eq!(**oldest, 0);
+        drop(oldest);
+        ::local::free_hazards();
+        ::gc().unwrap();
+        assert_eq!(COUNTER.destroyed(), 2);
+
+        drop(v);
+        COUNTER.assert_balanced();
+    }
+
+    #[test]
+    fn snapshots() {
+        let v = Arc::new(Versioned::new(0u64, 8));
+
+        let mut j = Vec::new();
+        for _ in 0..2 {
+            let v = v.clone();
+            j.push(thread::spawn(move || for i in 0..1000 {
+                v.store(i);
+            }));
+        }
+        for _ in 0..4 {
+            let v = v.clone();
+            j.push(thread::spawn(move || for _ in 0..1000 {
+                let (version, _) = v.latest();
+                if let Some((found, _)) = v.as_of(version.saturating_sub(4)) {
+                    assert!(found <= version);
+                }
+            }));
+        }
+        for i in j {
+            i.join().unwrap();
+        }
+
+        assert_eq!(v.version(), 2000);
+    }
+}
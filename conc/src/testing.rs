@@ -0,0 +1,243 @@
+//! Utilities for testing structures built upon `conc`.
+//!
+//! The main tool is `Tracked<T>`, a wrapper, whose constructions and destructions are counted, such
+//! that you can check that everything retired was eventually destroyed exactly once.
+//!
+//! # Example
+//!
+//! ```rust
+//! use conc::testing::{self, Tracked};
+//!
+//! let atomic = conc::Atomic::new(Some(Box::new(Tracked::new(1))));
+//! atomic.store(Some(Box::new(Tracked::new(2))), std::sync::atomic::Ordering::Release);
+//! drop(atomic);
+//!
+//! testing::assert_balanced();
+//! ```
+//!
+//! # Concurrent tests
+//!
+//! `Tracked::new` counts in a global counter, so tests running in parallel (as `cargo test` does by
+//! default) will see each other's objects. To avoid that, give each test its own `Counter` through
+//! `Tracked::with_counter`.
+
+use std::sync::atomic::{self, AtomicUsize};
+use std::ops;
+
+/// The canary of a live object.
+const CANARY: usize = 0x7AC4ED;
+
+/// The global counter.
+static COUNTER: Counter = Counter::new();
+
+/// A counter of constructions and destructions.
+#[derive(Debug)]
+pub struct Counter {
+    /// The number of objects created.
+    created: AtomicUsize,
+    /// The number of objects destroyed.
+    destroyed: AtomicUsize,
+}
+
+impl Counter {
+    /// Create a new counter.
+    ///
+    /// This is a `const fn`, so it can be used to initialize a `static`.
+    pub const fn new() -> Counter {
+        Counter {
+            created: AtomicUsize::new(0),
+            destroyed: AtomicUsize::new(0),
+        }
+    }
+
+    /// Get the number of objects created.
+    pub fn created(&self) -> usize {
+        self.created.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Get the number of objects destroyed.
+    pub fn destroyed(&self) -> usize {
+        self.destroyed.load(atomic::Ordering::SeqCst)
+    }
+
+    /// Get the number of objects currently alive.
+    pub fn live(&self) -> usize {
+        self.created() - self.destroyed()
+    }
+
+    /// Collect the garbage and assert that every object was destroyed exactly once.
+    ///
+    /// # Panics
+    ///
+    /// This panics if some objects are still alive (e.g. leaked) or were destroyed twice, or if
+    /// the collector is poisoned.
+    pub fn assert_balanced(&self) {
+        ::gc().unwrap();
+
+        let created = self.created();
+        let destroyed = self.destroyed();
+        assert!(destroyed <= created, "{} tracked objects destroyed twice.", destroyed - created);
+        assert!(destroyed == created, "{} tracked objects are still alive.", created - destroyed);
+    }
+}
+
+/// Get the number of objects created in the global counter.
+pub fn created() -> usize {
+    COUNTER.created()
+}
+
+/// Get the number of objects destroyed in the global counter.
+pub fn destroyed() -> usize {
+    COUNTER.destroyed()
+}
+
+/// Collect the garbage and assert that every object of the global counter was destroyed exactly
+/// once.
+///
+/// Note that the garbage accumulated locally in other (live) threads cannot be collected, so those
+/// threads should be joined before calling this.
+///
+/// # Panics
+///
+/// This panics if some objects are still alive (e.g. leaked), or if the collector is poisoned.
+pub fn assert_balanced() {
+    COUNTER.assert_balanced();
+}
+
+/// A value, whose constructions and destructions are counted.
+///
+/// This carries a canary, which is checked on access and destruction, such that double frees and
+/// (as long as the memory wasn't reused) use-after-free panic.
+#[derive(Debug)]
+pub struct Tracked<T> {
+    /// The inner value.
+    inner: T,
+    /// The counter this value is counted in.
+    counter: &'static Counter,
+    /// The canary, which is `CANARY` as long as the value is alive.
+    canary: usize,
+}
+
+impl<T> Tracked<T> {
+    /// Track a value in the global counter.
+    pub fn new(inner: T) -> Tracked<T> {
+        Tracked::with_counter(inner, &COUNTER)
+    }
+
+    /// Track a value in a given counter.
+    pub fn with_counter(inner: T, counter: &'static Counter) -> Tracked<T> {
+        counter.created.fetch_add(1, atomic::Ordering::SeqCst);
+
+        Tracked {
+            inner: inner,
+            counter: counter,
+            canary: CANARY,
+        }
+    }
+
+    /// Check that the value is alive.
+    ///
+    /// # Panics
+    ///
+    /// This panics if the value was detected to be destroyed.
+    pub fn check(&self) {
+        // Make sure the read isn't optimized out on the assumption of the value being alive.
+        let canary = unsafe { (&self.canary as *const usize).read_volatile() };
+        assert_eq!(canary, CANARY, "Accessing destroyed tracked value.");
+    }
+}
+
+impl<T: Clone> Clone for Tracked<T> {
+    fn clone(&self) -> Tracked<T> {
+        Tracked::with_counter(self.inner.clone(), self.counter)
+    }
+}
+
+impl<T> ops::Deref for Tracked<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.check();
+        &self.inner
+    }
+}
+
+impl<T> ops::DerefMut for Tracked<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.check();
+        &mut self.inner
+    }
+}
+
+impl<T> Drop for Tracked<T> {
+    fn drop(&mut self) {
+        self.check();
+
+        self.canary = 0;
+        self.counter.destroyed.fetch_add(1, atomic::Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{mem, thread};
+    use Atomic;
+
+    #[test]
+    fn count() {
+        static COUNTER: Counter = Counter::new();
+
+        let a = Tracked::with_counter(1, &COUNTER);
+        let b = a.clone();
+        assert_eq!(*a + *b, 2);
+        assert_eq!(COUNTER.created(), 2);
+        assert_eq!(COUNTER.live(), 2);
+
+        drop(a);
+        drop(b);
+        assert_eq!(COUNTER.destroyed(), 2);
+        COUNTER.assert_balanced();
+    }
+
+    #[test]
+    fn retired() {
+        static COUNTER: Counter = Counter::new();
+
+        let atomic = Atomic::new(Some(Box::new(Tracked::with_counter(0, &COUNTER))));
+        for i in 1..100 {
+            atomic.store(Some(Box::new(Tracked::with_counter(i, &COUNTER))), atomic::Ordering::Release);
+        }
+        drop(atomic);
+
+        COUNTER.assert_balanced();
+        assert_eq!(COUNTER.created(), 100);
+    }
+
+    #[test]
+    fn retired_in_threads() {
+        static COUNTER: Counter = Counter::new();
+
+        let j: Vec<_> = (0..4).map(|_| thread::spawn(|| {
+            let atomic = Atomic::new(None);
+            for i in 0..100 {
+                atomic.store(Some(Box::new(Tracked::with_counter(i, &COUNTER))), atomic::Ordering::Release);
+            }
+        })).collect();
+
+        for i in j {
+            i.join().unwrap();
+        }
+
+        COUNTER.assert_balanced();
+    }
+
+    #[test]
+    #[should_panic]
+    fn leak() {
+        static COUNTER: Counter = Counter::new();
+
+        mem::forget(Tracked::with_counter(0, &COUNTER));
+        COUNTER.assert_balanced();
+    }
+}
@@ -0,0 +1,309 @@
+//! Spawning threads with guaranteed cleanup.
+//!
+//! When a thread exits, its garbage is exported and its hazards are killed by the destructor of its
+//! thread-local state. However, the order in which the thread-local destructors are run is
+//! platform specific, and other destructors might create guards or retire garbage after the
+//! state is gone, falling back to slow paths, or in the worst case never being exported.
+//!
+//! The threads spawned through this module clean up right after their closure returns (or
+//! panics), while the thread-local state is still intact:
+//!
+//! 1. The garbage of the thread is exported.
+//! 2. The cached hazards of the thread are freed.
+//! 3. Optionally, garbage is collected through `conc::try_gc()`.
+//!
+//! Optionally, the threads reuse the local states of the threads exited before (see
+//! `Builder::reuse_state()`), sparing the setup and teardown of their own.
+//!
+//! For threads not spawned through this module, `install_panic_hook()` makes at least panicking
+//! threads flush their local state right away, rather than leaving it to the teardown.
+//!
+//! # Example
+//!
+//! ```rust
+//! use std::sync::atomic::Ordering;
+//!
+//! let atomic = conc::Atomic::new(Some(Box::new(1)));
+//!
+//! std::thread::scope(|s| {
+//!     conc::thread::Builder::new().gc_on_exit(true).spawn_scoped(s, || {
+//!         atomic.store(Some(Box::new(2)), Ordering::Release);
+//!     }).unwrap();
+//! });
+//!
+//! assert_eq!(*atomic.load(Ordering::Acquire).unwrap(), 2);
+//! ```
+
+use std::{io, panic, thread};
+use std::panic::AssertUnwindSafe;
+use std::sync::Once;
+use {global, local, try_gc, LocalState};
+
+/// A builder for threads with guaranteed cleanup.
+///
+/// This wraps `std::thread::Builder`.
+pub struct Builder {
+    /// The builder of the thread.
+    inner: thread::Builder,
+    /// Collect garbage, when the thread exits?
+    gc_on_exit: bool,
+    /// Adopt a parked local state on start, and park the local state on exit?
+    reuse_state: bool,
+}
+
+impl Builder {
+    /// Create a new builder with the default configuration.
+    pub fn new() -> Builder {
+        Builder {
+            inner: thread::Builder::new(),
+            gc_on_exit: false,
+            reuse_state: false,
+        }
+    }
+
+    /// Name the thread.
+    pub fn name(self, name: String) -> Builder {
+        Builder {
+            inner: self.inner.name(name),
+            gc_on_exit: self.gc_on_exit,
+            reuse_state: self.reuse_state,
+        }
+    }
+
+    /// Set the stack size of the thread.
+    pub fn stack_size(self, size: usize) -> Builder {
+        Builder {
+            inner: self.inner.stack_size(size),
+            gc_on_exit: self.gc_on_exit,
+            reuse_state: self.reuse_state,
+        }
+    }
+
+    /// Attempt to collect garbage (`conc::try_gc()`) after exporting the garbage of the thread.
+    ///
+    /// This is off by default. Note that a collection might take long, and might run destructors
+    /// of garbage retired by other threads.
+    pub fn gc_on_exit(self, gc_on_exit: bool) -> Builder {
+        Builder {
+            inner: self.inner,
+            gc_on_exit: gc_on_exit,
+            reuse_state: self.reuse_state,
+        }
+    }
+
+    /// Reuse the local states of the threads exited before.
+    ///
+    /// The thread adopts a parked local state on start (see `LocalState::unpark()`), and parks its
+    /// own on exit rather than tearing it down, such that its hazards and queue of garbage are
+    /// reused by the next thread. This is off by default, and meant for pools spawning lots of
+    /// short-lived threads.
+    pub fn reuse_state(self, reuse_state: bool) -> Builder {
+        Builder {
+            inner: self.inner,
+            gc_on_exit: self.gc_on_exit,
+            reuse_state: reuse_state,
+        }
+    }
+
+    /// Spawn a thread running `f`, which cleans up before exiting.
+    ///
+    /// An error is returned, if the thread couldn't be created.
+    pub fn spawn<F, T>(self, f: F) -> io::Result<thread::JoinHandle<T>>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (gc_on_exit, reuse_state) = (self.gc_on_exit, self.reuse_state);
+        self.inner.spawn(move || run(f, gc_on_exit, reuse_state))
+    }
+
+    /// Spawn a scoped thread running `f`, which cleans up before exiting.
+    ///
+    /// This is the counterpart of `std::thread::Builder::spawn_scoped`, so `f` can borrow from
+    /// the environment of `scope`. An error is returned, if the thread couldn't be created.
+    pub fn spawn_scoped<'scope, 'env, F, T>(self, scope: &'scope thread::Scope<'scope, 'env>, f: F)
+        -> io::Result<thread::ScopedJoinHandle<'scope, T>>
+    where
+        F: FnOnce() -> T + Send + 'scope,
+        T: Send + 'scope,
+    {
+        let (gc_on_exit, reuse_state) = (self.gc_on_exit, self.reuse_state);
+        self.inner.spawn_scoped(scope, move || run(f, gc_on_exit, reuse_state))
+    }
+}
+
+impl Default for Builder {
+    fn default() -> Builder {
+        Builder::new()
+    }
+}
+
+/// Spawn a thread running `f`, which cleans up before exiting.
+///
+/// This is the counterpart of `std::thread::spawn`.
+///
+/// # Panics
+///
+/// This panics if the thread couldn't be created.
+pub fn spawn<F, T>(f: F) -> thread::JoinHandle<T>
+where
+    F: FnOnce() -> T + Send + 'static,
+    T: Send + 'static,
+{
+    Builder::new().spawn(f).expect("Failed to spawn thread.")
+}
+
+/// Spawn a scoped thread running `f`, which cleans up before exiting.
+///
+/// This is the counterpart of `std::thread::Scope::spawn`.
+///
+/// # Panics
+///
+/// This panics if the thread couldn't be created.
+pub fn spawn_scoped<'scope, 'env, F, T>(scope: &'scope thread::Scope<'scope, 'env>, f: F)
+    -> thread::ScopedJoinHandle<'scope, T>
+where
+    F: FnOnce() -> T + Send + 'scope,
+    T: Send + 'scope,
+{
+    Builder::new().spawn_scoped(scope, f).expect("Failed to spawn thread.")
+}
+
+/// Install a panic hook, which flushes the local state of panicking threads.
+///
+/// When a thread panics, the hook exports its garbage and frees its cached hazards, before the
+/// thread starts unwinding. The hazards of the guards, which are alive at that point, are
+/// released as the guards are dropped by the unwinding. Hence, a panicking thread doesn't hold
+/// back garbage until its thread-local state is torn down (which might never happen, if the
+/// thread is kept alive, e.g. by a pool catching the panic).
+///
+/// The hook previously installed is run first. Installing the hook more than once has no effect.
+/// Panics in the middle of a garbage collection don't flush anything, as the state of the
+/// collector might be inconsistent.
+pub fn install_panic_hook() {
+    static INSTALLED: Once = Once::new();
+
+    INSTALLED.call_once(|| {
+        let prev = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            prev(info);
+
+            // Exporting garbage takes locks, which the thread might hold in a critical section.
+            if !global::in_critical() {
+                local::flush();
+            }
+        }));
+    });
+}
+
+/// Run `f`, and clean up the current thread afterwards.
+///
+/// If `f` panics, the thread is cleaned up before the panic is resumed. If `reuse_state` is set, a
+/// parked local state is adopted first, and the local state is parked rather than flushed.
+fn run<F: FnOnce() -> T, T>(f: F, gc_on_exit: bool, reuse_state: bool) -> T {
+    if reuse_state {
+        if let Some(state) = LocalState::unpark() {
+            state.adopt();
+        }
+    }
+
+    // The cleanup doesn't touch anything, which `f` could have left inconsistent.
+    let res = panic::catch_unwind(AssertUnwindSafe(f));
+
+    if reuse_state {
+        // Parking exports the garbage and keeps the hazards, which are freed on detachment.
+        LocalState::detach().park();
+    } else {
+        local::export_garbage();
+        local::free_hazards();
+    }
+    if gc_on_exit {
+        let _ = try_gc();
+    }
+
+    match res {
+        Ok(x) => x,
+        Err(err) => panic::resume_unwind(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic;
+    use Atomic;
+
+    #[test]
+    fn exports_garbage() {
+        let a = Atomic::new(Some(Box::new(1)));
+        run(|| a.store(Some(Box::new(2)), atomic::Ordering::Release), false, false);
+        assert!(!local::has_garbage());
+
+        let a = spawn(move || {
+            a.store(Some(Box::new(3)), atomic::Ordering::Release);
+            a
+        }).join().unwrap();
+        assert_eq!(*a.load(atomic::Ordering::Acquire).unwrap(), 3);
+    }
+
+    #[test]
+    fn cleanup_on_panic() {
+        let a = Atomic::new(Some(Box::new(1)));
+        let res = panic::catch_unwind(AssertUnwindSafe(|| run(|| {
+            a.store(Some(Box::new(2)), atomic::Ordering::Release);
+            panic!("Oh no");
+        }, true, false)));
+
+        assert!(res.is_err());
+        assert!(!local::has_garbage());
+    }
+
+    #[test]
+    fn panic_hook() {
+        install_panic_hook();
+        install_panic_hook();
+
+        spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            let res = panic::catch_unwind(AssertUnwindSafe(|| {
+                a.store(Some(Box::new(2)), atomic::Ordering::Release);
+                assert!(local::has_garbage());
+                panic!("Oh no");
+            }));
+
+            // The thread survived the panic, but its garbage is exported already.
+            assert!(res.is_err());
+            assert!(!local::has_garbage());
+        }).join().unwrap();
+    }
+
+    #[test]
+    fn reuse_state() {
+        let spawn = || Builder::new().reuse_state(true).spawn(|| {
+            let a = Atomic::new(Some(Box::new(1)));
+            let _guard = a.load(atomic::Ordering::Acquire);
+            a.store(Some(Box::new(2)), atomic::Ordering::Release);
+            assert!(local::has_garbage());
+        }).unwrap();
+
+        spawn().join().unwrap();
+        spawn().join().unwrap();
+
+        // The last thread parked its state, after exporting the garbage.
+        let state = LocalState::unpark().unwrap();
+        assert_eq!(state.cached_garbage(), 0);
+        assert!(state.cached_hazards() > 0);
+    }
+
+    #[test]
+    fn scoped() {
+        let x = 42;
+        thread::scope(|s| {
+            let h = spawn_scoped(s, || {
+                let a = Atomic::new(Some(Box::new(x)));
+                *a.load(atomic::Ordering::Acquire).unwrap()
+            });
+            assert_eq!(h.join().unwrap(), 42);
+        });
+    }
+}
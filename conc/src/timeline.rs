@@ -0,0 +1,204 @@
+//! Timeline of garbage collection cycles.
+//!
+//! The timeline records the garbage collection cycles into a bounded in-memory ring, which can be
+//! dumped on demand, such that e.g. latency spikes of the application can be correlated with the
+//! garbage collection activity.
+//!
+//! Recording is opt-in, as it reads the clock twice per cycle. When the ring is full, the oldest
+//! cycles are dropped.
+//!
+//! # Example
+//!
+//! ```rust
+//! conc::timeline::start(1024);
+//!
+//! // ...
+//!
+//! for cycle in conc::timeline::dump() {
+//!     println!("{:?}: {} freed in {:?}", cycle.trigger, cycle.freed, cycle.end - cycle.start);
+//! }
+//! ```
+
+use parking_lot::{self, Mutex};
+use std::collections::VecDeque;
+use std::sync::atomic::{self, AtomicBool};
+use std::thread::{self, ThreadId};
+use std::time::Instant;
+use global;
+
+/// Is the timeline recording?
+static RECORDING: AtomicBool = AtomicBool::new(false);
+/// The recorded cycles.
+static RING: Mutex<Ring> = parking_lot::const_mutex(Ring {
+    cycles: VecDeque::new(),
+    capacity: 0,
+});
+
+/// A bounded ring of cycles.
+struct Ring {
+    /// The cycles, oldest first.
+    cycles: VecDeque<Cycle>,
+    /// The maximal number of cycles.
+    capacity: usize,
+}
+
+impl Ring {
+    /// Add a cycle, dropping the oldest ones if the ring is full.
+    fn push(&mut self, cycle: Cycle) {
+        self.cycles.push_back(cycle);
+        self.truncate();
+    }
+
+    /// Drop the oldest cycles exceeding the capacity.
+    fn truncate(&mut self) {
+        while self.cycles.len() > self.capacity {
+            self.cycles.pop_front();
+        }
+    }
+}
+
+/// What triggered a garbage collection cycle.
+#[derive(Copy, Clone, PartialEq, Eq, Debug)]
+pub enum Trigger {
+    /// The probabilistic collection when garbage is exported.
+    Tick,
+    /// An explicit collection (e.g. `conc::gc()` or `conc::try_gc()`).
+    Explicit,
+    /// An explicit collection with a deadline (`conc::gc_until()`).
+    Deadline,
+    /// The emergency collection, when the memory budget is exceeded.
+    Budget,
+    /// The last-ditch collection, when allocation fails (see `oom`).
+    OutOfMemory,
+    /// The collection right after urgent garbage was retired (see `conc::add_garbage_urgent()`).
+    Urgent,
+}
+
+/// A recorded garbage collection cycle.
+#[derive(Clone, Debug)]
+pub struct Cycle {
+    /// When the cycle started.
+    pub start: Instant,
+    /// When the cycle ended.
+    pub end: Instant,
+    /// What triggered the cycle.
+    pub trigger: Trigger,
+    /// The identifier of the thread, which ran the cycle.
+    pub thread: ThreadId,
+    /// The name of the thread, which ran the cycle.
+    pub thread_name: Option<String>,
+    /// The number of garbage objects pending, when the hazards were scanned.
+    pub scanned: usize,
+    /// The number of garbage objects destroyed.
+    pub freed: usize,
+}
+
+/// Start recording, keeping the last `capacity` cycles.
+///
+/// If the timeline is already recording, this changes the capacity, keeping the cycles recorded
+/// so far (up to the new capacity).
+pub fn start(capacity: usize) {
+    let _critical = global::Critical::new();
+    let mut ring = RING.lock();
+    ring.capacity = capacity;
+    ring.truncate();
+    RECORDING.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Stop recording.
+///
+/// The cycles recorded so far are kept until `clear()` is called.
+pub fn stop() {
+    RECORDING.store(false, atomic::Ordering::Relaxed);
+}
+
+/// Get the recorded cycles, oldest first.
+pub fn dump() -> Vec<Cycle> {
+    let _critical = global::Critical::new();
+    RING.lock().cycles.iter().cloned().collect()
+}
+
+/// Remove the recorded cycles.
+pub fn clear() {
+    let _critical = global::Critical::new();
+    // Release the memory, as the timeline might not be used again.
+    RING.lock().cycles = VecDeque::new();
+}
+
+/// Get the start time of a cycle, if the timeline is recording.
+pub(crate) fn begin() -> Option<Instant> {
+    if RECORDING.load(atomic::Ordering::Relaxed) {
+        Some(Instant::now())
+    } else {
+        None
+    }
+}
+
+/// Record a cycle, which started at `start`.
+///
+/// This must be called in a critical section.
+pub(crate) fn record(start: Instant, trigger: Trigger, scanned: usize, freed: usize) {
+    let thread = thread::current();
+    let cycle = Cycle {
+        start: start,
+        end: Instant::now(),
+        trigger: trigger,
+        thread: thread.id(),
+        thread_name: thread.name().map(ToOwned::to_owned),
+        scanned: scanned,
+        freed: freed,
+    };
+
+    RING.lock().push(cycle);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use garbage::Garbage;
+    use std::ptr;
+
+    #[test]
+    fn ring_bounded() {
+        let mut ring = Ring {
+            cycles: VecDeque::new(),
+            capacity: 4,
+        };
+        for i in 0..8 {
+            let now = Instant::now();
+            ring.push(Cycle {
+                start: now,
+                end: now,
+                trigger: Trigger::Tick,
+                thread: thread::current().id(),
+                thread_name: None,
+                scanned: i,
+                freed: 0,
+            });
+        }
+
+        // The oldest cycles are dropped.
+        assert_eq!(ring.cycles.iter().map(|x| x.scanned).collect::<Vec<_>>(), [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn record_cycles() {
+        start(1 << 16);
+        for _ in 0..8 {
+            global::export_garbage(vec![Garbage::new(ptr::without_provenance(0x1), |_| {})]);
+            let _ = global::gc(Trigger::Explicit);
+        }
+
+        let cycles = dump();
+        let ours: Vec<_> = cycles.iter().filter(|x| x.thread == thread::current().id()).collect();
+        assert!(!ours.is_empty());
+        for cycle in ours {
+            assert_eq!(cycle.trigger, Trigger::Explicit);
+            assert!(cycle.start <= cycle.end);
+            assert!(cycle.freed <= cycle.scanned);
+        }
+
+        stop();
+        clear();
+    }
+}
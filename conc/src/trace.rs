@@ -0,0 +1,463 @@
+//! Recording and replaying the reclamation events.
+//!
+//! The trace records the events relevant to reclamation (the debug events, see
+//! `debug::DebugEvent`) into a bounded in-memory ring, stamped with the thread they occurred in
+//! and a logical timestamp. The timestamps are strictly increasing in the order, in which the
+//! events are recorded, so the trace is a total order of the events of all threads.
+//!
+//! A recorded trace can be re-executed through `replay()`, which runs the same schedule of guards,
+//! retirements and collections against the protocol, one event at a time. This turns a rare
+//! interleaving observed once (e.g. in a failing test) into one, which can be rerun, bisected, or
+//! checked against a modified protocol.
+//!
+//! Recording is opt-in, as every guard takes a lock, while it is on. When the ring is full, the
+//! oldest events are dropped.
+//!
+//! This module is only available with feature `debug-tools`.
+//!
+//! # Example
+//!
+//! ```rust
+//! conc::trace::start(1 << 16);
+//!
+//! // ...
+//!
+//! conc::trace::stop();
+//! let events = conc::trace::dump();
+//! if let Err(violation) = conc::trace::replay(&events) {
+//!     panic!("{}", violation);
+//! }
+//! ```
+
+use debug::DebugEvent;
+use parking_lot::{self, Mutex};
+use std::collections::{HashMap, VecDeque};
+use std::sync::Arc;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize};
+use std::thread::{self, ThreadId};
+use std::{fmt, panic};
+use {global, local, Guard};
+
+/// Is the trace recording?
+static RECORDING: AtomicBool = AtomicBool::new(false);
+/// The recorded events.
+static RING: Mutex<Ring> = parking_lot::const_mutex(Ring {
+    events: VecDeque::new(),
+    capacity: 0,
+    clock: 0,
+});
+
+/// A bounded ring of events.
+struct Ring {
+    /// The events, oldest first.
+    events: VecDeque<Event>,
+    /// The maximal number of events.
+    capacity: usize,
+    /// The logical clock, i.e. the timestamp of the next event.
+    clock: u64,
+}
+
+impl Ring {
+    /// Add an event, dropping the oldest ones if the ring is full.
+    fn push(&mut self, thread: ThreadId, event: DebugEvent) {
+        self.events.push_back(Event {
+            time: self.clock,
+            thread: thread,
+            event: event,
+        });
+        self.clock += 1;
+        self.truncate();
+    }
+
+    /// Drop the oldest events exceeding the capacity.
+    fn truncate(&mut self) {
+        while self.events.len() > self.capacity {
+            self.events.pop_front();
+        }
+    }
+}
+
+/// A recorded event.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Event {
+    /// The logical timestamp.
+    ///
+    /// This is unique, and increases with every event recorded (even across `clear()`).
+    pub time: u64,
+    /// The identifier of the thread, in which the event occurred.
+    pub thread: ThreadId,
+    /// The event.
+    pub event: DebugEvent,
+}
+
+/// Start recording, keeping the last `capacity` events.
+///
+/// If the trace is already recording, this changes the capacity, keeping the events recorded so
+/// far (up to the new capacity).
+pub fn start(capacity: usize) {
+    let _critical = global::Critical::new();
+    let mut ring = RING.lock();
+    ring.capacity = capacity;
+    ring.truncate();
+    RECORDING.store(true, atomic::Ordering::Relaxed);
+}
+
+/// Stop recording.
+///
+/// The events recorded so far are kept until `clear()` is called.
+pub fn stop() {
+    RECORDING.store(false, atomic::Ordering::Relaxed);
+}
+
+/// Get the recorded events, oldest first.
+pub fn dump() -> Vec<Event> {
+    let _critical = global::Critical::new();
+    RING.lock().events.iter().cloned().collect()
+}
+
+/// Remove the recorded events.
+pub fn clear() {
+    let _critical = global::Critical::new();
+    // Release the memory, as the trace might not be used again.
+    RING.lock().events = VecDeque::new();
+}
+
+/// Is the trace recording?
+pub(crate) fn is_recording() -> bool {
+    RECORDING.load(atomic::Ordering::Relaxed)
+}
+
+/// Record an event of the current thread.
+pub(crate) fn record(event: DebugEvent) {
+    // The ring allocates, so the allocation must not collect garbage, while it is locked.
+    let _critical = global::Critical::new();
+    RING.lock().push(thread::current().id(), event);
+}
+
+/// The kind of a protocol violation found by a replay.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum ViolationKind {
+    /// The object was destroyed, while a guard protected it.
+    DestroyedWhileGuarded,
+    /// The object was retired again, before it was destroyed.
+    RetiredTwice,
+}
+
+/// A protocol violation found by a replay.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Violation {
+    /// The index of the event, at which the violation was detected.
+    pub index: usize,
+    /// The recorded address of the object.
+    pub ptr: usize,
+    /// The kind of the violation.
+    pub kind: ViolationKind,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let what = match self.kind {
+            ViolationKind::DestroyedWhileGuarded => "destroyed while guarded",
+            ViolationKind::RetiredTwice => "retired twice",
+        };
+        write!(f, "Object 0x{:x} {} (event {}).", self.ptr, what, self.index)
+    }
+}
+
+/// An object standing in for a recorded object during a replay.
+///
+/// The objects are leaked, and their destructor only marks them as destroyed, such that a
+/// premature destruction can be detected rather than causing a use-after-free.
+struct Object {
+    /// Was the object destroyed?
+    destroyed: AtomicBool,
+}
+
+impl Object {
+    /// Leak a new object.
+    fn leak() -> &'static Object {
+        Box::leak(Box::new(Object {
+            destroyed: AtomicBool::new(false),
+        }))
+    }
+
+    /// Was the object destroyed?
+    fn is_destroyed(&self) -> bool {
+        self.destroyed.load(atomic::Ordering::Acquire)
+    }
+
+    /// Mark the object as destroyed.
+    fn destroy(&'static self) {
+        self.destroyed.store(true, atomic::Ordering::Release);
+    }
+}
+
+/// The current object at some recorded address.
+///
+/// Addresses are reused by the allocator, so an address stands for a new object, once the
+/// previous one was destroyed.
+struct Incarnation {
+    /// The object.
+    object: &'static Object,
+    /// Was the object retired?
+    retired: bool,
+}
+
+/// The state of a replay shared between its threads.
+struct Replay {
+    /// The index of the event to be executed next.
+    turn: AtomicUsize,
+    /// The objects by their recorded address.
+    objects: Mutex<HashMap<usize, Incarnation>>,
+    /// The violation found, if any.
+    violation: Mutex<Option<Violation>>,
+}
+
+impl Replay {
+    /// Get the current incarnation of `ptr`, creating it, if the previous one was destroyed.
+    fn incarnation(objects: &mut HashMap<usize, Incarnation>, ptr: usize) -> &mut Incarnation {
+        let inc = objects.entry(ptr).or_insert_with(|| Incarnation {
+            object: Object::leak(),
+            retired: false,
+        });
+        if inc.object.is_destroyed() {
+            *inc = Incarnation {
+                object: Object::leak(),
+                retired: false,
+            };
+        }
+
+        inc
+    }
+
+    /// Get the object to be protected by a guard on `ptr`.
+    fn object(&self, ptr: usize) -> &'static Object {
+        Replay::incarnation(&mut self.objects.lock(), ptr).object
+    }
+
+    /// Retire the object at `ptr`.
+    fn retire(&self, index: usize, ptr: usize) {
+        let object = {
+            let mut objects = self.objects.lock();
+            let inc = Replay::incarnation(&mut objects, ptr);
+            if inc.retired {
+                self.fail(Violation {
+                    index: index,
+                    ptr: ptr,
+                    kind: ViolationKind::RetiredTwice,
+                });
+                return;
+            }
+            inc.retired = true;
+            inc.object
+        };
+
+        ::add_garbage(object, Object::destroy);
+        // Export right away, such that the collections of every thread see the garbage.
+        local::export_garbage();
+    }
+
+    /// Record a violation, stopping the replay.
+    fn fail(&self, violation: Violation) {
+        let mut lock = self.violation.lock();
+        if lock.is_none() {
+            *lock = Some(violation);
+        }
+    }
+
+    /// Has the replay stopped?
+    fn failed(&self) -> bool {
+        self.violation.lock().is_some()
+    }
+
+    /// Execute the events of a thread, each at its turn.
+    fn run(&self, events: Vec<(usize, DebugEvent)>) {
+        // The guards held, by their recorded address.
+        let mut guards: Vec<(usize, Guard<Object>)> = Vec::new();
+        for (index, event) in events {
+            loop {
+                if self.failed() {
+                    return;
+                }
+                if self.turn.load(atomic::Ordering::Acquire) == index {
+                    break;
+                }
+                thread::yield_now();
+            }
+
+            match event {
+                DebugEvent::GuardCreated { ptr } => {
+                    let object = self.object(ptr);
+                    guards.push((ptr, Guard::new(|| object)));
+                },
+                DebugEvent::GuardDropped { ptr } => {
+                    // The guard might have been created before the oldest event kept.
+                    if let Some(i) = guards.iter().rposition(|&(x, _)| x == ptr) {
+                        let (_, guard) = guards.swap_remove(i);
+                        if guard.is_destroyed() {
+                            self.fail(Violation {
+                                index: index,
+                                ptr: ptr,
+                                kind: ViolationKind::DestroyedWhileGuarded,
+                            });
+                        }
+                    }
+                },
+                DebugEvent::GarbageAdded { ptr, .. } => self.retire(index, ptr),
+                DebugEvent::GcStarted => {
+                    // A poisoned collector is the business of the recorded program, not ours.
+                    let _ = ::gc();
+                },
+                DebugEvent::GcFinished { .. }
+                | DebugEvent::HazardFreed { .. }
+                | DebugEvent::GarbageStuck { .. } => (),
+            }
+
+            self.turn.store(index + 1, atomic::Ordering::Release);
+        }
+    }
+}
+
+/// Re-execute the recorded `events` against the protocol.
+///
+/// Every thread of the trace is replayed by a thread of its own, and the events are executed one
+/// at a time, in the order given (the order of `dump()`). Guards are created and dropped, objects
+/// retired, and garbage collected, as recorded. The hazards are freed, when their guards are
+/// dropped in the replay, so `HazardFreed` is informational only.
+///
+/// The replay stops at the first violation found, i.e. an object destroyed, while a guard of the
+/// replay protected it, or an object retired twice. The recorded objects are stood in for by
+/// leaked dummies, so a violation can't cause undefined behavior.
+///
+/// Garbage of other threads might be collected during the replay, and the replay emits events of
+/// its own, so recording should be stopped first.
+///
+/// # Panics
+///
+/// This panics, if a replay thread panics (e.g. because the collector is poisoned).
+pub fn replay(events: &[Event]) -> Result<(), Violation> {
+    // Split the events by thread, keeping the order of the threads' first events.
+    let mut threads: Vec<(ThreadId, Vec<(usize, DebugEvent)>)> = Vec::new();
+    for (index, event) in events.iter().enumerate() {
+        match threads.iter_mut().find(|&&mut (thread, _)| thread == event.thread) {
+            Some(&mut (_, ref mut list)) => list.push((index, event.event)),
+            None => threads.push((event.thread, vec![(index, event.event)])),
+        }
+    }
+
+    let replay = Arc::new(Replay {
+        turn: AtomicUsize::new(0),
+        objects: parking_lot::const_mutex(HashMap::new()),
+        violation: parking_lot::const_mutex(None),
+    });
+    let handles: Vec<_> = threads.into_iter().map(|(_, list)| {
+        let replay = replay.clone();
+        thread::spawn(move || replay.run(list))
+    }).collect();
+    for handle in handles {
+        if let Err(err) = handle.join() {
+            panic::resume_unwind(err);
+        }
+    }
+
+    let violation = *replay.violation.lock();
+    match violation {
+        Some(violation) => Err(violation),
+        None => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use Atomic;
+
+    #[test]
+    fn ring_bounded() {
+        let mut ring = Ring {
+            events: VecDeque::new(),
+            capacity: 4,
+            clock: 0,
+        };
+        for _ in 0..8 {
+            ring.push(thread::current().id(), DebugEvent::GcStarted);
+        }
+
+        // The oldest events are dropped, and the timestamps go on.
+        assert_eq!(ring.events.iter().map(|x| x.time).collect::<Vec<_>>(), [4, 5, 6, 7]);
+    }
+
+    #[test]
+    fn record_guards() {
+        start(1 << 16);
+        let a = Atomic::new(Some(Box::new(1)));
+        let ptr = {
+            let guard = a.load(atomic::Ordering::Acquire).unwrap();
+            &*guard as *const i32 as usize
+        };
+        a.store(None, atomic::Ordering::Release);
+        stop();
+
+        let events = dump();
+        let me = thread::current().id();
+        let ours: Vec<_> = events.iter().filter(|x| x.thread == me).map(|x| x.event).collect();
+        let created = ours.iter().position(|&x| x == DebugEvent::GuardCreated { ptr: ptr });
+        let dropped = ours.iter().position(|&x| x == DebugEvent::GuardDropped { ptr: ptr });
+        let retired = ours.iter().position(|&x| match x {
+            DebugEvent::GarbageAdded { ptr: x, .. } => x == ptr,
+            _ => false,
+        });
+        assert!(created.unwrap() < dropped.unwrap());
+        assert!(dropped.unwrap() < retired.unwrap());
+
+        // The timestamps are strictly increasing.
+        assert!(events.windows(2).all(|x| x[0].time < x[1].time));
+    }
+
+    #[test]
+    fn replay_schedule() {
+        let a = thread::current().id();
+        let b = thread::spawn(|| thread::current().id()).join().unwrap();
+        let event = |time, thread, event| Event {
+            time: time,
+            thread: thread,
+            event: event,
+        };
+
+        // `a` holds a guard, while `b` retires the object and collects.
+        let events = [
+            event(0, a, DebugEvent::GuardCreated { ptr: 0x10 }),
+            event(1, b, DebugEvent::GarbageAdded { ptr: 0x10, size: 0 }),
+            event(2, b, DebugEvent::GcStarted),
+            event(3, b, DebugEvent::GcFinished { pending: 1 }),
+            event(4, a, DebugEvent::GuardDropped { ptr: 0x10 }),
+            event(5, a, DebugEvent::GcStarted),
+            // The address is reused by a new object.
+            event(6, b, DebugEvent::GuardCreated { ptr: 0x10 }),
+            event(7, b, DebugEvent::GuardDropped { ptr: 0x10 }),
+        ];
+        assert_eq!(replay(&events), Ok(()));
+    }
+
+    #[test]
+    fn replay_retired_twice() {
+        let me = thread::current().id();
+        let event = |time, event| Event {
+            time: time,
+            thread: me,
+            event: event,
+        };
+
+        let events = [
+            event(0, DebugEvent::GuardCreated { ptr: 0x20 }),
+            event(1, DebugEvent::GarbageAdded { ptr: 0x20, size: 0 }),
+            event(2, DebugEvent::GarbageAdded { ptr: 0x20, size: 0 }),
+            event(3, DebugEvent::GuardDropped { ptr: 0x20 }),
+        ];
+        assert_eq!(replay(&events), Err(Violation {
+            index: 2,
+            ptr: 0x20,
+            kind: ViolationKind::RetiredTwice,
+        }));
+    }
+}